@@ -1,12 +1,22 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use piston_window::Key;
+
+use audio::filter::AudioFilter;
+use audio::sync::AudioSync;
+use nes::Region;
+use window::debug::DebugView;
+use window::renderer::VideoFilter;
 
 #[derive(Parser)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Commands,
     pub input: Option<PathBuf>,
+    // opens the most recently played ROM when none is given
+    #[arg(long = "resume", global = true)]
+    pub resume: bool,
 }
 
 #[derive(Subcommand)]
@@ -16,6 +26,10 @@ pub enum Commands {
         // the output file (stdout if not provided)
         #[arg(short, long)]
         output: Option<PathBuf>,
+        // labels jump, branch, and subroutine targets and notes where they're reached from (NES
+        // only)
+        #[arg(long = "xref")]
+        xref: bool,
     },
     // load and run a ROM
     Run {
@@ -28,11 +42,210 @@ pub enum Commands {
         // runs in benchmark mode
         #[arg(short = 'b')]
         bench_mode: bool,
+        // runs this many frames with no window, sound, or input and prints a hash of each
+        #[arg(long = "headless")]
+        headless: Option<u64>,
         // displays VRAM dump
         #[arg(short = 'v')]
         dump_vram: bool,
         // starts paused
         #[arg(short = 'p')]
         pause: bool,
+        // pauses while the window doesn't have focus
+        #[arg(long = "pause-on-unfocus", value_enum, default_value_t = Switch::On)]
+        pause_on_unfocus: Switch,
+        // exits instead of pausing when the CPU jams
+        #[arg(long = "exit-on-jam")]
+        exit_on_jam: bool,
+        // records and plays back NES input as an FCEUX movie
+        #[arg(long = "fm2")]
+        fm2: bool,
+        // speed multiplier while the fast-forward key is held
+        #[arg(long = "fast-forward", default_value_t = 8.0)]
+        fast_forward_speed: f64,
+        // runs at fast-forward speed, muted, for this many seconds of emulated time after boot
+        #[arg(long = "boot-turbo-secs")]
+        boot_turbo_secs: Option<f64>,
+        // runs at fast-forward speed, muted, until the PC first reaches this address (hex)
+        #[arg(long = "boot-turbo-until", value_parser = parse_hex, conflicts_with = "boot_turbo_secs")]
+        boot_turbo_until: Option<u32>,
+        // swaps the inputs fed to controller ports 1 and 2
+        #[arg(long = "swap-controllers")]
+        swap_controllers: bool,
+        // post-processing applied to the output
+        #[arg(long = "filter", value_enum, default_value_t = VideoFilter::None)]
+        filter: VideoFilter,
+        // scales with nearest-neighbor instead of bilinear filtering
+        #[arg(long = "nearest")]
+        nearest: bool,
+        // writes a Code/Data Log of executed and read ROM bytes on exit
+        #[arg(long = "coverage")]
+        coverage: Option<PathBuf>,
+        // counts executed instructions and their cycles by opcode, written next to the save file
+        // on exit
+        #[arg(long = "profile")]
+        profile: bool,
+        // serves a line-based debug console on this TCP port
+        #[arg(long = "debug-port")]
+        debug_port: Option<u16>,
+        // periodically logs audio buffer underruns and overruns
+        #[arg(long = "audio-stats")]
+        audio_stats: bool,
+        // keeps audio in step with emulation by nudging the resampling ratio, by dropping
+        // samples, not at all, or (NES only) by having the output pull what it needs
+        #[arg(long = "audio-sync", value_enum, default_value_t = AudioSync::Resample)]
+        audio_sync: AudioSync,
+        // filters the mixed audio like a console's analog output stage, or leaves it raw; by
+        // default, like the one being emulated
+        #[arg(long = "audio-filter", value_enum)]
+        audio_filter: Option<AudioFilter>,
+        // high-pass cutoffs for --audio-filter custom, in Hz; repeat for more than one
+        #[arg(long = "audio-high-pass")]
+        audio_high_pass: Vec<f32>,
+        // the low-pass cutoff for --audio-filter custom, in Hz
+        #[arg(long = "audio-low-pass")]
+        audio_low_pass: Option<f32>,
+        // runs an NES game with NTSC or PAL timing, whatever its header says
+        #[arg(long = "region", value_enum)]
+        region: Option<Region>,
+        // overrides the cartridge RAM size from the ROM header, in KB
+        #[arg(long = "cart-ram", value_parser = parse_ram_kb)]
+        cart_ram: Option<usize>,
+        // overrides the cartridge RAM size and makes it battery-backed, in KB
+        #[arg(long = "sram", value_parser = parse_ram_kb)]
+        sram: Option<usize>,
+        // writes every emulated frame to a numbered PNG in this directory
+        #[arg(long = "dump-frames")]
+        dump_frames: Option<PathBuf>,
+        // stops dumping frames after this many (5 minutes at 60 fps by default)
+        #[arg(long = "dump-frames-max", default_value_t = 18000)]
+        dump_frames_max: u32,
+        // keeps this many seconds of history to step back through while Backspace is held
+        #[arg(long = "rewind-secs", default_value_t = 0)]
+        rewind_secs: usize,
+        // writes emulated audio to a WAV file
+        #[arg(long = "record-audio")]
+        record_audio: Option<PathBuf>,
+        // a ripping aid for NES music: enabled channels keep sounding after their length counters
+        // run out, which real hardware doesn't do, so it's for analysis rather than normal play
+        #[arg(long = "music-mode")]
+        music_mode: bool,
+        // with --music-mode, restarts envelopes that would fade a note out, as if they looped
+        #[arg(long = "music-loop", requires = "music_mode")]
+        music_loop: bool,
+        // samples host input this many times per emulated frame, running the frame in as many
+        // slices, so a game reading its controllers partway through sees the latest input;
+        // movies are recorded and played back once per frame regardless
+        #[arg(long = "input-polls", default_value_t = 1)]
+        input_polls: u32,
+        // runs exactly one emulated frame per displayed frame instead of keeping pace with the
+        // clock, so a run with the same inputs always plays out the same
+        #[arg(long = "lockstep")]
+        lockstep: bool,
+        // reads each frame's controller input from stdin instead of the keyboard and gamepads,
+        // one line per frame (see input::script for the format), and runs on a fixed timestep
+        #[arg(long = "stdin-input")]
+        stdin_input: bool,
+        // quits when stdin ends instead of carrying on with nothing pressed
+        #[arg(long = "exit-on-eof", requires = "stdin_input")]
+        exit_on_eof: bool,
+        // draws every sprite on a line instead of dropping those past the hardware's limit, which
+        // removes flicker but isn't accurate; sprite overflow and sprite 0 hit still act as if
+        // the limit were there
+        #[arg(long = "no-sprite-limit")]
+        no_sprite_limit: bool,
+        // reloads the ROM, keeping cartridge RAM, whenever the file changes on disk
+        #[arg(long = "watch")]
+        watch: bool,
+        // writes a one-shot instruction trace, next to the save file, starting when the PC first
+        // reaches this address (hex)
+        #[arg(long = "trace-from", value_parser = parse_hex)]
+        trace_from: Option<u32>,
+        // starts the trace when the byte at this address (hex) first changes instead; only work
+        // RAM is watched on the Genesis
+        #[arg(long = "trace-on-change", value_parser = parse_hex, conflicts_with = "trace_from")]
+        trace_on_change: Option<u32>,
+        // stops the trace after this many instructions
+        #[arg(long = "trace-count", default_value_t = 10000)]
+        trace_count: u32,
+        // stops the trace after the instruction at this address (hex)
+        #[arg(long = "trace-until", value_parser = parse_hex)]
+        trace_until: Option<u32>,
+        // quits, saving cartridge RAM, when this key is pressed with Ctrl; closing the window
+        // does the same
+        #[arg(long = "quit-key", value_parser = parse_key, default_value = "Q")]
+        quit_key: Key,
+        // opens a debug view in a window of its own; can be given more than once
+        #[arg(long = "debug-window", value_enum)]
+        debug_windows: Vec<DebugView>,
+        // starts executing at this address (hex) instead of the reset vector
+        #[cfg(feature = "dev")]
+        #[arg(long = "entry", value_parser = parse_hex)]
+        entry: Option<u32>,
+        // loads a file into memory before running, as <hex address>:<file>
+        #[cfg(feature = "dev")]
+        #[arg(long = "inject", value_parser = parse_injection)]
+        inject: Vec<Injection>,
+    },
+    // runs every test ROM in a directory and reports which pass, exiting nonzero on any failure
+    #[cfg(feature = "test")]
+    Suite {
+        // directory searched recursively for .nes, .com/.cim, and .json test files, and ROMs
+        // with a .frames file of expected frame hashes
+        dir: PathBuf,
+        // records this run's frame hashes in the .frames files instead of checking them
+        #[arg(long = "bless")]
+        bless: bool,
     },
 }
+
+#[derive(Copy, Clone, PartialEq, Debug, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+// RAM sizes are given in KB; each system checks them against its own address space on load
+fn parse_ram_kb(s: &str) -> Result<usize, String> {
+    let kb = s
+        .parse::<usize>()
+        .map_err(|e| format!("invalid size {}: {}", s, e))?;
+    if kb.is_power_of_two() && kb <= 2048 {
+        Ok(kb * 1024)
+    } else {
+        Err(format!("{}KB is not a power of two from 1KB to 2048KB", kb))
+    }
+}
+
+#[cfg(feature = "dev")]
+#[derive(Clone)]
+pub struct Injection {
+    pub addr: u32,
+    pub path: PathBuf,
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0x").trim_start_matches('$'), 16)
+        .map_err(|e| format!("invalid address {}: {}", s, e))
+}
+
+// piston's key names, like Q, F12, or Backspace, in any case
+fn parse_key(s: &str) -> Result<Key, String> {
+    // SDL keycodes: ASCII, then scancodes with bit 30 set
+    (0..0x80)
+        .chain(0x40000039..0x40000120)
+        .map(Key::from)
+        .find(|&key| key != Key::Unknown && format!("{:?}", key).eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("unknown key {}", s))
+}
+
+#[cfg(feature = "dev")]
+fn parse_injection(s: &str) -> Result<Injection, String> {
+    match s.split_once(':') {
+        Some((addr, path)) if !path.is_empty() => Ok(Injection {
+            addr: parse_hex(addr)?,
+            path: PathBuf::from(path),
+        }),
+        _ => Err(format!("expected <hex address>:<file>, got {}", s)),
+    }
+}
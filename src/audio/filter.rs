@@ -0,0 +1,120 @@
+use std::f32::consts::PI;
+
+use clap::ValueEnum;
+use dasp::Frame;
+
+// the NES's output stage: two first-order high-pass filters and one first-order low-pass
+const NTSC_HIGH_PASS_HZ: [f32; 2] = [90.0, 440.0];
+const NTSC_LOW_PASS_HZ: f32 = 14_000.0;
+// a model 1 Genesis rolls its output off with a single low-pass at about 3.4kHz
+const GENESIS_LOW_PASS_HZ: f32 = 3_390.0;
+
+// what the mixed audio passes through before it's output or recorded
+#[derive(Copy, Clone, PartialEq, Debug, ValueEnum)]
+pub enum AudioFilter {
+    // the raw mix
+    None,
+    // the filters of an NTSC NES
+    Ntsc,
+    // the filter of a model 1 Genesis
+    Genesis,
+    // the cutoffs given by --audio-high-pass and --audio-low-pass
+    Custom,
+}
+
+impl AudioFilter {
+    // the filter chain for frames arriving at `sample_rate`
+    pub fn chain<F: Frame<Sample = f32>>(
+        self,
+        sample_rate: f32,
+        custom_high_pass: &[f32],
+        custom_low_pass: Option<f32>,
+    ) -> FilterChain<F> {
+        let (high_pass, low_pass) = match self {
+            AudioFilter::None => (&[][..], None),
+            AudioFilter::Ntsc => (&NTSC_HIGH_PASS_HZ[..], Some(NTSC_LOW_PASS_HZ)),
+            AudioFilter::Genesis => (&[][..], Some(GENESIS_LOW_PASS_HZ)),
+            AudioFilter::Custom => (custom_high_pass, custom_low_pass),
+        };
+        FilterChain {
+            filters: high_pass
+                .iter()
+                .map(|&cutoff| OnePole::high_pass(cutoff, sample_rate))
+                .chain(
+                    low_pass
+                        .into_iter()
+                        .map(|cutoff| OnePole::low_pass(cutoff, sample_rate)),
+                )
+                .collect(),
+        }
+    }
+}
+
+// a first-order RC filter, run on each channel of the frame separately
+struct OnePole<F> {
+    high_pass: bool,
+    alpha: f32,
+    prev_input: F,
+    prev_output: F,
+}
+
+impl<F: Frame<Sample = f32>> OnePole<F> {
+    fn high_pass(cutoff: f32, sample_rate: f32) -> OnePole<F> {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        OnePole {
+            high_pass: true,
+            alpha: rc / (rc + dt),
+            prev_input: F::EQUILIBRIUM,
+            prev_output: F::EQUILIBRIUM,
+        }
+    }
+
+    fn low_pass(cutoff: f32, sample_rate: f32) -> OnePole<F> {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        OnePole {
+            high_pass: false,
+            alpha: dt / (rc + dt),
+            prev_input: F::EQUILIBRIUM,
+            prev_output: F::EQUILIBRIUM,
+        }
+    }
+
+    fn process(&mut self, input: F) -> F {
+        let alpha = self.alpha;
+        let output: F = if self.high_pass {
+            let delta: F = input.zip_map(self.prev_input, |input, prev| input - prev);
+            delta.zip_map(self.prev_output, |delta, prev| alpha * (prev + delta))
+        } else {
+            input.zip_map(self.prev_output, |input, prev| {
+                prev + alpha * (input - prev)
+            })
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+// filters applied one after the other to mono samples or multichannel frames; an empty chain
+// passes them through untouched
+pub struct FilterChain<F = f32> {
+    filters: Vec<OnePole<F>>,
+}
+
+impl<F: Frame<Sample = f32>> FilterChain<F> {
+    pub fn process(&mut self, frame: F) -> F {
+        self.filters
+            .iter_mut()
+            .fold(frame, |frame, filter| filter.process(frame))
+    }
+}
+
+impl<F> Default for FilterChain<F> {
+    fn default() -> FilterChain<F> {
+        FilterChain {
+            filters: Vec::new(),
+        }
+    }
+}
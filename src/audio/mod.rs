@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub mod filter;
+pub mod output;
+pub mod pull;
+pub mod sync;
+pub mod wav;
+
+pub trait AudioSink {
+    fn push_samples(&mut self, samples: &[f32]);
+    fn close(&mut self) {}
+
+    // (underruns, overruns) for sinks that feed a real-time output
+    fn buffer_stats(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+// counters shared between an output callback and the emulation thread
+#[derive(Default)]
+pub struct BufferStats {
+    underruns: AtomicUsize,
+    overruns: AtomicUsize,
+}
+
+impl BufferStats {
+    // the callback padded its output with silence
+    pub fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // samples were dropped because the buffer got too far ahead
+    pub fn overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> (usize, usize) {
+        (
+            self.underruns.load(Ordering::Relaxed),
+            self.overruns.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,200 @@
+extern crate rb;
+
+use std::sync::{Arc, Mutex};
+
+use portaudio::*;
+
+use audio::pull::SampleBuffer;
+use audio::sync::{self, downsample, AudioSync};
+use audio::{AudioSink, BufferStats};
+
+use self::rb::{Producer, RB, RbConsumer, RbInspector, RbProducer, SpscRb};
+
+const CHANNELS: i32 = 1;
+pub const OUTPUT_HZ: f64 = 44_100.0;
+const MAX_BUFFER_FRAMES: usize = 3;
+const UNDERRUN_HINT_THRESHOLD: usize = 10;
+
+pub type OutputStream = Stream<NonBlocking, Output<f32>>;
+
+pub struct PortAudioSink {
+    output_buffer: Producer<f32>,
+    stream: OutputStream,
+    stats: Arc<BufferStats>,
+    log_stats: bool,
+    stats_report_ticks: usize,
+    ticks_since_report: usize,
+    last_report: (usize, usize),
+}
+
+impl PortAudioSink {
+    // `ticks_per_sample` is how many of the samples pushed to it make one output sample
+    pub fn new(
+        pa: PortAudio,
+        ticks_per_sample: f64,
+        log_stats: bool,
+        audio_sync: AudioSync,
+    ) -> Result<PortAudioSink, Error> {
+        let ticks_per_frame = (ticks_per_sample * OUTPUT_HZ / 60.0) as usize;
+        // the fill level rate control steers towards
+        let target_buffer_ticks = ticks_per_frame * 2;
+        let buffer = SpscRb::new(500_000);
+        let (buffer_producer, buffer_consumer) = (buffer.producer(), buffer.consumer());
+
+        let mut resample_data = Box::new(vec![0.0; 20_000]);
+        let inspector = buffer;
+        let stats = Arc::new(BufferStats::default());
+        let callback_stats = stats.clone();
+        // the fraction of a tick left over from the last callback
+        let mut tick_remainder = 0.0;
+
+        let callback = move |OutputStreamCallbackArgs { buffer, frames, .. }| {
+            if audio_sync != AudioSync::None {
+                while inspector.count() > ticks_per_frame * MAX_BUFFER_FRAMES {
+                    buffer_consumer.skip(ticks_per_frame).unwrap();
+                    callback_stats.overrun();
+                }
+            }
+            let ticks_per_output_sample = match audio_sync {
+                AudioSync::Resample | AudioSync::Pull => {
+                    sync::ticks_per_sample(ticks_per_sample, inspector.count(), target_buffer_ticks)
+                }
+                AudioSync::Drop | AudioSync::None => ticks_per_sample,
+            };
+            let ticks = ticks_per_output_sample * frames as f64;
+            let ticks_to_read = match audio_sync {
+                AudioSync::Resample | AudioSync::Pull | AudioSync::None => {
+                    let ticks = ticks + tick_remainder;
+                    tick_remainder = ticks.fract();
+                    inspector.count().min(ticks.floor() as usize)
+                }
+                AudioSync::Drop => {
+                    if inspector.count() > ticks_per_frame {
+                        inspector.count().min(ticks.floor() as usize)
+                    } else {
+                        inspector.count().min(ticks.ceil() as usize)
+                    }
+                }
+            };
+            // an empty buffer means emulation is paused, not that it fell behind
+            if ticks_to_read > 0 && ticks_to_read < ticks.floor() as usize {
+                callback_stats.underrun();
+            }
+            let ticks_to_read = ticks_to_read.min(resample_data.len());
+            buffer_consumer.read_blocking(&mut resample_data[0..ticks_to_read]);
+            // a short read fills only as much of the output as it covers, then goes silent
+            let samples =
+                ((ticks_to_read as f64 / ticks_per_output_sample).round() as usize).min(frames);
+            if samples > 0 {
+                downsample(&resample_data[0..ticks_to_read], &mut buffer[0..samples]);
+            }
+            for sample in buffer.iter_mut().take(frames).skip(samples) {
+                *sample = 0.0;
+            }
+            Continue
+        };
+        let settings = pa.default_output_stream_settings::<f32>(
+            CHANNELS,
+            OUTPUT_HZ,
+            FRAMES_PER_BUFFER_UNSPECIFIED,
+        )?;
+        let mut stream = pa.open_non_blocking_stream(settings, callback)?;
+        stream.start()?;
+
+        Ok(PortAudioSink {
+            output_buffer: buffer_producer,
+            stream,
+            stats,
+            log_stats,
+            // about five seconds' worth
+            stats_report_ticks: ticks_per_frame * 60 * 5,
+            ticks_since_report: 0,
+            last_report: (0, 0),
+        })
+    }
+}
+
+impl AudioSink for PortAudioSink {
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.output_buffer.write_blocking(samples);
+        if self.log_stats {
+            self.ticks_since_report += samples.len();
+            if self.ticks_since_report >= self.stats_report_ticks {
+                self.ticks_since_report = 0;
+                self.report_stats();
+            }
+        }
+    }
+
+    // lets the buffers already handed to PortAudio play out rather than cutting them off
+    fn close(&mut self) {
+        if let Err(e) = self.stream.stop().and_then(|()| self.stream.close()) {
+            error!(target: "audio", "couldn't close audio output: {}", e);
+        }
+    }
+
+    fn buffer_stats(&self) -> Option<(usize, usize)> {
+        Some(self.stats.get())
+    }
+}
+
+impl PortAudioSink {
+    fn report_stats(&mut self) {
+        let (underruns, overruns) = self.stats.get();
+        let new_underruns = underruns - self.last_report.0;
+        let new_overruns = overruns - self.last_report.1;
+        self.last_report = (underruns, overruns);
+        if new_underruns > 0 || new_overruns > 0 {
+            warn!(target: "audio", "audio buffer: {} underruns, {} overruns ({} and {} total)",
+                  new_underruns, new_overruns, underruns, overruns);
+        }
+        if new_underruns > UNDERRUN_HINT_THRESHOLD {
+            warn!(target: "audio", "frequent audio underruns; if emulation is running at full speed, try increasing the audio output latency");
+        }
+    }
+}
+
+// an output whose callback takes its samples straight from a sample buffer the emulator fills,
+// rather than having them pushed to it
+pub struct PullOutput {
+    stream: OutputStream,
+}
+
+impl PullOutput {
+    // `ticks_per_sample` is how many of the samples put in the buffer make one output sample
+    pub fn new(
+        pa: PortAudio,
+        pulled: Arc<Mutex<SampleBuffer>>,
+        ticks_per_sample: f64,
+    ) -> Result<PullOutput, Error> {
+        let target_buffer_ticks = (ticks_per_sample * OUTPUT_HZ / 60.0) as usize * 2;
+        let callback = move |OutputStreamCallbackArgs { buffer, frames, .. }| {
+            let mut pulled = pulled.lock().unwrap();
+            let fill = pulled.len();
+            pulled.set_ticks_per_sample(sync::ticks_per_sample(
+                ticks_per_sample,
+                fill,
+                target_buffer_ticks,
+            ));
+            let samples = pulled.drain(&mut buffer[0..frames]);
+            for sample in buffer.iter_mut().take(frames).skip(samples) {
+                *sample = 0.0;
+            }
+            Continue
+        };
+        let settings = pa.default_output_stream_settings::<f32>(
+            CHANNELS,
+            OUTPUT_HZ,
+            FRAMES_PER_BUFFER_UNSPECIFIED,
+        )?;
+        let mut stream = pa.open_non_blocking_stream(settings, callback)?;
+        stream.start()?;
+        Ok(PullOutput { stream })
+    }
+
+    pub fn close(&mut self) {
+        if let Err(e) = self.stream.stop().and_then(|()| self.stream.close()) {
+            error!(target: "audio", "couldn't close audio output: {}", e);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+use dasp::interpolate::linear::Linear;
+use dasp::interpolate::Interpolator;
+
+// about a third of a second of NES mix; past this, the oldest is dropped if nothing drains it
+const MAX_BUFFERED: usize = 1 << 18;
+
+// mixed samples waiting to be taken at the output rate, interpolated between the two mixed
+// samples either side of each output sample as it's taken, so each drain picks up exactly where
+// the last left off
+pub struct SampleBuffer {
+    mix: VecDeque<f32>,
+    ticks_per_sample: f64,
+    interpolator: Linear<f32>,
+    // how far past the interpolator's left sample the next output sample falls, in mixed samples
+    position: f64,
+    underruns: usize,
+    overruns: usize,
+}
+
+impl SampleBuffer {
+    // `ticks_per_sample` is how many mixed samples make one output sample
+    pub fn new(ticks_per_sample: f64) -> SampleBuffer {
+        SampleBuffer {
+            mix: VecDeque::new(),
+            ticks_per_sample,
+            interpolator: Linear::new(0.0, 0.0),
+            // the first output sample is the first mixed one, once it and the next are taken in
+            position: 2.0,
+            underruns: 0,
+            overruns: 0,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        self.mix.extend(samples);
+        if self.mix.len() > MAX_BUFFERED {
+            self.mix.drain(..self.mix.len() - MAX_BUFFERED);
+            self.overruns += 1;
+        }
+    }
+
+    // mixed samples not yet taken
+    pub fn len(&self) -> usize {
+        self.mix.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mix.is_empty()
+    }
+
+    // for rate control, steering how fast the buffer is drained
+    pub fn set_ticks_per_sample(&mut self, ticks_per_sample: f64) {
+        self.ticks_per_sample = ticks_per_sample;
+    }
+
+    // fills as much of `out` as the mix buffered so far covers and returns how many samples that
+    // was; running out partway is an underrun, unless nothing was buffered at all, which means
+    // emulation is paused rather than behind
+    pub fn drain(&mut self, out: &mut [f32]) -> usize {
+        let was_empty = self.mix.is_empty();
+        for (filled, sample) in out.iter_mut().enumerate() {
+            while self.position >= 1.0 {
+                match self.mix.pop_front() {
+                    Some(mixed) => self.interpolator.next_source_frame(mixed),
+                    None => {
+                        if !was_empty {
+                            self.underruns += 1;
+                        }
+                        return filled;
+                    }
+                }
+                self.position -= 1.0;
+            }
+            *sample = self.interpolator.interpolate(self.position);
+            self.position += self.ticks_per_sample;
+        }
+        out.len()
+    }
+
+    // (underruns, overruns)
+    pub fn stats(&self) -> (usize, usize) {
+        (self.underruns, self.overruns)
+    }
+}
@@ -0,0 +1,41 @@
+use clap::ValueEnum;
+
+// the largest change to the resampling ratio, too small to hear as a change in pitch
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+// how the output keeps up when emulation runs slightly faster or slower than the audio device
+#[derive(Copy, Clone, PartialEq, Debug, ValueEnum)]
+pub enum AudioSync {
+    // nudges the resampling ratio to hold the buffer near a target fill level
+    Resample,
+    // skips buffered input once it gets too far ahead, and reads short when it falls behind
+    Drop,
+    // lets the buffer drift
+    None,
+    // NES only: the output callback takes exactly the samples it needs, resampled as they're
+    // taken, with the ratio nudged as for resample
+    Pull,
+}
+
+// dynamic rate control: the input ticks to consume per output sample, raised when the buffer
+// is fuller than its target and lowered when it's emptier
+pub fn ticks_per_sample(nominal: f64, fill: usize, target: usize) -> f64 {
+    let error = (fill as f64 - target as f64) / target as f64;
+    nominal * (1.0 + MAX_RATE_ADJUST * error.clamp(-1.0, 1.0))
+}
+
+// averages the input into output.len() samples, each covering an equal share of it
+pub fn downsample(input: &[f32], output: &mut [f32]) {
+    let step = input.len() as f64 / output.len() as f64;
+    for (i, sample) in output.iter_mut().enumerate() {
+        let start = ((i as f64 * step) as usize).min(input.len());
+        let end = (((i + 1) as f64 * step) as usize)
+            .max(start + 1)
+            .min(input.len());
+        *sample = if start < end {
+            input[start..end].iter().sum::<f32>() / (end - start) as f32
+        } else {
+            0.0
+        };
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom};
+use std::io::prelude::*;
+use std::path::Path;
+
+use simple_error::{SimpleError, SimpleResult};
+
+use audio::AudioSink;
+
+const HEADER_LEN: u32 = 44;
+
+// 16-bit mono PCM, averaging the emulator's per-tick samples down to the output rate
+pub struct WavSink {
+    out: BufWriter<File>,
+    sample_rate: u32,
+    ticks_per_sample: f64,
+    ticks: f64,
+    sum: f32,
+    count: u32,
+    data_len: u32,
+    closed: bool,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, sample_rate: u32, ticks_per_sample: f64) -> SimpleResult<WavSink> {
+        let file = File::create(path).map_err(|io_error| SimpleError::new(io_error.to_string()))?;
+        let mut sink = WavSink {
+            out: BufWriter::new(file),
+            sample_rate,
+            ticks_per_sample,
+            ticks: 0.0,
+            sum: 0.0,
+            count: 0,
+            data_len: 0,
+            closed: false,
+        };
+        sink.write_header()
+            .map_err(|io_error| SimpleError::new(io_error.to_string()))?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let out = &mut self.out;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&self.sample_rate.to_le_bytes())?;
+        out.write_all(&(self.sample_rate * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&self.data_len.to_le_bytes())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn push_samples(&mut self, samples: &[f32]) {
+        for sample in samples {
+            self.sum += sample;
+            self.count += 1;
+            self.ticks += 1.0;
+            if self.ticks >= self.ticks_per_sample {
+                self.ticks -= self.ticks_per_sample;
+                let value = (self.sum / self.count as f32).max(-1.0).min(1.0);
+                let pcm = (value * i16::MAX as f32) as i16;
+                if let Err(io_error) = self.out.write_all(&pcm.to_le_bytes()) {
+                    error!(target: "apu", "audio recording failed: {}", io_error);
+                }
+                self.data_len += 2;
+                self.sum = 0.0;
+                self.count = 0;
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            if let Err(io_error) = self.finish() {
+                error!(target: "apu", "audio recording failed: {}", io_error);
+            }
+        }
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
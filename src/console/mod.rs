@@ -0,0 +1,146 @@
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+
+use window::Cpu;
+
+// a line typed into the console, with somewhere to send the response
+struct Request {
+    line: String,
+    response: Sender<String>,
+}
+
+// line-based TCP console; connections are served on background threads, but commands only run
+// when the window loop polls, so they never race the emulator
+pub struct Console {
+    receiver: Receiver<Request>,
+    state: Vec<u8>,
+}
+
+impl Console {
+    pub fn listen(port: u16) -> std::io::Result<Console> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!(target: "console", "debug console listening on port {}", port);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        thread::spawn(move || serve(stream, sender));
+                    }
+                    Err(e) => warn!(target: "console", "connection failed: {}", e),
+                }
+            }
+        });
+        Ok(Console {
+            receiver,
+            state: Vec::new(),
+        })
+    }
+
+    // runs any pending commands; called between frames
+    pub fn poll(&mut self, cpu: &mut dyn Cpu, pause: &mut bool) {
+        while let Ok(request) = self.receiver.try_recv() {
+            let response = execute(&request.line, cpu, &mut self.state, pause);
+            let _ = request.response.send(response);
+        }
+    }
+}
+
+fn serve(stream: TcpStream, sender: Sender<Request>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let (response_sender, response_receiver) = mpsc::channel();
+        if sender
+            .send(Request {
+                line,
+                response: response_sender,
+            })
+            .is_err()
+        {
+            return;
+        }
+        match response_receiver.recv() {
+            Ok(response) => {
+                if writeln!(writer, "{}", response).is_err() {
+                    return;
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+pub fn execute(line: &str, cpu: &mut dyn Cpu, state: &mut Vec<u8>, pause: &mut bool) -> String {
+    let args = line.split_whitespace().collect::<Vec<_>>();
+    let result = match args.as_slice() {
+        ["peek", addr] => parse_hex(addr).map(|addr| format!("{:02X}", cpu.peek(addr))),
+        ["poke", addr, val] => parse_hex(addr).and_then(|addr| {
+            parse_hex(val).map(|val| {
+                cpu.poke(addr, val as u8);
+                String::from("ok")
+            })
+        }),
+        ["vpeek", space, addr] => parse_hex(addr)
+            .and_then(|addr| cpu.peek_video(space, addr))
+            .map(|val| format!("{:02X}", val)),
+        ["vpoke", space, addr, val] => parse_hex(addr).and_then(|addr| {
+            parse_hex(val)
+                .and_then(|val| cpu.poke_video(space, addr, val as u8))
+                .map(|_| String::from("ok"))
+        }),
+        ["regs"] => Ok(cpu.registers()),
+        ["break", addr] => parse_hex(addr).map(|addr| {
+            cpu.add_breakpoint(addr);
+            String::from("ok")
+        }),
+        ["unbreak", addr] => parse_hex(addr).map(|addr| {
+            cpu.remove_breakpoint(addr);
+            String::from("ok")
+        }),
+        ["continue"] => {
+            *pause = false;
+            Ok(String::from("ok"))
+        }
+        ["state", "save"] => {
+            state.clear();
+            cpu.save_state(state);
+            Ok(format!("saved {} bytes", state.len()))
+        }
+        ["state", "load"] => {
+            if state.is_empty() {
+                Err(String::from("no saved state"))
+            } else {
+                cpu.load_state(&mut state.as_slice());
+                Ok(String::from("ok"))
+            }
+        }
+        ["disasm", addr, count] => parse_hex(addr).and_then(|addr| {
+            count
+                .parse::<usize>()
+                .map(|count| cpu.disassemble(addr, count).join("\n"))
+                .map_err(|e| format!("invalid count {}: {}", count, e))
+        }),
+        _ => Err(format!("unknown command: {}", line)),
+    };
+    match result {
+        Ok(response) => response,
+        Err(message) => format!("error: {}", message),
+    }
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0x").trim_start_matches('$'), 16)
+        .map_err(|e| format!("invalid number {}: {}", s, e))
+}
@@ -1,13 +1,15 @@
 extern crate array_init;
 
-use std::mem::swap;
-
+use image::RgbImage;
 use piston_window::*;
 
 use window::Cpu;
 
+use self::thumbnail::{read_thumbnail, skip_thumbnail, write_thumbnail};
 use super::record::Recorder;
 
+pub mod thumbnail;
+
 const SAVE_KEYS: [Key; 10] = [
     Key::F1,
     Key::F2,
@@ -27,13 +29,29 @@ pub struct Control<const B: usize> {
     right_shift_state: bool,
     left_ctrl_state: bool,
     right_ctrl_state: bool,
+    left_alt_state: bool,
+    right_alt_state: bool,
     pub reset: bool,
     pub pause: bool,
     pub step: bool,
     pub input_overlay: bool,
+    pub swap_controllers: bool,
+    pub export_tilesheet: Option<u8>,
+    // Some(whether to keep cartridge RAM) when a reload from disk was asked for
+    pub reload: Option<bool>,
+    // pressed with Ctrl to quit
+    pub quit_key: Key,
+    pub quit: bool,
     pub render_layers: usize,
     pub debug_cpu: bool,
     pub debug_video: bool,
+    pub fast_forward_speed: f64,
+    // what to go back to once fast-forward is let go
+    speed_before_fast_forward: Option<f64>,
+    // while held, frames are rewound instead of run
+    pub rewinding: bool,
+    // the slot just saved to, for the menu to pick up its new thumbnail
+    pub saved_slot: Option<usize>,
 }
 
 impl<const B: usize> Control<B> {
@@ -44,13 +62,24 @@ impl<const B: usize> Control<B> {
             right_shift_state: false,
             left_ctrl_state: false,
             right_ctrl_state: false,
+            left_alt_state: false,
+            right_alt_state: false,
             reset: false,
             pause: false,
             step: false,
             input_overlay: false,
+            swap_controllers: false,
+            export_tilesheet: None,
+            reload: None,
+            quit_key: Key::Q,
+            quit: false,
             render_layers: 0,
             debug_cpu: false,
-            debug_video: false
+            debug_video: false,
+            fast_forward_speed: 8.0,
+            speed_before_fast_forward: None,
+            rewinding: false,
+            saved_slot: None,
         }
     }
 
@@ -66,12 +95,15 @@ impl<const B: usize> Control<B> {
             for (i, key) in SAVE_KEYS.iter().enumerate() {
                 if *key == key_pressed {
                     if self.left_shift_state || self.right_shift_state {
-                        let mut vec = Vec::new();
-                        swap(&mut self.states[i], &mut vec);
-                        cpu.load_state(&mut vec.as_slice());
-                        swap(&mut self.states[i], &mut vec);
+                        cpu.load_state(&mut skip_thumbnail(&self.states[i]));
                     } else {
-                        cpu.save_state(&mut self.states[i]);
+                        let mut state = Vec::new();
+                        if let Some(frame) = cpu.frame_image() {
+                            write_thumbnail(&frame, &mut state);
+                        }
+                        cpu.save_state(&mut state);
+                        self.states[i] = state;
+                        self.saved_slot = Some(i);
                     }
                 }
             }
@@ -79,7 +111,12 @@ impl<const B: usize> Control<B> {
                 self.reset = true;
             }
             if key_pressed == Key::S && (self.left_ctrl_state || self.right_ctrl_state) {
-                recorder.toggle(frame_count);
+                // shift starts the movie from the current state rather than power-on
+                recorder.toggle(
+                    cpu,
+                    frame_count,
+                    self.left_shift_state || self.right_shift_state,
+                );
             }
             if key_pressed == Key::P
                 && (self.left_ctrl_state || self.right_ctrl_state)
@@ -87,17 +124,44 @@ impl<const B: usize> Control<B> {
             {
                 self.pause = !self.pause;
             } else if key_pressed == Key::P && (self.left_ctrl_state || self.right_ctrl_state) {
-                recorder.toggle_playback(frame_count);
+                recorder.toggle_playback(cpu, frame_count);
+            }
+            if key_pressed == Key::L && (self.left_ctrl_state || self.right_ctrl_state) {
+                // shift starts the reloaded ROM with fresh cartridge RAM
+                self.reload = Some(!(self.left_shift_state || self.right_shift_state));
+            }
+            if key_pressed == self.quit_key && (self.left_ctrl_state || self.right_ctrl_state) {
+                self.quit = true;
             }
             if key_pressed == Key::I && (self.left_ctrl_state || self.right_ctrl_state) {
                 self.input_overlay = !self.input_overlay;
             }
+            if key_pressed == Key::X && (self.left_ctrl_state || self.right_ctrl_state) {
+                self.swap_controllers = !self.swap_controllers;
+            }
+            if key_pressed == Key::T && (self.left_ctrl_state || self.right_ctrl_state) {
+                // shift and alt pick the palette line
+                self.export_tilesheet = Some(
+                    if self.left_shift_state || self.right_shift_state { 1 } else { 0 }
+                        | if self.left_alt_state || self.right_alt_state { 2 } else { 0 },
+                );
+            }
             if key_pressed == Key::Equals {
                 cpu.increase_speed();
             }
             if key_pressed == Key::Minus {
                 cpu.decrease_speed();
             }
+            if key_pressed == Key::Backquote {
+                // a held key repeats, by which point the speed is already the fast one
+                if self.speed_before_fast_forward.is_none() {
+                    self.speed_before_fast_forward = Some(cpu.speed());
+                }
+                cpu.set_speed(self.fast_forward_speed);
+            }
+            if key_pressed == Key::Backspace {
+                self.rewinding = true;
+            }
             if key_pressed == Key::LeftBracket {
                 self.render_layers = self.render_layers.wrapping_sub(1);
             }
@@ -123,15 +187,29 @@ impl<const B: usize> Control<B> {
 
         if let Some(Button::Keyboard(key_released)) = event.release_args() {
             self.process_modifier_keys(key_released, false);
+            if key_released == Key::Backquote {
+                if let Some(speed) = self.speed_before_fast_forward.take() {
+                    cpu.set_speed(speed);
+                }
+            }
+            if key_released == Key::Backspace {
+                self.rewinding = false;
+            }
         }
     }
 
+    pub fn slot_thumbnail(&self, slot: usize) -> Option<RgbImage> {
+        read_thumbnail(&self.states[slot])
+    }
+
     fn process_modifier_keys(&mut self, key_pressed: Key, state: bool) {
         match key_pressed {
             Key::RShift => self.right_shift_state = state,
             Key::LShift => self.left_shift_state = state,
             Key::RCtrl => self.right_ctrl_state = state,
             Key::LCtrl => self.left_ctrl_state = state,
+            Key::RAlt => self.right_alt_state = state,
+            Key::LAlt => self.left_alt_state = state,
             _ => (),
         }
     }
@@ -0,0 +1,51 @@
+use bytes::BufMut;
+use image::imageops;
+use image::imageops::FilterType;
+use image::RgbImage;
+
+// a save state can start with a small picture of the screen it was saved from, so slots can be
+// told apart without loading them: "THMB", width and height (big-endian u16s), then the RGB
+// pixels. States without one are read as they are.
+const MAGIC: &[u8; 4] = b"THMB";
+pub const THUMBNAIL_WIDTH: u32 = 64;
+pub const THUMBNAIL_HEIGHT: u32 = 60;
+
+pub fn write_thumbnail(frame: &RgbImage, out: &mut Vec<u8>) {
+    let thumbnail = imageops::resize(
+        frame,
+        THUMBNAIL_WIDTH,
+        THUMBNAIL_HEIGHT,
+        FilterType::Triangle,
+    );
+    out.put_slice(MAGIC);
+    out.put_u16(THUMBNAIL_WIDTH as u16);
+    out.put_u16(THUMBNAIL_HEIGHT as u16);
+    out.put_slice(&thumbnail);
+}
+
+// only looks at the header block, so it's cheap whatever the size of the state after it
+pub fn read_thumbnail(state: &[u8]) -> Option<RgbImage> {
+    split(state)
+        .and_then(|(width, height, pixels, _)| RgbImage::from_raw(width, height, pixels.to_vec()))
+}
+
+// what to hand to load_state
+pub fn skip_thumbnail(state: &[u8]) -> &[u8] {
+    match split(state) {
+        Some((_, _, _, rest)) => rest,
+        None => state,
+    }
+}
+
+fn split(state: &[u8]) -> Option<(u32, u32, &[u8], &[u8])> {
+    if state.len() < 8 || state[..4] != MAGIC[..] {
+        return None;
+    }
+    let width = u32::from(u16::from_be_bytes([state[4], state[5]]));
+    let height = u32::from(u16::from_be_bytes([state[6], state[7]]));
+    let end = 8 + (width * height * 3) as usize;
+    if state.len() < end {
+        return None;
+    }
+    Some((width, height, &state[8..end], &state[end..]))
+}
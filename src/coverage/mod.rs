@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::Range;
+use std::path::Path;
+
+use simple_error::{SimpleError, SimpleResult};
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+
+// one byte of flags per ROM byte, laid out like an FCEUX/Mesen Code/Data Log
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Coverage {
+    pub fn new(rom_size: usize) -> Coverage {
+        Coverage {
+            flags: vec![0; rom_size],
+        }
+    }
+
+    pub fn mark(&mut self, offset: usize, len: usize, flag: u8) {
+        let end = (offset + len).min(self.flags.len());
+        for flags in self.flags[offset.min(end)..end].iter_mut() {
+            *flags |= flag;
+        }
+    }
+
+    pub fn unexecuted_regions(&self, limit: usize) -> Vec<Range<usize>> {
+        let mut regions = Vec::new();
+        let mut start = None;
+        for (offset, &flags) in self.flags[..limit.min(self.flags.len())].iter().enumerate() {
+            match (start, flags & CODE > 0) {
+                (None, false) => start = Some(offset),
+                (Some(region_start), true) => {
+                    regions.push(region_start..offset);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(region_start) = start {
+            regions.push(region_start..limit.min(self.flags.len()));
+        }
+        regions
+    }
+
+    pub fn write(&self, path: &Path, program_size: usize) -> SimpleResult<()> {
+        let code = self.flags[..program_size.min(self.flags.len())]
+            .iter()
+            .filter(|&&flags| flags & CODE > 0)
+            .count();
+        let data = self.flags.iter().filter(|&&flags| flags & DATA > 0).count();
+        info!(target: "cartridge", "coverage: {} bytes executed, {} bytes read as data, {} total",
+              code, data, self.flags.len());
+        for region in self.unexecuted_regions(program_size) {
+            debug!(target: "cartridge", "never executed: {:06X}-{:06X}", region.start, region.end - 1);
+        }
+        File::create(path)
+            .and_then(|mut file| file.write_all(&self.flags))
+            .map_err(|io_error| SimpleError::new(io_error.to_string()))
+    }
+}
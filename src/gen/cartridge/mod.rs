@@ -1,13 +1,190 @@
+use std::cmp::min;
 use std::io::Read;
 
 use simple_error::{SimpleError, SimpleResult};
 
-pub fn read(src: &mut dyn Read, _save_data: Option<&mut dyn Read>) -> SimpleResult<Box<[u8]>> {
+use rom::db::{Entry, RomDb};
+use rom::RamOverrides;
+
+pub struct Cartridge {
+    pub rom: Box<[u8]>,
+    pub sram: Option<Sram>,
+    pub swap_controllers: bool,
+}
+
+// cartridge RAM from its start address; 8-bit RAM wired to one half of the data bus only
+// answers on every other address
+pub struct Sram {
+    pub start: u32,
+    pub data: Box<[u8]>,
+    pub battery: bool,
+    pub lanes: SramLanes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SramLanes {
+    Both,
+    Even,
+    Odd,
+}
+
+impl Sram {
+    pub fn contains(&self, addr: u32, size: u32) -> bool {
+        (addr..addr + size).any(|addr| self.offset(addr).is_some())
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        self.offset(addr).map_or(0, |offset| self.data[offset])
+    }
+
+    pub fn write(&mut self, addr: u32, val: u8) {
+        if let Some(offset) = self.offset(addr) {
+            self.data[offset] = val;
+        }
+    }
+
+    // the last address the RAM answers on
+    pub fn end(&self) -> u32 {
+        self.start + span(self.data.len(), self.lanes) - 1
+    }
+
+    fn offset(&self, addr: u32) -> Option<usize> {
+        let offset = addr.checked_sub(self.start)?;
+        let offset = match self.lanes {
+            SramLanes::Both => offset,
+            _ if offset % 2 == 1 => return None,
+            _ => offset / 2,
+        } as usize;
+        if offset < self.data.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+// how much of the address space RAM of a given size covers
+fn span(size: usize, lanes: SramLanes) -> u32 {
+    match lanes {
+        SramLanes::Both => size as u32,
+        _ => (size as u32 * 2).saturating_sub(1),
+    }
+}
+
+pub fn read(
+    src: &mut dyn Read,
+    save_data: Option<&mut dyn Read>,
+    ram: &RamOverrides,
+) -> SimpleResult<Cartridge> {
     let mut contents = Vec::new();
     src.read_to_end(&mut contents)
         .expect("error reading source");
     if contents[0x100..0x104] != [0x53, 0x45, 0x47, 0x41] {
         return Err(SimpleError::new("Not a Genesis/Mega Drive file."));
     }
-    Ok(contents.into_boxed_slice())
-}
\ No newline at end of file
+    let db = RomDb::bundled();
+    let db_entry = contents
+        .get(0x180..0x18E)
+        .and_then(|serial| db.gen(&String::from_utf8_lossy(serial)));
+    if let Some(entry) = db_entry {
+        info!(target: "cartridge", "ROM database: {:?}", entry);
+    }
+    let mut sram = read_sram(&contents, db_entry, ram);
+    if let Some(ref mut sram) = sram {
+        info!(
+            target: "cartridge",
+            "SRAM: {}KB at {:06X}-{:06X}{}",
+            sram.data.len() / 1024,
+            sram.start,
+            sram.end(),
+            if sram.battery { ", battery-backed" } else { "" }
+        );
+        match save_data {
+            Some(save_data) if sram.battery => {
+                let mut save = Vec::new();
+                save_data
+                    .read_to_end(&mut save)
+                    .map_err(|io_error| SimpleError::new(io_error.to_string()))?;
+                let bytes = min(save.len(), sram.data.len());
+                sram.data[..bytes].copy_from_slice(&save[..bytes]);
+                info!(target: "cartridge", "{} bytes loaded", bytes);
+            }
+            _ => (),
+        }
+    }
+    Ok(Cartridge {
+        swap_controllers: db_entry.map_or(false, |entry| entry.swap_controllers),
+        rom: contents.into_boxed_slice(),
+        sram,
+    })
+}
+
+// the header's "RA" block gives the RAM's type and address range, which the ROM database and
+// then the command line can resize; RAM has to fit in the 0x200000-0x3FFFFF half of the
+// cartridge space. Types $B0 and $B8 are 8-bit RAM on the even or odd bytes only.
+fn read_sram(contents: &[u8], db_entry: Option<&Entry>, ram: &RamOverrides) -> Option<Sram> {
+    let header = if contents.len() >= 0x1BC && contents[0x1B0..0x1B2] == [0x52, 0x41] {
+        let start = u32::from_be_bytes([
+            contents[0x1B4],
+            contents[0x1B5],
+            contents[0x1B6],
+            contents[0x1B7],
+        ]);
+        let end = u32::from_be_bytes([
+            contents[0x1B8],
+            contents[0x1B9],
+            contents[0x1BA],
+            contents[0x1BB],
+        ]);
+        let lanes = match contents[0x1B2] & 0x18 {
+            0x10 => SramLanes::Even,
+            0x18 => SramLanes::Odd,
+            _ => SramLanes::Both,
+        };
+        if start >= 0x200000 && end >= start && end <= 0x3FFFFF {
+            let size = match lanes {
+                SramLanes::Both => end - start + 1,
+                _ => (end - start) / 2 + 1,
+            };
+            Some((start, size as usize, contents[0x1B2] & 0x40 > 0, lanes))
+        } else {
+            warn!(
+                target: "cartridge",
+                "ignoring SRAM header range {:06X}-{:06X}",
+                start,
+                end
+            );
+            None
+        }
+    } else {
+        None
+    };
+    let start = header.map_or(0x200000, |(start, _, _, _)| start);
+    let mut size = header.map(|(_, size, _, _)| size);
+    let mut battery = header.map_or(false, |(_, _, battery, _)| battery);
+    let lanes = header.map_or(SramLanes::Both, |(_, _, _, lanes)| lanes);
+    if let Some(entry) = db_entry {
+        size = entry.ram.or(size);
+        battery = entry.battery.unwrap_or(battery);
+    }
+    match ram.sram.or(ram.cart_ram) {
+        Some(override_size) if start + span(override_size, lanes) > 0x400000 => warn!(
+            target: "cartridge",
+            "ignoring {}KB SRAM override, it doesn't fit from {:06X} to 3FFFFF",
+            override_size / 1024,
+            start
+        ),
+        Some(override_size) => {
+            size = Some(override_size);
+            battery |= ram.sram.is_some();
+        }
+        None => (),
+    }
+    // no RAM at all is what a size of 0 asks for
+    size.filter(|&size| size > 0).map(|size| Sram {
+        start,
+        data: vec![0; size].into_boxed_slice(),
+        battery,
+        lanes,
+    })
+}
@@ -0,0 +1,48 @@
+use bytes::{Buf, BufMut};
+
+// the 68000 and Z80 are clocked by dividing the 53.69MHz master clock by 7 and 15; the VDP
+// divides it itself, by 4 or 5 depending on the display width
+pub const M68K_DIVIDER: u32 = 7;
+pub const Z80_DIVIDER: u32 = 15;
+
+// counts master clock ticks, so each processor's clock keeps its phase relative to the others
+// even while that processor is halted
+pub struct MasterClock {
+    ticks: u64,
+    z80_phase: u32,
+}
+
+impl MasterClock {
+    pub fn new() -> MasterClock {
+        MasterClock {
+            ticks: 0,
+            z80_phase: 0,
+        }
+    }
+
+    // advances one master clock tick, returning whether the Z80's clock ticks with it
+    pub fn tick(&mut self) -> bool {
+        self.ticks = self.ticks.wrapping_add(1);
+        self.z80_phase += 1;
+        if self.z80_phase == Z80_DIVIDER {
+            self.z80_phase = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_u64(self.ticks);
+        out.put_u32(self.z80_phase);
+    }
+
+    pub fn load_state(&mut self, state: &mut dyn Buf) {
+        self.ticks = state.get_u64();
+        self.z80_phase = state.get_u32();
+    }
+}
@@ -1,25 +1,38 @@
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::convert::TryFrom;
+use std::fs::File;
 use std::fmt::UpperHex;
+use std::io::Write;
 use std::marker::PhantomData;
 use std::ops::{AddAssign, Range, Shl, Shr, Sub, SubAssign};
+use std::path::Path;
 
-use bytes::Buf;
+use bytes::{Buf, BufMut};
 use gfx_device_gl::Device;
+use image::{RgbImage, RgbaImage};
 use log::Level;
 use num_integer::Integer;
 use num_traits::{PrimInt, Signed, WrappingAdd, WrappingSub};
 use piston_window::*;
+use simple_error::{SimpleError, SimpleResult};
 
+use audio::AudioSink;
+use audio::filter::FilterChain;
+use coverage::{Coverage, CODE, DATA};
+use gen::cartridge::Sram;
+use gen::clock::{MasterClock, M68K_DIVIDER};
 use gen::m68k::opcodes::{
     AddressingMode, BitNum, brief_extension_word, Condition, Direction, ExchangeMode, opcode,
     Opcode, OperandDirection, OperandMode, Size,
 };
-use gen::vdp::bus::VdpBus;
+use gen::vdp::bus::{AddrTarget, VdpBus};
 use gen::vdp::Vdp;
+use gen::ym2612;
 use gen::z80;
 use input::ControllerState;
+use profile::Profile;
+use trace::Trace;
 use window;
 use window::Cpu as wcpu;
 
@@ -27,6 +40,8 @@ pub mod disassembler;
 pub mod opcodes;
 
 const MASTER_CLOCK_TICKS_PER_SECOND: f64 = 53_693_175.0;
+// 262 lines of 3420 master clock ticks
+const MASTER_CLOCK_TICKS_PER_FRAME: f64 = 896_040.0;
 
 trait DataSize: TryFrom<u32> + PrimInt + UpperHex {
     fn address_size() -> u32;
@@ -403,6 +418,7 @@ pub struct Cpu<'a> {
     pc: u32,
     cartridge: &'a Box<[u8]>,
     internal_ram: Box<[u8]>,
+    sram: Option<Sram>,
 
     inputs: [u8; 2],
     controller_th_bit: [u8; 4],
@@ -413,6 +429,7 @@ pub struct Cpu<'a> {
     instrumented: bool,
     cycle_count: u64,
     stopped: bool,
+    jammed: bool,
 
     pub speed_adj: f64,
 
@@ -420,16 +437,27 @@ pub struct Cpu<'a> {
     vdp_bus: &'a RefCell<VdpBus>,
 
     z80: z80::Cpu<'a>,
+    clock: MasterClock,
+
+    sinks: Vec<Box<dyn AudioSink>>,
+    filter: FilterChain,
+    muted: bool,
 
     pc_watches: Box<HashSet<u32>>,
     pc_breaks: Box<HashSet<u32>>,
     pc_ignores: Box<Vec<Range<u32>>>,
+    pc_trigger: Option<u32>,
+    pc_triggered: bool,
     memory_watches: Box<HashSet<u32>>,
     memory_breaks: Box<HashSet<u32>>,
     pause_on_frame_end: bool,
 
     test_ram_only: bool,
 
+    coverage: Option<Coverage>,
+    profile: Option<Profile<Opcode>>,
+    trace: Option<Trace>,
+
     phantom: PhantomData<&'a u8>,
 }
 
@@ -440,6 +468,7 @@ const NEGATIVE: u16 = 0b1000;
 const EXTEND: u16 = 0b10000;
 
 const SUPERVISOR_MODE: u16 = 0b0010000000000000;
+const TRACE: u16 = 0b1000000000000000;
 
 const INTERRUPT: u16 = 0b0000011100000000;
 const INTERRUPT_SHIFT: u16 = 8;
@@ -461,6 +490,7 @@ impl<'a> Cpu<'a> {
             pc: 0,
             cartridge,
             internal_ram: vec![0; 0x10000].into_boxed_slice(),
+            sram: None,
             inputs: [0, 0],
             controller_th_bit: [0, 0, 0, 0],
             controller_read_state: [0, 0, 0, 0],
@@ -469,17 +499,27 @@ impl<'a> Cpu<'a> {
             instrumented,
             cycle_count: 0,
             stopped: false,
+            jammed: false,
             speed_adj: 1.0,
             vdp,
             vdp_bus,
-            z80: z80::Cpu::new(cartridge, instrumented),
+            z80: z80::Cpu::new(cartridge, vdp_bus, instrumented),
+            clock: MasterClock::new(),
+            sinks: Vec::new(),
+            filter: FilterChain::default(),
+            muted: false,
             pc_watches: Box::new(HashSet::new()),
             pc_breaks: Box::new(HashSet::new()),
             pc_ignores: Box::new(vec![]),
+            pc_trigger: None,
+            pc_triggered: false,
             memory_watches: Box::new(HashSet::new()),
             memory_breaks: Box::new(HashSet::new()),
             pause_on_frame_end: false,
             test_ram_only: false,
+            coverage: None,
+            profile: None,
+            trace: None,
             phantom: PhantomData,
         };
 
@@ -490,13 +530,29 @@ impl<'a> Cpu<'a> {
 
     fn tick(&mut self, cycle_count: u8) {
         for _ in 0..cycle_count {
-            for _ in 0..7 {
+            for _ in 0..M68K_DIVIDER {
                 let cartridge = &self.cartridge;
                 let internal_ram = &self.internal_ram;
                 self.vdp
                     .as_mut()
                     .map(|vdp| vdp.tick(cartridge, internal_ram));
-                self.z80.tick(self.vdp_bus.borrow().z80_interrupt);
+                if self.clock.tick() {
+                    // the Z80 reaches the VDP itself, so the bus can't stay borrowed
+                    let z80_interrupt = self.vdp_bus.borrow().z80_interrupt;
+                    self.z80.tick(z80_interrupt);
+                }
+                if self.clock.ticks() % ym2612::MASTER_TICKS_PER_SAMPLE == 0 {
+                    let sample = self.z80.ym2612.step();
+                    // silence is still pushed to the sinks, so output stays in step
+                    let sample = [if self.muted {
+                        0.0
+                    } else {
+                        self.filter.process(sample as f32 / 32768.0)
+                    }];
+                    for sink in self.sinks.iter_mut() {
+                        sink.push_samples(&sample);
+                    }
+                }
                 self.ticks -= 1.0;
             }
             self.cycle_count = self.cycle_count.wrapping_add(1);
@@ -578,16 +634,35 @@ impl<'a> Cpu<'a> {
     }
 
     fn read_addr_offset_size<Size: DataSize>(&mut self, addr: u32, offset: u32, size: u32) -> Size {
+        if self.wraps(addr, size) {
+            let mut bytes = [0; 4];
+            for i in offset..size {
+                bytes[i as usize] = self.read_addr_offset_size((addr + i) & 0xFFFFFF, 0, 1);
+            }
+            return Size::from_memory_bytes(&bytes[offset as usize..size as usize]);
+        }
         let val = if self.test_ram_only {
             Size::from_memory_bytes(
                 &self.internal_ram[((addr + offset) as usize)..((addr + size) as usize)],
             )
         } else {
             match addr {
+                0x200000..=0x3FFFFF if self.maps_sram(addr, size) => {
+                    let sram = self.sram.as_ref().unwrap();
+                    let mut bytes = [0; 4];
+                    for i in offset..size {
+                        bytes[i as usize] = sram.read(addr + i);
+                    }
+                    Size::from_memory_bytes(&bytes[offset as usize..size as usize])
+                }
                 0x000000..=0x3FFFFF => {
                     if (addr + size) as usize > self.cartridge.len() {
                         Size::from(0).unwrap()
                     } else {
+                        if let Some(ref mut coverage) = self.coverage {
+                            let flag = if addr == self.pc & 0xFFFFFF { CODE } else { DATA };
+                            coverage.mark((addr + offset) as usize, (size - offset) as usize, flag);
+                        }
                         Size::from_memory_bytes(
                             &self.cartridge[((addr + offset) as usize)..((addr + size) as usize)],
                         )
@@ -628,6 +703,24 @@ impl<'a> Cpu<'a> {
         val
     }
 
+    // what a read would return, for the debugger, without anything a real read sets off: the VDP
+    // and controller ports and the sound chip read as 0, and neither coverage nor watches see it
+    fn peek_memory(&self, addr: u32) -> u8 {
+        let addr = addr & 0xFFFFFF;
+        if self.test_ram_only {
+            return self.internal_ram[addr as usize];
+        }
+        match addr {
+            0x200000..=0x3FFFFF if self.maps_sram(addr, 1) => {
+                self.sram.as_ref().unwrap().read(addr)
+            }
+            0x000000..=0x3FFFFF => self.cartridge.get(addr as usize).cloned().unwrap_or(0),
+            0xA00000..=0xA0FFFF => self.z80.peek_memory((addr - 0xA00000) as u16),
+            0xE00000..=0xFFFFFF => self.internal_ram[(addr & 0xFFFF) as usize],
+            _ => 0,
+        }
+    }
+
     fn read_controller<Size: DataSize>(&mut self, controller: usize) -> Size {
         let mut val = 0;
         val |= self.controller_th_bit[controller] << 6;
@@ -680,12 +773,28 @@ impl<'a> Cpu<'a> {
         if self.instrumented && self.memory_breaks.contains(&addr) {
             panic!()
         }
+        if self.wraps(addr, size) {
+            let mut bytes = [0; 4];
+            val.set_memory_bytes(&mut bytes[offset as usize..size as usize]);
+            for i in offset..size {
+                self.write_addr_offset_size((addr + i) & 0xFFFFFF, 0, 1, bytes[i as usize]);
+            }
+            return;
+        }
         if self.test_ram_only {
             val.set_memory_bytes(
                 &mut self.internal_ram[((addr + offset) as usize)..((addr + size) as usize)],
             );
         } else {
             match addr {
+                0x200000..=0x3FFFFF if self.maps_sram(addr, size) => {
+                    let sram = self.sram.as_mut().unwrap();
+                    let mut bytes = [0; 4];
+                    val.set_memory_bytes(&mut bytes[offset as usize..size as usize]);
+                    for i in offset..size {
+                        sram.write(addr + i, bytes[i as usize]);
+                    }
+                }
                 0x000000..=0x3FFFFF => {} // Vector table, ROM Cartridge
                 0x400000..=0x7FFFFF => {} // Expansion port
                 0xA00000..=0xA0FFFF => {
@@ -730,19 +839,50 @@ impl<'a> Cpu<'a> {
                 }
                 0xA11000..=0xA11FFF => {} // Z80 Control
                 0xC00011..=0xC0001B => {} // PSG
-                0xC00000..=0xDFFFFF => Size::write_to_vdp_bus(self.vdp_bus, addr, val),
+                0xC00000..=0xDFFFFF => {
+                    // data port writes stall the CPU until the VDP has room in its write FIFO
+                    if addr <= 0xC00003 && self.vdp.is_some() {
+                        while self.vdp_bus.borrow().fifo_full() {
+                            self.tick(1);
+                        }
+                    }
+                    Size::write_to_vdp_bus(self.vdp_bus, addr, val);
+                    if self.vdp.is_some() {
+                        while self.vdp_bus.borrow().dma_holds_cpu() {
+                            self.tick(1);
+                        }
+                    }
+                }
                 0xE00000..=0xFFFFFF => {
                     let ram_addr = addr & 0xFFFF;
-                    val.set_memory_bytes(
-                        &mut self.internal_ram
-                            [((ram_addr + offset) as usize)..((ram_addr + size) as usize)],
-                    );
+                    let range = ((ram_addr + offset) as usize)..((ram_addr + size) as usize);
+                    let awaited = self.trace.as_ref().and_then(|trace| trace.awaited_change());
+                    let watched = match awaited {
+                        Some(watch) if watch >= 0xE00000 => Some((watch & 0xFFFF) as usize),
+                        _ => None,
+                    }
+                    .filter(|watch| range.contains(watch))
+                    .map(|watch| (watch, self.internal_ram[watch]));
+                    val.set_memory_bytes(&mut self.internal_ram[range]);
+                    if let Some((watch, before)) = watched {
+                        if self.internal_ram[watch] != before {
+                            self.trace.as_mut().unwrap().begin();
+                        }
+                    }
                 }
                 _ => panic!(),
             }
         }
     }
 
+    // an access running past the top of the 24-bit address space carries on from the bottom, and
+    // one running past the end of work RAM carries on in its next mirror; either way it's made a
+    // byte at a time rather than sliced from one region
+    fn wraps(&self, addr: u32, size: u32) -> bool {
+        addr + size > 0x1000000
+            || (!self.test_ram_only && addr >= 0xE00000 && (addr & 0xFFFF) + size > 0x10000)
+    }
+
     fn addr_register(&self, register: usize) -> u32 {
         if register == 7 && self.flag(SUPERVISOR_MODE) {
             self.ssp
@@ -984,9 +1124,15 @@ impl<'a> Cpu<'a> {
         }
     }
 
+    // stacks the PC as it stands; traps, CHK, and divide by zero are raised after their
+    // operands are read, so that's the next instruction, and interrupts are taken between
+    // instructions. The frame always goes on the supervisor stack, with the status from before
+    // the switch
     fn process_exception(&mut self, vector: u32) {
+        let status = self.status;
+        self.set_flag(SUPERVISOR_MODE, true);
         self.push(self.pc);
-        self.push(self.status);
+        self.push(status);
         self.pc = self.read_addr(vector * 4);
         if self.instrumented {
             if vector == 28 {
@@ -997,11 +1143,12 @@ impl<'a> Cpu<'a> {
                 debug!(target: "cpu", "exception {} {:06X}", vector, self.pc);
             }
         }
-        self.set_flag(SUPERVISOR_MODE, true);
+        // on top of the raising instruction's own cycles: TRAP, taken TRAPV, and ILLEGAL come to
+        // 34 in all, and a taken CHK to 40 plus its effective address time
         self.tick(match vector {
             2 | 3 => 50,
-            6 => 40,
-            4 | 7 | 8 | 32..=47 => 34,
+            4 | 6 | 7 | 10 | 11 | 32..=47 => 30,
+            8 => 34,
             15..=31 => 44,
             5 => 38,
             _ => 0,
@@ -1293,6 +1440,15 @@ impl<'a> Cpu<'a> {
         self.set_flag(CARRY, carry);
     }
 
+    // SUB/SUBI/SUBQ flags: CMP's plus X, which copies the borrow
+    fn do_sub<Size: DataSize + WrappingSub>(&mut self, operand: Size, val: Size) -> Size {
+        let result = val.wrapping_sub(&operand);
+        self.do_cmp(operand, val);
+        let carry = self.flag(CARRY);
+        self.set_flag(EXTEND, carry);
+        result
+    }
+
     fn cmp<Size: DataSize + WrappingSub>(&mut self, mode: AddressingMode, register: usize) {
         let operand = self.read::<Size>(mode);
         let val = Size::from_register_value(self.d[register]);
@@ -1428,6 +1584,8 @@ impl<'a> Cpu<'a> {
         } else {
             false
         };
+        // the 68000 stores the addressing register of -(An) as it was before the instruction (later
+        // CPUs store it decremented), and ignores a load into the addressing register of (An)+
         let mut stored_addr_register_val = 0;
         let mut addr = match mode {
             AddressingMode::AddressWithPredecrement(register) => {
@@ -1747,55 +1905,19 @@ impl<'a> Cpu<'a> {
             OperandDirection::ToRegister => {
                 let operand: Size = self.read(mode);
                 let val = Size::from_register_value(self.d[register]);
-                let (carry, result) = match val.checked_sub(&operand) {
-                    Some(result) => (false, result),
-                    None => (true, val.wrapping_sub(&operand)),
-                };
-                let overflow = operand.is_negative() == result.is_negative()
-                    && operand.is_negative() != val.is_negative();
-                self.set_flag(EXTEND, carry);
-                self.set_flag(NEGATIVE, result.is_negative());
-                self.set_flag(ZERO, result.is_zero());
-                self.set_flag(OVERFLOW, overflow);
-                self.set_flag(CARRY, carry);
+                let result = self.do_sub(operand, val);
                 self.d[register] = result.apply_to_register(self.d[register]);
             }
             OperandDirection::ToMemory => {
                 let operand = Size::from_register_value(self.d[register]);
-                self.read_write(mode, &mut |cpu, val: Size| {
-                    let (carry, result) = match val.checked_sub(&operand) {
-                        Some(result) => (false, result),
-                        None => (true, val.wrapping_sub(&operand)),
-                    };
-                    let overflow = operand.is_negative() == result.is_negative()
-                        && operand.is_negative() != val.is_negative();
-                    cpu.set_flag(EXTEND, carry);
-                    cpu.set_flag(NEGATIVE, result.is_negative());
-                    cpu.set_flag(ZERO, result.is_zero());
-                    cpu.set_flag(OVERFLOW, overflow);
-                    cpu.set_flag(CARRY, carry);
-                    result
-                });
+                self.read_write(mode, &mut |cpu, val: Size| cpu.do_sub(operand, val));
             }
         }
     }
 
     fn subi<Size: DataSize + WrappingSub>(&mut self, mode: AddressingMode) {
         let operand = self.read_extension::<Size>();
-        self.read_write(mode, &mut |cpu, val: Size| {
-            let (carry, result) = match val.checked_sub(&operand) {
-                Some(result) => (false, result),
-                None => (true, val.wrapping_sub(&operand)),
-            };
-            let overflow = operand.is_negative() == result.is_negative()
-                && operand.is_negative() != val.is_negative();
-            cpu.set_flag(EXTEND, carry);
-            cpu.set_flag(NEGATIVE, result.is_negative());
-            cpu.set_flag(ZERO, result.is_zero());
-            cpu.set_flag(OVERFLOW, overflow);
-            cpu.set_flag(CARRY, carry);
-            result
-        });
+        self.read_write(mode, &mut |cpu, val: Size| cpu.do_sub(operand, val));
     }
 
     fn subq<Size: DataSize + WrappingSub>(&mut self, mode: AddressingMode, data: u8) {
@@ -1807,20 +1929,7 @@ impl<'a> Cpu<'a> {
             }
             _ => {
                 let operand = Size::from(data).unwrap();
-                self.read_write(mode, &mut |cpu, val: Size| {
-                    let (carry, result) = match val.checked_sub(&operand) {
-                        Some(result) => (false, result),
-                        None => (true, val.wrapping_sub(&operand)),
-                    };
-                    let overflow = operand.is_negative() == result.is_negative()
-                        && operand.is_negative() != val.is_negative();
-                    cpu.set_flag(EXTEND, carry);
-                    cpu.set_flag(NEGATIVE, result.is_negative());
-                    cpu.set_flag(ZERO, result.is_zero());
-                    cpu.set_flag(OVERFLOW, overflow);
-                    cpu.set_flag(CARRY, carry);
-                    result
-                });
+                self.read_write(mode, &mut |cpu, val: Size| cpu.do_sub(operand, val));
             }
         }
     }
@@ -1892,34 +2001,35 @@ impl<'a> Cpu<'a> {
 
     fn execute_opcode(&mut self) {
         let opcode_pc = self.pc;
+        let start_cycle = self.cycle_count;
         let opcode_hex = self.read_addr(opcode_pc);
         self.pc += 2;
 
         let opcode = opcode(opcode_hex);
+        if let Some(ref mut profile) = self.profile {
+            profile.begin(&opcode, start_cycle);
+        }
 
         if self.pc_breaks.contains(&opcode_pc) {
             self.instrumented = true;
             self.pause_on_frame_end = true;
         }
+        if self.pc_trigger == Some(opcode_pc) {
+            self.pc_trigger = None;
+            self.pc_triggered = true;
+        }
 
-        if self.instrumented {
-            let mut ignore = false;
-            for ignore_range in self.pc_ignores.iter() {
-                if ignore_range.start <= opcode_pc && ignore_range.end >= opcode_pc {
-                    ignore = true;
-                    break;
-                }
-            }
-            if !ignore {
-                log!(target: "cpu",
-                    if self.pc_breaks.contains(&opcode_pc) {
-                        Level::Error
-                    } else if self.pc_watches.contains(&opcode_pc) {
-                        Level::Warn
-                    } else {
-                        Level::Debug
-                    },
-                    "{:06X}:  {:04X}  {:36}D0:{:08X} D1:{:08X} D2:{:08X} D3:{:08X} D4:{:08X} D5:{:08X} D6:{:08X} D7:{:08X} A0:{:08X} A1:{:08X} A2:{:08X} A3:{:08X} A4:{:08X} A5:{:08X} A6:{:08X} A7:{:08X} SR:{:04X} USP:{:08X} {}{}{}{}{}",
+        let tracing = match self.trace {
+            Some(ref mut trace) => trace.instruction(opcode_pc),
+            None => false,
+        };
+        let logged = self.instrumented
+            && !self
+                .pc_ignores
+                .iter()
+                .any(|range| range.start <= opcode_pc && range.end >= opcode_pc);
+        if logged || tracing {
+            let line = format!("{:06X}:  {:04X}  {:36}D0:{:08X} D1:{:08X} D2:{:08X} D3:{:08X} D4:{:08X} D5:{:08X} D6:{:08X} D7:{:08X} A0:{:08X} A1:{:08X} A2:{:08X} A3:{:08X} A4:{:08X} A5:{:08X} A6:{:08X} A7:{:08X} SR:{:04X} USP:{:08X} {}{}{}{}{}",
                 opcode_pc,
                 opcode_hex,
                 opcode.disassemble(Some(if self.pc < 0x400000 {
@@ -1951,7 +2061,20 @@ impl<'a> Cpu<'a> {
                 if self.flag(ZERO) { "Z" } else { "z" },
                 if self.flag(OVERFLOW) { "V" } else { "v" },
                 if self.flag(CARRY) { "C" } else { "c" },
-                );
+            );
+            if tracing {
+                self.trace.as_mut().unwrap().line(&line);
+            }
+            if logged {
+                log!(target: "cpu",
+                    if self.pc_breaks.contains(&opcode_pc) {
+                        Level::Error
+                    } else if self.pc_watches.contains(&opcode_pc) {
+                        Level::Warn
+                    } else {
+                        Level::Debug
+                    },
+                    "{}", line);
             }
         }
 
@@ -2232,10 +2355,12 @@ impl<'a> Cpu<'a> {
                 }
             }
             Opcode::CLR { mode, size } => {
+                // the 68000 reads the destination before clearing it, which hardware registers
+                // with read side effects notice
                 match size {
-                    Size::Byte => self.write::<u8>(mode, 0),
-                    Size::Word => self.write::<u16>(mode, 0),
-                    Size::Long => self.write::<u32>(mode, 0),
+                    Size::Byte => self.read_write::<u8>(mode, &mut |_, _| 0),
+                    Size::Word => self.read_write::<u16>(mode, &mut |_, _| 0),
+                    Size::Long => self.read_write::<u32>(mode, &mut |_, _| 0),
                     Size::Illegal => panic!(),
                 }
                 self.set_flag(NEGATIVE, false);
@@ -2283,12 +2408,13 @@ impl<'a> Cpu<'a> {
                 condition,
                 register,
             } => {
+                // the displacement is relative to the extension word, which has already been read
                 let displacement = self.read_extension::<i16>();
                 if !self.check_condition(condition) {
                     let dec_value = ((self.d[register] & 0xFFFF) as i16).wrapping_sub(1);
                     self.d[register] = dec_value.apply_to_register(self.d[register]);
                     if dec_value != -1 {
-                        self.pc = self.pc.wrapping_add_signed((displacement - 2) as i32);
+                        self.pc = self.pc.wrapping_add_signed(displacement as i32 - 2);
                     } else {
                         self.tick(4);
                     }
@@ -2395,7 +2521,16 @@ impl<'a> Cpu<'a> {
                 self.set_flag(OVERFLOW, false);
                 self.set_flag(CARRY, false);
             }
-            Opcode::ILLEGAL => self.process_exception(4),
+            Opcode::ILLEGAL => {
+                // unlike traps, illegal and unimplemented instructions stack their own address
+                // so a handler can emulate them and return past them itself
+                self.pc = opcode_pc;
+                self.process_exception(match opcode_hex >> 12 {
+                    0b1010 => 10,
+                    0b1111 => 11,
+                    _ => 4,
+                });
+            }
             Opcode::JMP { mode } => self.pc = self.effective_addr(mode),
             Opcode::JSR { mode } => {
                 let addr = self.effective_addr(mode);
@@ -2407,7 +2542,14 @@ impl<'a> Cpu<'a> {
                 self.set_addr_register(register, val);
             }
             Opcode::LINK { register } => {
-                self.push(self.addr_register(register) - if register == 7 { 4 } else { 0 });
+                // the stack pointer is decremented before it's read, so LINK A7 pushes the
+                // address it's pushed to, and that's the frame the displacement is added to
+                let val = if register == 7 {
+                    self.addr_register(7).wrapping_sub(4)
+                } else {
+                    self.addr_register(register)
+                };
+                self.push(val);
                 self.set_addr_register(register, self.addr_register(7));
                 let displacement = self.read_extension::<i16>();
                 self.set_addr_register(
@@ -2600,6 +2742,9 @@ impl<'a> Cpu<'a> {
                 self.set_flag(ZERO, result.is_zero());
                 self.set_flag(OVERFLOW, false);
                 self.set_flag(CARRY, false);
+                // two cycles per 01 or 10 pair in the source, with a zero appended on the right
+                let bits = val as u16 as u32;
+                self.tick(2 * ((bits ^ (bits << 1)) & 0xFFFF).count_ones() as u8);
             }
             Opcode::MULU { mode, register } => {
                 let val = self.read::<u16>(mode) as u32;
@@ -2610,6 +2755,8 @@ impl<'a> Cpu<'a> {
                 self.set_flag(ZERO, result.is_zero());
                 self.set_flag(OVERFLOW, false);
                 self.set_flag(CARRY, false);
+                // two cycles per set bit in the source
+                self.tick(2 * val.count_ones() as u8);
             }
             Opcode::NBCD { mode } => {
                 self.read_write::<u8>(mode, &mut |cpu, val| {
@@ -2825,9 +2972,10 @@ impl<'a> Cpu<'a> {
                 }
             }
             Opcode::RTE => {
+                // both come off the supervisor stack, before the status can switch to the user's
                 let new_status = self.pop();
-                self.set_status(new_status);
                 self.pc = self.pop();
+                self.set_status(new_status);
             }
             Opcode::RTR => {
                 let new_status = (self.status & 0xFF00) | (self.pop::<u16>() & 0xFF);
@@ -2883,11 +3031,12 @@ impl<'a> Cpu<'a> {
                 }
             }
             Opcode::Scc { mode, condition } => {
-                if self.check_condition(condition) {
-                    self.write::<u8>(mode, 0xFF);
+                // a memory destination is read before it's written, and takes the same time
+                // either way; only setting a data register costs extra
+                let val = if self.check_condition(condition) { 0xFF } else { 0x00 };
+                self.read_write::<u8>(mode, &mut |_, _| val);
+                if let (AddressingMode::DataRegister(_), 0xFF) = (mode, val) {
                     self.tick(2);
-                } else {
-                    self.write::<u8>(mode, 0x00);
                 }
             }
             Opcode::STOP => {
@@ -2916,7 +3065,8 @@ impl<'a> Cpu<'a> {
                     Size::Long => self.read(mode),
                     Size::Byte | Size::Illegal => panic!(),
                 };
-                let result = self.addr_register(register).wrapping_add_signed(-operand);
+                // negating the operand would overflow for 0x80000000
+                let result = self.addr_register(register).wrapping_sub(operand as u32);
                 self.set_addr_register(register, result);
             }
             Opcode::SUBI { mode, size } => match size {
@@ -2967,6 +3117,7 @@ impl<'a> Cpu<'a> {
                 Size::Illegal => panic!(),
             },
             Opcode::UNLK { register } => {
+                // with A7, the popped value replaces the stack pointer's post-increment
                 self.set_addr_register(7, self.addr_register(register));
                 let val = self.pop();
                 self.set_addr_register(register, val);
@@ -2975,40 +3126,156 @@ impl<'a> Cpu<'a> {
         self.tick(opcode.cycle_count());
     }
 
+    // runs until the ticks added by do_frame are spent
+    fn run_ticks(&mut self, inputs: &[ControllerState<8>; 2]) -> bool {
+        while self.ticks > 0.0 {
+            self.next_operation(inputs);
+        }
+
+        self.pause_on_frame_end || self.jammed
+    }
+
     pub fn next_operation(&mut self, inputs: &[ControllerState<8>; 2]) {
         self.inputs = [inputs[0].to_u8(), inputs[1].to_u8()];
         if self.stopped {
-            self.ticks = 0.0;
-        } else {
-            if let Some((vdp_interrupt_vector, vdp_interrupt_level)) = {
-                let mut vdp_bus = self.vdp_bus.borrow_mut();
-                if vdp_bus.horizontal_interrupt {
-                    vdp_bus.horizontal_interrupt = false;
-                    Some((28, 4))
-                } else if vdp_bus.status.vertical_interrupt {
-                    vdp_bus.status.vertical_interrupt = false;
-                    Some((30, 6))
-                } else {
-                    None
-                }
-            } {
-                if vdp_interrupt_level > self.get_interrupt_level() {
-                    self.process_exception(vdp_interrupt_vector);
-                    self.set_interrupt_level(vdp_interrupt_level);
+            // VDP interrupts are at most level 6
+            if self.jammed || self.get_interrupt_level() >= 6 {
+                if !self.jammed {
+                    self.jammed = true;
+                    self.report_jam();
                 }
+                self.ticks = 0.0;
+                return;
             }
+        }
+        // a masked interrupt stays pending until the mask drops below its level
+        let pending_interrupt = self.vdp_bus.borrow().pending_interrupt();
+        if let Some((vdp_interrupt_vector, vdp_interrupt_level)) = pending_interrupt {
+            if vdp_interrupt_level > self.get_interrupt_level() {
+                self.vdp_bus
+                    .borrow_mut()
+                    .acknowledge_interrupt(vdp_interrupt_level);
+                self.stopped = false;
+                self.process_exception(vdp_interrupt_vector);
+                self.set_interrupt_level(vdp_interrupt_level);
+            }
+        }
+        if self.stopped {
+            // the rest of the system keeps running while waiting for an interrupt
+            self.tick(4);
+        } else {
             self.execute_opcode();
+            if let Some(ref mut profile) = self.profile {
+                profile.end(self.cycle_count);
+            }
         }
     }
 
+    fn report_jam(&mut self) {
+        let opcode_pc = self.pc.wrapping_sub(4);
+        let opcode_hex: u16 = self.read_addr_no_tick(opcode_pc);
+        let extension: u16 = self.read_addr_no_tick(opcode_pc + 2);
+        error!(target: "cpu",
+            "CPU jammed at {:06X} (stopped with interrupts masked)\t{:04X}  {:36}D0:{:08X} D1:{:08X} D2:{:08X} D3:{:08X} D4:{:08X} D5:{:08X} D6:{:08X} D7:{:08X} A0:{:08X} A1:{:08X} A2:{:08X} A3:{:08X} A4:{:08X} A5:{:08X} A6:{:08X} A7:{:08X} SR:{:04X} USP:{:08X} cyc:{}",
+            opcode_pc,
+            opcode_hex,
+            opcode(opcode_hex).disassemble(Some(&extension.to_be_bytes()), Some(opcode_pc)),
+            self.d[0],
+            self.d[1],
+            self.d[2],
+            self.d[3],
+            self.d[4],
+            self.d[5],
+            self.d[6],
+            self.d[7],
+            self.a[0],
+            self.a[1],
+            self.a[2],
+            self.a[3],
+            self.a[4],
+            self.a[5],
+            self.a[6],
+            self.ssp,
+            self.status,
+            self.a[7],
+            self.cycle_count,
+        );
+    }
+
     pub fn close(&mut self) {
         self.vdp.as_mut().map(|vdp| vdp.close());
+        for sink in self.sinks.iter_mut() {
+            sink.close();
+        }
+    }
+
+    // where the FM chip's samples go, at ym2612::SAMPLE_HZ
+    pub fn attach_audio_sinks(&mut self, sinks: Vec<Box<dyn AudioSink>>) {
+        self.sinks = sinks;
+    }
+
+    // applied to the FM output before every sink, so recordings sound like the live output
+    pub fn set_audio_filter(&mut self, filter: FilterChain) {
+        self.filter = filter;
+    }
+
+    // hands the output over to a new CPU, so a reboot doesn't reopen the audio stream
+    pub fn take_audio_sinks(&mut self) -> Vec<Box<dyn AudioSink>> {
+        std::mem::take(&mut self.sinks)
+    }
+
+    // maps cartridge RAM over the ROM space it covers
+    pub fn attach_sram(&mut self, sram: Sram) {
+        self.sram = Some(sram);
+    }
+
+    fn maps_sram(&self, addr: u32, size: u32) -> bool {
+        self.sram.as_ref().map_or(false, |sram| sram.contains(addr, size))
+    }
+
+    pub fn save_to_battery(&self, out: &mut dyn Write) -> std::io::Result<usize> {
+        match self.sram {
+            Some(ref sram) if sram.battery => out.write(&sram.data),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(self.cartridge.len()));
+    }
+
+    pub fn write_coverage(&self, path: &Path) -> SimpleResult<()> {
+        match self.coverage {
+            Some(ref coverage) => coverage.write(path, self.cartridge.len()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new());
+        self.z80.enable_profile();
+    }
+
+    pub fn write_profile(&self, path: &Path) -> SimpleResult<()> {
+        match self.profile {
+            Some(ref profile) => File::create(path)
+                .and_then(|mut file| {
+                    profile.write("68000", &mut file)?;
+                    self.z80.write_profile(&mut file)
+                })
+                .map_err(|io_error| SimpleError::new(io_error.to_string())),
+            None => Ok(()),
+        }
     }
 
     pub fn set_memory_watch(&mut self, addr: u32) {
         self.memory_watches.insert(addr);
     }
 
+    pub fn enable_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
     pub fn set_memory_break(&mut self, addr: u32) {
         self.memory_breaks.insert(addr);
     }
@@ -3030,6 +3297,25 @@ impl<'a> Cpu<'a> {
     pub fn add_pc_ignore_range(&mut self, range: Range<u32>) {
         self.pc_ignores.push(range);
     }
+
+    #[cfg(feature = "dev")]
+    pub fn set_entry(&mut self, pc: u32) {
+        self.pc = pc & 0xFFFFFF;
+        self.stopped = false;
+        self.jammed = false;
+    }
+
+    #[cfg(feature = "dev")]
+    pub fn inject(&mut self, addr: u32, data: &[u8]) {
+        for (i, &val) in data.iter().enumerate() {
+            let addr = (addr + i as u32) & 0xFFFFFF;
+            match addr {
+                // bypass the bus request so Z80 programs can be loaded at boot
+                0xA00000..=0xA0FFFF => self.z80.write_addr((addr - 0xA00000) as u16, val),
+                _ => self.write_addr_no_tick(addr, val),
+            }
+        }
+    }
 }
 
 #[cfg(feature = "test")]
@@ -3122,6 +3408,26 @@ pub mod testing {
         pub fn set_pc(&mut self, pc: u32) {
             self.pc = pc;
         }
+
+        pub fn cycle_count_for_test(&self) -> u64 {
+            self.cycle_count
+        }
+
+        pub fn z80_cycle_count_for_test(&self) -> u64 {
+            self.z80.get_cycle_count()
+        }
+
+        pub fn master_clock_for_test(&self) -> u64 {
+            self.clock.ticks()
+        }
+
+        pub fn d_for_test(&self, register: usize) -> u32 {
+            self.d[register]
+        }
+
+        pub fn framebuffer(&self) -> Option<(Vec<u8>, u32, u32)> {
+            self.vdp.as_ref().map(|vdp| vdp.framebuffer())
+        }
     }
 }
 
@@ -3129,21 +3435,26 @@ impl window::Cpu for Cpu<'_> {
     fn reset(&mut self, _soft: bool) {
         self.ssp = self.read_addr_no_tick(0x000000);
         self.pc = self.read_addr_no_tick(0x000004);
+        // the condition codes are left as they were
         self.set_interrupt_level(7);
         self.set_flag(SUPERVISOR_MODE, true);
+        self.set_flag(TRACE, false);
         self.stopped = false;
+        self.jammed = false;
     }
 
     fn do_frame(&mut self, time_secs: f64, inputs: &[ControllerState<8>; 2], debug: bool) -> bool {
         self.pause_on_frame_end = false;
         self.instrumented = debug;
         self.ticks += time_secs * MASTER_CLOCK_TICKS_PER_SECOND * self.speed_adj;
+        self.run_ticks(inputs)
+    }
 
-        while self.ticks > 0.0 {
-            self.next_operation(inputs);
-        }
-
-        self.pause_on_frame_end
+    fn do_frame_exact(&mut self, inputs: &[ControllerState<8>; 2], debug: bool) -> bool {
+        self.pause_on_frame_end = false;
+        self.instrumented = debug;
+        self.ticks += MASTER_CLOCK_TICKS_PER_FRAME;
+        self.run_ticks(inputs)
     }
 
     fn render(
@@ -3160,19 +3471,244 @@ impl window::Cpu for Cpu<'_> {
             .map(|vdp| vdp.render(c, texture_ctx, gl, device, layers, debug));
     }
 
-    fn save_state(&self, _out: &mut Vec<u8>) {
-        todo!()
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for &a in self.a.iter() {
+            out.put_u32(a);
+        }
+        out.put_u32(self.ssp);
+        for &d in self.d.iter() {
+            out.put_u32(d);
+        }
+        out.put_u16(self.status);
+        out.put_u32(self.pc);
+        out.put_slice(&self.internal_ram);
+        if let Some(ref sram) = self.sram {
+            out.put_slice(&sram.data);
+        }
+        out.put_slice(&self.inputs);
+        out.put_slice(&self.controller_th_bit);
+        out.put_slice(&self.controller_read_state);
+        out.put_u32(self.controller_decay);
+        out.put_f64(self.ticks);
+        out.put_u64(self.cycle_count);
+        out.put_u8(if self.stopped { 1 } else { 0 });
+        out.put_f64(self.speed_adj);
+        self.clock.save_state(out);
+        self.z80.save_state(out);
+        if let Some(ref vdp) = self.vdp {
+            vdp.save_state(out);
+        }
+        self.vdp_bus.borrow().save_state(out);
     }
 
-    fn load_state(&mut self, _state: &mut dyn Buf) {
-        todo!()
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        for a in self.a.iter_mut() {
+            *a = state.get_u32();
+        }
+        self.ssp = state.get_u32();
+        for d in self.d.iter_mut() {
+            *d = state.get_u32();
+        }
+        self.status = state.get_u16();
+        self.pc = state.get_u32();
+        state.copy_to_slice(&mut self.internal_ram);
+        if let Some(ref mut sram) = self.sram {
+            state.copy_to_slice(&mut sram.data);
+        }
+        state.copy_to_slice(&mut self.inputs);
+        state.copy_to_slice(&mut self.controller_th_bit);
+        state.copy_to_slice(&mut self.controller_read_state);
+        self.controller_decay = state.get_u32();
+        self.ticks = state.get_f64();
+        self.cycle_count = state.get_u64();
+        self.stopped = state.get_u8() == 1;
+        self.speed_adj = state.get_f64();
+        self.clock.load_state(state);
+        self.z80.load_state(state);
+        if let Some(ref mut vdp) = self.vdp {
+            vdp.load_state(state);
+        }
+        self.vdp_bus.borrow_mut().load_state(state);
+    }
+
+    fn jammed(&self) -> bool {
+        self.jammed
     }
 
     fn increase_speed(&mut self) {
-        todo!()
+        if self.speed_adj < 2.5 {
+            self.speed_adj += 0.25;
+        }
+        debug!(target: "ctrl", "speed adj {}", self.speed_adj);
     }
 
     fn decrease_speed(&mut self) {
-        todo!()
+        if self.speed_adj > 0.25 {
+            self.speed_adj -= 0.25;
+        }
+        debug!(target: "ctrl", "speed adj {}", self.speed_adj);
+    }
+
+    fn set_speed(&mut self, speed_adj: f64) {
+        self.speed_adj = speed_adj;
+        debug!(target: "ctrl", "speed adj {}", self.speed_adj);
+    }
+
+    fn speed(&self) -> f64 {
+        self.speed_adj
+    }
+
+    fn tilesheet(&self, palette_line: u8) -> Option<RgbaImage> {
+        self.vdp.as_ref().map(|vdp| vdp.tilesheet(palette_line))
+    }
+
+    fn frame_image(&self) -> Option<RgbImage> {
+        self.vdp.as_ref().and_then(|vdp| vdp.frame_image())
+    }
+
+    fn set_capture_frames(&mut self, capture: bool) {
+        if let Some(ref mut vdp) = self.vdp {
+            vdp.set_capture_frames(capture);
+        }
+    }
+
+    fn peek(&mut self, addr: u32) -> u8 {
+        self.peek_memory(addr)
+    }
+
+    fn poke(&mut self, addr: u32, val: u8) {
+        self.write_addr_no_tick(addr, val);
+    }
+
+    fn registers(&self) -> String {
+        let d = (0..8).map(|i| format!("D{}:{:08X}", i, self.d[i])).collect::<Vec<_>>();
+        let a = (0..7).map(|i| format!("A{}:{:08X}", i, self.a[i])).collect::<Vec<_>>();
+        format!(
+            "{} {} SSP:{:08X} USP:{:08X} SR:{:04X} PC:{:06X} cyc:{}",
+            d.join(" "),
+            a.join(" "),
+            self.ssp,
+            self.a[7],
+            self.status,
+            self.pc,
+            self.cycle_count
+        )
+    }
+
+    fn add_breakpoint(&mut self, addr: u32) {
+        self.set_pc_break(addr);
+    }
+
+    fn remove_breakpoint(&mut self, addr: u32) {
+        self.pc_breaks.remove(&addr);
+    }
+
+    fn disassemble(&mut self, addr: u32, count: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut pc = addr & 0xFFFFFF;
+        for _ in 0..count {
+            let opcode_hex =
+                u16::from(self.peek_memory(pc)) << 8 | u16::from(self.peek_memory(pc + 1));
+            let ext = (2..10)
+                .map(|offset| self.peek_memory(pc + offset))
+                .collect::<Vec<_>>();
+            let opcode = opcode(opcode_hex);
+            lines.push(format!(
+                "{:06X}\t{:04X} {}",
+                pc,
+                opcode_hex,
+                opcode.disassemble(Some(&ext), Some(pc))
+            ));
+            pc = (pc + 2 + opcode.extension_bytes() as u32) & 0xFFFFFF;
+        }
+        lines
+    }
+
+    fn peek_video(&mut self, space: &str, addr: u32) -> Result<u8, String> {
+        let target = video_memory(space)?;
+        let vdp = self.vdp.as_mut().ok_or("no VDP")?;
+        vdp.peek_memory(target, addr as usize)
+            .ok_or_else(|| format!("{} address {:X} out of range", space, addr))
+    }
+
+    fn poke_video(&mut self, space: &str, addr: u32, val: u8) -> Result<(), String> {
+        let target = video_memory(space)?;
+        if self.vdp_bus.borrow().dma_active() {
+            return Err(String::from("VDP DMA in progress"));
+        }
+        let vdp = self.vdp.as_mut().ok_or("no VDP")?;
+        if vdp.poke_memory(target, addr as usize, val) {
+            Ok(())
+        } else {
+            Err(format!("{} address {:X} out of range", space, addr))
+        }
+    }
+
+    fn audio_stats(&self) -> Option<(usize, usize)> {
+        self.sinks.iter().filter_map(|sink| sink.buffer_stats()).next()
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    fn set_pc_trigger(&mut self, addr: Option<u32>) {
+        self.pc_trigger = addr.map(|addr| addr & 0xFFFFFF);
+        self.pc_triggered = false;
+    }
+
+    fn pc_triggered(&mut self) -> bool {
+        std::mem::take(&mut self.pc_triggered)
+    }
+
+    fn state_layout(&self) -> Vec<(&'static str, usize)> {
+        let len = |save: &dyn Fn(&mut Vec<u8>)| {
+            let mut out = Vec::new();
+            save(&mut out);
+            out.len()
+        };
+        let mut layout: Vec<_> = ["A0", "A1", "A2", "A3", "A4", "A5", "A6", "USP", "SSP"]
+            .iter()
+            .chain(&["D0", "D1", "D2", "D3", "D4", "D5", "D6", "D7"])
+            .map(|&name| (name, 4))
+            .collect();
+        layout.extend(vec![
+            ("SR", 2),
+            ("PC", 4),
+            ("RAM", self.internal_ram.len()),
+            ("SRAM", self.sram.as_ref().map_or(0, |sram| sram.data.len())),
+            ("inputs", self.inputs.len()),
+            ("controller TH", self.controller_th_bit.len()),
+            ("controller read state", self.controller_read_state.len()),
+            ("controller decay", 4),
+            ("ticks", 8),
+            ("cycle count", 8),
+            ("stopped", 1),
+            ("speed", 8),
+            ("clock", len(&|out| self.clock.save_state(out))),
+            ("Z80", len(&|out| self.z80.save_state(out))),
+            (
+                "VDP",
+                len(&|out| {
+                    if let Some(ref vdp) = self.vdp {
+                        vdp.save_state(out);
+                    }
+                }),
+            ),
+            ("VDP bus", len(&|out| self.vdp_bus.borrow().save_state(out))),
+        ]);
+        layout
+    }
+}
+
+fn video_memory(space: &str) -> Result<AddrTarget, String> {
+    match space {
+        "vram" => Ok(AddrTarget::VRAM),
+        "cram" => Ok(AddrTarget::CRAM),
+        "vsram" => Ok(AddrTarget::VSRAM),
+        _ => Err(format!(
+            "unknown video memory {} (vram, cram or vsram)",
+            space
+        )),
     }
 }
@@ -2319,17 +2319,18 @@ impl Opcode {
                 mode.extension_bytes(Size::Word) + Size::Word.extension_bytes()
             }
 
-            Opcode::ANDI_to_CCR
-            | Opcode::EORI_to_CCR
-            | Opcode::MOVE_to_CCR { .. }
-            | Opcode::ORI_to_CCR => Size::Byte.extension_bytes(),
+            Opcode::ANDI_to_CCR | Opcode::EORI_to_CCR | Opcode::ORI_to_CCR => {
+                Size::Byte.extension_bytes()
+            }
+
+            Opcode::MOVE_from_SR { mode }
+            | Opcode::MOVE_to_CCR { mode }
+            | Opcode::MOVE_to_SR { mode } => mode.extension_bytes(Size::Word),
 
             Opcode::ANDI_to_SR
             | Opcode::DBcc { .. }
             | Opcode::EORI_to_SR
             | Opcode::LINK { .. }
-            | Opcode::MOVE_from_SR { .. }
-            | Opcode::MOVE_to_SR { .. }
             | Opcode::ORI_to_SR
             | Opcode::STOP => Size::Word.extension_bytes(),
 
@@ -3106,17 +3107,17 @@ impl Opcode {
                 _ => panic!(),
             },
             Opcode::EXG { .. } => 6,
+            // plus a data-dependent count added when the multiply executes
             Opcode::MULU { mode, .. } | Opcode::MULS { mode, .. } => match mode {
-                // TODO exact timings
-                AddressingMode::DataRegister(_) => 70,
-                AddressingMode::Address(_) | AddressingMode::AddressWithPostincrement(_) => 74,
-                AddressingMode::AddressWithPredecrement(_) => 76,
+                AddressingMode::DataRegister(_) => 38,
+                AddressingMode::Address(_) | AddressingMode::AddressWithPostincrement(_) => 42,
+                AddressingMode::AddressWithPredecrement(_) => 44,
                 AddressingMode::AddressWithDisplacement(_)
                 | AddressingMode::ProgramCounterWithDisplacement
-                | AddressingMode::AbsoluteShort => 78,
-                AddressingMode::AddressWithIndex(_) | AddressingMode::ProgramCounterWithIndex => 80,
-                AddressingMode::AbsoluteLong => 82,
-                AddressingMode::Immediate => 78,
+                | AddressingMode::AbsoluteShort => 46,
+                AddressingMode::AddressWithIndex(_) | AddressingMode::ProgramCounterWithIndex => 48,
+                AddressingMode::AbsoluteLong => 50,
+                AddressingMode::Immediate => 42,
                 _ => panic!(),
             },
             Opcode::ADDA { mode, size, .. } | Opcode::SUBA { mode, size, .. } => match size {
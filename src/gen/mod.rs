@@ -1,36 +1,71 @@
 use std::cell::RefCell;
 use std::error::Error;
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 
 use piston_window::*;
+use portaudio::PortAudio;
 use simple_error::SimpleResult;
 
 use Commands;
-use window::window_loop;
+use args::Switch;
+use audio::AudioSink;
+use audio::filter::AudioFilter;
+use audio::output::{PortAudioSink, OUTPUT_HZ};
+use audio::sync::AudioSync;
+use audio::wav::WavSink;
+use gen::cartridge::Cartridge;
+use input::script::InputScript;
+use menu::GEN_CONTROLS;
+use playtime::PlayTime;
+use rom::{write_save, RamOverrides};
+use trace::{Trace, TraceStart};
+use window::debug::DebugWindows;
+use window::renderer::RenderSettings;
+use window::{hash_frames, open_console, open_frame_dumper, window_loop, BootTurbo, Exit};
 
 pub mod cartridge;
+pub mod clock;
 pub mod m68k;
 pub mod vdp;
+pub mod ym2612;
 pub mod z80;
 
 pub fn load_cartridge(
     src: &mut dyn Read,
     save_data: Option<&mut dyn Read>,
-) -> SimpleResult<Box<[u8]>> {
-    cartridge::read(src, save_data)
+    ram: &RamOverrides,
+) -> SimpleResult<Cartridge> {
+    cartridge::read(src, save_data, ram)
 }
 
 pub fn disassemble(
-    cartridge: Box<[u8]>,
+    cartridge: Cartridge,
     mut out: &mut Box<dyn Write>,
 ) -> Result<(), Box<dyn Error>> {
-    m68k::disassembler::disassemble(cartridge, &mut out)
+    m68k::disassembler::disassemble(cartridge.rom, &mut out)
+}
+
+// hashes of each of the first `frames` frames, run with no window, sound, or input
+pub fn run_headless(mut cartridge: Cartridge, frames: u64) -> Vec<u64> {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let vdp = vdp::Vdp::new::<NoWindow>(&vdp_bus, None, RenderSettings::default(), false, false);
+    let mut cpu = m68k::Cpu::boot(&cartridge.rom, Some(vdp), &vdp_bus, false);
+    if let Some(sram) = cartridge.sram.take() {
+        cpu.attach_sram(sram);
+    }
+    hash_frames(
+        &mut cpu,
+        &[::input::player_1_gen(), ::input::player_2_gen()],
+        frames,
+    )
 }
 
 pub fn run(
     command: Commands,
-    cartridge: Box<[u8]>,
+    mut cartridge: Cartridge,
+    rom_path: &Path,
     save_path: PathBuf,
     mut window: PistonWindow<sdl2_window::Sdl2Window>,
 ) {
@@ -39,34 +74,239 @@ pub fn run(
         bench_mode,
         dump_vram,
         pause,
+        pause_on_unfocus,
+        exit_on_jam,
+        fast_forward_speed,
+        boot_turbo_secs,
+        boot_turbo_until,
+        input_polls,
+        lockstep,
+        stdin_input,
+        exit_on_eof,
+        quit_key,
+        swap_controllers,
+        filter,
+        nearest,
+        coverage,
+        profile,
+        record_audio,
+        audio_stats,
+        audio_sync,
+        audio_filter,
+        audio_high_pass,
+        audio_low_pass,
+        debug_port,
+        cart_ram,
+        sram,
+        dump_frames,
+        dump_frames_max,
+        rewind_secs,
+        no_sprite_limit,
+        watch,
+        trace_from,
+        trace_on_change,
+        trace_count,
+        trace_until,
+        debug_windows,
+        #[cfg(feature = "dev")]
+        entry,
+        #[cfg(feature = "dev")]
+        inject,
         ..
     } = command
     {
         window.set_size([320, 224]);
         let mut window = window.ups(60).bench_mode(bench_mode);
+        let mut debug_windows = DebugWindows::open(&window, &debug_windows);
 
         let mut inputs = [::input::player_1_gen(), ::input::player_2_gen()];
         let record_path = save_path.with_extension("rcd");
+        // keyed by the ROM as it was at startup, so reloading a rebuilt one keeps counting
+        let rom = std::fs::read(rom_path).unwrap_or_default();
+        let mut play_time = PlayTime::load(&save_path.with_extension("playtime"), &rom);
+        let mut console = open_console(debug_port);
+        let mut frame_dumper = open_frame_dumper(dump_frames, dump_frames_max);
+        let mut input_script = if stdin_input {
+            Some(InputScript::new(
+                Box::new(BufReader::new(std::io::stdin())),
+                exit_on_eof,
+            ))
+        } else {
+            None
+        };
+        // only the first boot, not reloads
+        let mut boot_turbo = boot_turbo_until
+            .map(BootTurbo::Pc)
+            .or(boot_turbo_secs.map(BootTurbo::Secs));
+
+        let audio_sync = if audio_sync == AudioSync::Pull {
+            warn!(target: "audio", "pulled audio is NES only; resampling instead");
+            AudioSync::Resample
+        } else {
+            audio_sync
+        };
+        let ticks_per_sample = ym2612::SAMPLE_HZ / OUTPUT_HZ;
+        let mut sinks: Vec<Box<dyn AudioSink>> = vec![Box::new(
+            PortAudioSink::new(
+                PortAudio::new().unwrap(),
+                ticks_per_sample,
+                audio_stats,
+                audio_sync,
+            )
+            .unwrap(),
+        )];
+        if let Some(ref path) = record_audio {
+            sinks.push(Box::new(
+                WavSink::create(path, OUTPUT_HZ as u32, ticks_per_sample).unwrap(),
+            ));
+        }
 
-        let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(instrument_cpu));
+        // each pass boots the current cartridge; a reload swaps in the one re-read from disk
+        // and keeps the window and audio output
+        loop {
+            // the ROM database can ask for swapped controllers, but can't undo the flag
+            let swap_controllers = swap_controllers || cartridge.swap_controllers;
 
-        let vdp = vdp::Vdp::new(&vdp_bus, Some(&mut window), dump_vram, instrument_cpu);
-        let mut cpu = m68k::Cpu::boot(&cartridge, Some(vdp), &vdp_bus, instrument_cpu);
+            let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(instrument_cpu));
 
-        window_loop(
-            window,
-            &mut inputs,
-            &record_path,
-            &mut cpu,
-            320.0,
-            224.0,
-            &Path::new("settings_gen.dat"),
-            pause,
-            instrument_cpu,
-        );
+            let mut vdp = vdp::Vdp::new(
+                &vdp_bus,
+                Some(&mut window),
+                RenderSettings {
+                    filter,
+                    smooth: !nearest,
+                },
+                dump_vram,
+                instrument_cpu,
+            );
+            if no_sprite_limit {
+                vdp.disable_sprite_limit();
+            }
+            let mut cpu = m68k::Cpu::boot(&cartridge.rom, Some(vdp), &vdp_bus, instrument_cpu);
+            if let Some(sram) = cartridge.sram.take() {
+                cpu.attach_sram(sram);
+            }
+            cpu.attach_audio_sinks(sinks);
+            cpu.set_audio_filter(audio_filter.unwrap_or(AudioFilter::Genesis).chain(
+                ym2612::SAMPLE_HZ as f32,
+                &audio_high_pass,
+                audio_low_pass,
+            ));
+            if coverage.is_some() {
+                cpu.enable_coverage();
+            }
+            if profile {
+                cpu.enable_profile();
+            }
+            let trace_start = trace_from
+                .map(TraceStart::Pc)
+                .or(trace_on_change.map(TraceStart::Change));
+            if let Some(start) = trace_start {
+                let path = save_path.with_extension("trace");
+                match Trace::create(&path, start, trace_count, trace_until) {
+                    Ok(trace) => cpu.enable_trace(trace),
+                    Err(e) => error!(target: "ctrl", "couldn't create {}: {}", path.display(), e),
+                }
+            }
+            #[cfg(feature = "dev")]
+            {
+                for injection in inject.iter() {
+                    cpu.inject(injection.addr, &std::fs::read(&injection.path).unwrap());
+                }
+                if let Some(pc) = entry {
+                    cpu.set_entry(pc);
+                }
+            }
 
-        cpu.close();
+            let reloaded = loop {
+                let exit = window_loop(
+                    &mut window,
+                    &mut inputs,
+                    &record_path,
+                    &mut cpu,
+                    320.0,
+                    224.0,
+                    &Path::new("settings_gen.dat"),
+                    GEN_CONTROLS,
+                    pause,
+                    pause_on_unfocus == Switch::On,
+                    exit_on_jam,
+                    fast_forward_speed,
+                    input_polls,
+                    lockstep,
+                    input_script.as_mut(),
+                    boot_turbo.take(),
+                    quit_key,
+                    swap_controllers,
+                    instrument_cpu,
+                    &mut console,
+                    &mut frame_dumper,
+                    rewind_secs * 60,
+                    if watch { Some(rom_path) } else { None },
+                    &mut debug_windows,
+                    &mut play_time,
+                );
+                match exit {
+                    Exit::Quit => break None,
+                    Exit::Reload { keep_sram } => {
+                        let mut save: Vec<u8> = Vec::new();
+                        if keep_sram {
+                            cpu.save_to_battery(&mut save).unwrap();
+                        }
+                        // a broken build keeps the current core running
+                        match reload_cartridge(rom_path, &save, &RamOverrides { cart_ram, sram }) {
+                            Ok(cartridge) => {
+                                info!(target: "ctrl", "reloaded {}", rom_path.display());
+                                break Some(cartridge);
+                            }
+                            Err(e) => {
+                                error!(target: "ctrl", "couldn't reload {}: {}", rom_path.display(), e)
+                            }
+                        }
+                    }
+                }
+            };
+
+            // saved before anything is torn down, so a failure there can't lose it
+            let mut save: Vec<u8> = Vec::new();
+            cpu.save_to_battery(&mut save).unwrap();
+            write_save(&save_path, &save);
+            sinks = if reloaded.is_some() {
+                cpu.take_audio_sinks()
+            } else {
+                Vec::new()
+            };
+            cpu.close();
+            if let Some(ref path) = coverage {
+                cpu.write_coverage(path).unwrap();
+            }
+            if profile {
+                cpu.write_profile(&save_path.with_extension("profile")).unwrap();
+            }
+            drop(cpu);
+            match reloaded {
+                Some(reloaded) => cartridge = reloaded,
+                None => break,
+            }
+        }
+        if let Some(ref mut frame_dumper) = frame_dumper {
+            frame_dumper.stop();
+        }
     } else {
         panic!()
     }
 }
+
+fn reload_cartridge(
+    rom_path: &Path,
+    save: &[u8],
+    ram: &RamOverrides,
+) -> Result<Cartridge, Box<dyn Error>> {
+    let mut file = File::open(rom_path)?;
+    let mut save_data = save;
+    Ok(load_cartridge(
+        &mut file,
+        if save.is_empty() { None } else { Some(&mut save_data) },
+        ram,
+    )?)
+}
@@ -1,13 +1,16 @@
 use std::fmt::{Debug, Formatter};
 
-#[derive(Copy, Clone, Debug)]
+use bincode::{deserialize_from, serialize};
+use bytes::{Buf, BufMut};
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum AddrMode {
     Read,
     ReadByte,
     Write,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum AddrTarget {
     VRAM,
     CRAM,
@@ -15,7 +18,7 @@ pub enum AddrTarget {
     Invalid,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Addr {
     pub mode: AddrMode,
     pub target: AddrTarget,
@@ -59,6 +62,7 @@ impl Addr {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Status {
     fifo_empty: bool,
     fifo_full: bool,
@@ -84,14 +88,14 @@ impl Status {
             | if self.vblank { 1 << 3 } else { 0 }
             | if self.hblank { 1 << 2 } else { 0 }
             | if self.dma { 1 << 1 } else { 0 }
-            | if self.pal { 1 << 1 } else { 0 }
+            | if self.pal { 1 } else { 0 }
     }
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Mode1 {
-    blank_leftmost_8: bool,
+    pub blank_leftmost_8: bool,
     pub enable_horizontal_interrupt: bool,
     use_high_color_bits: bool,
     pub freeze_hv_counter: bool,
@@ -111,7 +115,7 @@ impl Mode1 {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Mode2 {
     use_128k_vram: bool,
     pub enable_display: bool,
@@ -134,13 +138,13 @@ impl Mode2 {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum VerticalScrollingMode {
     Column16Pixels,
     FullScreen,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum HorizontalScrollingMode {
     Row1Pixel,
     Row8Pixel,
@@ -149,7 +153,7 @@ pub enum HorizontalScrollingMode {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Mode3 {
     enable_external_interrupt: bool,
     pub vertical_scrolling_mode: VerticalScrollingMode,
@@ -176,7 +180,7 @@ impl Mode3 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 enum InterlaceMode {
     NoInterlace,
     InterlaceNormal,
@@ -184,7 +188,7 @@ enum InterlaceMode {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Mode4 {
     pub h_40_wide_mode: bool,
     freeze_hsync: bool,
@@ -212,31 +216,32 @@ impl Mode4 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum WindowHPos {
     DrawToRight(u8),
     DrawToLeft(u8),
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum WindowVPos {
     DrawToTop(u8),
     DrawToBottom(u8),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum DmaType {
     RamToVram,
     VramFill,
     VramToVram,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum WriteData {
     Byte(u8),
     Word(u16),
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct VdpBus {
     address_register_pending_write: bool,
     pub status: Status,
@@ -262,15 +267,19 @@ pub struct VdpBus {
     dma_length: u16,
     dma_source_addr: u32,
     pub dma_type: DmaType,
+    dma_slots_remaining: u32,
     addr_register: u32,
     pub addr: Option<Addr>,
     pub start_dma: bool,
     pub read_data: u32,
     pub write_data: [WriteData; 4],
+    write_data_addr: [Option<Addr>; 4],
     write_data_start: usize,
     write_data_end: usize,
+    write_data_len: usize,
     pub horizontal_interrupt: bool,
     pub z80_interrupt: bool,
+    debug_register: u16,
 
     instrumented: bool,
 }
@@ -337,6 +346,7 @@ impl VdpBus {
             dma_length: 0,
             dma_source_addr: 0,
             dma_type: DmaType::RamToVram,
+            dma_slots_remaining: 0,
             addr_register: 0,
             addr: None,
             start_dma: false,
@@ -347,17 +357,28 @@ impl VdpBus {
                 WriteData::Byte(0),
                 WriteData::Byte(0),
             ],
+            write_data_addr: [None; 4],
             write_data_start: 0,
             write_data_end: 0,
+            write_data_len: 0,
             horizontal_interrupt: false,
             z80_interrupt: false,
+            debug_register: 0,
             instrumented,
         }
     }
 
     pub fn read_byte(&mut self, addr: u32) -> u8 {
         match addr {
-            0xC00000..=0xC00003 => (self.read_word(addr % 2) >> 8) as u8,
+            // a byte read of the data port is still a whole access, and advances the address
+            0xC00000..=0xC00003 => {
+                let data = self.read_word(0xC00000);
+                if addr & 1 == 0 {
+                    (data >> 8) as u8
+                } else {
+                    data as u8
+                }
+            }
             0xC00004 | 0xC00006 | 0xC00008 | 0xC0000A | 0xC0000C | 0xC0000E => {
                 (self.read_word(addr) >> 8) as u8
             }
@@ -394,7 +415,7 @@ impl VdpBus {
             }
             0xC00004 | 0xC00006 => {
                 self.address_register_pending_write = false;
-                self.status.to_u16()
+                self.read_status()
             }
             0xC00008 | 0xC0000A | 0xC0000C | 0xC0000E => {
                 if let InterlaceMode::NoInterlace = self.mode_4.interlace_mode {
@@ -440,11 +461,22 @@ impl VdpBus {
                     0
                 }
             }
-            0xC00004 => ((self.status.to_u16() as u32) << 16) | (self.status.to_u16() as u32),
+            0xC00004 => {
+                let status = self.read_status() as u32;
+                (status << 16) | status
+            }
             _ => panic!(),
         }
     }
 
+    // the sprite overflow and collision flags stay set until the status register is read
+    fn read_status(&mut self) -> u16 {
+        let status = self.status.to_u16();
+        self.status.sprite_limit = false;
+        self.status.sprite_overlap = false;
+        status
+    }
+
     fn fifo_munge(&self, data: u16, mode: AddrMode, target: AddrTarget) -> u16 {
         let fifo_val = self.fifo_garbage();
         match (mode, target) {
@@ -477,10 +509,7 @@ impl VdpBus {
                         debug!(target: "vdp", "{} {} write {:02X} {:08X} {:?}", self.beam_vpos, self.beam_hpos, data, addr, target)
                     }
                 }
-                self.write_data[self.write_data_end] = WriteData::Byte(data);
-                self.write_data_end = (self.write_data_end + 1) % 4;
-                self.status.fifo_empty = false;
-                self.status.fifo_full = self.write_data_end == (self.write_data_start + 3) % 4;
+                self.push_write_data(WriteData::Byte(data));
                 self.address_register_pending_write = false;
             }
             0xC00004..=0xC00007 => self.write_word(addr, ((data as u16) << 8) | (data as u16)),
@@ -503,10 +532,7 @@ impl VdpBus {
                         debug!(target: "vdp", "{} {} write {:04X} {:08X} {:?}", self.beam_vpos, self.beam_hpos, data, addr, target)
                     }
                 }
-                self.write_data[self.write_data_end] = WriteData::Word(data);
-                self.write_data_end = (self.write_data_end + 1) % 4;
-                self.status.fifo_empty = false;
-                self.status.fifo_full = self.write_data_end == (self.write_data_start + 3) % 4;
+                self.push_write_data(WriteData::Word(data));
                 self.address_register_pending_write = false;
             }
             0xC00004 | 0xC00006 => {
@@ -518,6 +544,7 @@ impl VdpBus {
                     }
                     if let Some(Addr { dma: true, .. }) = self.addr {
                         self.start_dma = true;
+                        self.status.dma = true;
                     }
                     self.address_register_pending_write = false;
                 } else if (data >> 14) & 0b11 == 0b10 {
@@ -541,27 +568,25 @@ impl VdpBus {
                                 }
                             }
                             0x02 => {
-                                self.plane_a_nametable_addr = data << 10;
+                                self.plane_a_nametable_addr = (data & 0x38) << 10;
                                 if self.instrumented {
                                     debug!(target: "vdp", "{} {} set plane A nametable {:04X}", self.beam_vpos, self.beam_hpos, self.plane_a_nametable_addr);
                                 }
                             }
-                            // TODO ignore lsb in 320 pixel mode
                             0x03 => {
-                                self.window_nametable_addr = data << 10;
+                                self.window_nametable_addr = (data & 0x3E) << 10;
                                 if self.instrumented {
                                     debug!(target: "vdp", "{} {} set window nametable {:04X}", self.beam_vpos, self.beam_hpos, self.window_nametable_addr);
                                 }
                             }
                             0x04 => {
-                                self.plane_b_nametable_addr = data << 13;
+                                self.plane_b_nametable_addr = (data & 0x07) << 13;
                                 if self.instrumented {
                                     debug!(target: "vdp", "{} {} set plane B nametable {:04X}", self.beam_vpos, self.beam_hpos, self.plane_b_nametable_addr);
                                 }
                             }
-                            // TODO ignore lsb in 320 pixel mode
                             0x05 => {
-                                self.sprite_table_addr = data << 9;
+                                self.sprite_table_addr = (data & 0x7F) << 9;
                                 if self.instrumented {
                                     debug!(target: "vdp", "{} {} set sprite table {:04X}", self.beam_vpos, self.beam_hpos, self.sprite_table_addr);
                                 }
@@ -595,7 +620,7 @@ impl VdpBus {
                                 }
                             }
                             0x0D => {
-                                self.horizontal_scroll_data_addr = data << 10;
+                                self.horizontal_scroll_data_addr = (data & 0x3F) << 10;
                                 if self.instrumented {
                                     debug!(target: "vdp", "{} {} set horizontal scroll data {:04X}", self.beam_vpos, self.beam_hpos, self.horizontal_scroll_data_addr);
                                 }
@@ -697,7 +722,12 @@ impl VdpBus {
                     self.address_register_pending_write = true;
                 }
             }
-            0xC0001C | 0xC0001E => {} // TODO: debug register
+            0xC0001C | 0xC0001E => {
+                self.debug_register = data;
+                if self.instrumented {
+                    debug!(target: "vdp", "{} {} set debug register {:04X}", self.beam_vpos, self.beam_hpos, self.debug_register);
+                }
+            }
             _ => panic!("{:06X}", addr),
         }
     }
@@ -728,6 +758,7 @@ impl VdpBus {
         write_data: Option<WriteData>,
     ) {
         let mut len = self.dma_length;
+        let mut transferred = 0;
         let mut first_write = true;
         if self.instrumented {
             debug!(target: "vdp", "{} {} DMA {:?} {:06X} to {:04X}, length {}",
@@ -838,26 +869,136 @@ impl VdpBus {
                 (self.dma_source_addr & (!0xFFFF)) | ((self.dma_source_addr + 1) & 0xFFFF);
             self.increment_addr();
             first_write = false;
+            transferred += 1;
             len = len.wrapping_sub(1);
             if len == 0 {
                 break;
             }
         }
+        // a slot moves a byte into VRAM or a word into CRAM or VSRAM; a copy reads and writes
+        // each byte
+        let slots_per_unit = match (&self.dma_type, target_type) {
+            (DmaType::RamToVram, AddrTarget::VRAM) | (DmaType::VramToVram, _) => 2,
+            _ => 1,
+        };
+        self.dma_slots_remaining = transferred * slots_per_unit;
         self.start_dma = false;
+        self.status.dma = self.dma_slots_remaining > 0;
         self.write_data_start = self.write_data_end;
+        self.write_data_len = 0;
         self.status.fifo_empty = true;
         self.status.fifo_full = false;
     }
 
-    pub fn next_write_data(&mut self) -> Option<WriteData> {
-        if self.write_data_start == self.write_data_end {
+    // the data is all written as soon as the DMA starts, but it stays busy until the VDP has
+    // had enough free access slots to have moved it
+    pub fn use_dma_slots(&mut self, slots: u32) {
+        if self.dma_slots_remaining > 0 {
+            self.dma_slots_remaining = self.dma_slots_remaining.saturating_sub(slots);
+            if self.dma_slots_remaining == 0 && !self.start_dma {
+                self.status.dma = false;
+            }
+        }
+    }
+
+    // started, or still taking up access slots
+    pub fn dma_active(&self) -> bool {
+        self.start_dma || self.status.dma
+    }
+
+    // a transfer from 68000 memory takes the bus away from the CPU until it's done
+    pub fn dma_holds_cpu(&self) -> bool {
+        match self.dma_type {
+            DmaType::RamToVram => self.status.dma,
+            _ => false,
+        }
+    }
+
+    // the highest-priority interrupt that's both pending and enabled, as (vector, level); a
+    // pending one stays pending while it's disabled, and is raised as soon as it's enabled
+    pub fn pending_interrupt(&self) -> Option<(u32, u16)> {
+        if self.status.vertical_interrupt && self.mode_2.enable_vertical_interrupt {
+            Some((30, 6))
+        } else if self.horizontal_interrupt && self.mode_1.enable_horizontal_interrupt {
+            Some((28, 4))
+        } else {
+            None
+        }
+    }
+
+    // the 68000 taking an interrupt clears it, and only it
+    pub fn acknowledge_interrupt(&mut self, level: u16) {
+        match level {
+            6 => self.status.vertical_interrupt = false,
+            4 => self.horizontal_interrupt = false,
+            _ => (),
+        }
+    }
+
+    pub fn display_enabled(&self) -> bool {
+        // debug register bit 6 blanks the display regardless of mode 2
+        self.mode_2.enable_display && self.debug_register & 0x40 == 0
+    }
+
+    pub fn end_frame(&mut self) {
+        self.status.interlaced_odd_frame = match self.mode_4.interlace_mode {
+            InterlaceMode::NoInterlace => false,
+            _ => !self.status.interlaced_odd_frame,
+        };
+    }
+
+    // each entry remembers the address it was written to, so the address can be changed
+    // (or a DMA set up) while earlier writes are still waiting to be drained
+    fn push_write_data(&mut self, data: WriteData) {
+        if self.write_data_len == 4 {
+            // the CPU is stalled while the FIFO is full, so this only happens when the bus is
+            // driven directly; drop the oldest entry
+            self.write_data_start = (self.write_data_start + 1) % 4;
+            self.write_data_len -= 1;
+        }
+        self.write_data[self.write_data_end] = data;
+        self.write_data_addr[self.write_data_end] = self.addr;
+        self.write_data_end = (self.write_data_end + 1) % 4;
+        self.write_data_len += 1;
+        if let Some(Addr {
+                        mode: AddrMode::Write,
+                        dma: false,
+                        ..
+                    }) = self.addr
+        {
+            self.increment_addr();
+        }
+        self.status.fifo_empty = false;
+        self.status.fifo_full = self.write_data_len == 4;
+    }
+
+    pub fn next_write_data(&mut self) -> Option<(Option<Addr>, WriteData)> {
+        if self.write_data_len == 0 {
             None
         } else {
             let data = self.write_data[self.write_data_start];
+            let addr = self.write_data_addr[self.write_data_start];
             self.write_data_start = (self.write_data_start + 1) % 4;
-            self.status.fifo_empty = self.write_data_start == self.write_data_end;
+            self.write_data_len -= 1;
+            self.status.fifo_empty = self.write_data_len == 0;
             self.status.fifo_full = false;
-            Some(data)
+            Some((addr, data))
         }
     }
+
+    pub fn fifo_empty(&self) -> bool {
+        self.write_data_len == 0
+    }
+
+    pub fn fifo_full(&self) -> bool {
+        self.write_data_len == 4
+    }
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_slice(&serialize(self).unwrap());
+    }
+
+    pub fn load_state(&mut self, saved: &mut dyn Buf) {
+        *self = deserialize_from(saved.reader()).unwrap();
+    }
 }
@@ -2,8 +2,10 @@ use std::borrow::BorrowMut;
 use std::cell::RefCell;
 use std::convert::TryInto;
 
+use bincode::{deserialize_from, serialize};
+use bytes::{Buf, BufMut};
 use gfx_device_gl::Device;
-use image::{GenericImage, Rgba};
+use image::{GenericImage, RgbImage, Rgba, RgbaImage};
 use num_integer::Integer;
 use piston_window::*;
 use triple_buffer::triple_buffer;
@@ -12,7 +14,7 @@ use gen::vdp::bus::{
     Addr, AddrMode, AddrTarget, DmaType, HorizontalScrollingMode, Status, VdpBus,
     VerticalScrollingMode, WindowHPos, WindowVPos, WriteData,
 };
-use window::renderer::Renderer;
+use window::renderer::{Renderer, RenderSettings};
 
 pub mod bus;
 
@@ -20,16 +22,28 @@ const BRIGHTNESS_VALS: [u8; 8] = [0, 52, 87, 116, 144, 172, 206, 255];
 const BRIGHTNESS_VALS_SHADOW: [u8; 8] = [0, 29, 52, 70, 87, 101, 116, 130];
 const BRIGHTNESS_VALS_HIGHLIGHT: [u8; 8] = [130, 144, 158, 172, 187, 206, 228, 255];
 
-const WINDOW_PRIORITY: usize = 7;
-const SPRITE_PRIORITY: usize = 6;
-const PLANE_A_PRIORITY: usize = 5;
-const PLANE_B_PRIORITY: usize = 4;
-const WINDOW: usize = 3;
-const SPRITE: usize = 2;
-const PLANE_A: usize = 1;
-const PLANE_B: usize = 0;
+const WINDOW_PRIORITY: usize = 8;
+const SPRITE_PRIORITY: usize = 7;
+const PLANE_A_PRIORITY: usize = 6;
+const PLANE_B_PRIORITY: usize = 5;
+const WINDOW: usize = 4;
+const SPRITE: usize = 3;
+const PLANE_A: usize = 2;
+const PLANE_B: usize = 1;
+const BACKDROP: usize = 0;
 
-#[derive(Copy, Clone)]
+// access slots the VDP has free for DMA on each line, H32 then H40; the display fetches take
+// most of them during active display
+const DMA_SLOTS_ACTIVE: [u32; 2] = [16, 18];
+const DMA_SLOTS_BLANKING: [u32; 2] = [167, 205];
+
+// the HV counter skips ahead partway through each line and frame, from the first value to the
+// second. the H counter is 9 bits, of which the 68000 reads the top 8: $93 then $E9 in H32, $B6
+// then $E4 in H40. NTSC's 262 lines read $EA then $E5
+const H_COUNTER_JUMP: [(u16, u16); 2] = [(0x127, 0x1D2), (0x16C, 0x1C9)];
+const V_COUNTER_JUMP: (u16, u16) = (0xEA, 0x1E5);
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
 enum SpritePixel {
     Transparent,
     Shadow,
@@ -51,10 +65,53 @@ struct Sprite {
     next: usize,
 }
 
+// the registers a line is drawn with, latched as it starts, so a write partway through a line
+// shows from the next one: the plane, window and scroll table addresses, the plane size, the
+// scrolling modes, the window position, and the backdrop color. the rest act as soon as they're
+// written: display enable and the leftmost column blank, shadow/highlight, H40, the interrupt
+// enables, and the data port and DMA registers. the sprite table address is read when the next
+// line's sprites are gathered at the end of this one
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct LineRegisters {
+    plane_a_nametable_addr: u16,
+    plane_b_nametable_addr: u16,
+    window_nametable_addr: u16,
+    horizontal_scroll_data_addr: u16,
+    plane_height: u16,
+    plane_width: u16,
+    vertical_scrolling_mode: VerticalScrollingMode,
+    horizontal_scrolling_mode: HorizontalScrollingMode,
+    window_h_pos: WindowHPos,
+    window_v_pos: WindowVPos,
+    bg_palette: u8,
+    bg_color: u8,
+}
+
+impl LineRegisters {
+    fn latch(bus: &VdpBus) -> LineRegisters {
+        LineRegisters {
+            plane_a_nametable_addr: bus.plane_a_nametable_addr,
+            plane_b_nametable_addr: bus.plane_b_nametable_addr,
+            window_nametable_addr: bus.window_nametable_addr,
+            horizontal_scroll_data_addr: bus.horizontal_scroll_data_addr,
+            plane_height: bus.plane_height,
+            plane_width: bus.plane_width,
+            vertical_scrolling_mode: bus.mode_3.vertical_scrolling_mode,
+            horizontal_scrolling_mode: bus.mode_3.horizontal_scrolling_mode,
+            window_h_pos: bus.window_h_pos,
+            window_v_pos: bus.window_v_pos,
+            bg_palette: bus.bg_palette,
+            bg_color: bus.bg_color,
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct Vdp<'a> {
-    image_buffers: [triple_buffer::Input<Box<[[u8; 4]; 71680]>>; 8],
-    renderer: Renderer<8>,
+    image_buffers: [triple_buffer::Input<Box<[[u8; 4]; 71680]>>; 9],
+    renderer: Renderer<9>,
+
+    line_registers: LineRegisters,
 
     scanline: u16,
     dot: u16,
@@ -64,6 +121,8 @@ pub struct Vdp<'a> {
     sprite_line_buffer: [(SpritePixel, bool); 320],
     dot_overflow: bool,
     prev_line_dot_overflow: bool,
+    // off to draw every sprite on a line instead of flickering past the hardware's limits
+    sprite_limit: bool,
 
     hblank_counter: u16,
 
@@ -78,12 +137,18 @@ pub struct Vdp<'a> {
 
     dump_mode: bool,
     instrumented: bool,
+
+    // only composited when something reads the finished frames, as it walks all nine layers
+    capture_frames: bool,
+    frame: Vec<u8>,
+    frame_width: u32,
 }
 
 impl<'a> Vdp<'a> {
     pub fn new<'b, W: Window>(
         bus: &'b RefCell<VdpBus>,
         window: Option<&mut PistonWindow<W>>,
+        render_settings: RenderSettings,
         dump_mode: bool,
         instrumented: bool,
     ) -> Vdp<'b> {
@@ -95,9 +160,11 @@ impl<'a> Vdp<'a> {
         let (buf5, buf5_out) = triple_buffer(&Box::new([[0u8; 4]; 71680]));
         let (buf6, buf6_out) = triple_buffer(&Box::new([[0u8; 4]; 71680]));
         let (buf7, buf7_out) = triple_buffer(&Box::new([[0u8; 4]; 71680]));
-        let image_buffers = [buf0, buf1, buf2, buf3, buf4, buf5, buf6, buf7];
+        let (buf8, buf8_out) = triple_buffer(&Box::new([[0u8; 4]; 71680]));
+        let image_buffers = [buf0, buf1, buf2, buf3, buf4, buf5, buf6, buf7, buf8];
         let image_buffer_outs = [
             buf0_out, buf1_out, buf2_out, buf3_out, buf4_out, buf5_out, buf6_out, buf7_out,
+            buf8_out,
         ];
         let renderer = Renderer::new(window, image_buffer_outs, 320, |image_buffer_out, image| {
             let pixels = image_buffer_out.output_buffer();
@@ -111,11 +178,14 @@ impl<'a> Vdp<'a> {
                     scanline += 1;
                 }
             }
-        });
+        }, render_settings);
+
+        let line_registers = LineRegisters::latch(&bus.borrow());
 
         Vdp {
             image_buffers,
             renderer,
+            line_registers,
             scanline: 0,
             dot: 0,
             h_counter: 0,
@@ -123,6 +193,7 @@ impl<'a> Vdp<'a> {
             sprite_line_buffer: [(SpritePixel::Transparent, false); 320],
             dot_overflow: false,
             prev_line_dot_overflow: false,
+            sprite_limit: true,
             hblank_counter: 0,
             vram: vec![0; 0x10000].into_boxed_slice(),
             cram: vec![0; 0x80].into_boxed_slice(),
@@ -132,14 +203,27 @@ impl<'a> Vdp<'a> {
             bus,
             dump_mode,
             instrumented,
+            capture_frames: cfg!(feature = "test"),
+            frame: vec![0; 71680 * 3],
+            frame_width: 256,
         }
     }
 
+    pub fn disable_sprite_limit(&mut self) {
+        self.sprite_limit = false;
+    }
+
+    pub fn set_capture_frames(&mut self, capture: bool) {
+        self.capture_frames = capture;
+    }
+
+    // the pixel clock is the master clock divided by 8 in H40 and by 10 in H32
     pub fn tick(&mut self, m68k_cartridge: &[u8], m68k_ram: &[u8]) {
+        let divider = if self.bus.borrow().mode_4.h_40_wide_mode { 4 } else { 5 };
         self.master_clock_ticks += 1;
-        while self.master_clock_ticks > 4 {
+        while self.master_clock_ticks > divider {
             self.do_tick(m68k_cartridge, m68k_ram);
-            self.master_clock_ticks -= 4;
+            self.master_clock_ticks -= divider;
         }
     }
 
@@ -155,6 +239,81 @@ impl<'a> Vdp<'a> {
     fn handle_bus_data(&mut self, m68k_cartridge: &[u8], m68k_ram: &[u8]) {
         let mut bus = self.bus.borrow_mut();
         let write_data = bus.next_write_data();
+        if let Some((
+                        Some(Addr {
+                                 mode: AddrMode::Write,
+                                 target,
+                                 addr,
+                                 dma: false,
+                                 ..
+                             }),
+                        data,
+                    )) = write_data
+        {
+            let addr = match target {
+                AddrTarget::CRAM | AddrTarget::VSRAM => (addr % 0x80) as usize,
+                _ => addr as usize,
+            };
+            match target {
+                AddrTarget::VRAM => match data {
+                    WriteData::Byte(val) => {
+                        if addr < self.vram.len() {
+                            self.vram[addr] = val;
+                        }
+                    }
+                    WriteData::Word(val) => {
+                        let (addr, val) = if addr % 2 == 1 {
+                            (addr - 1, val.swap_bytes())
+                        } else {
+                            (addr, val)
+                        };
+                        if addr < self.vram.len() {
+                            self.vram[addr] = (val >> 8) as u8;
+                        }
+                        if addr + 1 < self.vram.len() {
+                            self.vram[addr + 1] = (val & 0xFF) as u8;
+                        }
+                    }
+                },
+                AddrTarget::CRAM => match data {
+                    WriteData::Byte(val) => {
+                        if addr < self.cram.len() {
+                            self.cram[addr] = val & if addr % 2 == 1 { 0x1F } else { 0xFF };
+                        }
+                    }
+                    WriteData::Word(val) => {
+                        let addr = addr - (addr % 2);
+                        if addr < self.cram.len() {
+                            self.cram[addr] = ((val >> 8) as u8) & 0x1F;
+                        }
+                        if addr + 1 < self.cram.len() {
+                            self.cram[addr + 1] = (val & 0xFF) as u8;
+                        }
+                    }
+                },
+                AddrTarget::VSRAM => {
+                    if addr < self.vsram.len() {
+                        match data {
+                            WriteData::Byte(val) => {
+                                if addr < self.vsram.len() {
+                                    self.vsram[addr] = val;
+                                }
+                            }
+                            WriteData::Word(val) => {
+                                let addr = addr - (addr % 2);
+                                if addr < self.vsram.len() {
+                                    self.vsram[addr] = (val >> 8) as u8;
+                                }
+                                if addr + 1 < self.vsram.len() {
+                                    self.vsram[addr + 1] = (val & 0xFF) as u8;
+                                }
+                            }
+                        }
+                    }
+                }
+                AddrTarget::Invalid => {}
+            }
+        }
         match bus.addr {
             Some(Addr {
                      mode: AddrMode::Read,
@@ -207,88 +366,23 @@ impl<'a> Vdp<'a> {
                 }
                 _ => {}
             },
-            Some(Addr {
-                     mode: AddrMode::Write,
-                     target,
-                     addr,
-                     dma: false,
-                     ..
-                 }) => {
-                let addr = match target {
-                    AddrTarget::CRAM | AddrTarget::VSRAM => (addr % 0x80) as usize,
-                    _ => addr as usize,
-                };
-                if let Some(data) = write_data {
-                    match target {
-                        AddrTarget::VRAM => match data {
-                            WriteData::Byte(val) => {
-                                if addr < self.vram.len() {
-                                    self.vram[addr] = val;
-                                }
-                            }
-                            WriteData::Word(val) => {
-                                let (addr, val) = if addr % 2 == 1 {
-                                    (addr - 1, val.swap_bytes())
-                                } else {
-                                    (addr, val)
-                                };
-                                if addr < self.vram.len() {
-                                    self.vram[addr] = (val >> 8) as u8;
-                                }
-                                if addr + 1 < self.vram.len() {
-                                    self.vram[addr + 1] = (val & 0xFF) as u8;
-                                }
-                            }
-                        },
-                        AddrTarget::CRAM => match data {
-                            WriteData::Byte(val) => {
-                                if addr < self.cram.len() {
-                                    self.cram[addr] = val & if addr % 2 == 1 { 0x1F } else { 0xFF };
-                                }
-                            }
-                            WriteData::Word(val) => {
-                                let addr = addr - (addr % 2);
-                                if addr < self.cram.len() {
-                                    self.cram[addr] = ((val >> 8) as u8) & 0x1F;
-                                }
-                                if addr + 1 < self.cram.len() {
-                                    self.cram[addr + 1] = (val & 0xFF) as u8;
-                                }
-                            }
-                        },
-                        AddrTarget::VSRAM => {
-                            if addr < self.vsram.len() {
-                                match data {
-                                    WriteData::Byte(val) => {
-                                        if addr < self.vsram.len() {
-                                            self.vsram[addr] = val;
-                                        }
-                                    }
-                                    WriteData::Word(val) => {
-                                        let addr = addr - (addr % 2);
-                                        if addr < self.vsram.len() {
-                                            self.vsram[addr] = (val >> 8) as u8;
-                                        }
-                                        if addr + 1 < self.vsram.len() {
-                                            self.vsram[addr + 1] = (val & 0xFF) as u8;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        AddrTarget::Invalid => {}
-                    }
-                    bus.increment_addr();
-                }
-            }
             Some(Addr {
                      mode: AddrMode::Write,
                      target,
                      dma: true,
                      ..
                  }) => {
-                // TODO DMA shouldn't happen instantaneously
-                if bus.start_dma {
+                // the data moves at once; VdpBus::use_dma_slots paces how long the DMA stays busy
+                let fill_data = match write_data {
+                    Some((Some(Addr { dma: true, .. }), data)) => Some(data),
+                    _ => None,
+                };
+                // DMA doesn't start until writes queued ahead of it have drained from the FIFO
+                let ready = match bus.dma_type {
+                    DmaType::VramFill => fill_data.is_some(),
+                    _ => write_data.is_none() && bus.fifo_empty(),
+                };
+                if bus.start_dma && ready {
                     let target = match bus.dma_type {
                         DmaType::VramToVram => AddrTarget::VRAM,
                         _ => target,
@@ -302,10 +396,16 @@ impl<'a> Vdp<'a> {
                             AddrTarget::CRAM => self.cram.borrow_mut(),
                             AddrTarget::VSRAM => self.vsram.borrow_mut(),
                         },
-                        write_data,
+                        fill_data,
                     );
                 }
             }
+            // plain writes went through the FIFO above
+            Some(Addr {
+                     mode: AddrMode::Write,
+                     dma: false,
+                     ..
+                 }) => {}
             None => {}
         }
     }
@@ -325,6 +425,13 @@ impl<'a> Vdp<'a> {
         let max_sprites_per_frame = if bus.mode_4.h_40_wide_mode { 80 } else { 64 };
         let active_display_h = if bus.mode_4.h_40_wide_mode { 26 } else { 24 };
         let active_display_h_end = if bus.mode_4.h_40_wide_mode { 345 } else { 279 };
+        let line_length = if bus.mode_4.h_40_wide_mode { 420 } else { 342 };
+        // register 3's and 5's lowest bits are ignored in H40
+        let (window_mask, sprite_mask) =
+            if bus.mode_4.h_40_wide_mode { (!0x800, !0x200) } else { (!0, !0) };
+        let line = self.line_registers;
+        let window_nametable_addr = line.window_nametable_addr & window_mask;
+        let sprite_table_addr = bus.sprite_table_addr & sprite_mask;
 
         if self.h_counter >= active_display_h
             && self.h_counter <= active_display_h_end
@@ -334,26 +441,30 @@ impl<'a> Vdp<'a> {
             let x = self.dot;
             let y = self.scanline;
 
+            // blanked pixels leave every layer over the backdrop transparent, so it shows
+            // through, as it does in the border around an H32 display
             if self.dump_mode {
                 self.draw_dump_pixel(x, y, width);
-            } else {
+            } else if bus.display_enabled() && !(bus.mode_1.blank_leftmost_8 && x < 8) {
                 let i = y as usize * 320 as usize + ((320 - width) / 2) as usize + x as usize;
 
                 let mut shadow = false;
                 let mut highlight = false;
 
-                let window_tile_index = (y / 8) * 64 + (x / 8);
+                // the window's rows are 64 tiles in H40 and 32 in H32, whatever the plane size
+                let window_row_tiles = if bus.mode_4.h_40_wide_mode { 64 } else { 32 };
+                let window_tile_index = (y / 8) * window_row_tiles + (x / 8);
                 let window_tile_data_addr =
-                    (bus.window_nametable_addr + window_tile_index * 2) as usize;
+                    (window_nametable_addr + window_tile_index * 2) as usize;
                 let window_tile_data = (self.vram[window_tile_data_addr] as u16) << 8
                     | (self.vram[window_tile_data_addr + 1] as u16);
                 let window_priority = (window_tile_data >> 15) & 0b1 > 0;
 
-                let x_in_window = match bus.window_h_pos {
+                let x_in_window = match line.window_h_pos {
                     WindowHPos::DrawToRight(window_base) => x > window_base as u16 * 8,
                     WindowHPos::DrawToLeft(window_base) => x < window_base as u16 * 8,
                 };
-                let y_in_window = match bus.window_v_pos {
+                let y_in_window = match line.window_v_pos {
                     WindowVPos::DrawToTop(window_base) => y < window_base as u16 * 8,
                     WindowVPos::DrawToBottom(window_base) => y > window_base as u16 * 8,
                 };
@@ -376,25 +487,27 @@ impl<'a> Vdp<'a> {
                 let (plane_a_x, plane_a_y, plane_a_tile_data) = self.plane_scroll(
                     x,
                     y,
-                    bus.mode_3.vertical_scrolling_mode,
-                    bus.mode_3.horizontal_scrolling_mode,
-                    bus.plane_height,
-                    bus.plane_width,
-                    bus.horizontal_scroll_data_addr,
-                    bus.plane_a_nametable_addr,
+                    line.vertical_scrolling_mode,
+                    line.horizontal_scrolling_mode,
+                    line.plane_height,
+                    line.plane_width,
+                    line.horizontal_scroll_data_addr,
+                    line.plane_a_nametable_addr,
                     0,
+                    bus.mode_4.h_40_wide_mode,
                 );
 
                 let (plane_b_x, plane_b_y, plane_b_tile_data) = self.plane_scroll(
                     x,
                     y,
-                    bus.mode_3.vertical_scrolling_mode,
-                    bus.mode_3.horizontal_scrolling_mode,
-                    bus.plane_height,
-                    bus.plane_width,
-                    bus.horizontal_scroll_data_addr,
-                    bus.plane_b_nametable_addr,
+                    line.vertical_scrolling_mode,
+                    line.horizontal_scrolling_mode,
+                    line.plane_height,
+                    line.plane_width,
+                    line.horizontal_scroll_data_addr,
+                    line.plane_b_nametable_addr,
                     2,
+                    bus.mode_4.h_40_wide_mode,
                 );
 
                 let plane_a_priority = (plane_a_tile_data >> 15) & 0b1 > 0;
@@ -475,35 +588,48 @@ impl<'a> Vdp<'a> {
             }
         }
 
+        // spread the line's DMA slots evenly across it
+        let dma_slots = if self.v_counter >= 224 || !bus.display_enabled() {
+            DMA_SLOTS_BLANKING
+        } else {
+            DMA_SLOTS_ACTIVE
+        }[bus.mode_4.h_40_wide_mode as usize];
+        let h = self.h_counter as u32;
+        bus.use_dma_slots(
+            dma_slots * (h + 1) / line_length as u32 - dma_slots * h / line_length as u32,
+        );
+
         self.h_counter += 1;
         if self.h_counter > active_display_h && self.h_counter <= active_display_h_end {
             self.dot += 1;
         }
         if self.h_counter == if bus.mode_4.h_40_wide_mode { 6 } else { 5 } {
             bus.status.hblank = false;
-            bus.horizontal_interrupt = false;
-            bus.status.sprite_limit = false;
-            bus.status.sprite_overlap = false;
             self.prev_line_dot_overflow = self.dot_overflow;
             self.dot_overflow = false;
         } else if self.h_counter == if bus.mode_4.h_40_wide_mode { 330 } else { 266 } {
             self.v_counter += 1;
             if self.v_counter == 224 {
                 bus.status.vblank = true;
-                if bus.mode_2.enable_vertical_interrupt {
-                    bus.status.vertical_interrupt = true;
-                }
+                bus.status.vertical_interrupt = true;
                 bus.z80_interrupt = true;
+                let bg = self.get_color(bus.bg_palette, bus.bg_color, false, false);
+                self.frame_width = width as u32;
+                if self.capture_frames {
+                    self.capture_frame(
+                        if bus.mode_1.disable_display { [0, 0, 0, 0xff] } else { bg },
+                        !bus.mode_1.disable_display,
+                    );
+                }
                 for buf in &mut self.image_buffers {
                     buf.publish();
                 }
-                let bg = self.get_color(bus.bg_palette, bus.bg_color, false, false);
                 self.renderer.set_background(bg.map(|c| (c as f32) / 255.0));
                 for buf in &mut self.image_buffers {
                     buf.input_buffer().fill([0, 0, 0, 0]);
                 }
                 if self.dump_mode && self.instrumented {
-                    self.dump_sprite_table(bus.sprite_table_addr as usize);
+                    self.dump_sprite_table(sprite_table_addr as usize);
                 }
             } else if self.v_counter == 225 {
                 bus.z80_interrupt = false;
@@ -514,20 +640,22 @@ impl<'a> Vdp<'a> {
                 bus.status.vertical_interrupt = false;
             } else if self.v_counter == 262 {
                 self.v_counter = 0;
+                bus.end_frame();
             }
         } else if self.h_counter == if bus.mode_4.h_40_wide_mode { 358 } else { 294 } {
             bus.status.hblank = true;
-            if bus.mode_1.enable_horizontal_interrupt {
-                if self.scanline > 224 {
-                    self.hblank_counter = bus.horizontal_interrupt_counter;
-                } else if self.hblank_counter == 0 {
-                    bus.horizontal_interrupt = true;
-                    self.hblank_counter = bus.horizontal_interrupt_counter;
-                } else {
-                    self.hblank_counter -= 1;
-                }
+            // the line counter runs whether or not the interrupt is enabled: it's reloaded from
+            // register 0x0A through vertical blanking, and counts down once a line, raising an
+            // interrupt and reloading when it expires, so one comes every (register + 1) lines
+            if self.scanline > 224 {
+                self.hblank_counter = bus.horizontal_interrupt_counter;
+            } else if self.hblank_counter == 0 {
+                bus.horizontal_interrupt = true;
+                self.hblank_counter = bus.horizontal_interrupt_counter;
+            } else {
+                self.hblank_counter -= 1;
             }
-        } else if self.h_counter == if bus.mode_4.h_40_wide_mode { 420 } else { 324 } {
+        } else if self.h_counter >= line_length {
             self.h_counter = 0;
             self.dot = 0;
             self.scanline += 1;
@@ -535,9 +663,16 @@ impl<'a> Vdp<'a> {
                 self.prev_line_dot_overflow = false;
                 self.scanline = 0;
             }
+            self.line_registers = LineRegisters::latch(&bus);
+            if self.scanline < 224 {
+                let line = self.line_registers;
+                let backdrop = self.get_color(line.bg_palette, line.bg_color, false, false);
+                let row = self.scanline as usize * 320;
+                self.image_buffers[BACKDROP].input_buffer()[row..row + 320].fill(backdrop);
+            }
             self.fill_sprite_buffer(
                 self.scanline,
-                bus.sprite_table_addr as usize,
+                sprite_table_addr as usize,
                 max_sprites_per_line as usize,
                 max_sprites_per_frame,
                 width,
@@ -547,24 +682,11 @@ impl<'a> Vdp<'a> {
         }
 
         if !bus.mode_1.freeze_hv_counter {
-            bus.beam_vpos = self.v_counter;
-            if bus.beam_vpos > 234 {
-                bus.beam_vpos += 250;
-            }
-
-            bus.beam_hpos = self.h_counter;
-            if bus.mode_4.h_40_wide_mode {
-                if bus.beam_hpos > 364 {
-                    bus.beam_hpos += 92;
-                }
-            } else {
-                if bus.beam_hpos > 295 {
-                    bus.beam_hpos += 170;
-                }
-            }
-            if bus.beam_hpos > 512 {
-                bus.beam_hpos -= 512;
-            }
+            bus.beam_vpos = counter_jump(self.v_counter, V_COUNTER_JUMP);
+            bus.beam_hpos = counter_jump(
+                self.h_counter,
+                H_COUNTER_JUMP[bus.mode_4.h_40_wide_mode as usize],
+            );
         }
     }
 
@@ -579,17 +701,8 @@ impl<'a> Vdp<'a> {
         h_scroll_data_addr: u16,
         nametable_addr: u16,
         plane_offset: usize,
+        h_40_wide_mode: bool,
     ) -> (u16, u16, u16) {
-        let v_scroll_index = match v_scroll_mode {
-            VerticalScrollingMode::Column16Pixels => (x / 16 * 2 * 2) as usize,
-            VerticalScrollingMode::FullScreen => 0,
-        } + plane_offset;
-        let v_scroll = i16::from_be_bytes(
-            self.vsram[v_scroll_index..=v_scroll_index + 1]
-                .try_into()
-                .unwrap(),
-        );
-
         let h_scroll_index = match h_scroll_mode {
             HorizontalScrollingMode::Row1Pixel => (y * 2 * 2) as usize,
             HorizontalScrollingMode::Row8Pixel => (y / 8 * 8 * 2 * 2) as usize,
@@ -607,8 +720,26 @@ impl<'a> Vdp<'a> {
             )
         };
 
+        let v_scroll = match v_scroll_mode {
+            VerticalScrollingMode::Column16Pixels => {
+                // columns follow the fine horizontal scroll; the partial column on the left
+                // edge has no VSRAM entry of its own
+                let fine_h_scroll = (h_scroll & 0xF) as u16;
+                if x < fine_h_scroll {
+                    if h_40_wide_mode {
+                        self.read_v_scroll(19 * 2 * 2) & self.read_v_scroll(19 * 2 * 2 + 2)
+                    } else {
+                        0
+                    }
+                } else {
+                    self.read_v_scroll(((x - fine_h_scroll) / 16 * 2 * 2) as usize + plane_offset)
+                }
+            }
+            VerticalScrollingMode::FullScreen => self.read_v_scroll(plane_offset),
+        };
+
         let x = (x.wrapping_add_signed(-h_scroll)) % plane_width;
-        let y = (y.wrapping_add_signed(v_scroll)) % plane_height;
+        let y = (y + v_scroll) % plane_height;
 
         let tile_x = x / 8;
         let tile_y = y / 8;
@@ -620,6 +751,14 @@ impl<'a> Vdp<'a> {
         (x, y, tile_data)
     }
 
+    fn read_v_scroll(&self, index: usize) -> u16 {
+        if index + 1 < self.vsram.len() {
+            ((self.vsram[index] as u16) << 8 | self.vsram[index + 1] as u16) & 0x3FF
+        } else {
+            0
+        }
+    }
+
     fn fill_sprite_buffer(
         &mut self,
         y: u16,
@@ -638,13 +777,17 @@ impl<'a> Vdp<'a> {
         let mut unmasked_sprite_on_line = self.prev_line_dot_overflow;
         let mut masked = false;
         let mut total_sprites = 0;
+        let mut line_overflow = false;
         let mut line_sprites = [Sprite::default(); 20];
         while {
             let sprite_addr = sprite_table_addr + sprite_index * 8;
             let sprite = self.read_sprite(sprite_addr);
 
             if sprite.y <= y && sprite.y + 8 * sprite.height > y {
-                if !masked {
+                if sprites_in_line == max_sprites_per_line {
+                    line_overflow = true;
+                    status.sprite_limit = true;
+                } else if !masked {
                     if sprite.x == 0 {
                         if unmasked_sprite_on_line {
                             masked = true;
@@ -657,83 +800,138 @@ impl<'a> Vdp<'a> {
                             .min((width - dots_in_line) / 8);
                     }
                 }
-                sprites_in_line += 1;
-                dots_in_line += sprite.width * 8;
+                if !line_overflow {
+                    sprites_in_line += 1;
+                    dots_in_line += sprite.width * 8;
+                }
             }
 
             total_sprites += 1;
             sprite_index = sprite.next;
             sprite.next != 0
                 && sprite_index < max_sprites_per_frame
-                && sprites_in_line < max_sprites_per_line
+                && !line_overflow
                 && dots_in_line < width
                 && total_sprites < max_sprites_per_frame
         } {}
 
-        if sprites_in_line >= max_sprites_per_line {
-            status.sprite_limit = true;
-        }
         if dots_in_line >= width {
             self.dot_overflow = true;
         }
+        // without the limit, the sprites past it are drawn too, but the status flags still only
+        // see the ones the hardware would have drawn
+        let all_sprites = if self.sprite_limit {
+            None
+        } else {
+            Some(self.all_line_sprites(y, sprite_table_addr, max_sprites_per_frame))
+        };
         for x in 0..width {
-            let mut sprite_pixel = SpritePixel::Transparent;
-            let mut high_priority = false;
-
-            for sprite in line_sprites {
-                let x = x + 128;
-                if sprite.x <= x && sprite.x + 8 * sprite.width > x {
-                    let x_in_sprite = x - sprite.x;
-                    let y_in_sprite = y - sprite.y;
-                    let (mut x_tile, mut x_offset) = x_in_sprite.div_rem(&8);
-                    let (mut y_tile, mut y_offset) = y_in_sprite.div_rem(&8);
-                    if sprite.flip_vertical {
-                        y_tile = sprite.height - 1 - y_tile;
-                        y_offset = 7 - y_offset;
-                    }
-                    if sprite.flip_horizontal {
-                        x_tile = sprite.width - 1 - x_tile;
-                        x_offset = 7 - x_offset;
-                    }
-                    let tile_index = sprite.height * x_tile + y_tile;
-                    let tile_addr = (sprite.tile + tile_index) as usize * 0x20;
-                    let pixel_addr =
-                        tile_addr + (y_offset as usize * 8) / 2 + x_offset as usize / 2;
-                    let pixel_data = self.vram[pixel_addr];
-                    let palette_color = if x_offset % 2 == 1 {
-                        pixel_data & 0xF
-                    } else {
-                        pixel_data >> 4
-                    };
+            let (mut sprite_pixel, mut high_priority, overlap) =
+                self.sprite_pixel(&line_sprites, x + 128, y, enable_shadow_highlight);
+            if overlap {
+                status.sprite_overlap = true;
+            }
+            if let Some(ref all_sprites) = all_sprites {
+                let (pixel, priority, _) =
+                    self.sprite_pixel(all_sprites, x + 128, y, enable_shadow_highlight);
+                sprite_pixel = pixel;
+                high_priority = priority;
+            }
+            self.sprite_line_buffer[x as usize] = (sprite_pixel, high_priority);
+        }
+    }
 
-                    match (&sprite_pixel, palette_color) {
-                        (_, 0) => {}
-                        (SpritePixel::Transparent, _) => {
-                            sprite_pixel = if enable_shadow_highlight
-                                && sprite.palette_line == 3
-                                && palette_color == 14
-                            {
-                                SpritePixel::Highlight
-                            } else if enable_shadow_highlight
-                                && sprite.palette_line == 3
-                                && palette_color == 15
-                            {
-                                SpritePixel::Shadow
-                            } else {
-                                SpritePixel::Color {
-                                    palette_line: sprite.palette_line,
-                                    palette_color,
-                                }
-                            };
-                            high_priority = sprite.high_priority;
-                        }
-                        (_, _) => status.sprite_overlap = true,
-                    }
+    // every sprite on the line, in priority order, with none of the per-line limits; a sprite at
+    // x 0 still masks the ones after it
+    fn all_line_sprites(
+        &self,
+        y: u16,
+        sprite_table_addr: usize,
+        max_sprites_per_frame: usize,
+    ) -> Vec<Sprite> {
+        let mut sprites = Vec::new();
+        let mut sprite_index = 0;
+        let mut unmasked_sprite_on_line = self.prev_line_dot_overflow;
+        for _ in 0..max_sprites_per_frame {
+            let sprite = self.read_sprite(sprite_table_addr + sprite_index * 8);
+            if sprite.y <= y && sprite.y + 8 * sprite.height > y {
+                if sprite.x != 0 {
+                    unmasked_sprite_on_line = true;
+                    sprites.push(sprite);
+                } else if unmasked_sprite_on_line {
+                    break;
                 }
             }
+            sprite_index = sprite.next;
+            if sprite_index == 0 || sprite_index >= max_sprites_per_frame {
+                break;
+            }
+        }
+        sprites
+    }
 
-            self.sprite_line_buffer[x as usize] = (sprite_pixel, high_priority);
+    // the first opaque sprite pixel at x, y (in sprite coordinates), whether it's high priority,
+    // and whether another sprite's opaque pixel overlaps it
+    fn sprite_pixel(
+        &self,
+        sprites: &[Sprite],
+        x: u16,
+        y: u16,
+        enable_shadow_highlight: bool,
+    ) -> (SpritePixel, bool, bool) {
+        let mut sprite_pixel = SpritePixel::Transparent;
+        let mut high_priority = false;
+        let mut overlap = false;
+        for sprite in sprites {
+            if sprite.x <= x && sprite.x + 8 * sprite.width > x {
+                let x_in_sprite = x - sprite.x;
+                let y_in_sprite = y - sprite.y;
+                let (mut x_tile, mut x_offset) = x_in_sprite.div_rem(&8);
+                let (mut y_tile, mut y_offset) = y_in_sprite.div_rem(&8);
+                if sprite.flip_vertical {
+                    y_tile = sprite.height - 1 - y_tile;
+                    y_offset = 7 - y_offset;
+                }
+                if sprite.flip_horizontal {
+                    x_tile = sprite.width - 1 - x_tile;
+                    x_offset = 7 - x_offset;
+                }
+                let tile_index = sprite.height * x_tile + y_tile;
+                let tile_addr = (sprite.tile + tile_index) as usize * 0x20;
+                let pixel_addr = tile_addr + (y_offset as usize * 8) / 2 + x_offset as usize / 2;
+                let pixel_data = self.vram[pixel_addr];
+                let palette_color = if x_offset % 2 == 1 {
+                    pixel_data & 0xF
+                } else {
+                    pixel_data >> 4
+                };
+
+                match (&sprite_pixel, palette_color) {
+                    (_, 0) => {}
+                    (SpritePixel::Transparent, _) => {
+                        sprite_pixel = if enable_shadow_highlight
+                            && sprite.palette_line == 3
+                            && palette_color == 14
+                        {
+                            SpritePixel::Highlight
+                        } else if enable_shadow_highlight
+                            && sprite.palette_line == 3
+                            && palette_color == 15
+                        {
+                            SpritePixel::Shadow
+                        } else {
+                            SpritePixel::Color {
+                                palette_line: sprite.palette_line,
+                                palette_color,
+                            }
+                        };
+                        high_priority = sprite.high_priority;
+                    }
+                    (_, _) => overlap = true,
+                }
+            }
         }
+        (sprite_pixel, high_priority, overlap)
     }
 
     fn read_sprite(&self, sprite_addr: usize) -> Sprite {
@@ -823,10 +1021,53 @@ impl<'a> Vdp<'a> {
             };
             self.get_color(0, palette_color, false, false)
         };
-        self.image_buffers[0].input_buffer()
+        self.image_buffers[PLANE_B].input_buffer()
             [y as usize * 320 as usize + ((320 - width) / 2) as usize + x as usize] = pixel;
     }
 
+    // debug console access, straight to the memory rather than through the data port, so the
+    // address the CPU set up is left alone
+    fn memory(&mut self, target: AddrTarget) -> Option<&mut [u8]> {
+        match target {
+            AddrTarget::VRAM => Some(&mut self.vram[..]),
+            AddrTarget::CRAM => Some(&mut self.cram[..]),
+            AddrTarget::VSRAM => Some(&mut self.vsram[..]),
+            AddrTarget::Invalid => None,
+        }
+    }
+
+    pub fn peek_memory(&mut self, target: AddrTarget, addr: usize) -> Option<u8> {
+        self.memory(target)
+            .and_then(|memory| memory.get(addr).cloned())
+    }
+
+    pub fn poke_memory(&mut self, target: AddrTarget, addr: usize, val: u8) -> bool {
+        match self.memory(target).and_then(|memory| memory.get_mut(addr)) {
+            Some(byte) => {
+                *byte = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // all of VRAM as 4bpp tiles, 32 to a row, colored with one CRAM palette line
+    pub fn tilesheet(&self, palette_line: u8) -> RgbaImage {
+        let mut image = RgbaImage::new(256, (self.vram.len() / 0x20 / 32 * 8) as u32);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let tile_index = (y / 8 * 32 + x / 8) as usize;
+            let pixel_addr = tile_index * 0x20 + (y % 8 * 8) as usize / 2 + (x % 8) as usize / 2;
+            let pixel_data = self.vram[pixel_addr];
+            let palette_color = if x % 2 == 1 {
+                pixel_data & 0xF
+            } else {
+                pixel_data >> 4
+            };
+            *pixel = Rgba(self.get_color(palette_line & 3, palette_color, false, false));
+        }
+        image
+    }
+
     fn dump_sprite_table(&self, sprite_table_addr: usize) {
         debug!(target: "vdp", "Sprite table:");
         for sprite_index in 0..128 {
@@ -848,16 +1089,86 @@ impl<'a> Vdp<'a> {
     ) {
         self.dump_mode = debug;
         let bus = self.bus.borrow();
-        if !bus.mode_1.disable_display && bus.mode_2.enable_display {
+        if bus.mode_1.disable_display {
+            clear([0.0, 0.0, 0.0, 1.0], gl);
+        } else {
             self.renderer
                 .render(c, texture_ctx, gl, device, 1.0, layers);
-        } else if bus.mode_1.disable_display {
-            clear([0.0, 0.0, 0.0, 1.0], gl);
+        }
+    }
+
+    fn capture_frame(&mut self, bg: [u8; 4], show_layers: bool) {
+        for i in 0..71680 {
+            let mut color = bg;
+            if show_layers {
+                for layer in (0..9).rev() {
+                    let pixel = self.image_buffers[layer].input_buffer()[i];
+                    if pixel[3] > 0 {
+                        color = pixel;
+                        break;
+                    }
+                }
+            }
+            self.frame[i * 3..i * 3 + 3].copy_from_slice(&color[0..3]);
+        }
+    }
+
+    pub fn framebuffer(&self) -> (Vec<u8>, u32, u32) {
+        (self.frame.clone(), 320, 224)
+    }
+
+    // the last captured frame, or the picture last drawn when frames aren't being captured
+    pub fn frame_image(&self) -> Option<RgbImage> {
+        if self.capture_frames {
+            let (rgb, width, height) = self.framebuffer();
+            RgbImage::from_raw(width, height, rgb)
         } else {
-            self.renderer.clear_to_bgd(gl);
+            Some(self.renderer.image())
         }
     }
 
+    // the width of the last frame's active display, which framebuffer() centers in 320 pixels
+    pub fn active_width(&self) -> u32 {
+        self.frame_width
+    }
+
+    // the bus is saved separately, as it's shared with the CPU
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_u16(self.scanline);
+        out.put_u16(self.dot);
+        out.put_u16(self.h_counter);
+        out.put_u16(self.v_counter);
+        out.put_slice(&serialize(&self.line_registers).unwrap());
+        out.put_slice(&serialize(&self.sprite_line_buffer[..]).unwrap());
+        out.put_u8(if self.dot_overflow { 1 } else { 0 });
+        out.put_u8(if self.prev_line_dot_overflow { 1 } else { 0 });
+        out.put_u16(self.hblank_counter);
+        out.put_slice(&self.vram);
+        out.put_slice(&self.cram);
+        out.put_slice(&self.vsram);
+        out.put_u8(self.master_clock_ticks);
+        out.put_u8(if self.pixel_clock_tick { 1 } else { 0 });
+    }
+
+    pub fn load_state(&mut self, state: &mut dyn Buf) {
+        self.scanline = state.get_u16();
+        self.dot = state.get_u16();
+        self.h_counter = state.get_u16();
+        self.v_counter = state.get_u16();
+        self.line_registers = deserialize_from(state.reader()).unwrap();
+        let sprite_line_buffer: Vec<(SpritePixel, bool)> =
+            deserialize_from(state.reader()).unwrap();
+        self.sprite_line_buffer.copy_from_slice(&sprite_line_buffer);
+        self.dot_overflow = state.get_u8() == 1;
+        self.prev_line_dot_overflow = state.get_u8() == 1;
+        self.hblank_counter = state.get_u16();
+        state.copy_to_slice(&mut self.vram);
+        state.copy_to_slice(&mut self.cram);
+        state.copy_to_slice(&mut self.vsram);
+        self.master_clock_ticks = state.get_u8();
+        self.pixel_clock_tick = state.get_u8() == 1;
+    }
+
     pub fn close(&mut self) {
         for buf in &mut self.image_buffers {
             buf.publish();
@@ -865,3 +1176,11 @@ impl<'a> Vdp<'a> {
         self.renderer.close();
     }
 }
+
+fn counter_jump(counter: u16, (last, next): (u16, u16)) -> u16 {
+    if counter > last {
+        counter - last - 1 + next
+    } else {
+        counter
+    }
+}
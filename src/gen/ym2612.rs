@@ -0,0 +1,455 @@
+use std::f32::consts::PI;
+
+// the chip is clocked by the 68000's clock, and makes a sample every 144 of its cycles
+pub const MASTER_TICKS_PER_SAMPLE: u64 = 7 * 144;
+pub const SAMPLE_HZ: f64 = 53_693_175.0 / MASTER_TICKS_PER_SAMPLE as f64;
+
+// where each operator's registers sit within a block of 16: S1, S3, S2, S4 order
+const OPERATOR_OFFSETS: [u8; 4] = [0, 8, 4, 12];
+// a full-level modulator swings its carrier's phase by four cycles either way
+const MODULATION: f32 = 8.0 * PI;
+// the phase swing for each feedback setting
+const FEEDBACK: [f32; 8] = [
+    0.0,
+    PI / 16.0,
+    PI / 8.0,
+    PI / 4.0,
+    PI / 2.0,
+    PI,
+    2.0 * PI,
+    4.0 * PI,
+];
+// the envelope's attenuation is 10 bits, 96dB in all
+const MAX_ATTENUATION: i32 = 0x3FF;
+// how far an envelope moves on each of the eight steps of its cycle, by the low 2 bits of its
+// rate; rates below 48 move on only some steps, and ones above move further
+const ENVELOPE_STEPS: [[i32; 8]; 4] = [
+    [0, 1, 0, 1, 0, 1, 0, 1],
+    [0, 1, 0, 1, 1, 1, 0, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 1],
+];
+const FAST_ENVELOPE_STEPS: [[i32; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 1, 0, 0, 0, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1],
+];
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+enum Envelope {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct Operator {
+    multiple: u8,
+    total_level: u8,
+    key_scale: u8,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+
+    key_on: bool,
+    envelope: Envelope,
+    attenuation: i32,
+    phase: u32,
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            multiple: 0,
+            total_level: 0,
+            key_scale: 0,
+            attack_rate: 0,
+            decay_rate: 0,
+            sustain_rate: 0,
+            sustain_level: 0,
+            release_rate: 0,
+            key_on: false,
+            envelope: Envelope::Release,
+            attenuation: MAX_ATTENUATION,
+            phase: 0,
+        }
+    }
+
+    fn key(&mut self, on: bool) {
+        if on && !self.key_on {
+            self.envelope = Envelope::Attack;
+            self.phase = 0;
+        } else if !on && self.key_on {
+            self.envelope = Envelope::Release;
+        }
+        self.key_on = on;
+    }
+
+    // moves the envelope on one step of the envelope generator's counter
+    fn clock_envelope(&mut self, key_code: u8, counter: u32) {
+        let key_scale = key_code >> (3 - self.key_scale);
+        let rate = |rate: u8| {
+            if rate == 0 {
+                0
+            } else {
+                (rate * 2 + key_scale).min(63)
+            }
+        };
+        let rate = match self.envelope {
+            Envelope::Attack => rate(self.attack_rate),
+            Envelope::Decay => rate(self.decay_rate),
+            Envelope::Sustain => rate(self.sustain_rate),
+            Envelope::Release => rate(self.release_rate * 2 + 1),
+        };
+        let step = envelope_step(rate, counter);
+        match self.envelope {
+            Envelope::Attack => {
+                if rate >= 62 {
+                    self.attenuation = 0;
+                } else {
+                    self.attenuation += (!self.attenuation * step) >> 4;
+                }
+                if self.attenuation <= 0 {
+                    self.attenuation = 0;
+                    self.envelope = Envelope::Decay;
+                }
+            }
+            Envelope::Decay => {
+                self.attenuation = (self.attenuation + step).min(MAX_ATTENUATION);
+                let sustain_level = if self.sustain_level == 15 {
+                    0x3E0
+                } else {
+                    (self.sustain_level as i32) << 5
+                };
+                if self.attenuation >= sustain_level {
+                    self.envelope = Envelope::Sustain;
+                }
+            }
+            Envelope::Sustain | Envelope::Release => {
+                self.attenuation = (self.attenuation + step).min(MAX_ATTENUATION);
+            }
+        }
+    }
+
+    fn clock_phase(&mut self, fnum: u16, block: u8) {
+        let increment = ((fnum as u32) << block) >> 1;
+        let increment = if self.multiple == 0 {
+            increment / 2
+        } else {
+            increment * self.multiple as u32
+        };
+        self.phase = (self.phase + increment) & 0xFFFFF;
+    }
+
+    // from -1 to 1, with `modulation` added to the phase in radians
+    fn output(&self, modulation: f32) -> f32 {
+        let attenuation =
+            (self.attenuation + ((self.total_level as i32) << 3)).min(MAX_ATTENUATION);
+        if attenuation == MAX_ATTENUATION {
+            return 0.0;
+        }
+        let angle = self.phase as f32 / (1 << 20) as f32 * 2.0 * PI + modulation;
+        angle.sin() * (-(attenuation as f32) / 64.0).exp2()
+    }
+}
+
+fn envelope_step(rate: u8, counter: u32) -> i32 {
+    if rate < 2 {
+        0
+    } else if rate < 48 {
+        let shift = 11 - rate / 4;
+        if counter % (1 << shift) == 0 {
+            ENVELOPE_STEPS[(rate % 4) as usize][((counter >> shift) & 7) as usize]
+        } else {
+            0
+        }
+    } else if rate < 60 {
+        (1 + FAST_ENVELOPE_STEPS[(rate % 4) as usize][(counter & 7) as usize]) << (rate / 4 - 12)
+    } else {
+        8
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    feedback: u8,
+    algorithm: u8,
+    left: bool,
+    right: bool,
+    operators: [Operator; 4],
+    // operator 1's last two outputs, which it feeds back into itself
+    feedback_outputs: [f32; 2],
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            fnum: 0,
+            block: 0,
+            feedback: 0,
+            algorithm: 0,
+            left: true,
+            right: true,
+            operators: [Operator::new(); 4],
+            feedback_outputs: [0.0; 2],
+        }
+    }
+
+    // the block and the top bits of the frequency, which scale envelope rates
+    fn key_code(&self) -> u8 {
+        (self.block << 2) | (self.fnum >> 9) as u8
+    }
+
+    fn output(&mut self) -> f32 {
+        for operator in self.operators.iter_mut() {
+            operator.clock_phase(self.fnum, self.block);
+        }
+        let feedback = (self.feedback_outputs[0] + self.feedback_outputs[1]) / 2.0
+            * FEEDBACK[self.feedback as usize];
+        let ops = &self.operators;
+        let s1 = ops[0].output(feedback);
+        self.feedback_outputs = [s1, self.feedback_outputs[0]];
+        let m = |out: f32| out * MODULATION;
+        let out = match self.algorithm {
+            0 => {
+                let s2 = ops[1].output(m(s1));
+                let s3 = ops[2].output(m(s2));
+                ops[3].output(m(s3))
+            }
+            1 => {
+                let s2 = ops[1].output(0.0);
+                let s3 = ops[2].output(m(s1 + s2));
+                ops[3].output(m(s3))
+            }
+            2 => {
+                let s2 = ops[1].output(0.0);
+                let s3 = ops[2].output(m(s2));
+                ops[3].output(m(s1 + s3))
+            }
+            3 => {
+                let s2 = ops[1].output(m(s1));
+                let s3 = ops[2].output(0.0);
+                ops[3].output(m(s2 + s3))
+            }
+            4 => {
+                let s2 = ops[1].output(m(s1));
+                let s3 = ops[2].output(0.0);
+                s2 + ops[3].output(m(s3))
+            }
+            5 => ops[1].output(m(s1)) + ops[2].output(m(s1)) + ops[3].output(m(s1)),
+            6 => ops[1].output(m(s1)) + ops[2].output(0.0) + ops[3].output(0.0),
+            _ => s1 + ops[1].output(0.0) + ops[2].output(0.0) + ops[3].output(0.0),
+        };
+        out.clamp(-1.0, 1.0)
+    }
+}
+
+// the YM2612 FM chip: six four-operator channels, the sixth of which can instead play 8-bit
+// samples written to its DAC register, and two timers. detune, the LFO, SSG-EG, and channel 3's
+// per-operator frequencies aren't modeled
+#[derive(Serialize, Deserialize)]
+pub struct Ym2612 {
+    addr: [u8; 2],
+    fnum_latch: u8,
+    channels: [Channel; 6],
+    dac_enabled: bool,
+    dac: u8,
+
+    timer_a: u16,
+    timer_b: u8,
+    timer_a_counter: u16,
+    timer_b_counter: u16,
+    timer_b_divider: u8,
+    timer_control: u8,
+    status: u8,
+
+    envelope_divider: u8,
+    envelope_counter: u32,
+    sample: i16,
+}
+
+impl Ym2612 {
+    pub fn new() -> Ym2612 {
+        Ym2612 {
+            addr: [0; 2],
+            fnum_latch: 0,
+            channels: [Channel::new(); 6],
+            dac_enabled: false,
+            dac: 0x80,
+            timer_a: 0,
+            timer_b: 0,
+            timer_a_counter: 0,
+            timer_b_counter: 0,
+            timer_b_divider: 0,
+            timer_control: 0,
+            status: 0,
+            envelope_divider: 0,
+            envelope_counter: 0,
+            sample: 0,
+        }
+    }
+
+    // every port reads the status: the timer overflow flags, and a busy flag that's never set
+    pub fn read(&self, _port: u8) -> u8 {
+        self.status
+    }
+
+    // ports 0 and 2 select a register in parts 1 and 2, and ports 1 and 3 write it
+    pub fn write(&mut self, port: u8, val: u8) {
+        let part = ((port >> 1) & 1) as usize;
+        if port & 1 == 0 {
+            self.addr[part] = val;
+        } else {
+            self.write_register(part, self.addr[part], val);
+        }
+    }
+
+    fn write_register(&mut self, part: usize, reg: u8, val: u8) {
+        match reg {
+            0x24 if part == 0 => self.timer_a = (self.timer_a & 0x3) | (val as u16) << 2,
+            0x25 if part == 0 => self.timer_a = (self.timer_a & !0x3) | (val & 0x3) as u16,
+            0x26 if part == 0 => self.timer_b = val,
+            0x27 if part == 0 => {
+                if val & 0x1 > 0 && self.timer_control & 0x1 == 0 {
+                    self.timer_a_counter = self.timer_a;
+                }
+                if val & 0x2 > 0 && self.timer_control & 0x2 == 0 {
+                    self.timer_b_counter = self.timer_b as u16;
+                    self.timer_b_divider = 0;
+                }
+                if val & 0x10 > 0 {
+                    self.status &= !0x1;
+                }
+                if val & 0x20 > 0 {
+                    self.status &= !0x2;
+                }
+                self.timer_control = val;
+            }
+            0x28 if part == 0 => {
+                let channel = match val & 0x7 {
+                    channel @ 0..=2 => channel,
+                    channel @ 4..=6 => channel - 1,
+                    _ => return,
+                } as usize;
+                for (i, operator) in self.channels[channel].operators.iter_mut().enumerate() {
+                    operator.key(val & (0x10 << i) > 0);
+                }
+            }
+            0x2A if part == 0 => self.dac = val,
+            0x2B if part == 0 => self.dac_enabled = val & 0x80 > 0,
+            0x30..=0x9F if reg & 0x3 != 0x3 => {
+                let channel = &mut self.channels[part * 3 + (reg & 0x3) as usize];
+                let operator = OPERATOR_OFFSETS
+                    .iter()
+                    .position(|&offset| offset == reg & 0xC)
+                    .unwrap();
+                let operator = &mut channel.operators[operator];
+                match reg & 0xF0 {
+                    0x30 => operator.multiple = val & 0xF,
+                    0x40 => operator.total_level = val & 0x7F,
+                    0x50 => {
+                        operator.key_scale = val >> 6;
+                        operator.attack_rate = val & 0x1F;
+                    }
+                    0x60 => operator.decay_rate = val & 0x1F,
+                    0x70 => operator.sustain_rate = val & 0x1F,
+                    0x80 => {
+                        operator.sustain_level = val >> 4;
+                        operator.release_rate = val & 0xF;
+                    }
+                    _ => {}
+                }
+            }
+            0xA0..=0xB6 if reg & 0x3 != 0x3 => {
+                let channel = &mut self.channels[part * 3 + (reg & 0x3) as usize];
+                match reg & 0xFC {
+                    0xA0 => {
+                        channel.fnum = ((self.fnum_latch & 0x7) as u16) << 8 | val as u16;
+                        channel.block = (self.fnum_latch >> 3) & 0x7;
+                    }
+                    0xA4 => self.fnum_latch = val & 0x3F,
+                    0xB0 => {
+                        channel.feedback = (val >> 3) & 0x7;
+                        channel.algorithm = val & 0x7;
+                    }
+                    0xB4 => {
+                        channel.left = val & 0x80 > 0;
+                        channel.right = val & 0x40 > 0;
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_timers(&mut self) {
+        if self.timer_control & 0x1 > 0 {
+            self.timer_a_counter += 1;
+            if self.timer_a_counter >= 0x400 {
+                self.timer_a_counter = self.timer_a;
+                if self.timer_control & 0x4 > 0 {
+                    self.status |= 0x1;
+                }
+            }
+        }
+        // timer B counts once every 16 samples
+        self.timer_b_divider = (self.timer_b_divider + 1) % 16;
+        if self.timer_control & 0x2 > 0 && self.timer_b_divider == 0 {
+            self.timer_b_counter += 1;
+            if self.timer_b_counter >= 0x100 {
+                self.timer_b_counter = self.timer_b as u16;
+                if self.timer_control & 0x8 > 0 {
+                    self.status |= 0x2;
+                }
+            }
+        }
+    }
+
+    // makes the next sample, once every MASTER_TICKS_PER_SAMPLE master clock ticks
+    pub fn step(&mut self) -> i16 {
+        self.clock_timers();
+        // the envelopes move once every three samples
+        self.envelope_divider = (self.envelope_divider + 1) % 3;
+        if self.envelope_divider == 0 {
+            self.envelope_counter = self.envelope_counter.wrapping_add(1);
+            for channel in self.channels.iter_mut() {
+                let key_code = channel.key_code();
+                for operator in channel.operators.iter_mut() {
+                    operator.clock_envelope(key_code, self.envelope_counter);
+                }
+            }
+        }
+        let mut mix = 0.0;
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            let out = if i == 5 && self.dac_enabled {
+                (self.dac as f32 - 128.0) / 128.0
+            } else {
+                channel.output()
+            };
+            // the output is mono, so a channel panned either way is heard
+            if channel.left || channel.right {
+                mix += out;
+            }
+        }
+        self.sample = ((mix / 4.0).clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        self.sample
+    }
+
+    // the last sample step made
+    pub fn sample(&self) -> i16 {
+        self.sample
+    }
+}
+
+impl Default for Ym2612 {
+    fn default() -> Ym2612 {
+        Ym2612::new()
+    }
+}
@@ -1,8 +1,15 @@
+use std::cell::RefCell;
 use std::collections::HashSet;
+use std::io::Write;
 
+use bincode::{deserialize_from, serialize};
+use bytes::{Buf, BufMut};
 use log::Level;
 
+use gen::vdp::bus::VdpBus;
+use gen::ym2612::Ym2612;
 use gen::z80::opcodes::*;
+use profile::Profile;
 
 pub mod opcodes;
 
@@ -13,6 +20,9 @@ const HALF_CARRY: u8 = 0b10000;
 const ZERO: u8 = 0b1000000;
 const SIGN: u8 = 0b10000000;
 
+// nothing drives the data bus when the Genesis acknowledges an interrupt, so it reads as open bus
+const INTERRUPT_DATA: u8 = 0xFF;
+
 pub struct Cpu<'a> {
     pc: u16,
     sp: u16,
@@ -39,12 +49,16 @@ pub struct Cpu<'a> {
 
     ram: [u8; 0x2000],
     _cartridge: &'a Box<[u8]>,
+    // shared with the 68000; the ports are mirrored at 0x7F00
+    vdp_bus: &'a RefCell<VdpBus>,
     test_ram: Option<Box<[u8]>>,
+    test_port_writes: Option<Vec<(u16, u8)>>,
 
     bank_register: u32,
+    // on the Z80's bus, and reached by the 68000 through it
+    pub ym2612: Ym2612,
 
     cycles_to_next: u16,
-    ticks_to_next: u16,
     cycle_count: u64,
     pub instrumented: bool,
 
@@ -52,10 +66,16 @@ pub struct Cpu<'a> {
     pc_breaks: Box<HashSet<u16>>,
     memory_watches: Box<HashSet<u16>>,
     memory_breaks: Box<HashSet<u16>>,
+
+    profile: Option<Profile<Opcode>>,
 }
 
-impl Cpu<'_> {
-    pub fn new(cartridge: &Box<[u8]>, instrumented: bool) -> Cpu {
+impl<'a> Cpu<'a> {
+    pub fn new(
+        cartridge: &'a Box<[u8]>,
+        vdp_bus: &'a RefCell<VdpBus>,
+        instrumented: bool,
+    ) -> Cpu<'a> {
         Cpu {
             pc: 0,
             sp: 0,
@@ -79,16 +99,19 @@ impl Cpu<'_> {
             has_bus: true,
             ram: [0; 0x2000],
             _cartridge: cartridge,
+            vdp_bus,
             test_ram: None,
+            test_port_writes: None,
             bank_register: 0,
+            ym2612: Ym2612::new(),
             cycles_to_next: 0,
-            ticks_to_next: 0,
             cycle_count: 0,
             instrumented,
             pc_watches: Box::new(HashSet::new()),
             pc_breaks: Box::new(HashSet::new()),
             memory_watches: Box::new(HashSet::new()),
             memory_breaks: Box::new(HashSet::new()),
+            profile: None,
         }
     }
 
@@ -98,10 +121,14 @@ impl Cpu<'_> {
             None => match addr {
                 0x0000..=0x1FFF => self.ram[addr as usize],
                 0x2000..=0x3FFF => self.ram[(addr - 0x2000) as usize],
-                0x4000..=0x5FFF => 0, // TODO: YM2612
+                0x4000..=0x5FFF => self.ym2612.read((addr & 0x3) as u8),
                 0x6000..=0x60FF => 0xFF,
                 0x6100..=0x7EFF => 0xFF,
-                0x7F00..=0x7F1F => 0, // TODO: VDP
+                0x7F00..=0x7F0F => self
+                    .vdp_bus
+                    .borrow_mut()
+                    .read_byte(0xC00000 | (addr & 0x1F) as u32),
+                0x7F10..=0x7F1F => 0,
                 0x7F20..=0x7FFF => 0xFF,
                 0x8000..=0xFFFF => {
                     self._cartridge[self.bank_register as usize + (addr - 0x8000) as usize]
@@ -136,6 +163,23 @@ impl Cpu<'_> {
         val
     }
 
+    // RAM and the banked cartridge window as read_addr sees them, leaving the sound chip and VDP
+    // ports alone
+    pub fn peek_memory(&self, addr: u16) -> u8 {
+        match &self.test_ram {
+            Some(ram) => ram[addr as usize],
+            None => match addr {
+                0x0000..=0x3FFF => self.ram[(addr % 0x2000) as usize],
+                0x8000..=0xFFFF => self
+                    ._cartridge
+                    .get(self.bank_register as usize + (addr - 0x8000) as usize)
+                    .cloned()
+                    .unwrap_or(0xFF),
+                _ => 0xFF,
+            },
+        }
+    }
+
     pub fn read_word_addr(&mut self, addr: u16) -> u16 {
         (self.read_addr(addr) as u16) | ((self.read_addr(addr + 1) as u16) << 8)
     }
@@ -275,20 +319,51 @@ impl Cpu<'_> {
             None => match addr {
                 0x0000..=0x1FFF => self.ram[addr as usize] = val,
                 0x2000..=0x3FFF => self.ram[(addr - 0x2000) as usize] = val,
-                0x4000..=0x5FFF => {} // TODO: YM2612
+                0x4000..=0x5FFF => self.ym2612.write((addr & 0x3) as u8, val),
                 0x6000 => {
                     self.bank_register =
                         ((self.bank_register >> 1) & 0xFF8000) | (((val & 0b1) as u32) << 23);
                 }
                 0x6001..=0x60FF => {}
                 0x6100..=0x7EFF => {}
-                0x7F00..=0x7F1F => {} // TODO: VDP
+                0x7F00..=0x7F07 | 0x7F11 | 0x7F13 | 0x7F15 | 0x7F17 => self
+                    .vdp_bus
+                    .borrow_mut()
+                    .write_byte(0xC00000 | (addr & 0x1F) as u32, val),
+                0x7F08..=0x7F1F => {}
                 0x7F20..=0x7FFF => panic!(),
                 0x8000..=0xFFFF => {}
             },
         }
     }
 
+    // nothing answers on the Genesis Z80's I/O ports, so reads float high; under test a read
+    // returns the port's high byte, as the FUSE test harness does, and writes are recorded
+    fn read_port(&mut self, port: u16) -> u8 {
+        match self.test_port_writes {
+            Some(_) => (port >> 8) as u8,
+            None => 0xFF,
+        }
+    }
+
+    fn write_port(&mut self, port: u16, val: u8) {
+        if let Some(ref mut writes) = self.test_port_writes {
+            writes.push((port, val));
+        }
+    }
+
+    // IN and OUT put A on the upper address lines with an immediate port, and B with (C)
+    fn port_addr(&mut self, mode: AddrMode) -> u16 {
+        match mode {
+            AddrMode::Immediate => {
+                let low = self.read_byte(mode).unwrap();
+                (self.a[self.af_bank] as u16) << 8 | low as u16
+            }
+            AddrMode::Register(Register::C) => self.bc[self.register_bank],
+            _ => panic!(),
+        }
+    }
+
     pub fn write_word(&mut self, addr: u16, val: u16) {
         self.write_addr(addr, (val & 0xFF) as u8);
         self.write_addr(addr + 1, (val >> 8) as u8);
@@ -545,49 +620,79 @@ impl Cpu<'_> {
         self.i = 0;
         self.r = 0;
         self.interrupt_mode = 0;
+        self.stopped = false;
     }
 
+    // called once per Z80 clock cycle, every 15 master clock ticks
     pub fn tick(&mut self, interrupt: bool) {
-        if self.stopped {
-            self.cycles_to_next = 0;
-            self.ticks_to_next = 0;
-        } else if self.reset {
+        if self.reset {
             self.reset();
         } else if self.has_bus {
-            if self.ticks_to_next == 0 {
-                if self.cycles_to_next == 0 {
-                    if self.bus_req {
-                        self.has_bus = false;
-                        self.bus_req = false;
-                    } else {
-                        if interrupt && self.interrupt_enabled {
-                            match self.interrupt_mode {
-                                0 => {}
-                                1 => {
-                                    self.push(self.pc);
-                                    self.pc = 0x38;
-                                    self.cycles_to_next += 11;
-                                }
-                                2 => {}
-                                _ => panic!(),
+            if self.cycles_to_next == 0 {
+                if self.bus_req {
+                    self.has_bus = false;
+                    self.bus_req = false;
+                } else {
+                    if interrupt && self.interrupt_enabled {
+                        // taking an interrupt disables further ones until the handler enables
+                        // them again, and wakes a halted CPU to return past the HALT
+                        self.interrupt_enabled = false;
+                        self.interrupt_enabled_tmp = false;
+                        if self.stopped {
+                            self.stopped = false;
+                            self.pc = self.pc.wrapping_add(1);
+                        }
+                        match self.interrupt_mode {
+                            0 => {
+                                // only an RST can be supplied; anything else is taken as RST 38h
+                                self.push(self.pc);
+                                self.pc = if INTERRUPT_DATA & 0xC7 == 0xC7 {
+                                    (INTERRUPT_DATA & 0x38) as u16
+                                } else {
+                                    0x38
+                                };
+                                self.cycles_to_next += 11;
+                            }
+                            1 => {
+                                self.push(self.pc);
+                                self.pc = 0x38;
+                                self.cycles_to_next += 11;
                             }
+                            2 => {
+                                let vector = (self.i as u16) << 8 | INTERRUPT_DATA as u16;
+                                self.push(self.pc);
+                                self.pc = self.read_word_addr(vector);
+                                self.cycles_to_next += 19;
+                            }
+                            _ => panic!(),
                         }
+                    }
+                    if self.stopped {
+                        // halted, it runs NOPs until an interrupt
+                        self.cycles_to_next = 4;
+                    } else {
                         self.execute_opcode();
-                        assert_ne!(self.cycles_to_next, 0);
+                        if let Some(ref mut profile) = self.profile {
+                            profile.end(u64::from(self.cycles_to_next));
+                        }
                     }
+                    assert_ne!(self.cycles_to_next, 0);
                 }
-                self.cycles_to_next = self.cycles_to_next.saturating_sub(1);
-                self.ticks_to_next = 15;
-                self.cycle_count = self.cycle_count.wrapping_add(1);
             }
-            self.ticks_to_next = self.ticks_to_next.saturating_sub(1);
+            self.cycles_to_next = self.cycles_to_next.saturating_sub(1);
+            self.cycle_count = self.cycle_count.wrapping_add(1);
         }
     }
 
     fn execute_opcode(&mut self) {
         let opcode_pc = self.pc;
+        // cycles_to_next already holds any interrupt's cycles
+        let start_cycle = u64::from(self.cycles_to_next);
         let (opcode, opcode_reads) = self.get_opcode();
         self.pc = self.pc.wrapping_add(opcode_reads);
+        if let Some(ref mut profile) = self.profile {
+            profile.begin(&opcode, start_cycle);
+        }
 
         if self.instrumented {
             log!(target: "z80",
@@ -791,12 +896,11 @@ impl Cpu<'_> {
                 let mut a = self.a[self.af_bank];
                 let adj_lo = self.flag(HALF_CARRY) || a & 0xF > 0x9;
                 let adj_hi = self.flag(CARRY) || a > 0x99;
-                let half_carry = if self.flag(SUBTRACT) && !self.flag(HALF_CARRY) {
-                    false
-                } else if self.flag(SUBTRACT) && self.flag(HALF_CARRY) {
-                    a & 0xF < 0x6
+                // after a subtraction, H survives only if the low nibble borrows again
+                let half_carry = if self.flag(SUBTRACT) {
+                    self.flag(HALF_CARRY) && a & 0xF < 0x6
                 } else {
-                    a & 0xF > 0xA
+                    a & 0xF > 0x9
                 };
                 if adj_hi && adj_lo {
                     if self.flag(SUBTRACT) {
@@ -897,20 +1001,51 @@ impl Cpu<'_> {
             Opcode::HALT => {
                 self.stopped = true;
                 self.pc = opcode_pc;
+                self.cycles_to_next += 4;
             }
             Opcode::IM(mode) => {
                 self.interrupt_mode = mode;
                 self.cycles_to_next += 8;
             }
             Opcode::IN(dest, src) => {
-                self.read_byte(src);
-                self.write_byte_or_word(dest, Some(0xFF), None);
-                self.cycles_to_next += match dest {
-                    AddrMode::Immediate => 10,
-                    AddrMode::Register(_) => 11,
+                let port = self.port_addr(src);
+                let val = self.read_port(port);
+                self.write_byte_or_word(dest, Some(val), None);
+                // only the (C) form touches the flags
+                if let AddrMode::Register(_) = src {
+                    self.set_flag(ZERO, val == 0);
+                    self.set_flag(PARITY_OVERFLOW, Self::parity(val));
+                    self.set_flag(SIGN, val & 0x80 > 0);
+                    self.set_flag(SUBTRACT, false);
+                    self.set_flag(HALF_CARRY, false);
+                }
+                self.cycles_to_next += match src {
+                    AddrMode::Immediate => 11,
+                    AddrMode::Register(_) => 12,
                     _ => panic!(),
                 }
             }
+            Opcode::INI | Opcode::INIR | Opcode::IND | Opcode::INDR => {
+                let val = self.read_port(self.bc[self.register_bank]);
+                self.write_addr(self.hl[self.register_bank], val);
+                let c = self.bc[self.register_bank] as u8;
+                let k = if let Opcode::INI | Opcode::INIR = opcode {
+                    self.hl[self.register_bank] = self.hl[self.register_bank].wrapping_add(1);
+                    val as u16 + c.wrapping_add(1) as u16
+                } else {
+                    self.hl[self.register_bank] = self.hl[self.register_bank].wrapping_sub(1);
+                    val as u16 + c.wrapping_sub(1) as u16
+                };
+                self.bc[self.register_bank] = self.bc[self.register_bank].wrapping_sub(0x100);
+                self.set_block_io_flags(val, k);
+                if let Opcode::INIR | Opcode::INDR = opcode {
+                    if self.bc[self.register_bank] >> 8 != 0 {
+                        self.pc = opcode_pc;
+                        self.cycles_to_next += 5;
+                    }
+                }
+                self.cycles_to_next += 16;
+            }
             Opcode::INC(mode) => {
                 match mode {
                     AddrMode::Indexed(_)
@@ -1062,14 +1197,35 @@ impl Cpu<'_> {
                 self.cycles_to_next += Self::arithmetic_cycles(mode) + 4 * (opcode_reads - 1);
             }
             Opcode::OUT(dest, src) => {
-                self.read_byte(src);
-                self.read_byte(dest);
+                let port = self.port_addr(dest);
+                let val = self.read_byte(src).unwrap();
+                self.write_port(port, val);
                 self.cycles_to_next += match dest {
                     AddrMode::Immediate => 11,
                     AddrMode::Register(_) => 12,
                     _ => panic!(),
                 }
             }
+            Opcode::OUTI | Opcode::OUTIR | Opcode::OUTD | Opcode::OUTDR => {
+                let val = self.read_addr(self.hl[self.register_bank]);
+                // B is decremented before it goes out on the address bus
+                self.bc[self.register_bank] = self.bc[self.register_bank].wrapping_sub(0x100);
+                self.write_port(self.bc[self.register_bank], val);
+                if let Opcode::OUTI | Opcode::OUTIR = opcode {
+                    self.hl[self.register_bank] = self.hl[self.register_bank].wrapping_add(1);
+                } else {
+                    self.hl[self.register_bank] = self.hl[self.register_bank].wrapping_sub(1);
+                }
+                let k = val as u16 + (self.hl[self.register_bank] & 0xFF);
+                self.set_block_io_flags(val, k);
+                if let Opcode::OUTIR | Opcode::OUTDR = opcode {
+                    if self.bc[self.register_bank] >> 8 != 0 {
+                        self.pc = opcode_pc;
+                        self.cycles_to_next += 5;
+                    }
+                }
+                self.cycles_to_next += 16;
+            }
             Opcode::POP(mode) => {
                 let val = self.pop();
                 self.write_byte_or_word(mode, None, Some(val));
@@ -1526,6 +1682,77 @@ impl Cpu<'_> {
         self.pc_breaks.insert(addr);
     }
 
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    pub fn write_profile(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        match self.profile {
+            Some(ref profile) => profile.write("Z80", out),
+            None => Ok(()),
+        }
+    }
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_u16(self.pc);
+        out.put_u16(self.sp);
+        out.put_u16(self.ix);
+        out.put_u16(self.iy);
+        out.put_u8(self.i);
+        out.put_u8(self.r);
+        for bank in 0..2 {
+            out.put_u8(self.a[bank]);
+            out.put_u8(self.f[bank]);
+            out.put_u16(self.bc[bank]);
+            out.put_u16(self.de[bank]);
+            out.put_u16(self.hl[bank]);
+        }
+        out.put_u8(self.register_bank as u8);
+        out.put_u8(self.af_bank as u8);
+        out.put_u8(if self.interrupt_enabled { 1 } else { 0 });
+        out.put_u8(if self.interrupt_enabled_tmp { 1 } else { 0 });
+        out.put_u8(self.interrupt_mode);
+        out.put_u8(if self.reset { 1 } else { 0 });
+        out.put_u8(if self.stopped { 1 } else { 0 });
+        out.put_u8(if self.bus_req { 1 } else { 0 });
+        out.put_u8(if self.has_bus { 1 } else { 0 });
+        out.put_slice(&self.ram);
+        out.put_u32(self.bank_register);
+        out.put_slice(&serialize(&self.ym2612).unwrap());
+        out.put_u16(self.cycles_to_next);
+        out.put_u64(self.cycle_count);
+    }
+
+    pub fn load_state(&mut self, state: &mut dyn Buf) {
+        self.pc = state.get_u16();
+        self.sp = state.get_u16();
+        self.ix = state.get_u16();
+        self.iy = state.get_u16();
+        self.i = state.get_u8();
+        self.r = state.get_u8();
+        for bank in 0..2 {
+            self.a[bank] = state.get_u8();
+            self.f[bank] = state.get_u8();
+            self.bc[bank] = state.get_u16();
+            self.de[bank] = state.get_u16();
+            self.hl[bank] = state.get_u16();
+        }
+        self.register_bank = state.get_u8() as usize;
+        self.af_bank = state.get_u8() as usize;
+        self.interrupt_enabled = state.get_u8() == 1;
+        self.interrupt_enabled_tmp = state.get_u8() == 1;
+        self.interrupt_mode = state.get_u8();
+        self.reset = state.get_u8() == 1;
+        self.stopped = state.get_u8() == 1;
+        self.bus_req = state.get_u8() == 1;
+        self.has_bus = state.get_u8() == 1;
+        state.copy_to_slice(&mut self.ram);
+        self.bank_register = state.get_u32();
+        self.ym2612 = deserialize_from(state.reader()).unwrap();
+        self.cycles_to_next = state.get_u16();
+        self.cycle_count = state.get_u64();
+    }
+
     fn arithmetic_cycles(mode: AddrMode) -> u16 {
         match mode {
             AddrMode::Register(_) => 4,
@@ -1577,6 +1804,19 @@ impl Cpu<'_> {
         ((op1_sign ^ op2_sign) ^ (!subtract)) & (op1_sign ^ result_sign)
     }
 
+    // the block I/O flags, per "The Undocumented Z80 Documented": S and Z follow the decremented
+    // B, N is bit 7 of the byte transferred, and H, C and P/V come from k, the byte plus the
+    // adjusted C (for input) or the new L (for output)
+    fn set_block_io_flags(&mut self, val: u8, k: u16) {
+        let b = (self.bc[self.register_bank] >> 8) as u8;
+        self.set_flag(CARRY, k > 0xFF);
+        self.set_flag(HALF_CARRY, k > 0xFF);
+        self.set_flag(PARITY_OVERFLOW, Self::parity((k as u8 & 0b111) ^ b));
+        self.set_flag(ZERO, b == 0);
+        self.set_flag(SIGN, b & 0x80 > 0);
+        self.set_flag(SUBTRACT, val & 0x80 > 0);
+    }
+
     fn parity(val: u8) -> bool {
         val.count_ones() % 2 == 0
     }
@@ -1591,14 +1831,30 @@ pub mod testing {
     use gen::z80::opcodes::Opcode;
 
     impl Cpu<'_> {
+        pub fn get_bc(&self) -> u16 {
+            self.bc[0]
+        }
+
         pub fn get_de(&self) -> u16 {
             self.de[0]
         }
 
+        pub fn get_hl(&self) -> u16 {
+            self.hl[0]
+        }
+
+        pub fn get_af(&self) -> u16 {
+            (self.a[self.af_bank] as u16) << 8 | self.f[self.af_bank] as u16
+        }
+
         pub fn get_pc(&self) -> u16 {
             self.pc
         }
 
+        pub fn get_sp(&self) -> u16 {
+            self.sp
+        }
+
         pub fn get_cycle_count(&self) -> u64 {
             self.cycle_count
         }
@@ -1616,6 +1872,11 @@ pub mod testing {
             ram[start..start + src.len()].copy_from_slice(src);
             self.sp = (ram.len() - 1) as u16;
             self.test_ram = Some(ram.into_boxed_slice());
+            self.test_port_writes = Some(Vec::new());
+        }
+
+        pub fn port_writes(&self) -> &[(u16, u8)] {
+            self.test_port_writes.as_ref().unwrap()
         }
 
         pub fn init_zex_test_vectors(&mut self) {
@@ -1748,15 +2009,22 @@ pub mod testing {
         }
 
         pub fn output_test_string(&self) {
+            print!("{}", self.test_string());
+        }
+
+        // the character or $-terminated string passed to CP/M's BDOS print call
+        pub fn test_string(&self) -> String {
             if self.bc[self.register_bank] & 0xFF == 2 {
-                print!("{}", (self.de[self.register_bank] & 0xFF) as u8 as char)
+                String::from((self.de[self.register_bank] & 0xFF) as u8 as char)
             } else {
                 let mut n = self.de[self.register_bank] as usize;
                 let test_ram: &[u8] = self.test_ram.as_ref().unwrap().borrow();
+                let mut string = String::new();
                 while test_ram[n] != '$' as u8 {
-                    print!("{}", test_ram[n] as char);
+                    string.push(test_ram[n] as char);
                     n += 1;
                 }
+                string
             }
         }
     }
@@ -3,6 +3,8 @@ use piston_window::Button::*;
 
 use self::Input::*;
 
+pub mod script;
+
 #[derive(PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum Input {
     Button(::piston_window::Button),
@@ -0,0 +1,78 @@
+use std::io::BufRead;
+
+use super::ControllerState;
+
+// controller input read one line per frame, so another program can drive the emulator: one or
+// two hex bitmasks, for controllers 1 and 2, with bit i for the i-th control in the menu (for
+// the NES, A B Select Start Up Down Left Right from bit 0). A missing second mask is 0, an empty
+// line is a frame with nothing pressed, and lines starting with # are skipped without using up a
+// frame.
+pub struct InputScript {
+    src: Box<dyn BufRead>,
+    exit_on_eof: bool,
+    line_number: usize,
+    ended: bool,
+}
+
+impl InputScript {
+    pub fn new(src: Box<dyn BufRead>, exit_on_eof: bool) -> InputScript {
+        InputScript {
+            src,
+            exit_on_eof,
+            line_number: 0,
+            ended: false,
+        }
+    }
+
+    // sets the next frame's inputs, blocking until its line arrives; false when the emulator
+    // should stop, which is at the end of the stream if it was asked to, or on a line that can't
+    // be read. Otherwise everything is held released once the stream ends.
+    pub fn next_frame<const B: usize>(&mut self, inputs: &mut [ControllerState<B>; 2]) -> bool {
+        let mut line = String::new();
+        while !self.ended {
+            line.clear();
+            match self.src.read_line(&mut line) {
+                Ok(0) => self.ended = true,
+                Ok(_) => {
+                    self.line_number += 1;
+                    if line.trim_start().starts_with('#') {
+                        continue;
+                    }
+                    return match parse_line(&line) {
+                        Ok(masks) => {
+                            inputs[0].set_from_u8(masks[0]);
+                            inputs[1].set_from_u8(masks[1]);
+                            true
+                        }
+                        Err(e) => {
+                            error!(target: "ctrl", "input line {}: {}", self.line_number, e);
+                            false
+                        }
+                    };
+                }
+                Err(e) => {
+                    error!(target: "ctrl", "couldn't read input: {}", e);
+                    return false;
+                }
+            }
+        }
+        inputs[0].set_from_u8(0);
+        inputs[1].set_from_u8(0);
+        !self.exit_on_eof
+    }
+}
+
+pub fn parse_line(line: &str) -> Result<[u8; 2], String> {
+    let mut masks = [0u8; 2];
+    let mut fields = line.split_whitespace();
+    for mask in masks.iter_mut() {
+        if let Some(field) = fields.next() {
+            *mask = u8::from_str_radix(field.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("invalid mask {}: {}", field, e))?;
+        }
+    }
+    match fields.next() {
+        Some(field) => Err(format!("unexpected {}", field)),
+        None => Ok(masks),
+    }
+}
@@ -10,6 +10,7 @@ extern crate gfx_device_gl;
 extern crate graphics;
 extern crate hex_slice;
 extern crate image;
+extern crate json;
 #[macro_use]
 extern crate log;
 extern crate num_integer;
@@ -17,6 +18,7 @@ extern crate num_traits;
 extern crate piston_window;
 extern crate portaudio;
 extern crate rfd;
+extern crate sdl2;
 extern crate sdl2_window;
 extern crate serde;
 #[macro_use]
@@ -33,30 +35,77 @@ use clap::Parser;
 use piston_window::{PistonWindow, WindowSettings};
 
 use args::{Args, Commands};
-use rom::Rom;
+use recent::RecentFiles;
+use rom::{RamOverrides, Rom};
 
 pub mod args;
+pub mod audio;
+pub mod console;
 pub mod control;
+pub mod coverage;
 pub mod gen;
 pub mod input;
 pub mod menu;
 pub mod nes;
+pub mod playtime;
+pub mod profile;
+pub mod recent;
 pub mod record;
 pub mod rom;
+#[cfg(feature = "test")]
+pub mod suite;
+pub mod trace;
 pub mod window;
 
 pub fn run() {
     let args = Args::parse();
-    let window: PistonWindow<sdl2_window::Sdl2Window> =
-        WindowSettings::new("emu", [300, 300]).build().unwrap();
+    #[cfg(feature = "test")]
+    {
+        if let Commands::Suite { ref dir, bless } = args.command {
+            std::process::exit(if suite::run(dir, bless) { 0 } else { 1 });
+        }
+    }
+    let window: Option<PistonWindow<sdl2_window::Sdl2Window>> = match args.command {
+        Commands::Run {
+            headless: Some(_), ..
+        } => None,
+        _ => Some(WindowSettings::new("emu", [300, 300]).build().unwrap()),
+    };
 
+    let ram = match args.command {
+        Commands::Run { cart_ram, sram, .. } => RamOverrides { cart_ram, sram },
+        _ => RamOverrides::default(),
+    };
+
+    let mut recent = RecentFiles::load(Path::new("recent.txt"));
+    let input = match (&args.input, args.resume) {
+        (Some(input), _) => Some(input.clone()),
+        (None, true) => {
+            let last = recent.last().map(PathBuf::from);
+            if last.is_none() {
+                warn!(target: "ctrl", "No recently played ROM to resume; pick one to start.");
+            }
+            last
+        }
+        (None, false) => None,
+    };
+
+    let mut rom_path = None;
     let mut save_path = None;
     let rom: Option<Rom> = loop {
-        let input_file = match args.input {
+        let input_file = match input {
             Some(ref i) => Some(PathBuf::from(i)),
-            None => rfd::FileDialog::new().pick_file(),
+            None => {
+                // starts where the last ROM was opened from
+                let mut dialog = rfd::FileDialog::new();
+                if let Some(dir) = recent.last().and_then(Path::parent) {
+                    dialog = dialog.set_directory(dir);
+                }
+                dialog.pick_file()
+            }
         };
         if let Some(input_file) = input_file {
+            rom_path = Some(input_file.clone());
             save_path = Some(
                 PathBuf::from(".")
                     .join(input_file.file_name().unwrap())
@@ -68,6 +117,7 @@ pub fn run() {
                     Ok(ref mut file) => Some(file),
                     Err(_) => None,
                 },
+                &ram,
             );
             if let Ok(cartridge) = nes {
                 break Some(Rom::Nes(cartridge));
@@ -78,11 +128,27 @@ pub fn run() {
                     Ok(ref mut file) => Some(file),
                     Err(_) => None,
                 },
+                &ram,
             );
             if let Ok(cartridge) = gen {
                 break Some(Rom::Genesis(cartridge));
             };
-            if args.input.is_some() {
+            // with an explicit entry point, a headerless binary runs on the Genesis CPU
+            #[cfg(feature = "dev")]
+            {
+                if let Commands::Run { entry: Some(_), .. } = args.command {
+                    let mut contents = Vec::new();
+                    File::open(&input_file)
+                        .and_then(|mut file| file.read_to_end(&mut contents))
+                        .unwrap();
+                    break Some(Rom::Genesis(gen::cartridge::Cartridge {
+                        rom: contents.into_boxed_slice(),
+                        sram: None,
+                        swap_controllers: false,
+                    }));
+                }
+            }
+            if input.is_some() {
                 break None;
             }
         } else {
@@ -93,13 +159,16 @@ pub fn run() {
         None => panic!("Couldn't load ROM"),
         Some(rom) => rom,
     };
+    if let Some(ref rom_path) = rom_path {
+        recent.add(rom_path);
+    }
     let save_path = match save_path {
         None => panic!("Couldn't create save data"),
         Some(save_path) => save_path,
     };
 
     match args.command {
-        Commands::Disassemble { output } => {
+        Commands::Disassemble { output, xref } => {
             let mut out = match output {
                 Some(ref path) => {
                     Box::new(File::create(&Path::new(path)).unwrap()) as Box<dyn Write>
@@ -107,13 +176,53 @@ pub fn run() {
                 None => Box::new(std::io::stdout()) as Box<dyn Write>,
             };
             match rom {
-                Rom::Nes(cartridge) => nes::disassemble(cartridge, &mut out).unwrap(),
-                Rom::Genesis(cartridge) => gen::disassemble(cartridge, &mut out).unwrap(),
+                Rom::Nes(cartridge) => nes::disassemble(cartridge, xref, &mut out).unwrap(),
+                Rom::Genesis(cartridge) => {
+                    if xref {
+                        warn!(target: "ctrl", "--xref is only supported for NES ROMs");
+                    }
+                    gen::disassemble(cartridge, &mut out).unwrap()
+                }
+            }
+        }
+        Commands::Run {
+            headless: Some(frames),
+            region,
+            ..
+        } => {
+            let hashes = match rom {
+                Rom::Nes(mut cartridge) => {
+                    if let Some(region) = region {
+                        cartridge.region = region;
+                    }
+                    nes::run_headless(cartridge, frames)
+                }
+                Rom::Genesis(cartridge) => gen::run_headless(cartridge, frames),
+            };
+            for (frame, hash) in hashes.iter().enumerate() {
+                println!("{} {:016X}", frame + 1, hash);
+            }
+        }
+        Commands::Run { .. } => {
+            let window = window.unwrap();
+            match rom {
+                Rom::Nes(cartridge) => nes::run(
+                    args.command,
+                    cartridge,
+                    &rom_path.unwrap(),
+                    save_path,
+                    window,
+                ),
+                Rom::Genesis(cartridge) => gen::run(
+                    args.command,
+                    cartridge,
+                    &rom_path.unwrap(),
+                    save_path,
+                    window,
+                ),
             }
         }
-        Commands::Run { .. } => match rom {
-            Rom::Nes(cartridge) => nes::run(args.command, cartridge, save_path, window),
-            Rom::Genesis(cartridge) => gen::run(args.command, cartridge, save_path, window),
-        },
+        #[cfg(feature = "test")]
+        Commands::Suite { .. } => unreachable!(),
     }
 }
@@ -1,14 +1,17 @@
 extern crate bincode;
 
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use piston_window::*;
 use piston_window::Button::*;
 use serde::{Deserialize, Serialize};
 
+use control::thumbnail::{THUMBNAIL_HEIGHT, THUMBNAIL_WIDTH};
 use input::{ControllerState, Input};
 use input::Input::*;
+use playtime::format_play_time;
 
 pub const NES_CONTROLS: [(&str, usize); 8] = [
     ("Up", 4),
@@ -21,6 +24,17 @@ pub const NES_CONTROLS: [(&str, usize); 8] = [
     ("Start", 3),
 ];
 
+pub const GEN_CONTROLS: [(&str, usize); 8] = [
+    ("Up", 4),
+    ("Down", 5),
+    ("Left", 6),
+    ("Right", 7),
+    ("A", 0),
+    ("B", 1),
+    ("C", 2),
+    ("Start", 3),
+];
+
 #[derive(Serialize, Deserialize)]
 struct Buttons<const B: usize>(#[serde(with = "serde_arrays")] [Input; B]);
 
@@ -30,6 +44,9 @@ pub struct Menu<'a, const B: usize> {
     buttons: [Buttons<B>; 2],
     current_index: usize,
     awaiting_input: bool,
+    settings_path: PathBuf,
+    // one per save slot, F1 to F10
+    thumbnails: [Option<G2dTexture>; 10],
 }
 
 impl<'a, const B: usize> Menu<'a, B> {
@@ -58,9 +75,15 @@ impl<'a, const B: usize> Menu<'a, B> {
             buttons,
             current_index: 0,
             awaiting_input: false,
+            settings_path: PathBuf::from(settings_path),
+            thumbnails: Default::default(),
         }
     }
 
+    pub fn set_thumbnail(&mut self, slot: usize, thumbnail: Option<G2dTexture>) {
+        self.thumbnails[slot] = thumbnail;
+    }
+
     pub fn update_controls(&self, controls: &mut [ControllerState<B>; 2]) {
         controls[0].set_buttons(&self.buttons[0].0);
         controls[1].set_buttons(&self.buttons[1].0);
@@ -68,10 +91,23 @@ impl<'a, const B: usize> Menu<'a, B> {
 
     pub fn event(&mut self, event: &Event) -> bool {
         if self.awaiting_input {
-            if let Some(button) = event.release_args() {
+            // Escape cancels rather than being bound; a stick has to be pushed past halfway
+            let input = match (event.release_args(), event.controller_axis_args()) {
+                (Some(Keyboard(Key::Escape)), _) => {
+                    self.awaiting_input = false;
+                    None
+                }
+                (Some(button), _) => Some(Button(button)),
+                (None, Some(axis_args)) if axis_args.position.abs() > 0.5 => Some(Axis(axis_args)),
+                _ => None,
+            };
+            if let Some(input) = input {
                 self.buttons[self.current_index / 8].0
-                    [self.control_labels[self.current_index % 8].1] = Button(button);
+                    [self.control_labels[self.current_index % 8].1] = input;
                 self.awaiting_input = false;
+                if self.conflicts().contains(&self.current_index) {
+                    warn!(target: "ctrl", "{} is bound more than once", input_to_string(input));
+                }
             }
         } else {
             match event.release_args() {
@@ -97,8 +133,25 @@ impl<'a, const B: usize> Menu<'a, B> {
         self.showing
     }
 
-    pub fn render(&self, c: Context, gl: &mut G2d, glyphs: &mut Glyphs) {
+    pub fn showing(&self) -> bool {
+        self.showing
+    }
+
+    // menu indices of buttons sharing a physical input with another button, on either controller
+    pub fn conflicts(&self) -> Vec<usize> {
+        let bound = (0..16)
+            .map(|index| self.buttons[index / 8].0[self.control_labels[index % 8].1])
+            .collect::<Vec<_>>();
+        (0..16)
+            .filter(|&index| {
+                (0..16).any(|other| other != index && same_input(bound[index], bound[other]))
+            })
+            .collect()
+    }
+
+    pub fn render(&self, c: Context, gl: &mut G2d, glyphs: &mut Glyphs, play_time: Duration) {
         if self.showing {
+            let conflicts = self.conflicts();
             rectangle(
                 [0.0, 0.0, 0.0, 0.7],
                 [0.0, 0.0, 293.0, 240.0],
@@ -109,6 +162,7 @@ impl<'a, const B: usize> Menu<'a, B> {
                 "Player 1",
                 0,
                 self.buttons[0].0,
+                &conflicts,
                 c.trans(10.0, 20.0),
                 gl,
                 glyphs,
@@ -117,10 +171,81 @@ impl<'a, const B: usize> Menu<'a, B> {
                 "Player 2",
                 8,
                 self.buttons[1].0,
+                &conflicts,
                 c.trans(10.0, 135.0),
                 gl,
                 glyphs,
             );
+            text(
+                [1.0, 1.0, 1.0, 1.0],
+                8,
+                &format!("Played {}", format_play_time(play_time)),
+                glyphs,
+                c.trans(170.0, 230.0).transform,
+                gl,
+            )
+                .unwrap();
+            // a conflict is worth fixing before anything else, so its list takes the slots' place
+            if conflicts.is_empty() {
+                self.render_thumbnails(c.trans(170.0, 12.0), gl, glyphs);
+            } else {
+                let c = c.trans(170.0, 20.0);
+                text(
+                    [1.0, 0.4, 0.4, 1.0],
+                    8,
+                    "Bound more than once:",
+                    glyphs,
+                    c.transform,
+                    gl,
+                )
+                    .unwrap();
+                let mut listed: Vec<Input> = Vec::new();
+                for &index in conflicts.iter() {
+                    let input = self.buttons[index / 8].0[self.control_labels[index % 8].1];
+                    if !listed.iter().any(|&other| same_input(input, other)) {
+                        listed.push(input);
+                        text(
+                            [1.0, 0.4, 0.4, 1.0],
+                            8,
+                            &input_to_string(input),
+                            glyphs,
+                            c.trans(0.0, 12.0 * listed.len() as f64).transform,
+                            gl,
+                        )
+                            .unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    // the save slots in rows of three, at half size; empty slots are a gray box
+    fn render_thumbnails(&self, c: Context, gl: &mut G2d, glyphs: &mut Glyphs) {
+        let width = f64::from(THUMBNAIL_WIDTH) / 2.0;
+        let height = f64::from(THUMBNAIL_HEIGHT) / 2.0;
+        for (slot, thumbnail) in self.thumbnails.iter().enumerate() {
+            let c = c.trans(
+                (width + 4.0) * (slot % 3) as f64,
+                (height + 4.0) * (slot / 3) as f64,
+            );
+            match *thumbnail {
+                Some(ref texture) => image(texture, c.scale(0.5, 0.5).transform, gl),
+                None => rectangle(
+                    [0.3, 0.3, 0.3, 1.0],
+                    [0.0, 0.0, width, height],
+                    c.transform,
+                    gl,
+                ),
+            }
+            text(
+                [1.0, 1.0, 1.0, 1.0],
+                8,
+                &format!("F{}", slot + 1),
+                glyphs,
+                c.trans(1.0, 9.0).transform,
+                gl,
+            )
+                .unwrap();
         }
     }
 
@@ -129,6 +254,7 @@ impl<'a, const B: usize> Menu<'a, B> {
         header_text: &str,
         start_index: usize,
         buttons: [Input; B],
+        conflicts: &[usize],
         c: Context,
         gl: &mut G2d,
         glyphs: &mut Glyphs,
@@ -139,6 +265,7 @@ impl<'a, const B: usize> Menu<'a, B> {
                 name,
                 &input_to_string(buttons[array_index]),
                 self.current_index == start_index + menu_index,
+                conflicts.contains(&(start_index + menu_index)),
                 c.trans(0.0, 12.0 * (1.0 + menu_index as f64)),
                 gl,
                 glyphs,
@@ -155,6 +282,7 @@ impl<'a, const B: usize> Menu<'a, B> {
         name: &str,
         value: &str,
         highlight: bool,
+        conflict: bool,
         c: Context,
         gl: &mut G2d,
         glyphs: &mut Glyphs,
@@ -173,7 +301,11 @@ impl<'a, const B: usize> Menu<'a, B> {
         }
         text([1.0, 1.0, 1.0, 1.0], 8, name, glyphs, c.transform, gl).unwrap();
         text(
-            [1.0, 1.0, 1.0, 1.0],
+            if conflict {
+                [1.0, 0.4, 0.4, 1.0]
+            } else {
+                [1.0, 1.0, 1.0, 1.0]
+            },
             8,
             value,
             glyphs,
@@ -184,11 +316,21 @@ impl<'a, const B: usize> Menu<'a, B> {
     }
 
     pub fn save_settings(&self) {
-        let settings_file = File::create(Path::new("settings.dat")).unwrap();
+        let settings_file = File::create(&self.settings_path).unwrap();
         bincode::serialize_into(settings_file, &self.buttons).unwrap();
     }
 }
 
+// axis bindings match on direction, not on how far the stick was pushed when it was bound
+fn same_input(a: Input, b: Input) -> bool {
+    match (a, b) {
+        (Axis(a), Axis(b)) => {
+            a.id == b.id && a.axis == b.axis && a.position.signum() == b.position.signum()
+        }
+        _ => a == b,
+    }
+}
+
 fn input_to_string(input: Input) -> String {
     match input {
         Button(Keyboard(key)) => format!("{:?}", key),
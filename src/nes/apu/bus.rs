@@ -63,6 +63,33 @@ impl SquareCtrl {
             _ => panic!("bad APU channel control write {:04X}", address),
         }
     }
+
+    // disabling clears the length counter right away, along with any load still waiting for the
+    // channel's next tick, so enabling it again doesn't bring the note back
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+            self.length_counter_load = None;
+        }
+    }
+
+    // the period the sweep unit would move to next; pulse 1 negates with a ones' complement, so
+    // it ends up one lower than pulse 2
+    pub fn sweep_target(&self) -> u16 {
+        let change = self.timer >> self.sweep.shift_count;
+        if self.sweep.negate {
+            let adj = if self.sweep.ones_complement_adj { 1 } else { 0 };
+            self.timer.saturating_sub(change + adj)
+        } else {
+            self.timer + change
+        }
+    }
+
+    // the channel is silenced for a period out of range either way, even with the sweep disabled
+    pub fn sweep_muted(&self) -> bool {
+        self.timer < 8 || self.sweep_target() > 0x7FF
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -101,6 +128,14 @@ impl TriangleCtrl {
             _ => panic!("bad APU channel control write {:04X}", address),
         }
     }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+            self.length_counter_load = None;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -111,7 +146,7 @@ pub struct NoiseCtrl {
     pub constant_volume: bool,
     pub envelope_param: u8,
 
-    pub loop_noise: bool,
+    pub short_mode: bool,
     pub timer: u16,
 
     pub length_counter: u8,
@@ -119,7 +154,7 @@ pub struct NoiseCtrl {
 }
 
 impl NoiseCtrl {
-    fn write(&mut self, address: u16, value: u8) {
+    fn write(&mut self, address: u16, value: u8, pal: bool) {
         match address {
             0 => {
                 self.halt_flag_envelope_loop = (value >> 5) & 1 > 0;
@@ -128,8 +163,12 @@ impl NoiseCtrl {
             }
             1 => (),
             2 => {
-                self.loop_noise = (value >> 7) & 1 > 0;
-                self.timer = super::noise::TIMER_VALUES[(value & 0xF) as usize];
+                self.short_mode = (value >> 7) & 1 > 0;
+                self.timer = if pal {
+                    super::noise::PAL_TIMER_VALUES
+                } else {
+                    super::noise::TIMER_VALUES
+                }[(value & 0xF) as usize];
             }
             3 => {
                 if self.enabled {
@@ -139,6 +178,14 @@ impl NoiseCtrl {
             _ => panic!("bad APU channel control write {:04X}", address),
         }
     }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+            self.length_counter_load = None;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -159,12 +206,16 @@ pub struct DmcCtrl {
 }
 
 impl DmcCtrl {
-    fn write(&mut self, address: u16, value: u8) {
+    fn write(&mut self, address: u16, value: u8, pal: bool) {
         match address {
             0 => {
                 self.irq_enabled = (value >> 7) & 1 > 0;
                 self.loop_sample = (value >> 6) & 1 > 0;
-                self.rate = super::dmc::TIMER_VALUES[(value & 0xF) as usize];
+                self.rate = if pal {
+                    super::dmc::PAL_TIMER_VALUES
+                } else {
+                    super::dmc::TIMER_VALUES
+                }[(value & 0xF) as usize];
             }
             1 => self.direct_load = Some(value & (!0x80)),
             2 => self.sample_address = 0xC000 + u16::from(value) * 64,
@@ -189,6 +240,9 @@ pub struct ApuBus {
     pub dmc_delay: bool,
     pub frame_interrupt: bool,
     pub dmc_interrupt: bool,
+
+    // picks the PAL noise and DMC periods
+    pub pal: bool,
 }
 
 impl ApuBus {
@@ -244,8 +298,8 @@ impl ApuBus {
                 halt_flag_envelope_loop: false,
                 constant_volume: false,
                 envelope_param: 0,
-                loop_noise: false,
-                timer: 0,
+                short_mode: false,
+                timer: super::noise::TIMER_VALUES[0],
                 length_counter: 0,
                 length_counter_load: None,
             },
@@ -266,6 +320,7 @@ impl ApuBus {
             dmc_delay: false,
             frame_interrupt: false,
             dmc_interrupt: false,
+            pal: false,
         }
     }
 
@@ -274,18 +329,18 @@ impl ApuBus {
             0x4000..=0x4003 => self.pulse_1.write(address - 0x4000, value),
             0x4004..=0x4007 => self.pulse_2.write(address - 0x4004, value),
             0x4008..=0x400B => self.triangle.write(address - 0x4008, value),
-            0x400C..=0x400F => self.noise.write(address - 0x400C, value),
+            0x400C..=0x400F => self.noise.write(address - 0x400C, value, self.pal),
             0x4010..=0x4013 => {
-                self.dmc.write(address - 0x4010, value);
+                self.dmc.write(address - 0x4010, value, self.pal);
                 if !self.dmc.irq_enabled {
                     self.dmc_interrupt = false;
                 }
             }
             0x4015 => {
-                self.pulse_1.enabled = value & 1 > 0;
-                self.pulse_2.enabled = value & 2 > 0;
-                self.triangle.enabled = value & 4 > 0;
-                self.noise.enabled = value & 8 > 0;
+                self.pulse_1.set_enabled(value & 1 > 0);
+                self.pulse_2.set_enabled(value & 2 > 0);
+                self.triangle.set_enabled(value & 4 > 0);
+                self.noise.set_enabled(value & 8 > 0);
                 self.dmc.enabled = value & 0x10 > 0;
                 self.dmc.enabled_set = true;
                 self.dmc_interrupt = false;
@@ -330,10 +385,10 @@ impl ApuBus {
     }
 
     pub fn reset(&mut self, retain_mode: bool) {
-        self.pulse_1.enabled = false;
-        self.pulse_2.enabled = false;
-        self.triangle.enabled = false;
-        self.noise.enabled = false;
+        self.pulse_1.set_enabled(false);
+        self.pulse_2.set_enabled(false);
+        self.triangle.set_enabled(false);
+        self.noise.set_enabled(false);
         self.dmc.enabled = false;
         self.dmc_interrupt = false;
         self.frame_interrupt = false;
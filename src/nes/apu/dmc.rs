@@ -4,6 +4,9 @@ use nes::cartridge::CartridgeBus;
 pub const TIMER_VALUES: [u16; 16] = [
     428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
 ];
+pub const PAL_TIMER_VALUES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
 
 #[derive(Serialize, Deserialize)]
 pub struct Dmc {
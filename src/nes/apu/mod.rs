@@ -1,21 +1,23 @@
 extern crate dasp;
 extern crate portaudio;
-extern crate rb;
 extern crate time;
 
 use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
 
 use bincode::{deserialize_from, serialize};
 use bytes::*;
 
+use audio::AudioSink;
+use audio::filter::FilterChain;
+use audio::pull::SampleBuffer;
 use nes::cartridge::CartridgeBus;
+use nes::Region;
 
 use self::bus::*;
 use self::dmc::*;
 use self::noise::*;
-use self::portaudio::*;
 use self::pulse::*;
-use self::rb::{Producer, RB, RbConsumer, RbInspector, RbProducer, SpscRb};
 use self::triangle::*;
 
 pub mod bus;
@@ -24,19 +26,25 @@ mod noise;
 mod pulse;
 mod triangle;
 
-const CHANNELS: i32 = 1;
-const TARGET_HZ: f64 = 44_100.0;
-const TICKS_PER_SAMPLE: f64 = 20.2922108844;
-const APPROX_TICKS_PER_FRAME: usize = 14915;
-const MAX_BUFFER_FRAMES: usize = 3;
+pub const TARGET_HZ: f64 = 44_100.0;
+pub const TICKS_PER_SAMPLE: f64 = 20.2922108844;
+// the rate samples are mixed at, one every other CPU cycle
+pub const MIX_HZ: f64 = TARGET_HZ * TICKS_PER_SAMPLE;
+
+// the CPU cycles at which the frame counter clocks the envelopes and lengths; the fourth step
+// ends the 4-step sequence, and the fifth the 5-step one
+const NTSC_FRAME_STEPS: [i32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_STEPS: [i32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+// how much mix builds up before it's handed to the sample buffer, so its lock isn't taken every
+// other cycle; a few output samples' worth
+const PULL_BATCH: usize = 128;
 
 const LENGTH_TABLE: [u8; 0x20] = [
     0x0A, 0xFE, 0x14, 0x02, 0x28, 0x04, 0x50, 0x06, 0xA0, 0x08, 0x3C, 0x0A, 0x0E, 0x0C, 0x1A, 0x0E,
     0x0C, 0x10, 0x18, 0x12, 0x30, 0x14, 0x60, 0x16, 0xC0, 0x18, 0x48, 0x1A, 0x10, 0x1C, 0x20, 0x1E,
 ];
 
-pub type OutputStream = Stream<NonBlocking, Output<f32>>;
-
 pub struct Apu<'a> {
     pulse_1: Pulse,
     pulse_2: Pulse,
@@ -44,87 +52,97 @@ pub struct Apu<'a> {
     noise: Noise,
     dmc: Dmc,
     frame_counter: i32,
+    frame_steps: [i32; 5],
     apu_tick: bool,
-    output_buffer: Producer<f32>,
-    stream: Option<OutputStream>,
+    music_mode: bool,
+    music_loop: bool,
+    muted: bool,
+    filter: FilterChain,
+    sinks: Vec<Box<dyn AudioSink>>,
+    // where the mix goes for drain_samples, once enabled, and what's waiting to go there
+    pulled: Option<Arc<Mutex<SampleBuffer>>>,
+    unpulled: Vec<f32>,
     bus: &'a RefCell<ApuBus>,
 }
 
 impl<'a> Apu<'a> {
-    pub fn new(bus: &RefCell<ApuBus>, pa: Option<PortAudio>) -> Result<Apu, Error> {
-        let buffer = SpscRb::new(500_000);
-        let (buffer_producer, buffer_consumer) = (buffer.producer(), buffer.consumer());
-
-        let mut resample_data = Box::new(vec![0.0; 20_000]);
-        let inspector = buffer;
-
-        let callback = move |OutputStreamCallbackArgs { buffer, frames, .. }| {
-            let ticks = TICKS_PER_SAMPLE * frames as f64;
-            let ticks_to_read;
-            if inspector.count() > APPROX_TICKS_PER_FRAME {
-                ticks_to_read = inspector.count().min(ticks.floor() as usize);
-            } else {
-                ticks_to_read = inspector.count().min(ticks.ceil() as usize);
-            }
-            while inspector.count() > APPROX_TICKS_PER_FRAME * MAX_BUFFER_FRAMES {
-                buffer_consumer.skip(APPROX_TICKS_PER_FRAME).unwrap();
-            }
-            buffer_consumer.read_blocking(&mut resample_data[0..ticks_to_read]);
-            let ticks_per_sample = ((ticks_to_read as f32) / (frames as f32)).floor() as i16;
-            let mut buffer_ptr = 0;
-            let mut sum = 0.0;
-            let mut ticks = 0;
-            for tick_val in resample_data.iter().take(ticks_to_read) {
-                sum += tick_val;
-                ticks += 1;
-                if ticks >= ticks_per_sample {
-                    buffer[buffer_ptr] = sum / (ticks as f32);
-                    buffer_ptr += 1;
-                    if buffer_ptr >= frames {
-                        break;
-                    }
-                    sum = 0.0;
-                    ticks = 0;
-                }
-            }
-            while buffer_ptr < frames {
-                buffer[buffer_ptr] = 0.0;
-                buffer_ptr += 1;
-            }
-            Continue
-        };
-        let stream = pa.map(|pa| {
-            let settings = pa
-                .default_output_stream_settings::<f32>(
-                    CHANNELS,
-                    TARGET_HZ,
-                    FRAMES_PER_BUFFER_UNSPECIFIED,
-                )
-                .unwrap();
-            let mut stream = pa.open_non_blocking_stream(settings, callback).unwrap();
-            stream.start().unwrap();
-            stream
-        });
-
-        Ok(Apu {
+    pub fn new(bus: &RefCell<ApuBus>, sinks: Vec<Box<dyn AudioSink>>) -> Apu {
+        Apu {
             pulse_1: Pulse::new(),
             pulse_2: Pulse::new(),
             triangle: Triangle::new(),
             noise: Noise::new(),
             dmc: Dmc::new(),
             frame_counter: 0,
+            frame_steps: NTSC_FRAME_STEPS,
             apu_tick: false,
-            output_buffer: buffer_producer,
-            stream,
+            music_mode: false,
+            music_loop: false,
+            muted: false,
+            filter: FilterChain::default(),
+            sinks,
+            pulled: None,
+            unpulled: Vec::with_capacity(PULL_BATCH),
             bus,
-        })
+        }
+    }
+
+    // a ripping aid, not accurate playback: enabled channels keep sounding after their length
+    // counters run out, so notes the game lets expire play on; the counters themselves, and what
+    // the game reads back from $4015, are unaffected. Looping also restarts decaying envelopes
+    // as if their loop flags were set, so notes that would fade out hold on instead.
+    pub fn enable_music_mode(&mut self, looping: bool) {
+        self.music_mode = true;
+        self.music_loop = looping;
+    }
+
+    // applied to the mix before every sink, so recordings sound like the live output
+    pub fn set_audio_filter(&mut self, filter: FilterChain) {
+        self.filter = filter;
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.frame_steps = match region {
+            Region::Ntsc => NTSC_FRAME_STEPS,
+            Region::Pal => PAL_FRAME_STEPS,
+        };
+        self.bus.borrow_mut().pal = region == Region::Pal;
+    }
+
+    // sends the mix to `buffer` as well as to the sinks; an audio callback holding the same
+    // buffer can take exactly as many output samples as it needs from it, on its own thread
+    pub fn enable_sample_buffer(&mut self, buffer: Arc<Mutex<SampleBuffer>>) {
+        self.pulled = Some(buffer);
+        self.unpulled.clear();
+    }
+
+    // fills as much of `out` as the mix so far covers, resampled to the output rate, and returns
+    // how many samples that was; what's left over stays for the next call
+    pub fn drain_samples(&mut self, out: &mut [f32]) -> usize {
+        self.flush_pulled();
+        match self.pulled {
+            Some(ref pulled) => pulled.lock().unwrap().drain(out),
+            None => 0,
+        }
+    }
+
+    fn flush_pulled(&mut self) {
+        if let Some(ref pulled) = self.pulled {
+            pulled.lock().unwrap().push(&self.unpulled);
+            self.unpulled.clear();
+        }
+    }
+
+    // silence is still pushed to the sinks, so output stays in step
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
     }
 
     fn clock_envelope(&mut self, bus: &mut ApuBus) {
-        self.pulse_1.clock_envelope(&bus.pulse_1);
-        self.pulse_2.clock_envelope(&bus.pulse_2);
+        self.pulse_1.clock_envelope(&bus.pulse_1, self.music_loop);
+        self.pulse_2.clock_envelope(&bus.pulse_2, self.music_loop);
         self.triangle.clock_linear_counter(&mut bus.triangle);
-        self.noise.clock_envelope(&bus.noise);
+        self.noise.clock_envelope(&bus.noise, self.music_loop);
     }
 
     fn clock_length_and_sweep(&mut self, bus: &mut ApuBus) {
@@ -145,25 +163,26 @@ impl<'a> Apu<'a> {
             self.frame_counter = if self.apu_tick { -2 } else { -3 };
             bus.frame_mode_written = false;
         }
+        let steps = self.frame_steps;
         match self.frame_counter {
-            7457 => {
+            n if n == steps[0] => {
                 self.clock_envelope(&mut bus);
             }
-            14913 => {
+            n if n == steps[1] => {
                 self.clock_envelope(&mut bus);
                 self.clock_length_and_sweep(&mut bus);
             }
-            22371 => {
+            n if n == steps[2] => {
                 self.clock_envelope(&mut bus);
             }
-            29828 => {
+            n if n == steps[3] - 1 => {
                 if !bus.frame_mode {
                     if !bus.frame_irq_inhibit {
                         bus.frame_interrupt = true;
                     }
                 }
             }
-            29829 => {
+            n if n == steps[3] => {
                 if !bus.frame_mode {
                     if !bus.frame_irq_inhibit {
                         bus.frame_interrupt = true;
@@ -172,7 +191,7 @@ impl<'a> Apu<'a> {
                     self.clock_length_and_sweep(&mut bus);
                 }
             }
-            29830 => {
+            n if n == steps[3] + 1 => {
                 if !bus.frame_mode {
                     if !bus.frame_irq_inhibit {
                         bus.frame_interrupt = true;
@@ -180,7 +199,7 @@ impl<'a> Apu<'a> {
                     self.frame_counter = 0;
                 }
             }
-            37281 => {
+            n if n == steps[4] => {
                 self.clock_envelope(&mut bus);
                 self.clock_length_and_sweep(&mut bus);
                 self.frame_counter = -1;
@@ -189,17 +208,31 @@ impl<'a> Apu<'a> {
         }
 
         if self.apu_tick {
-            let pulse_1 = self.pulse_1.tick(&mut bus.pulse_1);
-            let pulse_2 = self.pulse_2.tick(&mut bus.pulse_2);
-            let triangle = self.triangle.tick(&mut bus.triangle);
-            let noise = self.noise.tick(&mut bus.noise);
+            let pulse_1 = self.pulse_1.tick(&mut bus.pulse_1, self.music_mode);
+            let pulse_2 = self.pulse_2.tick(&mut bus.pulse_2, self.music_mode);
+            let triangle = self.triangle.tick(&mut bus.triangle, self.music_mode);
+            let noise = self.noise.tick(&mut bus.noise, self.music_mode);
             let dmc = self.dmc.tick(&mut bus, cartridge);
-            if self.stream.is_some() {
-                self.output_buffer
-                    .write_blocking(&[(pulse_1 + pulse_2) * 0.00752
+            if !self.sinks.is_empty() || self.pulled.is_some() {
+                let sample = if self.muted {
+                    [0.0]
+                } else {
+                    let mix = (pulse_1 + pulse_2) * 0.00752
                         + triangle * 0.00851
                         + noise * 0.00494
-                        + dmc * 0.00335]);
+                        + dmc * 0.00335
+                        + cartridge.expansion_audio() * 0.00752;
+                    [self.filter.process(mix)]
+                };
+                for sink in self.sinks.iter_mut() {
+                    sink.push_samples(&sample);
+                }
+                if self.pulled.is_some() {
+                    self.unpulled.push(sample[0]);
+                    if self.unpulled.len() == PULL_BATCH {
+                        self.flush_pulled();
+                    }
+                }
             }
         }
 
@@ -207,12 +240,29 @@ impl<'a> Apu<'a> {
         self.apu_tick = apu_tick;
     }
 
+    // hands the output over to a new APU, so a reboot doesn't reopen the audio stream
+    pub fn take_sinks(&mut self) -> Vec<Box<dyn AudioSink>> {
+        std::mem::take(&mut self.sinks)
+    }
+
     pub fn close(&mut self) {
-        if let Some(ref mut stream) = self.stream {
-            stream.abort().unwrap();
+        for sink in self.sinks.iter_mut() {
+            sink.close();
         }
     }
 
+    pub fn buffer_stats(&self) -> Option<(usize, usize)> {
+        self.sinks
+            .iter()
+            .filter_map(|sink| sink.buffer_stats())
+            .next()
+            .or_else(|| {
+                self.pulled
+                    .as_ref()
+                    .map(|pulled| pulled.lock().unwrap().stats())
+            })
+    }
+
     pub fn save_state(&self, out: &mut Vec<u8>) {
         out.put_slice(&serialize(&self.pulse_1).unwrap());
         out.put_slice(&serialize(&self.pulse_2).unwrap());
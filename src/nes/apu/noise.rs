@@ -1,12 +1,16 @@
 use nes::apu::*;
 
+// NTSC periods, in CPU cycles
 pub const TIMER_VALUES: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
 ];
+pub const PAL_TIMER_VALUES: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
 
 #[derive(Serialize, Deserialize)]
 pub struct Noise {
-    curr_timer: u16,
+    timer_tick: u16,
     shift_register: u16,
     envelope_delay: u8,
     envelope_value: u8,
@@ -16,7 +20,7 @@ pub struct Noise {
 impl Noise {
     pub fn new() -> Noise {
         Noise {
-            curr_timer: 0,
+            timer_tick: 0,
             shift_register: 1,
             envelope_delay: 0,
             envelope_value: 15,
@@ -24,7 +28,7 @@ impl Noise {
         }
     }
 
-    pub fn tick(&mut self, ctrl_bus: &mut NoiseCtrl) -> f32 {
+    pub fn tick(&mut self, ctrl_bus: &mut NoiseCtrl, ignore_length: bool) -> f32 {
         if !ctrl_bus.enabled {
             ctrl_bus.length_counter = 0;
         } else if let Some(length_counter) = ctrl_bus.length_counter_load.take() {
@@ -32,26 +36,25 @@ impl Noise {
             self.length_written = true;
         }
 
-        let tick_val;
-        if ctrl_bus.length_counter > 0 {
-            if self.curr_timer == 0 {
-                self.curr_timer = ctrl_bus.timer;
-                let feedback_bit = if ctrl_bus.loop_noise {
-                    (self.shift_register & 0x40) >> 6
-                } else {
-                    (self.shift_register & 0x2) >> 1
-                };
-                let feedback = (self.shift_register & 0x1) ^ feedback_bit;
-                self.shift_register >>= 1;
-                let feedback_applied = (self.shift_register & !(0x4000)) | (feedback << 14);
-                self.shift_register = feedback_applied;
+        // the 15-bit LFSR feeds back bit 0 xor bit 1, or bit 6 in short mode, which cycles
+        // through 93 steps (from the power-on state) instead of 32767; it keeps running while
+        // the length counter silences the output
+        if self.timer_tick >= ctrl_bus.timer {
+            self.timer_tick -= ctrl_bus.timer;
+            let feedback_bit = if ctrl_bus.short_mode {
+                (self.shift_register & 0x40) >> 6
             } else {
-                self.curr_timer -= 1;
-            }
-            tick_val = self.shift_register & 0x1 == 0;
-        } else {
-            tick_val = false;
+                (self.shift_register & 0x2) >> 1
+            };
+            let feedback = (self.shift_register & 0x1) ^ feedback_bit;
+            self.shift_register >>= 1;
+            let feedback_applied = (self.shift_register & !(0x4000)) | (feedback << 14);
+            self.shift_register = feedback_applied;
         }
+        // ticked every other CPU cycle
+        self.timer_tick += 2;
+        let tick_val = (ctrl_bus.length_counter > 0 || (ignore_length && ctrl_bus.enabled))
+            && self.shift_register & 0x1 == 0;
         if tick_val {
             if ctrl_bus.constant_volume {
                 f32::from(ctrl_bus.envelope_param)
@@ -69,7 +72,7 @@ impl Noise {
         }
     }
 
-    pub fn clock_envelope(&mut self, ctrl_bus: &NoiseCtrl) {
+    pub fn clock_envelope(&mut self, ctrl_bus: &NoiseCtrl, force_loop: bool) {
         if self.length_written {
             self.length_written = false;
             self.envelope_delay = ctrl_bus.envelope_param;
@@ -77,7 +80,7 @@ impl Noise {
         }
         if self.envelope_value == 0 {
             self.envelope_delay = ctrl_bus.envelope_param;
-            if ctrl_bus.halt_flag_envelope_loop {
+            if ctrl_bus.halt_flag_envelope_loop || force_loop {
                 self.envelope_value = 15;
             }
         } else if self.envelope_delay > 0 {
@@ -29,26 +29,7 @@ impl Pulse {
         }
     }
 
-    fn sweep_target_period(&self, ctrl_bus: &SquareCtrl) -> u16 {
-        if ctrl_bus.sweep.enabled {
-            let shift_amount = ctrl_bus.timer >> ctrl_bus.sweep.shift_count;
-            if ctrl_bus.sweep.negate {
-                ctrl_bus.timer
-                    - shift_amount
-                    - if ctrl_bus.sweep.ones_complement_adj {
-                    1
-                } else {
-                    0
-                }
-            } else {
-                ctrl_bus.timer + shift_amount
-            }
-        } else {
-            ctrl_bus.timer
-        }
-    }
-
-    pub fn tick(&mut self, ctrl_bus: &mut SquareCtrl) -> f32 {
+    pub fn tick(&mut self, ctrl_bus: &mut SquareCtrl, ignore_length: bool) -> f32 {
         if !ctrl_bus.enabled {
             ctrl_bus.length_counter = 0;
         } else if let Some(length_counter) = ctrl_bus.length_counter_load.take() {
@@ -56,24 +37,17 @@ impl Pulse {
             self.length_written = true;
         }
 
-        let tick_val;
-        if ctrl_bus.length_counter > 0
-            && self.sweep_target_period(&ctrl_bus) <= 0x7FF
-            && ctrl_bus.timer >= 8
-        {
-            if self.curr_timer == 0 {
-                self.curr_timer = ctrl_bus.timer;
-                if ctrl_bus.timer >= 8 {
-                    self.curr_cycle += 1;
-                    self.curr_cycle %= 8;
-                }
-            } else {
-                self.curr_timer -= 1;
-            }
-            tick_val = DUTY_CYCLES[ctrl_bus.duty_cycle][self.curr_cycle];
+        // the timer keeps running while muted, so unmuting doesn't restart the waveform
+        if self.curr_timer == 0 {
+            self.curr_timer = ctrl_bus.timer;
+            self.curr_cycle += 1;
+            self.curr_cycle %= 8;
         } else {
-            tick_val = false;
+            self.curr_timer -= 1;
         }
+        let tick_val = (ctrl_bus.length_counter > 0 || (ignore_length && ctrl_bus.enabled))
+            && !ctrl_bus.sweep_muted()
+            && DUTY_CYCLES[ctrl_bus.duty_cycle][self.curr_cycle];
         if tick_val {
             if ctrl_bus.constant_volume {
                 f32::from(ctrl_bus.envelope_param)
@@ -89,15 +63,16 @@ impl Pulse {
         if !ctrl_bus.halt_flag_envelope_loop && ctrl_bus.length_counter > 0 {
             ctrl_bus.length_counter -= 1;
         }
+        // the divider counts down whether or not the channel is muted; only the period update
+        // is held back
+        if self.sweep_counter == 0
+            && ctrl_bus.sweep.enabled
+            && ctrl_bus.sweep.shift_count > 0
+            && !ctrl_bus.sweep_muted()
+        {
+            ctrl_bus.timer = ctrl_bus.sweep_target();
+        }
         if self.sweep_counter == 0 || ctrl_bus.sweep.reload {
-            if self.sweep_counter == 0 {
-                if ctrl_bus.sweep.enabled {
-                    let target_period = self.sweep_target_period(&ctrl_bus);
-                    if target_period <= 0x7FF {
-                        ctrl_bus.timer = target_period;
-                    }
-                }
-            }
             self.sweep_counter = ctrl_bus.sweep.period;
             ctrl_bus.sweep.reload = false;
         } else {
@@ -105,7 +80,7 @@ impl Pulse {
         }
     }
 
-    pub fn clock_envelope(&mut self, ctrl_bus: &SquareCtrl) {
+    pub fn clock_envelope(&mut self, ctrl_bus: &SquareCtrl, force_loop: bool) {
         if self.length_written {
             self.length_written = false;
             self.envelope_delay = ctrl_bus.envelope_param;
@@ -113,7 +88,7 @@ impl Pulse {
         }
         if self.envelope_value == 0 {
             self.envelope_delay = ctrl_bus.envelope_param;
-            if ctrl_bus.halt_flag_envelope_loop {
+            if ctrl_bus.halt_flag_envelope_loop || force_loop {
                 self.envelope_value = 15;
             }
         } else if self.envelope_delay > 0 {
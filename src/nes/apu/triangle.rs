@@ -16,31 +16,31 @@ impl Triangle {
         }
     }
 
-    pub fn tick(&mut self, ctrl_bus: &mut TriangleCtrl) -> f32 {
+    pub fn tick(&mut self, ctrl_bus: &mut TriangleCtrl, ignore_length: bool) -> f32 {
         if !ctrl_bus.enabled {
             ctrl_bus.length_counter = 0;
         } else if let Some(length_counter) = ctrl_bus.length_counter_load.take() {
             ctrl_bus.length_counter = LENGTH_TABLE[length_counter as usize];
         }
 
-        if ctrl_bus.length_counter > 0 && self.linear_counter > 0 {
+        // the sequencer stops when either counter runs out, but its output holds at the current
+        // step rather than dropping to 0, which would click; periods below 2 would play at 28kHz
+        // and up, so they're held too, as most emulators do to avoid pops
+        if (ctrl_bus.length_counter > 0 || (ignore_length && ctrl_bus.enabled))
+            && self.linear_counter > 0
+            && ctrl_bus.timer >= 2
+        {
             if self.timer_tick >= ctrl_bus.timer + 1 {
-                self.timer_tick -= if ctrl_bus.timer == 0 {
-                    2
-                } else {
-                    ctrl_bus.timer + 1
-                };
+                self.timer_tick -= ctrl_bus.timer + 1;
                 self.timer_phase += 1;
                 self.timer_phase %= 32;
             }
             self.timer_tick += 2;
-            if self.timer_phase < 16 {
-                15.0 - f32::from(self.timer_phase)
-            } else {
-                f32::from(self.timer_phase) - 16.0
-            }
+        }
+        if self.timer_phase < 16 {
+            15.0 - f32::from(self.timer_phase)
         } else {
-            0.0
+            f32::from(self.timer_phase) - 16.0
         }
     }
 
@@ -1,9 +1,11 @@
-use std::cmp::max;
 use std::io::prelude::*;
 use std::io::Result;
 
 use bytes::*;
 
+use nes::cartridge::mirror_prg_ram;
+use nes::cartridge::mirror_prg_rom;
+use nes::cartridge::read_battery;
 use nes::cartridge::Cartridge;
 use nes::cartridge::CartridgeBus;
 use nes::cartridge::Header;
@@ -13,6 +15,7 @@ use nes::cartridge::NametableMirroring::*;
 struct Mapper0Cpu {
     prg_rom: Vec<u8>,
     prg_ram: Vec<u8>,
+    battery_save: bool,
 }
 
 struct Mapper0Ppu {
@@ -26,7 +29,8 @@ pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
     Cartridge {
         cpu_bus: Box::new(Mapper0Cpu {
             prg_rom: prg_rom.to_vec(),
-            prg_ram: vec![0; (u16::from(max(header.prg_ram_blocks, 1)) * 0x2000) as usize],
+            prg_ram: vec![0; header.prg_ram_size],
+            battery_save: header.battery_save,
         }),
         ppu_bus: Box::new(Mapper0Ppu {
             chr_rom: if uses_chr_ram {
@@ -37,28 +41,30 @@ pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
             mirroring: header.mirroring,
             uses_chr_ram,
         }),
+        swap_controllers: false,
+        region: header.region,
     }
 }
 
 impl CartridgeBus for Mapper0Cpu {
     fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
         match address {
-            0x6000..=0x7FFF => self.prg_ram[(address - 0x6000) as usize],
-            0x8000..=0xBFFF => self.prg_rom[(address - 0x8000) as usize],
-            0xC000..=0xFFFF => {
-                if self.prg_rom.len() <= 0x4000 {
-                    self.prg_rom[(address - 0xC000) as usize]
-                } else {
-                    self.prg_rom[(address - 0x8000) as usize]
-                }
-            }
+            0x6000..=0x7FFF => mirror_prg_ram(address, self.prg_ram.len())
+                .map_or(open_bus, |offset| self.prg_ram[offset]),
+            0x8000..=0xFFFF => self
+                .prg_rom_offset(address)
+                .map_or(open_bus, |offset| self.prg_rom[offset]),
             _ => open_bus,
         }
     }
 
     fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
         match address {
-            0x6000..=0x7FFF => self.prg_ram[(address - 0x6000) as usize] = value,
+            0x6000..=0x7FFF => {
+                if let Some(offset) = mirror_prg_ram(address, self.prg_ram.len()) {
+                    self.prg_ram[offset] = value;
+                }
+            }
             _ => (),
         }
     }
@@ -67,12 +73,20 @@ impl CartridgeBus for Mapper0Cpu {
         address
     }
 
-    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
-        Ok(0)
+    fn save_to_battery(&self, out: &mut dyn Write) -> Result<usize> {
+        if self.battery_save {
+            out.write(self.prg_ram.as_slice())
+        } else {
+            Ok(0)
+        }
     }
 
-    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
-        unimplemented!();
+    fn load_from_battery(&mut self, inp: &mut dyn Read) -> Result<usize> {
+        if self.battery_save {
+            read_battery(&mut self.prg_ram, inp)
+        } else {
+            Ok(0)
+        }
     }
 
     fn save_state(&self, out: &mut Vec<u8>) {
@@ -82,6 +96,17 @@ impl CartridgeBus for Mapper0Cpu {
     fn load_state(&mut self, state: &mut dyn Buf) {
         state.copy_to_slice(&mut self.prg_ram);
     }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => mirror_prg_rom((address - 0x8000) as usize, self.prg_rom.len()),
+            _ => None,
+        }
+    }
 }
 
 impl CartridgeBus for Mapper0Ppu {
@@ -138,4 +163,12 @@ impl CartridgeBus for Mapper0Ppu {
             state.copy_to_slice(&mut self.chr_rom);
         }
     }
+
+    fn rom_size(&self) -> usize {
+        if self.uses_chr_ram {
+            0
+        } else {
+            self.chr_rom.len()
+        }
+    }
 }
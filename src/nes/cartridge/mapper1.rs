@@ -1,5 +1,4 @@
 use std::cell::RefCell;
-use std::cmp::max;
 use std::io::prelude::*;
 use std::io::Result;
 use std::ops::Deref;
@@ -8,6 +7,9 @@ use std::rc::Rc;
 use bincode::{deserialize_from, serialize};
 use bytes::*;
 
+use nes::cartridge::mirror_prg_ram;
+use nes::cartridge::mirror_prg_rom;
+use nes::cartridge::read_battery;
 use nes::cartridge::Cartridge;
 use nes::cartridge::CartridgeBus;
 use nes::cartridge::Header;
@@ -138,24 +140,28 @@ impl CtrlRegisters {
             % max_addr
     }
 
-    fn prg_low_bank(&self, addr: u16, max_addr: usize) -> usize {
-        ((addr as usize)
-            + match self.prg_bank_mode {
-            PrgBankMode::Switch32K => (self.prg_bank & (!1)) << 14,
-            PrgBankMode::FixLowBank => 0,
-            PrgBankMode::FixHiBank => self.prg_bank << 14,
-        })
-            % max_addr
+    fn prg_low_bank(&self, addr: u16, max_addr: usize) -> Option<usize> {
+        mirror_prg_rom(
+            (addr as usize)
+                + match self.prg_bank_mode {
+                    PrgBankMode::Switch32K => (self.prg_bank & (!1)) << 14,
+                    PrgBankMode::FixLowBank => 0,
+                    PrgBankMode::FixHiBank => self.prg_bank << 14,
+                },
+            max_addr,
+        )
     }
 
-    fn prg_hi_bank(&self, addr: u16, max_addr: usize) -> usize {
-        ((addr as usize)
-            + match self.prg_bank_mode {
-            PrgBankMode::Switch32K => (self.prg_bank | 1) << 14,
-            PrgBankMode::FixLowBank => self.prg_bank << 14,
-            PrgBankMode::FixHiBank => max_addr - 0x4000,
-        })
-            % max_addr
+    fn prg_hi_bank(&self, addr: u16, max_addr: usize) -> Option<usize> {
+        mirror_prg_rom(
+            (addr as usize)
+                + match self.prg_bank_mode {
+                    PrgBankMode::Switch32K => (self.prg_bank | 1) << 14,
+                    PrgBankMode::FixLowBank => self.prg_bank << 14,
+                    PrgBankMode::FixHiBank => max_addr.saturating_sub(0x4000),
+                },
+            max_addr,
+        )
     }
 }
 
@@ -189,7 +195,7 @@ pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
     }));
     let cpu_bus = Box::new(Mapper1Cpu {
         prg_rom: prg_rom.to_vec(),
-        prg_ram: vec![0; (u16::from(max(header.prg_ram_blocks, 1)) * 0x2000) as usize],
+        prg_ram: vec![0; header.prg_ram_size],
         ctrl: Rc::clone(&ctrl_register),
         battery_save: header.battery_save,
         last_write_cycle: 0,
@@ -205,18 +211,19 @@ pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
             uses_chr_ram,
             ctrl: Rc::clone(&ctrl_register),
         }),
+        swap_controllers: false,
+        region: header.region,
     }
 }
 
 impl CartridgeBus for Mapper1Cpu {
     fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
-        let ctrl = self.ctrl.borrow();
         match address {
-            0x6000..=0x7FFF => self.prg_ram[(address - 0x6000) as usize],
-            0x8000..=0xBFFF => {
-                self.prg_rom[ctrl.prg_low_bank(address - 0x8000, self.prg_rom.len())]
-            }
-            0xC000..=0xFFFF => self.prg_rom[ctrl.prg_hi_bank(address - 0xC000, self.prg_rom.len())],
+            0x6000..=0x7FFF => mirror_prg_ram(address, self.prg_ram.len())
+                .map_or(open_bus, |offset| self.prg_ram[offset]),
+            0x8000..=0xFFFF => self
+                .prg_rom_offset(address)
+                .map_or(open_bus, |offset| self.prg_rom[offset]),
             _ => open_bus,
         }
     }
@@ -226,7 +233,9 @@ impl CartridgeBus for Mapper1Cpu {
         match address {
             0x6000..=0x7FFF => {
                 if ctrl.prg_ram_enabled {
-                    self.prg_ram[(address - 0x6000) as usize] = value;
+                    if let Some(offset) = mirror_prg_ram(address, self.prg_ram.len()) {
+                        self.prg_ram[offset] = value;
+                    }
                 }
             }
             0x8000..=0xFFFF => {
@@ -253,8 +262,7 @@ impl CartridgeBus for Mapper1Cpu {
 
     fn load_from_battery(&mut self, inp: &mut dyn Read) -> Result<usize> {
         if self.battery_save {
-            self.prg_ram.clear();
-            inp.read_to_end(&mut self.prg_ram)
+            read_battery(&mut self.prg_ram, inp)
         } else {
             Ok(0)
         }
@@ -273,6 +281,29 @@ impl CartridgeBus for Mapper1Cpu {
         self.battery_save = state.get_u8() == 1;
         self.last_write_cycle = state.get_u64();
     }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    // the last bank is fixed at $C000 in the mode games boot in
+    fn prg_banks(&self) -> Vec<(u16, &[u8])> {
+        let count = self.prg_rom.chunks(0x4000).count();
+        self.prg_rom
+            .chunks(0x4000)
+            .enumerate()
+            .map(|(i, bank)| (if i + 1 == count { 0xC000 } else { 0x8000 }, bank))
+            .collect()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        let ctrl = self.ctrl.borrow();
+        match address {
+            0x8000..=0xBFFF => ctrl.prg_low_bank(address - 0x8000, self.prg_rom.len()),
+            0xC000..=0xFFFF => ctrl.prg_hi_bank(address - 0xC000, self.prg_rom.len()),
+            _ => None,
+        }
+    }
 }
 
 impl CartridgeBus for Mapper1Ppu {
@@ -362,4 +393,12 @@ impl CartridgeBus for Mapper1Ppu {
             state.copy_to_slice(&mut self.chr_rom);
         }
     }
+
+    fn rom_size(&self) -> usize {
+        if self.uses_chr_ram {
+            0
+        } else {
+            self.chr_rom.len()
+        }
+    }
 }
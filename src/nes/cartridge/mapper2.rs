@@ -0,0 +1,173 @@
+use std::io::prelude::*;
+use std::io::Result;
+
+use bytes::*;
+
+use nes::cartridge::mirror_prg_rom;
+use nes::cartridge::Cartridge;
+use nes::cartridge::CartridgeBus;
+use nes::cartridge::Header;
+use nes::cartridge::NametableMirroring;
+use nes::cartridge::NametableMirroring::*;
+
+// UxROM: a switchable 16KB PRG bank at $8000 with the last one fixed at $C000, and CHR RAM. As
+// with AxROM, bus conflicts aren't modeled.
+struct Mapper2Cpu {
+    prg_rom: Vec<u8>,
+    prg_bank: usize,
+}
+
+struct Mapper2Ppu {
+    chr_rom: Vec<u8>,
+    mirroring: NametableMirroring,
+    uses_chr_ram: bool,
+}
+
+pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
+    let uses_chr_ram = chr_rom.len() == 0;
+    Cartridge {
+        cpu_bus: Box::new(Mapper2Cpu {
+            prg_rom: prg_rom.to_vec(),
+            prg_bank: 0,
+        }),
+        ppu_bus: Box::new(Mapper2Ppu {
+            chr_rom: if uses_chr_ram {
+                vec![0; 0x2000]
+            } else {
+                chr_rom.to_vec()
+            },
+            mirroring: header.mirroring,
+            uses_chr_ram,
+        }),
+        swap_controllers: false,
+        region: header.region,
+    }
+}
+
+impl CartridgeBus for Mapper2Cpu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x8000..=0xFFFF => self
+                .prg_rom_offset(address)
+                .map_or(open_bus, |offset| self.prg_rom[offset]),
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        match address {
+            0x8000..=0xFFFF => self.prg_bank = value as usize,
+            _ => (),
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        address
+    }
+
+    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_u8(self.prg_bank as u8);
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        self.prg_bank = state.get_u8() as usize;
+    }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    // the last bank is fixed at $C000
+    fn prg_banks(&self) -> Vec<(u16, &[u8])> {
+        let count = self.prg_rom.chunks(0x4000).count();
+        self.prg_rom
+            .chunks(0x4000)
+            .enumerate()
+            .map(|(i, bank)| (if i + 1 == count { 0xC000 } else { 0x8000 }, bank))
+            .collect()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        let bank = match address {
+            0x8000..=0xBFFF => self.prg_bank,
+            0xC000..=0xFFFF => (self.prg_rom.len() / 0x4000).saturating_sub(1),
+            _ => return None,
+        };
+        mirror_prg_rom(
+            bank * 0x4000 + (address as usize % 0x4000),
+            self.prg_rom.len(),
+        )
+    }
+}
+
+impl CartridgeBus for Mapper2Ppu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.chr_rom[address as usize],
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        if self.uses_chr_ram {
+            match address {
+                0x0000..=0x1FFF => self.chr_rom[address as usize] = value,
+                _ => (),
+            }
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        match address {
+            0x2000..=0x23FF => address - 0x2000,
+            0x2400..=0x27FF => match self.mirroring {
+                Vertical => address - 0x2000,
+                Horizontal => address - 0x2400,
+                _ => unimplemented!(),
+            },
+            0x2800..=0x2BFF => match self.mirroring {
+                Vertical => address - 0x2800,
+                Horizontal => address - 0x2400,
+                _ => unimplemented!(),
+            },
+            0x2C00..=0x2FFF => address - 0x2800,
+            _ => panic!("Bad nametable mirror request {:04X}", address),
+        }
+    }
+
+    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        if self.uses_chr_ram {
+            out.put_slice(&self.chr_rom);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        if self.uses_chr_ram {
+            state.copy_to_slice(&mut self.chr_rom);
+        }
+    }
+
+    fn rom_size(&self) -> usize {
+        if self.uses_chr_ram {
+            0
+        } else {
+            self.chr_rom.len()
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::io::Result;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use bincode::{deserialize_from, serialize};
+use bytes::*;
+
+use nes::cartridge::mirror_prg_ram;
+use nes::cartridge::mirror_prg_rom;
+use nes::cartridge::read_battery;
+use nes::cartridge::Cartridge;
+use nes::cartridge::CartridgeBus;
+use nes::cartridge::Header;
+use nes::cartridge::NametableMirroring;
+use nes::cartridge::NametableMirroring::*;
+
+#[derive(Serialize, Deserialize)]
+struct PpuRegisters {
+    mirroring: NametableMirroring,
+    one_screen_mirroring_hi: bool,
+    chr_banks: [usize; 8],
+}
+
+#[derive(Serialize, Deserialize)]
+struct Irq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl Irq {
+    fn tick(&mut self) {
+        if self.enabled {
+            if self.cycle_mode {
+                self.clock();
+            } else {
+                // scanline mode approximates 341 PPU dots per line
+                self.prescaler -= 3;
+                if self.prescaler <= 0 {
+                    self.prescaler += 341;
+                    self.clock();
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0b001 > 0;
+        self.enabled = value & 0b010 > 0;
+        self.cycle_mode = value & 0b100 > 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pulse {
+    volume: u8,
+    duty: u8,
+    ignore_duty: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn new() -> Pulse {
+        Pulse {
+            volume: 0,
+            duty: 0,
+            ignore_duty: false,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            step: 15,
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.ignore_duty = value & 0x80 > 0;
+                self.duty = (value >> 4) & 0x7;
+                self.volume = value & 0xF;
+            }
+            1 => self.period = (self.period & 0xF00) | u16::from(value),
+            2 => {
+                self.period = (self.period & 0xFF) | (u16::from(value & 0xF) << 8);
+                self.enabled = value & 0x80 > 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn tick(&mut self, shift: u8) {
+        if self.enabled {
+            if self.timer == 0 {
+                self.timer = self.period >> shift;
+                self.step = self.step.wrapping_sub(1) & 0xF;
+            } else {
+                self.timer -= 1;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn new() -> Sawtooth {
+        Sawtooth {
+            rate: 0,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0xF00) | u16::from(value),
+            2 => {
+                self.period = (self.period & 0xFF) | (u16::from(value & 0xF) << 8);
+                self.enabled = value & 0x80 > 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn tick(&mut self, shift: u8) {
+        if self.enabled {
+            if self.timer == 0 {
+                self.timer = self.period >> shift;
+                // the rate is added on every other step, and the seventh addition resets instead
+                self.step += 1;
+                if self.step == 14 {
+                    self.step = 0;
+                    self.accumulator = 0;
+                } else if self.step % 2 == 0 {
+                    self.accumulator = self.accumulator.wrapping_add(self.rate);
+                }
+            } else {
+                self.timer -= 1;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CpuRegisters {
+    prg_bank_16k: usize,
+    prg_bank_8k: usize,
+    prg_ram_enabled: bool,
+    irq: Irq,
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    sawtooth: Sawtooth,
+    audio_halt: bool,
+    frequency_shift: u8,
+}
+
+struct Mapper24Cpu {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    swap_address_lines: bool,
+    registers: CpuRegisters,
+    ppu_registers: Rc<RefCell<PpuRegisters>>,
+    battery_save: bool,
+}
+
+struct Mapper24Ppu {
+    chr_rom: Vec<u8>,
+    uses_chr_ram: bool,
+    registers: Rc<RefCell<PpuRegisters>>,
+}
+
+// VRC6; mapper 26 is the same board with the A0 and A1 lines swapped
+pub fn read(
+    header: &Header,
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    swap_address_lines: bool,
+) -> Cartridge {
+    let uses_chr_ram = chr_rom.len() == 0;
+    let ppu_registers = Rc::new(RefCell::new(PpuRegisters {
+        mirroring: Vertical,
+        one_screen_mirroring_hi: false,
+        chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+    }));
+    Cartridge {
+        cpu_bus: Box::new(Mapper24Cpu {
+            prg_rom: prg_rom.to_vec(),
+            prg_ram: vec![0; header.prg_ram_size],
+            swap_address_lines,
+            registers: CpuRegisters {
+                prg_bank_16k: 0,
+                prg_bank_8k: 0,
+                prg_ram_enabled: false,
+                irq: Irq {
+                    latch: 0,
+                    counter: 0,
+                    prescaler: 341,
+                    enabled: false,
+                    enable_after_ack: false,
+                    cycle_mode: false,
+                    pending: false,
+                },
+                pulse_1: Pulse::new(),
+                pulse_2: Pulse::new(),
+                sawtooth: Sawtooth::new(),
+                audio_halt: false,
+                frequency_shift: 0,
+            },
+            ppu_registers: Rc::clone(&ppu_registers),
+            battery_save: header.battery_save,
+        }),
+        ppu_bus: Box::new(Mapper24Ppu {
+            chr_rom: if uses_chr_ram {
+                vec![0; 0x2000]
+            } else {
+                chr_rom.to_vec()
+            },
+            uses_chr_ram,
+            registers: Rc::clone(&ppu_registers),
+        }),
+        swap_controllers: false,
+        region: header.region,
+    }
+}
+
+impl CartridgeBus for Mapper24Cpu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.registers.prg_ram_enabled => {
+                mirror_prg_ram(address, self.prg_ram.len())
+                    .map_or(open_bus, |offset| self.prg_ram[offset])
+            }
+            0x8000..=0xFFFF => self
+                .prg_rom_offset(address)
+                .map_or(open_bus, |offset| self.prg_rom[offset]),
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        // mapper 26 swaps A0 and A1 on the way to the VRC6's register pins; PRG RAM is wired
+        // straight
+        let address = if self.swap_address_lines && address >= 0x8000 {
+            (address & !0b11) | ((address & 0b01) << 1) | ((address & 0b10) >> 1)
+        } else {
+            address
+        };
+        let registers = &mut self.registers;
+        match address {
+            0x6000..=0x7FFF => {
+                if registers.prg_ram_enabled {
+                    if let Some(offset) = mirror_prg_ram(address, self.prg_ram.len()) {
+                        self.prg_ram[offset] = value;
+                    }
+                }
+            }
+            0x8000..=0xFFFF => match address & 0xF003 {
+                0x8000..=0x8003 => registers.prg_bank_16k = (value & 0xF) as usize,
+                0x9000..=0x9002 => registers.pulse_1.write(address & 0b11, value),
+                0x9003 => {
+                    registers.audio_halt = value & 0b001 > 0;
+                    registers.frequency_shift = if value & 0b100 > 0 {
+                        8
+                    } else if value & 0b010 > 0 {
+                        4
+                    } else {
+                        0
+                    };
+                }
+                0xA000..=0xA002 => registers.pulse_2.write(address & 0b11, value),
+                0xB000..=0xB002 => registers.sawtooth.write(address & 0b11, value),
+                0xB003 => {
+                    registers.prg_ram_enabled = value & 0x80 > 0;
+                    let mut ppu_registers = self.ppu_registers.borrow_mut();
+                    ppu_registers.mirroring = match (value >> 2) & 0b11 {
+                        0 => Vertical,
+                        1 => Horizontal,
+                        _ => SingleScreen,
+                    };
+                    ppu_registers.one_screen_mirroring_hi = (value >> 2) & 0b11 == 3;
+                }
+                0xC000..=0xC003 => registers.prg_bank_8k = (value & 0x1F) as usize,
+                0xD000..=0xD003 => {
+                    self.ppu_registers.borrow_mut().chr_banks[(address & 0b11) as usize] =
+                        value as usize
+                }
+                0xE000..=0xE003 => {
+                    self.ppu_registers.borrow_mut().chr_banks[4 + (address & 0b11) as usize] =
+                        value as usize
+                }
+                0xF000 => registers.irq.latch = value,
+                0xF001 => registers.irq.write_control(value),
+                0xF002 => registers.irq.acknowledge(),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn load_trainer(&mut self, trainer: &[u8]) {
+        for (byte, &value) in self.prg_ram.iter_mut().skip(0x1000).zip(trainer) {
+            *byte = value;
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        address
+    }
+
+    fn save_to_battery(&self, out: &mut dyn Write) -> Result<usize> {
+        if self.battery_save {
+            out.write(self.prg_ram.as_slice())
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn load_from_battery(&mut self, inp: &mut dyn Read) -> Result<usize> {
+        if self.battery_save {
+            read_battery(&mut self.prg_ram, inp)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_slice(&self.prg_ram);
+        out.put_slice(&serialize(&self.registers).unwrap());
+        out.put_slice(&serialize(self.ppu_registers.borrow().deref()).unwrap());
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        state.copy_to_slice(&mut self.prg_ram);
+        self.registers = deserialize_from(state.reader()).unwrap();
+        self.ppu_registers.replace(deserialize_from(state.reader()).unwrap());
+    }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    // the last bank is fixed at $E000 and the one before it is put in the 8KB window at $C000;
+    // the rest are halves of the 16KB bank at $8000
+    fn prg_banks(&self) -> Vec<(u16, &[u8])> {
+        let count = self.prg_rom.chunks(0x2000).count();
+        self.prg_rom
+            .chunks(0x2000)
+            .enumerate()
+            .map(|(i, bank)| match count - i {
+                1 => (0xE000, bank),
+                2 => (0xC000, bank),
+                _ => (0x8000 + (i as u16 % 2) * 0x2000, bank),
+            })
+            .collect()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        match address {
+            0x8000..=0xBFFF => mirror_prg_rom(
+                self.registers.prg_bank_16k * 0x4000 + (address - 0x8000) as usize,
+                len,
+            ),
+            0xC000..=0xDFFF => mirror_prg_rom(
+                self.registers.prg_bank_8k * 0x2000 + (address - 0xC000) as usize,
+                len,
+            ),
+            0xE000..=0xFFFF => mirror_prg_rom(
+                len.saturating_sub(0x2000) + (address - 0xE000) as usize,
+                len,
+            ),
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self) {
+        let registers = &mut self.registers;
+        registers.irq.tick();
+        if !registers.audio_halt {
+            registers.pulse_1.tick(registers.frequency_shift);
+            registers.pulse_2.tick(registers.frequency_shift);
+            registers.sawtooth.tick(registers.frequency_shift);
+        }
+    }
+
+    fn irq_interrupt(&self) -> bool {
+        self.registers.irq.pending
+    }
+
+    fn expansion_audio(&self) -> f32 {
+        f32::from(
+            self.registers.pulse_1.output()
+                + self.registers.pulse_2.output()
+                + self.registers.sawtooth.output(),
+        )
+    }
+}
+
+impl CartridgeBus for Mapper24Ppu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.chr_rom[self.chr_offset(address)],
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        if self.uses_chr_ram {
+            match address {
+                0x0000..=0x1FFF => {
+                    let offset = self.chr_offset(address);
+                    self.chr_rom[offset] = value
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        let registers = self.registers.borrow();
+        let table = match address {
+            0x2000..=0x2FFF => (address - 0x2000) / 0x400,
+            _ => panic!("Bad nametable mirror request {:04X}", address),
+        };
+        let page = match registers.mirroring {
+            Vertical => table & 1,
+            Horizontal => table >> 1,
+            SingleScreen => {
+                if registers.one_screen_mirroring_hi {
+                    1
+                } else {
+                    0
+                }
+            }
+            _ => unimplemented!(),
+        };
+        page * 0x400 + address % 0x400
+    }
+
+    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        if self.uses_chr_ram {
+            out.put_slice(&self.chr_rom);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        if self.uses_chr_ram {
+            state.copy_to_slice(&mut self.chr_rom);
+        }
+    }
+
+    fn rom_size(&self) -> usize {
+        if self.uses_chr_ram {
+            0
+        } else {
+            self.chr_rom.len()
+        }
+    }
+}
+
+impl Mapper24Ppu {
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.registers.borrow().chr_banks[(address / 0x400) as usize];
+        (bank * 0x400 + (address % 0x400) as usize) % self.chr_rom.len()
+    }
+}
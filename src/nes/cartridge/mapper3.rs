@@ -5,6 +5,7 @@ use std::rc::Rc;
 
 use bytes::*;
 
+use nes::cartridge::mirror_prg_rom;
 use nes::cartridge::Cartridge;
 use nes::cartridge::CartridgeBus;
 use nes::cartridge::Header;
@@ -31,27 +32,20 @@ pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
         }),
         ppu_bus: Box::new(Mapper3Ppu {
             chr_rom: chr_rom.to_vec(),
-            mirroring: if header.flags_6 & 0x1 == 1 {
-                Vertical
-            } else {
-                Horizontal
-            },
+            mirroring: header.mirroring,
             chr_bank: chr_bank.clone(),
         }),
+        swap_controllers: false,
+        region: header.region,
     }
 }
 
 impl CartridgeBus for Mapper3Cpu {
     fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
         match address {
-            0x8000..=0xBFFF => self.prg_rom[(address - 0x8000) as usize],
-            0xC000..=0xFFFF => {
-                if self.prg_rom.len() <= 0x4000 {
-                    self.prg_rom[(address - 0xC000) as usize]
-                } else {
-                    self.prg_rom[(address - 0x8000) as usize]
-                }
-            }
+            0x8000..=0xFFFF => self
+                .prg_rom_offset(address)
+                .map_or(open_bus, |offset| self.prg_rom[offset]),
             _ => open_bus,
         }
     }
@@ -84,12 +78,26 @@ impl CartridgeBus for Mapper3Cpu {
     fn load_state(&mut self, state: &mut dyn Buf) {
         self.chr_bank.replace(state.get_u8() as usize);
     }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => mirror_prg_rom((address - 0x8000) as usize, self.prg_rom.len()),
+            _ => None,
+        }
+    }
 }
 
 impl CartridgeBus for Mapper3Ppu {
     fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
         match address {
-            0x0000..=0x1FFF => self.chr_rom[(self.chr_bank.get() * 0x2000) + address as usize],
+            // banks past the end of a smaller CHR ROM wrap around, as the unused bits aren't wired
+            0x0000..=0x1FFF => {
+                self.chr_rom[(self.chr_bank.get() * 0x2000 + address as usize) % self.chr_rom.len()]
+            }
             _ => open_bus,
         }
     }
@@ -125,4 +133,8 @@ impl CartridgeBus for Mapper3Ppu {
     fn save_state(&self, _out: &mut Vec<u8>) {}
 
     fn load_state(&mut self, _state: &mut dyn Buf) {}
+
+    fn rom_size(&self) -> usize {
+        self.chr_rom.len()
+    }
 }
@@ -0,0 +1,311 @@
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::io::Result;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use bincode::{deserialize_from, serialize};
+use bytes::*;
+
+use nes::cartridge::mirror_prg_ram;
+use nes::cartridge::mirror_prg_rom;
+use nes::cartridge::read_battery;
+use nes::cartridge::Cartridge;
+use nes::cartridge::CartridgeBus;
+use nes::cartridge::Header;
+use nes::cartridge::NametableMirroring;
+use nes::cartridge::NametableMirroring::*;
+
+#[derive(Serialize, Deserialize)]
+struct Registers {
+    // $8000: which of R0-R7 the next $8001 write sets, the PRG mode, and the CHR inversion
+    bank_select: u8,
+    banks: [usize; 8],
+    mirroring: NametableMirroring,
+    prg_ram_enabled: bool,
+    prg_ram_protected: bool,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Registers {
+    fn write(&mut self, address: u16, value: u8) {
+        match address & 0xE001 {
+            0x8000 => self.bank_select = value,
+            0x8001 => self.banks[(self.bank_select & 0x7) as usize] = value as usize,
+            0xA000 => self.mirroring = if value & 1 > 0 { Horizontal } else { Vertical },
+            0xA001 => {
+                self.prg_ram_enabled = value & 0x80 > 0;
+                self.prg_ram_protected = value & 0x40 > 0;
+            }
+            0xC000 => self.irq_latch = value,
+            0xC001 => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            0xE000 => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            0xE001 => self.irq_enabled = true,
+            _ => unreachable!(),
+        }
+    }
+
+    // an empty counter, or one just told to, reloads from the latch; the IRQ is raised whenever
+    // the counter ends up at zero
+    fn clock_irq(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn prg_offset(&self, address: u16, max_addr: usize) -> Option<usize> {
+        let last_bank = (max_addr / 0x2000).saturating_sub(1);
+        let swapped = self.bank_select & 0x40 > 0;
+        let bank = match address {
+            0x8000..=0x9FFF if swapped => last_bank.saturating_sub(1),
+            0x8000..=0x9FFF => self.banks[6] & 0x3F,
+            0xA000..=0xBFFF => self.banks[7] & 0x3F,
+            0xC000..=0xDFFF if swapped => self.banks[6] & 0x3F,
+            0xC000..=0xDFFF => last_bank.saturating_sub(1),
+            _ => last_bank,
+        };
+        mirror_prg_rom(bank * 0x2000 + address as usize % 0x2000, max_addr)
+    }
+
+    // two 2K banks and four 1K banks, with the 2K ones at $1000 when inverted
+    fn chr_offset(&self, address: u16, max_addr: usize) -> usize {
+        let address = if self.bank_select & 0x80 > 0 {
+            address ^ 0x1000
+        } else {
+            address
+        } as usize;
+        let bank = match address {
+            0x0000..=0x0FFF => (self.banks[address / 0x800] & !1) + (address / 0x400) % 2,
+            _ => self.banks[2 + (address - 0x1000) / 0x400],
+        };
+        (bank * 0x400 + address % 0x400) % max_addr
+    }
+}
+
+struct Mapper4Cpu {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    registers: Rc<RefCell<Registers>>,
+    battery_save: bool,
+}
+
+struct Mapper4Ppu {
+    chr_rom: Vec<u8>,
+    uses_chr_ram: bool,
+    registers: Rc<RefCell<Registers>>,
+}
+
+// MMC3 (TxROM): 8K PRG and 1K/2K CHR banking, mirroring control, and the scanline IRQ counter,
+// clocked as PPU A12 rises
+pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
+    let uses_chr_ram = chr_rom.len() == 0;
+    let registers = Rc::new(RefCell::new(Registers {
+        bank_select: 0,
+        banks: [0, 2, 4, 5, 6, 7, 0, 1],
+        mirroring: header.mirroring,
+        prg_ram_enabled: true,
+        prg_ram_protected: false,
+        irq_latch: 0,
+        irq_counter: 0,
+        irq_reload: false,
+        irq_enabled: false,
+        irq_pending: false,
+    }));
+    Cartridge {
+        cpu_bus: Box::new(Mapper4Cpu {
+            prg_rom: prg_rom.to_vec(),
+            prg_ram: vec![0; header.prg_ram_size],
+            registers: Rc::clone(&registers),
+            battery_save: header.battery_save,
+        }),
+        ppu_bus: Box::new(Mapper4Ppu {
+            chr_rom: if uses_chr_ram {
+                vec![0; 0x2000]
+            } else {
+                chr_rom.to_vec()
+            },
+            uses_chr_ram,
+            registers: Rc::clone(&registers),
+        }),
+        swap_controllers: false,
+        region: header.region,
+    }
+}
+
+impl CartridgeBus for Mapper4Cpu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.registers.borrow().prg_ram_enabled => {
+                mirror_prg_ram(address, self.prg_ram.len())
+                    .map_or(open_bus, |offset| self.prg_ram[offset])
+            }
+            0x8000..=0xFFFF => self
+                .prg_rom_offset(address)
+                .map_or(open_bus, |offset| self.prg_rom[offset]),
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        let mut registers = self.registers.borrow_mut();
+        match address {
+            0x6000..=0x7FFF => {
+                if registers.prg_ram_enabled && !registers.prg_ram_protected {
+                    if let Some(offset) = mirror_prg_ram(address, self.prg_ram.len()) {
+                        self.prg_ram[offset] = value;
+                    }
+                }
+            }
+            0x8000..=0xFFFF => registers.write(address, value),
+            _ => (),
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        address
+    }
+
+    fn save_to_battery(&self, out: &mut dyn Write) -> Result<usize> {
+        if self.battery_save {
+            out.write(self.prg_ram.as_slice())
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn load_from_battery(&mut self, inp: &mut dyn Read) -> Result<usize> {
+        if self.battery_save {
+            read_battery(&mut self.prg_ram, inp)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_slice(&self.prg_ram);
+        out.put_slice(&serialize(self.registers.borrow().deref()).unwrap());
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        state.copy_to_slice(&mut self.prg_ram);
+        self.registers
+            .replace(deserialize_from(state.reader()).unwrap());
+    }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    // the last two banks sit at $C000 and $E000 in the mode games boot in; the rest are put at
+    // $8000
+    fn prg_banks(&self) -> Vec<(u16, &[u8])> {
+        let count = self.prg_rom.chunks(0x2000).count();
+        self.prg_rom
+            .chunks(0x2000)
+            .enumerate()
+            .map(|(i, bank)| match count - i {
+                1 => (0xE000, bank),
+                2 => (0xC000, bank),
+                _ => (0x8000, bank),
+            })
+            .collect()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => self
+                .registers
+                .borrow()
+                .prg_offset(address, self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    fn irq_interrupt(&self) -> bool {
+        self.registers.borrow().irq_pending
+    }
+}
+
+impl CartridgeBus for Mapper4Ppu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x0000..=0x1FFF => {
+                let registers = self.registers.borrow();
+                self.chr_rom[registers.chr_offset(address, self.chr_rom.len())]
+            }
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        if self.uses_chr_ram {
+            match address {
+                0x0000..=0x1FFF => {
+                    let offset = self
+                        .registers
+                        .borrow()
+                        .chr_offset(address, self.chr_rom.len());
+                    self.chr_rom[offset] = value
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        let offset = address - 0x2000;
+        match self.registers.borrow().mirroring {
+            Vertical => offset % 0x800,
+            Horizontal => offset / 0x800 * 0x400 + offset % 0x400,
+            _ => unimplemented!(),
+        }
+    }
+
+    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        if self.uses_chr_ram {
+            out.put_slice(&self.chr_rom);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        if self.uses_chr_ram {
+            state.copy_to_slice(&mut self.chr_rom);
+        }
+    }
+
+    fn rom_size(&self) -> usize {
+        if self.uses_chr_ram {
+            0
+        } else {
+            self.chr_rom.len()
+        }
+    }
+
+    fn ppu_a12_rise(&mut self) {
+        self.registers.borrow_mut().clock_irq();
+    }
+}
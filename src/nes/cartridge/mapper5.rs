@@ -0,0 +1,546 @@
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::io::Result;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use bincode::{deserialize_from, serialize};
+use bytes::*;
+
+use nes::cartridge::mirror_prg_rom;
+use nes::cartridge::read_battery;
+use nes::cartridge::Cartridge;
+use nes::cartridge::CartridgeBus;
+use nes::cartridge::Header;
+use nes::cartridge::PpuFetch;
+
+#[derive(Serialize, Deserialize)]
+struct PpuRegisters {
+    chr_mode: u8,
+    // $5120-$512B, with the $5130 upper bits already applied
+    chr_banks: [usize; 12],
+    chr_upper: usize,
+    last_set_b: bool,
+    exram_mode: u8,
+    nametables: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    exram: Vec<u8>,
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: usize,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    // what the PPU is fetching, and which tile of the line the last nametable fetch was for
+    fetch: PpuFetch,
+    large_sprites: bool,
+    tile: u16,
+    scanline: u16,
+    ext_attribute: u8,
+    split_tile: bool,
+}
+
+impl PpuRegisters {
+    fn split_y(&self) -> u16 {
+        (u16::from(self.split_scroll) + self.scanline) % 240
+    }
+
+    // CIRAM page 0 or 1, ExRAM, or the fill tile, chosen per nametable by $5105
+    fn nametable(&self, address: u16, internal_ram: &[u8]) -> u8 {
+        let offset = (address % 0x400) as usize;
+        let table = (address - 0x2000) / 0x400;
+        match (self.nametables >> (table * 2)) & 0b11 {
+            0 => internal_ram[offset],
+            1 => internal_ram[0x400 + offset],
+            2 => {
+                if self.exram_mode < 2 {
+                    self.exram[offset]
+                } else {
+                    0
+                }
+            }
+            _ => {
+                if offset < 0x3C0 {
+                    self.fill_tile
+                } else {
+                    self.fill_attribute * 0x55
+                }
+            }
+        }
+    }
+
+    fn in_split(&self, tile: u16) -> bool {
+        let threshold = u16::from(self.split_control & 0x1F);
+        self.split_control & 0x80 > 0
+            && self.exram_mode < 2
+            && if self.split_control & 0x40 > 0 {
+                tile >= threshold
+            } else {
+                tile < threshold
+            }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CpuRegisters {
+    prg_mode: u8,
+    // $5114-$5117; bit 7 selects ROM over RAM
+    prg_banks: [u8; 4],
+    prg_ram_protect: [u8; 2],
+    prg_ram_bank: u8,
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+struct Mapper5Cpu {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    registers: CpuRegisters,
+    ppu_registers: Rc<RefCell<PpuRegisters>>,
+    battery_save: bool,
+}
+
+struct Mapper5Ppu {
+    chr_rom: Vec<u8>,
+    uses_chr_ram: bool,
+    registers: Rc<RefCell<PpuRegisters>>,
+}
+
+// MMC5 (ExROM): PRG and CHR banking, ExRAM, fill mode, the vertical split and the scanline IRQ;
+// the extra pulse and PCM channels aren't emulated
+pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
+    let uses_chr_ram = chr_rom.len() == 0;
+    let ppu_registers = Rc::new(RefCell::new(PpuRegisters {
+        chr_mode: 3,
+        chr_banks: [0; 12],
+        chr_upper: 0,
+        last_set_b: false,
+        exram_mode: 0,
+        nametables: 0,
+        fill_tile: 0,
+        fill_attribute: 0,
+        exram: vec![0; 0x400],
+        split_control: 0,
+        split_scroll: 0,
+        split_bank: 0,
+        irq_compare: 0,
+        irq_enabled: false,
+        irq_pending: false,
+        in_frame: false,
+        fetch: PpuFetch::Idle,
+        large_sprites: false,
+        tile: 0,
+        scanline: 0,
+        ext_attribute: 0,
+        split_tile: false,
+    }));
+    Cartridge {
+        cpu_bus: Box::new(Mapper5Cpu {
+            prg_rom: prg_rom.to_vec(),
+            prg_ram: vec![0; header.prg_ram_size],
+            registers: CpuRegisters {
+                prg_mode: 3,
+                prg_banks: [0xFF; 4],
+                prg_ram_protect: [0; 2],
+                prg_ram_bank: 0,
+                multiplicand: 0xFF,
+                multiplier: 0xFF,
+            },
+            ppu_registers: Rc::clone(&ppu_registers),
+            battery_save: header.battery_save,
+        }),
+        ppu_bus: Box::new(Mapper5Ppu {
+            chr_rom: if uses_chr_ram {
+                vec![0; 0x2000]
+            } else {
+                chr_rom.to_vec()
+            },
+            uses_chr_ram,
+            registers: Rc::clone(&ppu_registers),
+        }),
+        swap_controllers: false,
+        region: header.region,
+    }
+}
+
+impl CartridgeBus for Mapper5Cpu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x5204 => {
+                let mut ppu_registers = self.ppu_registers.borrow_mut();
+                let status = u8::from(ppu_registers.irq_pending) << 7
+                    | u8::from(ppu_registers.in_frame) << 6
+                    | (open_bus & 0x3F);
+                ppu_registers.irq_pending = false;
+                status
+            }
+            0x5205 => self.product() as u8,
+            0x5206 => (self.product() >> 8) as u8,
+            0x5C00..=0x5FFF => {
+                let ppu_registers = self.ppu_registers.borrow();
+                if ppu_registers.exram_mode >= 2 {
+                    ppu_registers.exram[(address - 0x5C00) as usize]
+                } else {
+                    open_bus
+                }
+            }
+            0x6000..=0x7FFF => self
+                .prg_ram_offset(self.registers.prg_ram_bank, address)
+                .map_or(open_bus, |offset| self.prg_ram[offset]),
+            0x8000..=0xFFFF => match self.prg_ram_bank(address) {
+                Some(bank) => self
+                    .prg_ram_offset(bank, address)
+                    .map_or(open_bus, |offset| self.prg_ram[offset]),
+                None => self
+                    .prg_rom_offset(address)
+                    .map_or(open_bus, |offset| self.prg_rom[offset]),
+            },
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        let registers = &mut self.registers;
+        match address {
+            0x5100 => registers.prg_mode = value & 0b11,
+            0x5101 => self.ppu_registers.borrow_mut().chr_mode = value & 0b11,
+            0x5102 => registers.prg_ram_protect[0] = value & 0b11,
+            0x5103 => registers.prg_ram_protect[1] = value & 0b11,
+            0x5104 => self.ppu_registers.borrow_mut().exram_mode = value & 0b11,
+            0x5105 => self.ppu_registers.borrow_mut().nametables = value,
+            0x5106 => self.ppu_registers.borrow_mut().fill_tile = value,
+            0x5107 => self.ppu_registers.borrow_mut().fill_attribute = value & 0b11,
+            0x5113 => registers.prg_ram_bank = value & 0x7,
+            0x5114..=0x5117 => registers.prg_banks[(address - 0x5114) as usize] = value,
+            0x5120..=0x512B => {
+                let mut ppu_registers = self.ppu_registers.borrow_mut();
+                let bank = (ppu_registers.chr_upper << 8) | value as usize;
+                ppu_registers.chr_banks[(address - 0x5120) as usize] = bank;
+                ppu_registers.last_set_b = address >= 0x5128;
+            }
+            0x5130 => self.ppu_registers.borrow_mut().chr_upper = (value & 0b11) as usize,
+            0x5200 => self.ppu_registers.borrow_mut().split_control = value,
+            0x5201 => self.ppu_registers.borrow_mut().split_scroll = value,
+            0x5202 => self.ppu_registers.borrow_mut().split_bank = value as usize,
+            0x5203 => self.ppu_registers.borrow_mut().irq_compare = value,
+            0x5204 => self.ppu_registers.borrow_mut().irq_enabled = value & 0x80 > 0,
+            0x5205 => registers.multiplicand = value,
+            0x5206 => registers.multiplier = value,
+            0x5C00..=0x5FFF => {
+                let mut ppu_registers = self.ppu_registers.borrow_mut();
+                let offset = (address - 0x5C00) as usize;
+                // in the nametable modes writes only land during rendering; outside it they store zero
+                match ppu_registers.exram_mode {
+                    0 | 1 => {
+                        ppu_registers.exram[offset] = if ppu_registers.in_frame { value } else { 0 }
+                    }
+                    2 => ppu_registers.exram[offset] = value,
+                    _ => (),
+                }
+            }
+            0x6000..=0x7FFF => {
+                let bank = self.registers.prg_ram_bank;
+                self.write_prg_ram(bank, address, value);
+            }
+            0x8000..=0xFFFF => {
+                if let Some(bank) = self.prg_ram_bank(address) {
+                    self.write_prg_ram(bank, address, value);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn load_trainer(&mut self, trainer: &[u8]) {
+        for (byte, &value) in self.prg_ram.iter_mut().skip(0x1000).zip(trainer) {
+            *byte = value;
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        address
+    }
+
+    fn save_to_battery(&self, out: &mut dyn Write) -> Result<usize> {
+        if self.battery_save {
+            out.write(self.prg_ram.as_slice())
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn load_from_battery(&mut self, inp: &mut dyn Read) -> Result<usize> {
+        if self.battery_save {
+            read_battery(&mut self.prg_ram, inp)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_slice(&self.prg_ram);
+        out.put_slice(&serialize(&self.registers).unwrap());
+        out.put_slice(&serialize(self.ppu_registers.borrow().deref()).unwrap());
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        state.copy_to_slice(&mut self.prg_ram);
+        self.registers = deserialize_from(state.reader()).unwrap();
+        self.ppu_registers.replace(deserialize_from(state.reader()).unwrap());
+    }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    // 8KB banks where they'd sit with the ROM mapped in 32KB pieces, as in the mode games boot
+    // in, which keeps the last one at $E000
+    fn prg_banks(&self) -> Vec<(u16, &[u8])> {
+        self.prg_rom
+            .chunks(0x2000)
+            .enumerate()
+            .map(|(i, bank)| (0x8000 + (i as u16 % 4) * 0x2000, bank))
+            .collect()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        let (bank, size) = self.prg_bank(address);
+        if bank & 0x80 == 0 {
+            return None;
+        }
+        let bank = (bank & 0x7F) as usize & !(size / 0x2000 - 1);
+        mirror_prg_rom(
+            bank * 0x2000 + (address as usize - 0x8000) % size,
+            self.prg_rom.len(),
+        )
+    }
+
+    fn irq_interrupt(&self) -> bool {
+        let ppu_registers = self.ppu_registers.borrow();
+        ppu_registers.irq_enabled && ppu_registers.irq_pending
+    }
+}
+
+impl Mapper5Cpu {
+    fn product(&self) -> u16 {
+        u16::from(self.registers.multiplicand) * u16::from(self.registers.multiplier)
+    }
+
+    // the register selecting the bank at this address, and the size of the window it switches
+    fn prg_bank(&self, address: u16) -> (u8, usize) {
+        let banks = &self.registers.prg_banks;
+        match (self.registers.prg_mode, address) {
+            (0, _) => (banks[3] | 0x80, 0x8000),
+            (1, 0x8000..=0xBFFF) => (banks[1], 0x4000),
+            (1, _) => (banks[3] | 0x80, 0x4000),
+            (2, 0x8000..=0xBFFF) => (banks[1], 0x4000),
+            (2, 0xC000..=0xDFFF) => (banks[2], 0x2000),
+            (2, _) => (banks[3] | 0x80, 0x2000),
+            (_, 0x8000..=0x9FFF) => (banks[0], 0x2000),
+            (_, 0xA000..=0xBFFF) => (banks[1], 0x2000),
+            (_, 0xC000..=0xDFFF) => (banks[2], 0x2000),
+            (_, _) => (banks[3] | 0x80, 0x2000),
+        }
+    }
+
+    fn prg_ram_bank(&self, address: u16) -> Option<u8> {
+        let (bank, size) = self.prg_bank(address);
+        if bank & 0x80 > 0 {
+            None
+        } else {
+            // a 16K RAM window is two consecutive 8K pages
+            let page = ((address as usize - 0x8000) % size / 0x2000) as u8;
+            Some((bank & 0x7 & !((size / 0x2000) as u8 - 1)) + page)
+        }
+    }
+
+    fn prg_ram_offset(&self, bank: u8, address: u16) -> Option<usize> {
+        mirror_prg_rom(
+            bank as usize * 0x2000 + (address as usize % 0x2000),
+            self.prg_ram.len(),
+        )
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.registers.prg_ram_protect == [0b10, 0b01]
+    }
+
+    fn write_prg_ram(&mut self, bank: u8, address: u16, value: u8) {
+        if self.prg_ram_writable() {
+            if let Some(offset) = self.prg_ram_offset(bank, address) {
+                self.prg_ram[offset] = value;
+            }
+        }
+    }
+}
+
+impl CartridgeBus for Mapper5Ppu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.chr_rom[self.chr_offset(address)],
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        if self.uses_chr_ram {
+            match address {
+                0x0000..=0x1FFF => {
+                    let offset = self.chr_offset(address);
+                    self.chr_rom[offset] = value
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        let registers = self.registers.borrow();
+        // only asked for the CIRAM pages; ExRAM and fill are handled by read_nametable
+        let table = (address - 0x2000) / 0x400;
+        let page = u16::from((registers.nametables >> (table * 2)) & 1);
+        page * 0x400 + address % 0x400
+    }
+
+    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        if self.uses_chr_ram {
+            out.put_slice(&self.chr_rom);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        if self.uses_chr_ram {
+            state.copy_to_slice(&mut self.chr_rom);
+        }
+    }
+
+    fn rom_size(&self) -> usize {
+        if self.uses_chr_ram {
+            0
+        } else {
+            self.chr_rom.len()
+        }
+    }
+
+    fn read_nametable(&self, address: u16, internal_ram: &[u8]) -> u8 {
+        self.registers.borrow().nametable(address, internal_ram)
+    }
+
+    // the rendering fetches also step through the line's tiles, for the split and the extended
+    // attributes; $2007 reads and debugger peeks only see the nametables
+    fn fetch_nametable(&mut self, address: u16, internal_ram: &[u8]) -> u8 {
+        let mut registers = self.registers.borrow_mut();
+        let offset = (address % 0x400) as usize;
+        let tile_fetch = offset < 0x3C0;
+        let background = match registers.fetch {
+            PpuFetch::Background { .. } => true,
+            _ => false,
+        };
+        if background {
+            if tile_fetch {
+                let tile = registers.tile;
+                registers.split_tile = registers.in_split(tile);
+                registers.tile = tile.saturating_add(1);
+                if registers.split_tile {
+                    let y = registers.split_y();
+                    return registers.exram[(y / 8 * 32 + tile % 32) as usize];
+                }
+                if registers.exram_mode == 1 {
+                    registers.ext_attribute = registers.exram[offset];
+                }
+            } else if registers.split_tile {
+                let y = registers.split_y();
+                let x = registers.tile.wrapping_sub(1) % 32;
+                let attribute = registers.exram[(0x3C0 + y / 32 * 8 + x / 4) as usize];
+                let shift = (y & 0x10) / 4 + (x & 0x2);
+                return ((attribute >> shift) & 0b11) * 0x55;
+            } else if registers.exram_mode == 1 {
+                return (registers.ext_attribute >> 6) * 0x55;
+            }
+        }
+        registers.nametable(address, internal_ram)
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, internal_ram: &mut [u8]) {
+        let mut registers = self.registers.borrow_mut();
+        let offset = (address % 0x400) as usize;
+        let table = (address - 0x2000) / 0x400;
+        match (registers.nametables >> (table * 2)) & 0b11 {
+            0 => internal_ram[offset] = value,
+            1 => internal_ram[0x400 + offset] = value,
+            2 => {
+                if registers.exram_mode < 2 {
+                    registers.exram[offset] = value
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn ppu_fetch(&mut self, fetch: PpuFetch) {
+        let mut registers = self.registers.borrow_mut();
+        match fetch {
+            PpuFetch::Sprites { large } => registers.large_sprites = large,
+            PpuFetch::Background { scanline } => {
+                registers.tile = 0;
+                registers.scanline = scanline;
+                if scanline == 0 {
+                    registers.in_frame = true;
+                    registers.irq_pending = false;
+                }
+                if registers.irq_compare != 0 && scanline == u16::from(registers.irq_compare) {
+                    registers.irq_pending = true;
+                }
+            }
+            PpuFetch::Idle => registers.in_frame = false,
+        }
+        registers.fetch = fetch;
+    }
+}
+
+impl Mapper5Ppu {
+    fn chr_offset(&self, address: u16) -> usize {
+        let registers = self.registers.borrow();
+        let address = address as usize;
+        let offset = match registers.fetch {
+            PpuFetch::Background { .. } if registers.split_tile => {
+                // the split has its own 4K bank, and its own fine scroll
+                let y = registers.split_y() as usize;
+                registers.split_bank * 0x1000 + (address & 0xFF8) + (y & 0x7)
+            }
+            PpuFetch::Background { .. } if registers.exram_mode == 1 => {
+                let bank = (registers.chr_upper << 6) | (registers.ext_attribute & 0x3F) as usize;
+                bank * 0x1000 + (address & 0xFFF)
+            }
+            _ => {
+                let set_b = match registers.fetch {
+                    PpuFetch::Sprites { .. } => false,
+                    PpuFetch::Background { .. } => registers.large_sprites,
+                    PpuFetch::Idle => registers.large_sprites && registers.last_set_b,
+                };
+                let size = 0x2000 >> registers.chr_mode;
+                let register = if set_b {
+                    match registers.chr_mode {
+                        0 | 1 => 11,
+                        2 => 9 + (address & 0xFFF) / 0x800 * 2,
+                        _ => 8 + (address & 0xFFF) / 0x400,
+                    }
+                } else {
+                    (address / size + 1) * (8 >> registers.chr_mode) - 1
+                };
+                registers.chr_banks[register] * size + address % size
+            }
+        };
+        offset % self.chr_rom.len()
+    }
+}
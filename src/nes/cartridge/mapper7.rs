@@ -0,0 +1,151 @@
+use std::cell::Cell;
+use std::io::prelude::*;
+use std::io::Result;
+use std::rc::Rc;
+
+use bytes::*;
+
+use nes::cartridge::mirror_prg_rom;
+use nes::cartridge::Cartridge;
+use nes::cartridge::CartridgeBus;
+use nes::cartridge::Header;
+
+// AxROM: 32KB PRG banks, CHR RAM, and a register bit picking which nametable fills all four
+// screens. AMROM/AOROM boards have bus conflicts but ANROM doesn't, and games write values
+// that match ROM anyway, so conflicts aren't modeled.
+struct Mapper7Cpu {
+    prg_rom: Vec<u8>,
+    prg_bank: usize,
+    nametable_hi: Rc<Cell<bool>>,
+}
+
+struct Mapper7Ppu {
+    chr_ram: Vec<u8>,
+    nametable_hi: Rc<Cell<bool>>,
+}
+
+pub fn read(header: &Header, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
+    let nametable_hi = Rc::new(Cell::new(false));
+    Cartridge {
+        cpu_bus: Box::new(Mapper7Cpu {
+            prg_rom: prg_rom.to_vec(),
+            prg_bank: 0,
+            nametable_hi: nametable_hi.clone(),
+        }),
+        ppu_bus: Box::new(Mapper7Ppu {
+            chr_ram: if chr_rom.len() == 0 {
+                vec![0; 0x2000]
+            } else {
+                chr_rom.to_vec()
+            },
+            nametable_hi,
+        }),
+        swap_controllers: false,
+        region: header.region,
+    }
+}
+
+impl CartridgeBus for Mapper7Cpu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x8000..=0xFFFF => self
+                .prg_rom_offset(address)
+                .map_or(open_bus, |offset| self.prg_rom[offset]),
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        match address {
+            0x8000..=0xFFFF => {
+                self.prg_bank = (value & 0b111) as usize;
+                self.nametable_hi.replace(value & 0b10000 > 0);
+            }
+            _ => (),
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        address
+    }
+
+    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_u8(self.prg_bank as u8);
+        out.put_u8(self.nametable_hi.get() as u8);
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        self.prg_bank = state.get_u8() as usize;
+        self.nametable_hi.replace(state.get_u8() > 0);
+    }
+
+    fn rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn prg_banks(&self) -> Vec<(u16, &[u8])> {
+        self.prg_rom
+            .chunks(0x8000)
+            .map(|bank| (0x8000, bank))
+            .collect()
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => mirror_prg_rom(
+                self.prg_bank * 0x8000 + (address - 0x8000) as usize,
+                self.prg_rom.len(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl CartridgeBus for Mapper7Ppu {
+    fn read_memory(&self, address: u16, open_bus: u8) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.chr_ram[address as usize],
+            _ => open_bus,
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8, _cpu_cycle: u64) {
+        match address {
+            0x0000..=0x1FFF => self.chr_ram[address as usize] = value,
+            _ => (),
+        }
+    }
+
+    fn mirror_nametable(&self, address: u16) -> u16 {
+        match address {
+            0x2000..=0x2FFF => {
+                (address & 0x3FF) | if self.nametable_hi.get() { 0x400 } else { 0 }
+            }
+            _ => panic!("Bad nametable mirror request {:04X}", address),
+        }
+    }
+
+    fn save_to_battery(&self, _out: &mut dyn Write) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn load_from_battery(&mut self, _inp: &mut dyn Read) -> Result<usize> {
+        unimplemented!();
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.put_slice(&self.chr_ram);
+    }
+
+    fn load_state(&mut self, state: &mut dyn Buf) {
+        state.copy_to_slice(&mut self.chr_ram);
+    }
+}
@@ -1,12 +1,22 @@
+use std::cmp::max;
 use std::io::prelude::*;
 use std::io::Result;
 
 use bytes::Buf;
 use simple_error::*;
 
+use nes::Region;
+use rom::db::{Entry, RomDb};
+use rom::RamOverrides;
+
 mod mapper0;
 mod mapper1;
+mod mapper2;
+mod mapper24;
 mod mapper3;
+mod mapper4;
+mod mapper5;
+mod mapper7;
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 enum NametableMirroring {
@@ -22,9 +32,22 @@ enum NametableMirroring {
     _SingleScreenFixed,
 }
 
+// what the PPU is fetching, for mappers that bank CHR or count scanlines by watching it
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PpuFetch {
+    // sprite patterns for the next line
+    Sprites { large: bool },
+    // background tiles, starting with the two prefetched at the end of the previous line
+    Background { scanline: u16 },
+    // rendering is off, or past the visible lines
+    Idle,
+}
+
 pub struct Cartridge {
     pub cpu_bus: Box<dyn CartridgeBus>,
     pub ppu_bus: Box<dyn CartridgeBus>,
+    pub swap_controllers: bool,
+    pub region: Region,
 }
 
 pub trait CartridgeBus {
@@ -35,13 +58,69 @@ pub trait CartridgeBus {
     fn load_from_battery(&mut self, inp: &mut dyn Read) -> Result<usize>;
     fn save_state(&self, out: &mut Vec<u8>);
     fn load_state(&mut self, state: &mut dyn Buf);
+
+    fn rom_size(&self) -> usize {
+        0
+    }
+
+    fn prg_rom_offset(&self, _address: u16) -> Option<usize> {
+        None
+    }
+
+    // the PRG ROM cut into the banks the board switches between, each with the address it's
+    // usually switched in at, for disassembly; empty for boards whose PRG ROM stays put
+    fn prg_banks(&self) -> Vec<(u16, &[u8])> {
+        Vec::new()
+    }
+
+    // the 512 bytes of trainer some dumps carry, which go into PRG RAM at $7000 before the game
+    // starts; boards that power up with their RAM disabled put it there directly
+    fn load_trainer(&mut self, trainer: &[u8]) {
+        for (i, &value) in trainer.iter().enumerate() {
+            self.write_memory(0x7000 + i as u16, value, 0);
+        }
+    }
+
+    // called once per CPU cycle, for mappers with their own timers
+    fn tick(&mut self) {}
+
+    fn irq_interrupt(&self) -> bool {
+        false
+    }
+
+    // expansion audio output, on the same scale as the APU's pulse channels
+    fn expansion_audio(&self) -> f32 {
+        0.0
+    }
+
+    // nametable accesses normally land in one of the PPU's two internal pages, but a mapper can
+    // supply the data itself
+    fn read_nametable(&self, address: u16, internal_ram: &[u8]) -> u8 {
+        internal_ram[self.mirror_nametable(address) as usize]
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, internal_ram: &mut [u8]) {
+        internal_ram[self.mirror_nametable(address) as usize] = value;
+    }
+
+    // the PPU's own nametable and attribute fetches while rendering, which a mapper can track
+    fn fetch_nametable(&mut self, address: u16, internal_ram: &[u8]) -> u8 {
+        self.read_nametable(address, internal_ram)
+    }
+
+    // called on the PPU side as each line's fetches start
+    fn ppu_fetch(&mut self, _fetch: PpuFetch) {}
+
+    // called on the PPU side when its address line A12 goes high, as it moves from fetching one
+    // pattern table to the other; MMC3 counts scanlines by it
+    fn ppu_a12_rise(&mut self) {}
 }
 
 #[derive(Debug)]
 pub struct Header {
-    prg_rom_blocks: u8,
-    chr_rom_blocks: u8,
-    prg_ram_blocks: u8,
+    prg_rom_size: usize,
+    chr_rom_size: usize,
+    prg_ram_size: usize,
     flags_6: u8,
     flags_7: u8,
     _flags_9: u8,
@@ -49,21 +128,51 @@ pub struct Header {
 
     mirroring: NametableMirroring,
     battery_save: bool,
+    trainer: bool,
+    nes_2: bool,
+    region: Region,
 }
 
-pub fn read(src: &mut dyn Read, save_data: Option<&mut dyn Read>) -> SimpleResult<Cartridge> {
+pub fn read(
+    src: &mut dyn Read,
+    save_data: Option<&mut dyn Read>,
+    ram: &RamOverrides,
+) -> SimpleResult<Cartridge> {
     let mut contents = Vec::new();
     src.read_to_end(&mut contents)
         .expect("error reading source");
-    if contents[0..4] != [0x4E, 0x45, 0x53, 0x1A] {
+    if contents.len() < 16 || contents[0..4] != [0x4E, 0x45, 0x53, 0x1A] {
         return Err(SimpleError::new("Not a NES file."));
     }
-    let header = Header {
-        prg_rom_blocks: contents[4],
-        chr_rom_blocks: contents[5],
-        prg_ram_blocks: contents[8],
+    let nes_2 = contents[7] & 0b1100 == 0b1000;
+    // old dumps may have junk (e.g. "DiskDude!") from byte 7 on; only trust it with clean padding
+    let clean_padding = nes_2 || contents[12..16] == [0, 0, 0, 0];
+    let flags_7 = if clean_padding { contents[7] } else { 0 };
+    let mut header = Header {
+        prg_rom_size: if nes_2 {
+            nes_2_rom_size(contents[4], contents[9] & 0xF, 0x4000)
+        } else {
+            contents[4] as usize * 0x4000
+        },
+        chr_rom_size: if nes_2 {
+            nes_2_rom_size(contents[5], contents[9] >> 4, 0x2000)
+        } else {
+            contents[5] as usize * 0x2000
+        },
+        prg_ram_size: max(
+            if nes_2 {
+                let size =
+                    nes_2_ram_size(contents[10] & 0xF).max(nes_2_ram_size(contents[10] >> 4));
+                (size + 0x1FFF) / 0x2000
+            } else if clean_padding {
+                contents[8] as usize
+            } else {
+                0
+            },
+            1,
+        ) * 0x2000,
         flags_6: contents[6],
-        flags_7: contents[7],
+        flags_7,
         _flags_9: contents[9],
         _flags_10: contents[10],
         mirroring: if contents[6] & 0b1 > 0 {
@@ -72,26 +181,61 @@ pub fn read(src: &mut dyn Read, save_data: Option<&mut dyn Read>) -> SimpleResul
             NametableMirroring::Horizontal
         },
         battery_save: contents[6] & 0b10 > 0,
+        trainer: contents[6] & 0b100 > 0,
+        nes_2,
+        // NES 2.0 gives the timing in byte 12, where multi-region carts run as NTSC and Dendy
+        // clones as the closer PAL; iNES only has a rarely set bit in byte 9
+        region: if nes_2 {
+            match contents[12] & 0b11 {
+                1 | 3 => Region::Pal,
+                _ => Region::Ntsc,
+            }
+        } else if clean_padding && contents[9] & 1 > 0 {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        },
     };
     info!(target: "cartridge", "header: {:?}", header);
-    assert_eq!([0, 0, 0, 0, 0], contents[11..16]);
-    // TODO check for trainer
-    let prg_end = 16 + (u32::from(header.prg_rom_blocks) * 0x4000) as usize;
-    let chr_end = prg_end + (u32::from(header.chr_rom_blocks) * 0x2000) as usize;
-    let prg_rom = &contents[16..prg_end];
+    let prg_start = 16usize + if header.trainer { 0x200 } else { 0 };
+    let prg_end = prg_start.saturating_add(header.prg_rom_size);
+    let chr_end = prg_end.saturating_add(header.chr_rom_size);
+    if contents.len() < chr_end {
+        return Err(SimpleError::new("NES file is truncated."));
+    }
+    let prg_rom = &contents[prg_start..prg_end];
     let chr_rom = &contents[prg_end..chr_end];
 
-    let mapper = (header.flags_6 >> 4) + (header.flags_7 & 0b11110000);
+    let mut mapper = u16::from((header.flags_6 >> 4) | (header.flags_7 & 0b11110000));
+    if header.nes_2 {
+        mapper |= u16::from(contents[8] & 0xF) << 8;
+    }
+    let db = RomDb::bundled();
+    let db_entry = db.nes(&contents[prg_start..chr_end]);
+    if let Some(entry) = db_entry {
+        apply_db_entry(&mut header, &mut mapper, entry);
+    }
+    apply_ram_overrides(&mut header, ram);
     info!(target: "cartridge", "Using mapper {}", mapper);
 
     let mut cartridge = match mapper {
         0 => Ok(mapper0::read(&header, prg_rom, chr_rom)),
         1 => Ok(mapper1::read(&header, prg_rom, chr_rom)),
+        2 => Ok(mapper2::read(&header, prg_rom, chr_rom)),
         3 => Ok(mapper3::read(&header, prg_rom, chr_rom)),
+        4 => Ok(mapper4::read(&header, prg_rom, chr_rom)),
+        5 => Ok(mapper5::read(&header, prg_rom, chr_rom)),
+        7 => Ok(mapper7::read(&header, prg_rom, chr_rom)),
+        24 => Ok(mapper24::read(&header, prg_rom, chr_rom, false)),
+        26 => Ok(mapper24::read(&header, prg_rom, chr_rom, true)),
         _ => unimplemented!(),
     };
 
     if let Ok(ref mut cartridge) = cartridge {
+        if header.trainer {
+            cartridge.cpu_bus.load_trainer(&contents[16..prg_start]);
+        }
+        cartridge.swap_controllers = db_entry.map_or(false, |entry| entry.swap_controllers);
         if let Some(save_data) = save_data {
             let bytes = cartridge
                 .cpu_bus
@@ -103,3 +247,92 @@ pub fn read(src: &mut dyn Read, save_data: Option<&mut dyn Read>) -> SimpleResul
 
     cartridge
 }
+
+// PRG ROM smaller than the banks it's mapped into (a 16KB NROM, or an 8KB dump) shows up
+// mirrored, the way address lines the board doesn't decode wrap; with no PRG ROM at all, reads
+// are open bus
+fn mirror_prg_rom(offset: usize, len: usize) -> Option<usize> {
+    if len == 0 {
+        None
+    } else {
+        Some(offset % len)
+    }
+}
+
+// PRG RAM smaller than $6000-$7FFF mirrors the same way, and a board with none (or a RAM size
+// override of 0) leaves the range open bus
+fn mirror_prg_ram(address: u16, len: usize) -> Option<usize> {
+    mirror_prg_rom(address as usize - 0x6000, len)
+}
+
+// fills battery-backed RAM from a save without resizing it; a save that's short (or empty)
+// leaves the rest as it was, and anything past the end of the RAM is ignored
+fn read_battery(ram: &mut [u8], inp: &mut dyn Read) -> Result<usize> {
+    let mut filled = 0;
+    while filled < ram.len() {
+        match inp.read(&mut ram[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn apply_db_entry(header: &mut Header, mapper: &mut u16, entry: &Entry) {
+    info!(target: "cartridge", "ROM database: {:?}", entry);
+    if let Some(db_mapper) = entry.mapper {
+        *mapper = db_mapper;
+    }
+    if let Some(vertical) = entry.vertical_mirroring {
+        header.mirroring = if vertical {
+            NametableMirroring::Vertical
+        } else {
+            NametableMirroring::Horizontal
+        };
+    }
+    if let Some(battery) = entry.battery {
+        header.battery_save = battery;
+    }
+    if let Some(size) = entry.ram {
+        header.prg_ram_size = size;
+    }
+}
+
+// PRG RAM is seen through the 8KB window at $6000-$7FFF; smaller sizes mirror across it
+fn apply_ram_overrides(header: &mut Header, ram: &RamOverrides) {
+    if let Some(size) = ram.sram.or(ram.cart_ram) {
+        if size > 0x2000 {
+            warn!(
+                target: "cartridge",
+                "ignoring {}KB PRG RAM override, only 8KB fits at $6000-$7FFF",
+                size / 1024
+            );
+        } else {
+            header.prg_ram_size = size;
+            header.battery_save |= ram.sram.is_some();
+        }
+    }
+    info!(
+        target: "cartridge",
+        "PRG RAM: {}KB at $6000-$7FFF{}",
+        header.prg_ram_size / 1024,
+        if header.battery_save { ", battery-backed" } else { "" }
+    );
+}
+
+fn nes_2_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0xF {
+        // exponent-multiplier notation: 2^E * (MM * 2 + 1) bytes
+        (1usize << (lsb >> 2)).saturating_mul((lsb & 0b11) as usize * 2 + 1)
+    } else {
+        (((msb as usize) << 8) | lsb as usize) * unit
+    }
+}
+
+fn nes_2_ram_size(shift_count: u8) -> usize {
+    if shift_count == 0 {
+        0
+    } else {
+        64 << shift_count
+    }
+}
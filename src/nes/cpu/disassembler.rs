@@ -1,19 +1,55 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::prelude::*;
 
 use nes::cartridge::CartridgeBus;
 
+use super::opcodes::{AddressingMode, Opcode, OPCODES};
+
 pub fn disassemble(
     cartridge: Box<dyn CartridgeBus>,
     start: u16,
+    xref: bool,
     out: &mut dyn Write,
 ) -> Result<(), Box<dyn Error>> {
-    use super::opcodes::OPCODES;
+    let banks = cartridge.prg_banks();
+    if banks.is_empty() {
+        let read_memory = |addr: u16| cartridge.read_memory(addr, 0);
+        return if xref {
+            disassemble_xref(&read_memory, start, 0xffff, out)
+        } else {
+            disassemble_range(&read_memory, start, 0xffff, out)
+        };
+    }
+    // each bank on its own, at the address it's usually switched in at; references to other
+    // banks can only be followed by address, so they're approximate
+    for (i, &(bank_start, bank)) in banks.iter().enumerate() {
+        let end = (bank_start as usize + bank.len() - 1).min(0xffff) as u16;
+        writeln!(out, "; bank {} at ${:04X}", i, bank_start)?;
+        let read_memory = |addr: u16| {
+            bank.get(addr.wrapping_sub(bank_start) as usize)
+                .cloned()
+                .unwrap_or(0)
+        };
+        if xref {
+            disassemble_xref(&read_memory, bank_start, end, out)?;
+        } else {
+            disassemble_range(&read_memory, bank_start, end, out)?;
+        }
+    }
+    Ok(())
+}
 
+pub fn disassemble_range(
+    read_memory: &dyn Fn(u16) -> u8,
+    start: u16,
+    end: u16,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
     let mut pc = start;
 
     loop {
-        let opcode_hex = cartridge.read_memory(pc, 0);
+        let opcode_hex = read_memory(pc);
 
         let (ref opcode, ref mode) = OPCODES[usize::from(opcode_hex)];
         write!(out, "{:04X}\t{:02X} ", pc, opcode_hex)?;
@@ -23,7 +59,7 @@ pub fn disassemble(
         let mut operand = 0u16;
         let mut shift = 0;
         for _ in 0..mode.bytes() {
-            let operand_byte = cartridge.read_memory(pc, 0);
+            let operand_byte = read_memory(pc);
             operand += u16::from(operand_byte) << shift;
             shift += 8;
             write!(out, "{:02X} ", operand_byte)?;
@@ -33,9 +69,118 @@ pub fn disassemble(
         }
 
         write!(out, "\t{:?} {}\n", opcode, mode.format_operand(operand, pc))?;
-        if pc >= 0xffff {
+        if pc >= end {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Reference {
+    Call,
+    Jump,
+    Data,
+}
+
+// an instruction's opcode byte, operand, and the address just past it
+fn decode(read_memory: &dyn Fn(u16) -> u8, pc: u16) -> (u8, u16, u16) {
+    let opcode_hex = read_memory(pc);
+    let (_, ref mode) = OPCODES[usize::from(opcode_hex)];
+    let mut operand = 0u16;
+    for i in 0..mode.bytes() {
+        operand |= u16::from(read_memory(pc.wrapping_add(1 + u16::from(i)))) << (8 * i);
+    }
+    (
+        opcode_hex,
+        operand,
+        pc.wrapping_add(1 + u16::from(mode.bytes())),
+    )
+}
+
+// where an instruction sends control or reads from, if it's a fixed address
+fn reference(
+    opcode: &Opcode,
+    mode: &AddressingMode,
+    operand: u16,
+    next: u16,
+) -> Option<(u16, Reference)> {
+    match (opcode, mode) {
+        (Opcode::JSR, _) => Some((operand, Reference::Call)),
+        (Opcode::JMP, AddressingMode::Absolute) => Some((operand, Reference::Jump)),
+        (_, AddressingMode::Relative) => Some((
+            next.wrapping_add(operand as u8 as i8 as u16),
+            Reference::Jump,
+        )),
+        (_, AddressingMode::Absolute)
+        | (_, AddressingMode::AbsoluteIndexedX)
+        | (_, AddressingMode::AbsoluteIndexedY)
+        | (_, AddressingMode::Indirect) => Some((operand, Reference::Data)),
+        _ => None,
+    }
+}
+
+// a first pass collects the jump, branch, subroutine, and data targets inside the range, and
+// the second labels them as the linear disassembly reaches them
+pub fn disassemble_xref(
+    read_memory: &dyn Fn(u16) -> u8,
+    start: u16,
+    end: u16,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut references: BTreeMap<u16, Vec<(u16, Reference)>> = BTreeMap::new();
+    let mut pc = start;
+    loop {
+        let (opcode_hex, operand, next) = decode(read_memory, pc);
+        let (ref opcode, ref mode) = OPCODES[usize::from(opcode_hex)];
+        if let Some((target, kind)) = reference(opcode, mode, operand, next) {
+            if target >= start && target <= end {
+                references.entry(target).or_default().push((pc, kind));
+            }
+        }
+        if next > end || next <= pc {
+            break;
+        }
+        pc = next;
+    }
+
+    let mut pc = start;
+    loop {
+        if let Some(froms) = references.get(&pc) {
+            writeln!(out)?;
+            for &(kind, what) in &[
+                (Reference::Call, "called from"),
+                (Reference::Jump, "jumped to from"),
+                (Reference::Data, "read from"),
+            ] {
+                let from = froms
+                    .iter()
+                    .filter(|&&(_, from_kind)| from_kind == kind)
+                    .map(|(from, _)| format!("${:04X}", from))
+                    .collect::<Vec<_>>();
+                if !from.is_empty() {
+                    writeln!(out, "; {} {}", what, from.join(", "))?;
+                }
+            }
+            writeln!(out, "L{:04X}:", pc)?;
+        }
+        let (opcode_hex, operand, next) = decode(read_memory, pc);
+        let (ref opcode, ref mode) = OPCODES[usize::from(opcode_hex)];
+        write!(out, "{:04X}\t{:02X} ", pc, opcode_hex)?;
+        for i in 0..mode.bytes() {
+            write!(out, "{:02X} ", (operand >> (8 * i)) & 0xFF)?;
+        }
+        let operand = match reference(opcode, mode, operand, next) {
+            Some((target, kind)) if kind != Reference::Data && references.contains_key(&target) => {
+                format!("L{:04X}", target)
+            }
+            _ => mode.format_operand(operand, next),
+        };
+        writeln!(out, "\t{:?} {}", opcode, operand)?;
+        if next > end || next <= pc {
             break;
         }
+        pc = next;
     }
     Ok(())
 }
@@ -1,31 +1,71 @@
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
 use std::io::prelude::*;
 use std::io::Result;
+use std::mem;
 use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use bincode::{deserialize_from, serialize};
 use bytes::*;
 use gfx_device_gl::Device;
+use image::{RgbImage, RgbaImage};
 use piston_window::{Context, G2d, G2dTextureContext};
+use simple_error::{SimpleError, SimpleResult};
 
+use audio::AudioSink;
+use audio::filter::FilterChain;
+use audio::pull::SampleBuffer;
+use coverage::{Coverage, CODE, DATA};
 use input::ControllerState;
 use nes::apu::*;
 use nes::apu::bus::*;
 use nes::cartridge::CartridgeBus;
 use nes::ppu::*;
 use nes::ppu::bus::*;
+use nes::Region;
+use profile::Profile;
+use trace::Trace;
 use window;
 use window::Cpu as wcpu;
 
 use self::opcodes::AddressingMode;
 use self::opcodes::AddressingMode::*;
-use self::opcodes::Opcode;
+pub use self::opcodes::Opcode;
 
 pub mod disassembler;
 mod opcodes;
 
-const CPU_TICKS_PER_SECOND: f64 = 1_789_773.0;
+const CPU_TICKS_PER_FRAME: u64 = 29_781;
+const JAM_DETECT_FRAMES: u64 = 120;
+const JAM_HISTORY_LEN: usize = 8;
+// watched writes kept for the host; older ones are dropped past this many
+const MAX_MEMORY_WATCH_HITS: usize = 4096;
+
+// what step_instruction ran
+#[derive(Debug)]
+pub struct StepInfo {
+    pub pc: u16,
+    pub opcode: Opcode,
+    // including any OAM DMA that finished first, and the interrupt taken after it
+    pub cycles: u64,
+    // whether the instruction's address is watched or a breakpoint
+    pub pc_watched: bool,
+    // its writes to watched addresses, in order
+    pub memory_watches: Vec<MemoryWatchHit>,
+}
+
+// a write to a watched address, reported at the address watch_address gives for it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MemoryWatchHit {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+    // the instruction that made the write
+    pub pc: u16,
+}
 
 pub struct Cpu<'a> {
     a: u8,
@@ -53,11 +93,31 @@ pub struct Cpu<'a> {
     cycle_count: u64,
 
     pub speed_adj: f64,
+    region: Region,
 
     memory_watches: Box<HashSet<u16>>,
+    // recorded whenever a watched address is written, until taken; past MAX_MEMORY_WATCH_HITS
+    // the oldest are dropped
+    memory_watch_hits: VecDeque<MemoryWatchHit>,
+    opcode_pc: u16,
     pc_watches: Box<HashSet<u16>>,
     pc_breaks: Box<HashSet<u16>>,
     pc_ignores: Box<Vec<Range<u16>>>,
+    pc_trigger: Option<u16>,
+    // the breakpoint a frame stopped at, until the caller takes it
+    hit_breakpoint: Option<u16>,
+    // the breakpoint just stopped at, which lets its instruction run when the frame resumes
+    resume_pc: Option<u16>,
+    pc_triggered: bool,
+    pause_on_frame_end: bool,
+
+    jammed: bool,
+    loop_start_cycle: u64,
+    pc_history: Box<VecDeque<u16>>,
+
+    coverage: Option<Coverage>,
+    profile: Option<Profile<Opcode>>,
+    trace: Option<Trace>,
 }
 
 const CARRY: u8 = 0b1;
@@ -97,14 +157,28 @@ impl<'a> Cpu<'a> {
             instrumented,
             pc_watches: Box::new(HashSet::new()),
             memory_watches: Box::new(HashSet::new()),
+            memory_watch_hits: VecDeque::new(),
+            opcode_pc: 0,
             pc_breaks: Box::new(HashSet::new()),
             pc_ignores: Box::new(Vec::new()),
+            pc_trigger: None,
+            hit_breakpoint: None,
+            resume_pc: None,
+            pc_triggered: false,
+            pause_on_frame_end: false,
             delayed_irq_flag: None,
             irq: false,
             prev_irq: false,
             dmc_delay: 0,
             cycle_count: 0,
             speed_adj: 1.0,
+            region: Region::Ntsc,
+            jammed: false,
+            loop_start_cycle: 0,
+            pc_history: Box::new(VecDeque::with_capacity(JAM_HISTORY_LEN)),
+            coverage: None,
+            profile: None,
+            trace: None,
         };
 
         cpu.reset(false);
@@ -120,11 +194,17 @@ impl<'a> Cpu<'a> {
         for _ in 0..3 {
             self.ppu.tick();
         }
+        // PAL runs 16 dots every 5 cycles
+        if self.region == Region::Pal && self.cycle_count % 5 == 0 {
+            self.ppu.tick();
+        }
+        self.cartridge.tick();
         self.apu.tick(self.cartridge);
         self.ppu_bus.borrow_mut().tick();
         let mut apu_bus = self.apu_bus.borrow_mut();
         if apu_bus.dmc_delay {
             apu_bus.dmc_delay = false;
+            // a fetch during OAM DMA reuses its alignment cycle, except on the last two puts
             self.dmc_delay = match self.oam_dma_write {
                 Some((_, 255)) => 3,
                 Some((_, 254)) => 1,
@@ -137,13 +217,13 @@ impl<'a> Cpu<'a> {
             }
         }
         if self.oam_dma_write.is_none() && self.dmc_delay == 0 {
-            let irq_interrupt = apu_bus.irq_interrupt()
+            let irq_interrupt = (apu_bus.irq_interrupt() || self.cartridge.irq_interrupt())
                 && !match self.delayed_irq_flag {
                 Some(val) => val,
                 None => self.flag(INTERRUPT),
             };
             let ppu_bus = self.ppu_bus.borrow();
-            let nmi_interrupt = ppu_bus.nmi_interrupt && ppu_bus.nmi_interrupt_age > 1;
+            let nmi_interrupt = ppu_bus.nmi_interrupt && !ppu_bus.nmi_suppressible();
             self.prev_irq = self.irq || nmi_interrupt;
             self.irq = irq_interrupt;
         }
@@ -164,7 +244,7 @@ impl<'a> Cpu<'a> {
 
     fn read_word(&mut self, address: u16) -> u16 {
         let lo_byte = u16::from(self.read_memory(address));
-        let hi_byte = u16::from(self.read_memory(address + 1));
+        let hi_byte = u16::from(self.read_memory(address.wrapping_add(1)));
         (hi_byte << 8) + lo_byte
     }
 
@@ -219,9 +299,12 @@ impl<'a> Cpu<'a> {
                 value | (self.open_bus & 0xF0)
             }
             0x4018..=0x401F => self.open_bus,
-            _ => self.cartridge.read_memory(address, self.open_bus),
+            _ => {
+                self.log_coverage(address, DATA);
+                self.cartridge.read_memory(address, self.open_bus)
+            }
         };
-        if self.instrumented && self.memory_watches.contains(&address) {
+        if self.instrumented && self.memory_watches.contains(&watch_address(address)) {
             warn!(target: "cpu", "read memory {:04X} {:02X} {} {}", address, value,
                   self.ppu.instrumentation_short(), self.apu.instrumentation_short());
         }
@@ -240,10 +323,22 @@ impl<'a> Cpu<'a> {
     }
 
     fn write_memory_no_tick(&mut self, address: u16, value: u8) {
-        if self.instrumented && self.memory_watches.contains(&address) {
+        self.loop_start_cycle = self.cycle_count;
+        let canonical = watch_address(address);
+        if self.memory_watches.contains(&canonical) {
+            let old = self.peek_memory(canonical);
+            self.watch_hit(canonical, old, value);
+        }
+        if self.instrumented && self.memory_watches.contains(&canonical) {
             warn!(target: "cpu", "write memory {:04X} {:02X} {} {}", address, value,
                   self.ppu.instrumentation_short(), self.apu.instrumentation_short());
         }
+        let awaited = self
+            .trace
+            .as_ref()
+            .and_then(|trace| trace.awaited_change())
+            .filter(|&watch| watch_address(watch as u16) == canonical)
+            .map(|_| self.peek_memory(canonical));
         match address {
             0x0000..=0x1FFF => self.internal_ram[(address % 0x800) as usize] = value,
             0x2000..=0x3FFF => self.ppu_bus.borrow_mut().write(address, value),
@@ -261,6 +356,23 @@ impl<'a> Cpu<'a> {
                 .cartridge
                 .write_memory(address, value, self.cycle_count),
         }
+        if let Some(before) = awaited {
+            if self.peek_memory(canonical) != before {
+                self.trace.as_mut().unwrap().begin();
+            }
+        }
+    }
+
+    fn watch_hit(&mut self, addr: u16, old: u8, new: u8) {
+        if self.memory_watch_hits.len() == MAX_MEMORY_WATCH_HITS {
+            self.memory_watch_hits.pop_front();
+        }
+        self.memory_watch_hits.push_back(MemoryWatchHit {
+            addr,
+            old,
+            new,
+            pc: self.opcode_pc,
+        });
     }
 
     fn read_memory_mode(
@@ -473,8 +585,13 @@ impl<'a> Cpu<'a> {
         use self::Opcode::*;
 
         let opcode_pc = self.pc;
+        self.opcode_pc = opcode_pc;
+        let start_cycle = self.cycle_count;
+        self.check_for_loop(opcode_pc);
+        // keep instruction fetches from being logged as data reads
+        let coverage = self.coverage.take();
         let opcode_hex = self.read_memory(opcode_pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
 
         let (ref opcode, ref mode) = OPCODES[usize::from(opcode_hex)];
         let operand_pc = self.pc;
@@ -487,63 +604,55 @@ impl<'a> Cpu<'a> {
             2 => self.read_word(operand_pc),
             _ => panic!("too many bytes"),
         };
-        self.pc += u16::from(mode.bytes());
-
-        if self.instrumented {
-            let pc = self.pc - u16::from(mode.bytes()) - 1;
-            if self.pc_breaks.contains(&pc) {
-                panic!("{:04X}\t{:02X} {}\t{:?} {}\t\tA:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} ppu:{} apu:{} cyc:{}",
-                       pc,
-                       opcode_hex,
-                       match mode.bytes() {
-                           1 => format!("{:02X}", operand),
-                           2 => format!("{:02X} {:02X}", operand & 0xff, operand >> 8),
-                           _ => String::from(""),
-                       },
-                       opcode,
-                       mode.format_operand(operand, self.pc),
-                       self.a, self.x, self.y, self.p, self.sp,
-                       self.ppu.instrumentation_short(),
-                       self.apu.instrumentation_short(),
-                       self.cycle_count);
-            } else if self.pc_watches.contains(&pc) {
-                warn!(target: "cpu", "{:04X}\t{:02X} {}\t{:?} {}\t\tA:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} ppu:{} apu:{} cyc:{}",
-                      pc,
-                      opcode_hex,
-                      match mode.bytes() {
-                          1 => format!("{:02X}", operand),
-                          2 => format!("{:02X} {:02X}", operand & 0xff, operand >> 8),
-                          _ => String::from(""),
-                      },
-                      opcode,
-                      mode.format_operand(operand, self.pc),
-                      self.a, self.x, self.y, self.p, self.sp,
-                      self.ppu.instrumentation_short(),
-                      self.apu.instrumentation_short(),
-                      self.cycle_count);
-            } else {
-                let mut ignore = false;
-                for ignore_range in self.pc_ignores.iter() {
-                    if ignore_range.start <= pc && ignore_range.end >= pc {
-                        ignore = true;
-                        break;
-                    }
-                }
-                if !ignore {
-                    debug!(target: "cpu", "{:04X}\t{:02X} {}\t{:?} {}\t\tA:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} ppu:{} apu:{} cyc:{}",
-                           pc,
-                           opcode_hex,
-                           match mode.bytes() {
-                               1 => format!("{:02X}", operand),
-                               2 => format!("{:02X} {:02X}", operand & 0xff, operand >> 8),
-                               _ => String::from(""),
-                           },
-                           opcode,
-                           mode.format_operand(operand, self.pc),
-                           self.a, self.x, self.y, self.p, self.sp,
-                           self.ppu.instrumentation_short(),
-                           self.apu.instrumentation_short(),
-                           self.cycle_count);
+        self.pc = self.pc.wrapping_add(u16::from(mode.bytes()));
+        if let Some(ref mut profile) = self.profile {
+            profile.begin(opcode, start_cycle);
+        }
+        self.coverage = coverage;
+        // an instruction at the top of memory carries on from $0000
+        for offset in 0..=u16::from(mode.bytes()) {
+            self.log_coverage(opcode_pc.wrapping_add(offset), CODE);
+        }
+
+        if self.pc_trigger == Some(opcode_pc) {
+            self.pc_trigger = None;
+            self.pc_triggered = true;
+        }
+
+        let tracing = match self.trace {
+            Some(ref mut trace) => trace.instruction(u32::from(opcode_pc)),
+            None => false,
+        };
+        if self.instrumented || tracing {
+            let pc = opcode_pc;
+            let line = format!("{:04X}\t{:02X} {}\t{:?} {}\t\tA:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} ppu:{} apu:{} cyc:{}",
+                               pc,
+                               opcode_hex,
+                               match mode.bytes() {
+                                   1 => format!("{:02X}", operand),
+                                   2 => format!("{:02X} {:02X}", operand & 0xff, operand >> 8),
+                                   _ => String::from(""),
+                               },
+                               opcode,
+                               mode.format_operand(operand, self.pc),
+                               self.a, self.x, self.y, self.p, self.sp,
+                               self.ppu.instrumentation_short(),
+                               self.apu.instrumentation_short(),
+                               self.cycle_count);
+            if tracing {
+                self.trace.as_mut().unwrap().line(&line);
+            }
+            if self.instrumented {
+                if self.pc_breaks.contains(&pc) {
+                    error!(target: "cpu", "{}", line);
+                } else if self.pc_watches.contains(&pc) {
+                    warn!(target: "cpu", "{}", line);
+                } else if !self
+                    .pc_ignores
+                    .iter()
+                    .any(|range| range.start <= pc && range.end >= pc)
+                {
+                    debug!(target: "cpu", "{}", line);
                 }
             }
         }
@@ -1107,11 +1216,63 @@ impl<'a> Cpu<'a> {
             }
 
             XXX => {
-                unimplemented!("{:02X}", opcode_hex)
+                self.pc = opcode_pc;
+                if !self.jammed {
+                    self.jammed = true;
+                    self.report_jam(&format!("jam opcode {:02X}", opcode_hex));
+                }
             }
         }
     }
 
+    fn check_for_loop(&mut self, pc: u16) {
+        if self.pc_history.back() != Some(&pc) {
+            if self.pc_history.len() == JAM_HISTORY_LEN {
+                self.pc_history.pop_front();
+            }
+            self.pc_history.push_back(pc);
+            self.loop_start_cycle = self.cycle_count;
+        } else if !self.jammed
+            && self.cycle_count.wrapping_sub(self.loop_start_cycle)
+            > JAM_DETECT_FRAMES * CPU_TICKS_PER_FRAME
+        {
+            self.jammed = true;
+            self.report_jam(&format!(
+                "no progress for {} frames",
+                JAM_DETECT_FRAMES
+            ));
+        }
+    }
+
+    fn peek_memory(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.internal_ram[(address % 0x800) as usize],
+            0x2000..=0x401F => self.open_bus,
+            _ => self.cartridge.read_memory(address, self.open_bus),
+        }
+    }
+
+    fn report_jam(&self, reason: &str) {
+        let mut trace: Vec<u8> = Vec::new();
+        for &pc in self.pc_history.iter() {
+            disassembler::disassemble_range(
+                &|addr| self.peek_memory(addr),
+                pc,
+                pc.saturating_add(1),
+                &mut trace,
+            )
+                .unwrap();
+        }
+        error!(target: "cpu", "CPU jammed at {:04X} ({})\tA:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} ppu:{} apu:{} cyc:{}\n{}",
+               self.pc,
+               reason,
+               self.a, self.x, self.y, self.p, self.sp,
+               self.ppu.instrumentation_short(),
+               self.apu.instrumentation_short(),
+               self.cycle_count,
+               String::from_utf8_lossy(&trace));
+    }
+
     fn irq(&mut self) {
         let old_pc = self.pc;
         let p = self.p | 0b00100000;
@@ -1131,23 +1292,138 @@ impl<'a> Cpu<'a> {
         self.set_flag(INTERRUPT, true);
     }
 
+    // runs until the ticks added by do_frame are spent, stopping early if the CPU jams
+    // stops before an instruction at a breakpoint, leaving the rest of the ticks for when the
+    // frame resumes
+    fn run_ticks(&mut self, inputs: &[ControllerState<8>; 2]) -> bool {
+        while self.ticks > 0.0 {
+            if self.oam_dma_write.is_none()
+                && self.pc_breaks.contains(&self.pc)
+                && self.resume_pc != Some(self.pc)
+            {
+                self.hit_breakpoint = Some(self.pc);
+                self.resume_pc = Some(self.pc);
+                return true;
+            }
+            self.resume_pc = None;
+            self.next_operation(inputs);
+            if self.jammed {
+                self.ticks = 0.0;
+                return true;
+            }
+        }
+
+        self.pause_on_frame_end
+    }
+
     pub fn next_operation(&mut self, inputs: &[ControllerState<8>; 2]) {
         if self.controller_strobe {
             self.last_inputs = [inputs[0].to_u8(), inputs[1].to_u8()];
         }
         if let Some((addr, i)) = self.oam_dma_write {
-            let data = self.read_memory(u16::from(addr) * 0x100 + u16::from(i));
-            self.write_memory(0x2004, data);
-            self.oam_dma_write = if i < 255 { Some((addr, i + 1)) } else { None };
+            self.oam_dma_step(addr, i);
         } else {
-            self.delayed_irq_flag = None;
-            self.execute_opcode();
-            if self.prev_irq {
-                self.irq();
+            self.run_instruction();
+        }
+    }
+
+    fn oam_dma_step(&mut self, addr: u8, i: u8) {
+        let data = self.read_memory(u16::from(addr) * 0x100 + u16::from(i));
+        self.write_memory(0x2004, data);
+        self.oam_dma_write = if i < 255 { Some((addr, i + 1)) } else { None };
+    }
+
+    fn run_instruction(&mut self) {
+        self.delayed_irq_flag = None;
+        self.execute_opcode();
+        if let Some(ref mut profile) = self.profile {
+            profile.end(self.cycle_count);
+        }
+        if self.prev_irq {
+            self.irq();
+        }
+    }
+
+    // runs one instruction for a stepping debugger, with the PPU and APU kept in step as usual;
+    // controllers read what they were last given. The watch hits it makes are returned rather
+    // than queued for take_memory_watch_hits.
+    pub fn step_instruction(&mut self) -> StepInfo {
+        use self::opcodes::OPCODES;
+
+        let start_cycle = self.cycle_count;
+        let queued = mem::take(&mut self.memory_watch_hits);
+        while let Some((addr, i)) = self.oam_dma_write {
+            self.oam_dma_step(addr, i);
+        }
+        let pc = self.pc;
+        let opcode = OPCODES[usize::from(self.peek_memory(pc))].0;
+        self.run_instruction();
+        let hits = mem::replace(&mut self.memory_watch_hits, queued);
+        StepInfo {
+            pc,
+            opcode,
+            cycles: self.cycle_count - start_cycle,
+            pc_watched: self.pc_watches.contains(&pc) || self.pc_breaks.contains(&pc),
+            memory_watches: hits.into_iter().collect(),
+        }
+    }
+
+    fn log_coverage(&mut self, address: u16, flag: u8) {
+        if let Some(ref mut coverage) = self.coverage {
+            if let Some(offset) = self.cartridge.prg_rom_offset(address) {
+                coverage.mark(offset, 1, flag);
             }
         }
     }
 
+    pub fn enable_coverage(&mut self, chr_rom_size: usize) {
+        // CDL files follow the PRG flags with CHR flags, which aren't tracked
+        self.coverage = Some(Coverage::new(self.cartridge.rom_size() + chr_rom_size));
+    }
+
+    pub fn write_coverage(&self, path: &Path) -> SimpleResult<()> {
+        match self.coverage {
+            Some(ref coverage) => coverage.write(path, self.cartridge.rom_size()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    pub fn write_profile(&self, path: &Path) -> SimpleResult<()> {
+        match self.profile {
+            Some(ref profile) => File::create(path)
+                .and_then(|mut file| profile.write("6502", &mut file))
+                .map_err(|io_error| SimpleError::new(io_error.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    // switches the clock rate, frame length, and APU timings; best done straight after boot
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    pub fn enable_music_mode(&mut self, looping: bool) {
+        self.apu.enable_music_mode(looping);
+    }
+
+    pub fn set_audio_filter(&mut self, filter: FilterChain) {
+        self.apu.set_audio_filter(filter);
+    }
+
+    pub fn enable_sample_buffer(&mut self, buffer: Arc<Mutex<SampleBuffer>>) {
+        self.apu.enable_sample_buffer(buffer);
+    }
+
+    pub fn take_audio_sinks(&mut self) -> Vec<Box<dyn AudioSink>> {
+        self.apu.take_sinks()
+    }
+
     pub fn close(&mut self) {
         self.apu.close();
         self.ppu.close();
@@ -1163,6 +1439,18 @@ impl<'a> Cpu<'a> {
         result
     }
 
+    #[cfg(feature = "dev")]
+    pub fn set_entry(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    #[cfg(feature = "dev")]
+    pub fn inject(&mut self, address: u16, data: &[u8]) {
+        for (i, &value) in data.iter().enumerate() {
+            self.write_memory_no_tick(address.wrapping_add(i as u16), value);
+        }
+    }
+
     pub fn setup_for_test(&mut self, p_start: u8, pc_start: u16) {
         self.p = p_start;
         self.pc = pc_start;
@@ -1176,6 +1464,25 @@ impl<'a> Cpu<'a> {
         self.a
     }
 
+    pub fn cycle_count_for_test(&self) -> u64 {
+        self.cycle_count
+    }
+
+    #[cfg(feature = "test")]
+    pub fn oam_for_test(&self) -> &[u8] {
+        self.ppu.oam_for_test()
+    }
+
+    #[cfg(feature = "test")]
+    pub fn scanline_for_test(&self) -> u16 {
+        self.ppu.scanline_for_test()
+    }
+
+    #[cfg(feature = "test")]
+    pub fn framebuffer(&self) -> (Vec<u8>, u32, u32) {
+        self.ppu.framebuffer()
+    }
+
     pub fn set_pc_watch(&mut self, addr: u16) {
         self.pc_watches.insert(addr);
     }
@@ -1188,21 +1495,42 @@ impl<'a> Cpu<'a> {
         self.pc_ignores.push(range);
     }
 
+    // the PC of the breakpoint the last frame stopped at, if it did
+    pub fn take_breakpoint(&mut self) -> Option<u16> {
+        self.hit_breakpoint.take()
+    }
+
     pub fn set_memory_watch(&mut self, addr: u16) {
-        self.memory_watches.insert(addr);
+        self.memory_watches.insert(watch_address(addr));
+    }
+
+    // the writes to watched addresses since the last call, oldest first
+    pub fn take_memory_watch_hits(&mut self) -> Vec<MemoryWatchHit> {
+        self.memory_watch_hits.drain(..).collect()
+    }
+
+    pub fn enable_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
     }
 }
 
 impl window::Cpu for Cpu<'_> {
     fn reset(&mut self, soft: bool) {
+        // a soft reset runs the interrupt sequence with its stack writes suppressed, so only SP
+        // and the I flag change; at power-on A, X, and Y come up clear
         if soft {
-            self.sp -= 3;
+            self.sp = self.sp.wrapping_sub(3);
             self.p |= 0x4;
         } else {
+            self.a = 0;
+            self.x = 0;
+            self.y = 0;
             self.sp = 0xfd;
             self.p = 0x34;
         };
         self.pc = self.read_word_no_tick(0xFFFC);
+        self.jammed = false;
+        self.pc_history.clear();
 
         self.apu_bus.borrow_mut().reset(soft);
         self.write_memory_no_tick(0x2000, 0);
@@ -1217,14 +1545,17 @@ impl window::Cpu for Cpu<'_> {
     }
 
     fn do_frame(&mut self, time_secs: f64, inputs: &[ControllerState<8>; 2], debug: bool) -> bool {
+        self.pause_on_frame_end = false;
         self.instrumented = debug;
-        self.ticks += time_secs * CPU_TICKS_PER_SECOND * self.speed_adj;
-
-        while self.ticks > 0.0 {
-            self.next_operation(inputs);
-        }
+        self.ticks += time_secs * self.region.cpu_hz() * self.speed_adj;
+        self.run_ticks(inputs)
+    }
 
-        false
+    fn do_frame_exact(&mut self, inputs: &[ControllerState<8>; 2], debug: bool) -> bool {
+        self.pause_on_frame_end = false;
+        self.instrumented = debug;
+        self.ticks += self.region.cpu_ticks_per_frame();
+        self.run_ticks(inputs)
     }
 
     fn render(
@@ -1291,6 +1622,10 @@ impl window::Cpu for Cpu<'_> {
         self.apu_bus.borrow_mut().load_state(state);
     }
 
+    fn jammed(&self) -> bool {
+        self.jammed
+    }
+
     fn increase_speed(&mut self) {
         if self.speed_adj < 2.5 {
             self.speed_adj += 0.25;
@@ -1304,4 +1639,146 @@ impl window::Cpu for Cpu<'_> {
         }
         debug!(target: "ctrl", "speed adj {}", self.speed_adj);
     }
+
+    fn set_speed(&mut self, speed_adj: f64) {
+        self.speed_adj = speed_adj;
+        debug!(target: "ctrl", "speed adj {}", self.speed_adj);
+    }
+
+    fn speed(&self) -> f64 {
+        self.speed_adj
+    }
+
+    fn tilesheet(&self, palette_line: u8) -> Option<RgbaImage> {
+        Some(self.ppu.tilesheet(palette_line))
+    }
+
+    fn frame_image(&self) -> Option<RgbImage> {
+        self.ppu.frame_image()
+    }
+
+    fn set_capture_frames(&mut self, capture: bool) {
+        self.ppu.set_capture_frames(capture);
+    }
+
+    fn audio_stats(&self) -> Option<(usize, usize)> {
+        self.apu.buffer_stats()
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.apu.set_muted(muted);
+    }
+
+    fn set_pc_trigger(&mut self, addr: Option<u32>) {
+        self.pc_trigger = addr.map(|addr| addr as u16);
+        self.pc_triggered = false;
+    }
+
+    fn pc_triggered(&mut self) -> bool {
+        std::mem::take(&mut self.pc_triggered)
+    }
+
+    fn peek(&mut self, addr: u32) -> u8 {
+        self.peek_memory(addr as u16)
+    }
+
+    fn poke(&mut self, addr: u32, val: u8) {
+        self.write_memory_no_tick(addr as u16, val);
+    }
+
+    fn registers(&self) -> String {
+        format!(
+            "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X} cyc:{}",
+            self.a, self.x, self.y, self.p, self.sp, self.pc, self.cycle_count
+        )
+    }
+
+    fn add_breakpoint(&mut self, addr: u32) {
+        self.pc_breaks.insert(addr as u16);
+    }
+
+    fn remove_breakpoint(&mut self, addr: u32) {
+        self.pc_breaks.remove(&(addr as u16));
+    }
+
+    fn disassemble(&mut self, addr: u32, count: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut pc = addr as u16;
+        for _ in 0..count {
+            let mut line: Vec<u8> = Vec::new();
+            disassembler::disassemble_range(&|addr| self.peek_memory(addr), pc, pc, &mut line)
+                .unwrap();
+            lines.push(String::from_utf8_lossy(&line).trim_end().to_string());
+            let (_, ref mode) = opcodes::OPCODES[usize::from(self.peek_memory(pc))];
+            pc = pc.wrapping_add(1 + u16::from(mode.bytes()));
+        }
+        lines
+    }
+
+    fn peek_video(&mut self, space: &str, addr: u32) -> std::result::Result<u8, String> {
+        match (space, addr) {
+            ("vram", 0..=0x3FFF) => Ok(self.ppu.peek_vram(addr as u16)),
+            ("oam", 0..=0xFF) => Ok(self.ppu.peek_oam(addr as u8)),
+            ("vram", _) | ("oam", _) => Err(format!("{} address {:X} out of range", space, addr)),
+            _ => Err(format!("unknown video memory {} (vram or oam)", space)),
+        }
+    }
+
+    fn poke_video(&mut self, space: &str, addr: u32, val: u8) -> std::result::Result<(), String> {
+        if space == "oam" && self.oam_dma_write.is_some() {
+            return Err(String::from("OAM DMA in progress"));
+        }
+        match (space, addr) {
+            ("vram", 0..=0x3FFF) => self.ppu.poke_vram(addr as u16, val),
+            ("oam", 0..=0xFF) => self.ppu.poke_oam(addr as u8, val),
+            ("vram", _) | ("oam", _) => {
+                return Err(format!("{} address {:X} out of range", space, addr))
+            }
+            _ => return Err(format!("unknown video memory {} (vram or oam)", space)),
+        }
+        Ok(())
+    }
+
+    fn state_layout(&self) -> Vec<(&'static str, usize)> {
+        let len = |save: &dyn Fn(&mut Vec<u8>)| {
+            let mut out = Vec::new();
+            save(&mut out);
+            out.len()
+        };
+        vec![
+            ("A", 1),
+            ("X", 1),
+            ("Y", 1),
+            ("P", 1),
+            ("SP", 1),
+            ("PC", 2),
+            ("OAM DMA", serialize(&self.oam_dma_write).unwrap().len()),
+            ("RAM", self.internal_ram.len()),
+            ("controller strobe", 1),
+            ("controller 1", 1),
+            ("controller 2", 1),
+            ("ticks", 8),
+            ("open bus", 1),
+            ("delayed IRQ flag", serialize(&self.delayed_irq_flag).unwrap().len()),
+            ("IRQ", 1),
+            ("previous IRQ", 1),
+            ("DMC delay", 1),
+            ("cycle count", 8),
+            ("cartridge", len(&|out| self.cartridge.save_state(out))),
+            ("PPU", len(&|out| self.ppu.save_state(out))),
+            ("PPU bus", len(&|out| self.ppu_bus.borrow().save_state(out))),
+            ("APU", len(&|out| self.apu.save_state(out))),
+            ("APU bus", len(&|out| self.apu_bus.borrow().save_state(out))),
+        ]
+    }
+}
+
+// mirrors are watched as one address: internal RAM at $0000-$07FF and the PPU registers at
+// $2000-$2007
+fn watch_address(address: u16) -> u16 {
+    match address {
+        0x0000..=0x1FFF => address % 0x800,
+        0x2000..=0x3FFF => 0x2000 + address % 8,
+        _ => address,
+    }
 }
@@ -35,14 +35,8 @@ impl AddressingMode {
             IndexedIndirect => format!("(${:02X},X) ", operand),
             IndirectIndexed => format!("(${:02X}),Y ", operand),
             Absolute => format!("${:04X} ", operand),
-            Relative => format!("${:04X} ", {
-                let offset = operand as i16;
-                if offset >= 0 {
-                    pc.wrapping_add(offset as u16)
-                } else {
-                    pc.wrapping_sub((-offset) as u16)
-                }
-            }),
+            // the offset is a signed byte
+            Relative => format!("${:04X} ", pc.wrapping_add(operand as u8 as i8 as u16)),
             AbsoluteIndexedX => format!("${:04X},X ", operand),
             AbsoluteIndexedY => format!("${:04X},Y ", operand),
             Indirect => format!("(${:04X})", operand),
@@ -51,7 +45,7 @@ impl AddressingMode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Opcode {
     ADC,
     // add with carry
@@ -1,39 +1,122 @@
 use std::cell::RefCell;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
+use clap::ValueEnum;
 use piston_window::*;
 use portaudio::PortAudio;
 use simple_error::SimpleResult;
 
 use Commands;
+use args::Switch;
+use audio::AudioSink;
+use audio::filter::AudioFilter;
+use audio::output::{PortAudioSink, PullOutput};
+use audio::pull::SampleBuffer;
+use audio::sync::AudioSync;
+use audio::wav::WavSink;
+use input::script::InputScript;
+use menu::NES_CONTROLS;
 use nes::cartridge::Cartridge;
-use window::window_loop;
+use playtime::PlayTime;
+use rom::{write_save, RamOverrides};
+use trace::{Trace, TraceStart};
+use window::debug::DebugWindows;
+use window::renderer::RenderSettings;
+use window::{hash_frames, open_console, open_frame_dumper, window_loop, BootTurbo, Exit};
 
 pub mod apu;
 pub mod cartridge;
 pub mod cpu;
 pub mod ppu;
 
+// the TV standard a game was made for, which sets the clock rates and the length of a frame
+#[derive(Copy, Clone, PartialEq, Debug, ValueEnum, Serialize, Deserialize)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    pub fn cpu_hz(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+        }
+    }
+
+    // NTSC has 341 x 262 dots, less the one skipped on odd frames, at 3 dots a cycle; PAL has
+    // 341 x 312 at 3.2 a cycle
+    pub fn cpu_ticks_per_frame(self) -> f64 {
+        match self {
+            Region::Ntsc => 29_780.5,
+            Region::Pal => 33_247.5,
+        }
+    }
+
+    pub fn frame_hz(self) -> u64 {
+        match self {
+            Region::Ntsc => 60,
+            Region::Pal => 50,
+        }
+    }
+
+    // APU cycles, one every other CPU cycle, per output sample
+    pub fn ticks_per_sample(self) -> f64 {
+        self.cpu_hz() / 2.0 / apu::TARGET_HZ
+    }
+
+    // the rate the APU mixes samples at
+    pub fn mix_hz(self) -> f64 {
+        self.cpu_hz() / 2.0
+    }
+}
+
 pub fn load_cartridge(
     src: &mut dyn Read,
     save_data: Option<&mut dyn Read>,
+    ram: &RamOverrides,
 ) -> SimpleResult<Cartridge> {
-    cartridge::read(src, save_data)
+    cartridge::read(src, save_data, ram)
 }
 
 pub fn disassemble(
     cartridge: Cartridge,
+    xref: bool,
     mut out: &mut Box<dyn Write>,
 ) -> Result<(), Box<dyn Error>> {
-    cpu::disassembler::disassemble(cartridge.cpu_bus, 0x8000, &mut out)
+    cpu::disassembler::disassemble(cartridge.cpu_bus, 0x8000, xref, &mut out)
+}
+
+// hashes of each of the first `frames` frames, run with no window, sound, or input
+pub fn run_headless(mut cartridge: Cartridge, frames: u64) -> Vec<u64> {
+    let region = cartridge.region;
+    let ppu_bus = RefCell::new(ppu::bus::PpuBus::new());
+    let apu_bus = RefCell::new(apu::bus::ApuBus::new());
+    let ppu = ppu::Ppu::new::<NoWindow>(
+        &mut cartridge.ppu_bus,
+        &ppu_bus,
+        None,
+        RenderSettings::default(),
+        false,
+    );
+    let apu = apu::Apu::new(&apu_bus, Vec::new());
+    let mut cpu = cpu::Cpu::boot(&mut cartridge.cpu_bus, ppu, &ppu_bus, apu, &apu_bus, false);
+    cpu.set_region(region);
+    hash_frames(
+        &mut cpu,
+        &[::input::player_1_nes(), ::input::player_2_nes()],
+        frames,
+    )
 }
 
 pub fn run(
     command: Commands,
     mut cartridge: Cartridge,
+    rom_path: &Path,
     save_path: PathBuf,
     mut window: PistonWindow<sdl2_window::Sdl2Window>,
 ) {
@@ -42,57 +125,271 @@ pub fn run(
         instrument_ppu,
         bench_mode,
         pause,
+        pause_on_unfocus,
+        exit_on_jam,
+        fast_forward_speed,
+        boot_turbo_secs,
+        boot_turbo_until,
+        input_polls,
+        lockstep,
+        stdin_input,
+        exit_on_eof,
+        quit_key,
+        swap_controllers,
+        fm2,
+        filter,
+        nearest,
+        coverage,
+        profile,
+        record_audio,
+        music_mode,
+        music_loop,
+        no_sprite_limit,
+        audio_stats,
+        audio_sync,
+        audio_filter,
+        audio_high_pass,
+        audio_low_pass,
+        region,
+        debug_port,
+        cart_ram,
+        sram,
+        dump_frames,
+        dump_frames_max,
+        rewind_secs,
+        watch,
+        trace_from,
+        trace_on_change,
+        trace_count,
+        trace_until,
+        debug_windows,
+        #[cfg(feature = "dev")]
+        entry,
+        #[cfg(feature = "dev")]
+        inject,
         ..
     } = command
     {
+        // the header's, unless overridden; a reload keeps the region it started with
+        let region = region.unwrap_or(cartridge.region);
+        info!(target: "cartridge", "region: {:?}", region);
+
         window.set_size([293, 240]);
-        let mut window = window.ups(60).ups_reset(0).bench_mode(bench_mode);
+        // lockstep runs a frame per update, so PAL games have to update at 50 Hz
+        let mut window = window.ups(region.frame_hz()).ups_reset(0).bench_mode(bench_mode);
+        let mut debug_windows = DebugWindows::open(&window, &debug_windows);
 
         let mut inputs = [::input::player_1_nes(), ::input::player_2_nes()];
-        let record_path = save_path.with_extension("rcd");
-
-        let ppu_bus = RefCell::new(ppu::bus::PpuBus::new());
-        let apu_bus = RefCell::new(apu::bus::ApuBus::new());
-
-        let ppu = ppu::Ppu::new(
-            &mut cartridge.ppu_bus,
-            &ppu_bus,
-            Some(&mut window),
-            instrument_ppu,
-        );
-        let apu = apu::Apu::new(&apu_bus, Some(PortAudio::new().unwrap())).unwrap();
-
-        let mut cpu = cpu::Cpu::boot(
-            &mut cartridge.cpu_bus,
-            ppu,
-            &ppu_bus,
-            apu,
-            &apu_bus,
-            instrument_cpu,
-        );
-
-        window_loop(
-            window,
-            &mut inputs,
-            &record_path,
-            &mut cpu,
-            293.0,
-            240.0,
-            &Path::new("settings_nes.dat"),
-            pause,
-            instrument_cpu,
-        );
-
-        cpu.close();
-        let mut save: Vec<u8> = Vec::new();
-        cpu.save_to_battery(&mut save).unwrap();
-        if save.len() > 0 {
-            File::create(save_path.as_path())
-                .unwrap()
-                .write(save.as_slice())
-                .unwrap();
+        let record_path = save_path.with_extension(if fm2 { "fm2" } else { "rcd" });
+        // keyed by the ROM as it was at startup, so reloading a rebuilt one keeps counting
+        let rom = std::fs::read(rom_path).unwrap_or_default();
+        let mut play_time = PlayTime::load(&save_path.with_extension("playtime"), &rom);
+        let mut console = open_console(debug_port);
+        let mut frame_dumper = open_frame_dumper(dump_frames, dump_frames_max);
+        let mut input_script = if stdin_input {
+            Some(InputScript::new(
+                Box::new(BufReader::new(std::io::stdin())),
+                exit_on_eof,
+            ))
+        } else {
+            None
+        };
+        // only the first boot, not reloads
+        let mut boot_turbo = boot_turbo_until
+            .map(BootTurbo::Pc)
+            .or(boot_turbo_secs.map(BootTurbo::Secs));
+
+        // in pull mode the output takes its samples from a buffer the APU fills, instead of
+        // being one of its sinks
+        let mut sinks: Vec<Box<dyn AudioSink>> = Vec::new();
+        let mut pull_output = None;
+        if audio_sync == AudioSync::Pull {
+            let buffer = Arc::new(Mutex::new(SampleBuffer::new(region.ticks_per_sample())));
+            pull_output = Some((
+                PullOutput::new(
+                    PortAudio::new().unwrap(),
+                    buffer.clone(),
+                    region.ticks_per_sample(),
+                )
+                .unwrap(),
+                buffer,
+            ));
+        } else {
+            sinks.push(Box::new(
+                PortAudioSink::new(
+                    PortAudio::new().unwrap(),
+                    region.ticks_per_sample(),
+                    audio_stats,
+                    audio_sync,
+                )
+                .unwrap(),
+            ));
+        }
+        if let Some(ref path) = record_audio {
+            sinks.push(Box::new(
+                WavSink::create(path, apu::TARGET_HZ as u32, region.ticks_per_sample()).unwrap(),
+            ));
+        }
+
+        // each pass boots the current cartridge; a reload swaps in the one re-read from disk
+        // and keeps the window and audio output
+        loop {
+            // the ROM database can ask for swapped controllers, but can't undo the flag
+            let swap_controllers = swap_controllers || cartridge.swap_controllers;
+
+            let ppu_bus = RefCell::new(ppu::bus::PpuBus::new());
+            let apu_bus = RefCell::new(apu::bus::ApuBus::new());
+
+            let chr_rom_size = cartridge.ppu_bus.rom_size();
+            let mut ppu = ppu::Ppu::new(
+                &mut cartridge.ppu_bus,
+                &ppu_bus,
+                Some(&mut window),
+                RenderSettings {
+                    filter,
+                    smooth: !nearest,
+                },
+                instrument_ppu,
+            );
+            if no_sprite_limit {
+                ppu.disable_sprite_limit();
+            }
+            let apu = apu::Apu::new(&apu_bus, sinks);
+
+            let mut cpu = cpu::Cpu::boot(
+                &mut cartridge.cpu_bus,
+                ppu,
+                &ppu_bus,
+                apu,
+                &apu_bus,
+                instrument_cpu,
+            );
+            cpu.set_region(region);
+            if let Some((_, ref buffer)) = pull_output {
+                cpu.enable_sample_buffer(buffer.clone());
+            }
+            if coverage.is_some() {
+                cpu.enable_coverage(chr_rom_size);
+            }
+            if profile {
+                cpu.enable_profile();
+            }
+            if music_mode {
+                cpu.enable_music_mode(music_loop);
+            }
+            cpu.set_audio_filter(audio_filter.unwrap_or(AudioFilter::Ntsc).chain(
+                region.mix_hz() as f32,
+                &audio_high_pass,
+                audio_low_pass,
+            ));
+            let trace_start = trace_from
+                .map(TraceStart::Pc)
+                .or(trace_on_change.map(TraceStart::Change));
+            if let Some(start) = trace_start {
+                let path = save_path.with_extension("trace");
+                match Trace::create(&path, start, trace_count, trace_until) {
+                    Ok(trace) => cpu.enable_trace(trace),
+                    Err(e) => error!(target: "ctrl", "couldn't create {}: {}", path.display(), e),
+                }
+            }
+            #[cfg(feature = "dev")]
+            {
+                for injection in inject.iter() {
+                    cpu.inject(injection.addr as u16, &std::fs::read(&injection.path).unwrap());
+                }
+                if let Some(pc) = entry {
+                    cpu.set_entry(pc as u16);
+                }
+            }
+
+            let reloaded = loop {
+                let exit = window_loop(
+                    &mut window,
+                    &mut inputs,
+                    &record_path,
+                    &mut cpu,
+                    293.0,
+                    240.0,
+                    &Path::new("settings_nes.dat"),
+                    NES_CONTROLS,
+                    pause,
+                    pause_on_unfocus == Switch::On,
+                    exit_on_jam,
+                    fast_forward_speed,
+                    input_polls,
+                    lockstep,
+                    input_script.as_mut(),
+                    boot_turbo.take(),
+                    quit_key,
+                    swap_controllers,
+                    instrument_cpu,
+                    &mut console,
+                    &mut frame_dumper,
+                    rewind_secs * region.frame_hz() as usize,
+                    if watch { Some(rom_path) } else { None },
+                    &mut debug_windows,
+                    &mut play_time,
+                );
+                match exit {
+                    Exit::Quit => break None,
+                    Exit::Reload { keep_sram } => {
+                        let mut save: Vec<u8> = Vec::new();
+                        if keep_sram {
+                            cpu.save_to_battery(&mut save).unwrap();
+                        }
+                        // a broken build keeps the current core running
+                        match reload_cartridge(rom_path, &save, &RamOverrides { cart_ram, sram }) {
+                            Ok(cartridge) => {
+                                info!(target: "ctrl", "reloaded {}", rom_path.display());
+                                break Some(cartridge);
+                            }
+                            Err(e) => {
+                                error!(target: "ctrl", "couldn't reload {}: {}", rom_path.display(), e)
+                            }
+                        }
+                    }
+                }
+            };
+
+            // saved before anything is torn down, so a failure there can't lose it
+            let mut save: Vec<u8> = Vec::new();
+            cpu.save_to_battery(&mut save).unwrap();
+            write_save(&save_path, &save);
+            sinks = if reloaded.is_some() { cpu.take_audio_sinks() } else { Vec::new() };
+            cpu.close();
+            if let Some(ref path) = coverage {
+                cpu.write_coverage(path).unwrap();
+            }
+            if profile {
+                cpu.write_profile(&save_path.with_extension("profile")).unwrap();
+            }
+            drop(cpu);
+            match reloaded {
+                Some(reloaded) => cartridge = reloaded,
+                None => break,
+            }
+        }
+        if let Some((ref mut output, _)) = pull_output {
+            output.close();
+        }
+        if let Some(ref mut frame_dumper) = frame_dumper {
+            frame_dumper.stop();
         }
     } else {
         panic!()
     }
 }
+
+fn reload_cartridge(
+    rom_path: &Path,
+    save: &[u8],
+    ram: &RamOverrides,
+) -> Result<Cartridge, Box<dyn Error>> {
+    let mut file = File::open(rom_path)?;
+    let mut save_data = save;
+    Ok(load_cartridge(
+        &mut file,
+        if save.is_empty() { None } else { Some(&mut save_data) },
+        ram,
+    )?)
+}
@@ -193,7 +193,7 @@ impl PpuBus {
                     self.status.vertical_blank = false;
                     self.first_write = false;
                     self.status.just_read = true;
-                    if self.nmi_interrupt_age < 2 {
+                    if self.nmi_suppressible() {
                         self.nmi_interrupt = false;
                     }
                     value
@@ -223,7 +223,7 @@ impl PpuBus {
             0 => {
                 let was_gen_nmi = self.ctrl.gen_nmi;
                 self.ctrl = Ctrl::from_u8(value);
-                if !self.ctrl.gen_nmi && self.nmi_interrupt_age < 2 {
+                if !self.ctrl.gen_nmi && self.nmi_suppressible() {
                     self.nmi_interrupt = false;
                 }
                 if self.ctrl.gen_nmi
@@ -256,6 +256,11 @@ impl PpuBus {
         }
     }
 
+    // an NMI raised on the same or the previous PPU dot can still be cancelled
+    pub fn nmi_suppressible(&self) -> bool {
+        self.nmi_interrupt_age < 2
+    }
+
     pub fn tick(&mut self) {
         self.decay_register.tick();
     }
@@ -5,11 +5,12 @@ use std::cell::RefCell;
 use bincode::{deserialize_from, serialize};
 use bytes::*;
 use gfx_device_gl::Device;
-use image::{GenericImage, Rgba};
+use image::{GenericImage, RgbImage, Rgba, RgbaImage};
 use piston_window::*;
 
-use nes::cartridge::CartridgeBus;
-use window::renderer::Renderer;
+use nes::cartridge::{CartridgeBus, PpuFetch};
+use nes::Region;
+use window::renderer::{Renderer, RenderSettings};
 
 use self::bus::*;
 use self::triple_buffer::TripleBuffer;
@@ -49,6 +50,12 @@ pub struct Ppu<'a> {
 
     scanline: u16,
     dot: u16,
+    // 262 on NTSC, 312 on PAL, the last being the pre-render line
+    lines: u16,
+    // PAL's emphasis bits have red and green the other way around
+    swaps_red_green: bool,
+    // PAL never shortens a line on odd frames
+    skips_dot: bool,
 
     vram_addr: u16,
     tmp_vram_addr: u16,
@@ -75,6 +82,11 @@ pub struct Ppu<'a> {
     oam: [Sprite; 8],
     sec_oam: [Sprite; 8],
     sprite_overflow_tick_delay: Option<u8>,
+    // off to also draw the sprites past the eighth on a line; they're kept apart from the eight
+    // the hardware would draw, which are all that sprite 0 hit and overflow see
+    sprite_limit: bool,
+    extra_oam: Vec<Sprite>,
+    extra_sec_oam: Vec<Sprite>,
 
     internal_ram: Box<[u8]>,
     palette_ram: Box<[u8]>,
@@ -84,6 +96,10 @@ pub struct Ppu<'a> {
     bus: &'a RefCell<PpuBus>,
 
     instrumented: bool,
+
+    // only copied out when something reads the finished frames, as it's a copy per frame
+    capture_frames: bool,
+    frame: Box<[usize; 61440]>,
 }
 
 impl<'a> Ppu<'a> {
@@ -91,6 +107,7 @@ impl<'a> Ppu<'a> {
         cartridge: &'b mut Box<dyn CartridgeBus>,
         bus: &'b RefCell<PpuBus>,
         window: Option<&mut PistonWindow<W>>,
+        render_settings: RenderSettings,
         instrumented: bool,
     ) -> Ppu<'b> {
         let (image_buffer, image_buffer_out) =
@@ -116,12 +133,15 @@ impl<'a> Ppu<'a> {
                     scanline += 1;
                 }
             }
-        });
+        }, render_settings);
         Ppu {
             image_buffer,
             renderer,
             scanline: 0,
             dot: 0,
+            lines: 262,
+            swaps_red_green: false,
+            skips_dot: true,
             vram_addr: 0,
             tmp_vram_addr: 0,
             fine_x_scroll: 0,
@@ -141,15 +161,52 @@ impl<'a> Ppu<'a> {
             oam: Default::default(),
             sec_oam: Default::default(),
             sprite_overflow_tick_delay: None,
+            sprite_limit: true,
+            extra_oam: Vec::new(),
+            extra_sec_oam: Vec::new(),
             internal_ram: vec![0; 0x800].into_boxed_slice(),
             palette_ram: vec![0; 0x20].into_boxed_slice(),
             oam_ram: vec![0; 0x100].into_boxed_slice(),
             cartridge,
             bus,
             instrumented,
+            capture_frames: cfg!(feature = "test"),
+            frame: Box::new([0usize; 61440]),
         }
     }
 
+    pub fn disable_sprite_limit(&mut self) {
+        self.sprite_limit = false;
+    }
+
+    pub fn set_capture_frames(&mut self, capture: bool) {
+        self.capture_frames = capture;
+    }
+
+    // PAL adds 50 lines of vertical blank
+    pub fn set_region(&mut self, region: Region) {
+        self.lines = match region {
+            Region::Ntsc => 262,
+            Region::Pal => 312,
+        };
+        self.skips_dot = region == Region::Ntsc;
+        self.swaps_red_green = region == Region::Pal;
+    }
+
+    fn prerender_line(&self) -> u16 {
+        self.lines - 1
+    }
+
+    // 8x16 sprites take their table from the tile number, and empty slots fetch tile $FF
+    fn sprites_high(&self) -> bool {
+        let ctrl = &self.bus.borrow().ctrl;
+        ctrl.sprite_size_large || ctrl.sprite_pattern_table_high
+    }
+
+    fn background_high(&self) -> bool {
+        self.bus.borrow().ctrl.bgd_pattern_table_high
+    }
+
     fn rendering(&self) -> bool {
         let mask = &self.bus.borrow().mask;
         mask.show_bgd || mask.show_sprite
@@ -192,13 +249,10 @@ impl<'a> Ppu<'a> {
     fn process_data_write(&mut self) {
         let mut bus = self.bus.borrow_mut();
         if let Some(data) = bus.data_write.take() {
-            let addr = self.vram_addr;
+            let addr = self.vram_addr & 0x3FFF;
             self.write_memory(addr, data);
-            self.vram_addr += if bus.ctrl.address_increment_vertical {
-                32
-            } else {
-                1
-            };
+            let rendering = bus.mask.show_bgd || bus.mask.show_sprite;
+            self.increment_vram_addr(bus.ctrl.address_increment_vertical, rendering);
         }
         if let Some(mut data) = bus.oam_data_write.take() {
             let addr = bus.oam_addr;
@@ -213,10 +267,10 @@ impl<'a> Ppu<'a> {
     fn read_memory(&self, address: u16, grayscale: bool) -> u8 {
         match address {
             0x0000..=0x1FFF => self.cartridge.read_memory(address, 0),
-            0x2000..=0x2FFF => self.internal_ram[self.cartridge.mirror_nametable(address) as usize],
-            0x3000..=0x3EFF => {
-                self.internal_ram[(self.cartridge.mirror_nametable(address - 0x1000)) as usize]
-            }
+            0x2000..=0x2FFF => self.cartridge.read_nametable(address, &self.internal_ram),
+            0x3000..=0x3EFF => self
+                .cartridge
+                .read_nametable(address - 0x1000, &self.internal_ram),
             0x3F00..=0x3FFF => {
                 let mut palette_address = address;
                 if palette_address & 0x13 == 0x10 {
@@ -230,18 +284,20 @@ impl<'a> Ppu<'a> {
     }
 
     fn read_memory_under_palette(&self, address: u16) -> u8 {
-        self.internal_ram[(self.cartridge.mirror_nametable(address - 0x1000)) as usize]
+        self.cartridge
+            .read_nametable(address - 0x1000, &self.internal_ram)
     }
 
     fn write_memory(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x1FFF => self.cartridge.write_memory(address, value, 0),
             0x2000..=0x2FFF => {
-                self.internal_ram[self.cartridge.mirror_nametable(address) as usize] = value
+                self.cartridge
+                    .write_nametable(address, value, &mut self.internal_ram)
             }
             0x3000..=0x3EFF => {
-                self.internal_ram[(self.cartridge.mirror_nametable(address - 0x1000)) as usize] =
-                    value
+                self.cartridge
+                    .write_nametable(address - 0x1000, value, &mut self.internal_ram)
             }
             0x3F00..=0x3FFF => {
                 let mut palette_address = address;
@@ -266,24 +322,18 @@ impl<'a> Ppu<'a> {
             if bus.nmi_interrupt && bus.nmi_interrupt_age < 255 {
                 bus.nmi_interrupt_age += 1;
             }
-            if self.vram_addr >= 0x3F00 && self.vram_addr < 0x4000 {
-                bus.palette_data = self.read_memory(self.vram_addr, bus.mask.grayscale);
+            let addr = self.vram_addr & 0x3FFF;
+            let rendering = bus.mask.show_bgd || bus.mask.show_sprite;
+            if addr >= 0x3F00 {
+                bus.palette_data = self.read_memory(addr, bus.mask.grayscale);
                 if bus.read_buffer.is_none() {
-                    bus.read_buffer = Some(self.read_memory_under_palette(self.vram_addr));
-                    self.vram_addr += if bus.ctrl.address_increment_vertical {
-                        32
-                    } else {
-                        1
-                    };
+                    bus.read_buffer = Some(self.read_memory_under_palette(addr));
+                    self.increment_vram_addr(bus.ctrl.address_increment_vertical, rendering);
                 }
             } else {
                 if bus.read_buffer.is_none() {
-                    bus.read_buffer = Some(self.read_memory(self.vram_addr, bus.mask.grayscale));
-                    self.vram_addr += if bus.ctrl.address_increment_vertical {
-                        32
-                    } else {
-                        1
-                    };
+                    bus.read_buffer = Some(self.read_memory(addr, bus.mask.grayscale));
+                    self.increment_vram_addr(bus.ctrl.address_increment_vertical, rendering);
                 }
             }
             bus.oam_data = self.oam_ram[bus.oam_addr as usize];
@@ -293,24 +343,27 @@ impl<'a> Ppu<'a> {
         match self.scanline {
             0..=239 => self.tick_render(),
             240 => self.tick_post_render(),
-            241..=260 => self.tick_vblank(),
-            261 => self.tick_prerender(),
+            line if line == self.prerender_line() => self.tick_prerender(),
+            line if line < self.prerender_line() => self.tick_vblank(),
             _ => panic!("Bad scanline {}", self.scanline),
         }
         self.dot += 1;
         if self.dot == 341 || (self.skip_tick && self.dot == 340) {
             self.dot = 0;
             self.scanline += 1;
-            self.scanline %= 262;
+            self.scanline %= self.lines;
             if self.scanline == 0 {
                 self.odd_frame = !self.odd_frame;
             }
         }
-        self.skip_tick =
-            self.scanline == 261 && self.dot == 339 && self.odd_frame && self.rendering();
+        self.skip_tick = self.skips_dot
+            && self.scanline == self.prerender_line()
+            && self.dot == 339
+            && self.odd_frame
+            && self.rendering();
         let mut bus = self.bus.borrow_mut();
         bus.status.just_read = false;
-        bus.addr = self.vram_addr;
+        bus.addr = self.vram_addr & 0x3FFF;
     }
 
     fn reload_shift(&mut self) {
@@ -348,7 +401,10 @@ impl<'a> Ppu<'a> {
                 }
             }
             if show_sprite && (show_sprite_left8 || self.dot >= 10) {
-                for sprite in self.oam.iter() {
+                // the first opaque sprite in OAM order is the only one considered against the
+                // background, so a sprite behind it hides later ones even when it's behind the
+                // background itself
+                for sprite in self.oam.iter().chain(self.extra_oam.iter()) {
                     if sprite.id != 64 && u16::from(sprite.x) <= self.dot - 2 {
                         let mut sprite_x = self.dot - 2 - u16::from(sprite.x);
                         if sprite_x < 8 {
@@ -366,6 +422,7 @@ impl<'a> Ppu<'a> {
                                 sprite_palette |= (sprite.attr & 3) << 2;
                                 obj_palette = u16::from(sprite_palette) + 16;
                                 obj_priority = sprite.attr & 0x20 > 0;
+                                break;
                             }
                         }
                     }
@@ -379,7 +436,16 @@ impl<'a> Ppu<'a> {
                 0x3F00 + if self.rendering() { palette } else { 0 },
                 bus.mask.grayscale,
             );
-            let color_index = (0xc0 * bus.mask.color_emphasis + color * 3) as usize;
+            // the mask is sampled per dot so mid-frame writes only tint what's drawn after them;
+            // each emphasis combination has its own 64-color block in the palette
+            let emphasis = if self.swaps_red_green {
+                bus.mask.color_emphasis & 0b100
+                    | (bus.mask.color_emphasis & 0b001) << 1
+                    | (bus.mask.color_emphasis & 0b010) >> 1
+            } else {
+                bus.mask.color_emphasis
+            };
+            let color_index = usize::from(emphasis) * 0xC0 + usize::from(color) * 3;
             self.image_buffer.input_buffer()[(self.dot - 2 + self.scanline * 256) as usize] =
                 color_index;
         }
@@ -397,32 +463,50 @@ impl<'a> Ppu<'a> {
 
     fn scroll_horizontal(&mut self) {
         if self.rendering() {
-            if (self.vram_addr & 0x001F) == 31 {
-                self.vram_addr ^= 0x041F;
-            } else {
-                self.vram_addr += 1;
-            }
+            self.increment_coarse_x();
         }
     }
 
     fn scroll_vertical(&mut self) {
         if self.rendering() {
-            let fine_y = (self.vram_addr & 0x7000) >> 12;
-            if fine_y < 7 {
-                self.vram_addr += 0x1000;
+            self.increment_y();
+        }
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if (self.vram_addr & 0x001F) == 31 {
+            self.vram_addr ^= 0x041F;
+        } else {
+            self.vram_addr += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        let fine_y = (self.vram_addr & 0x7000) >> 12;
+        if fine_y < 7 {
+            self.vram_addr += 0x1000;
+        } else {
+            self.vram_addr &= !0x7000;
+            let mut coarse_y = (self.vram_addr & 0x3E0) >> 5;
+            if coarse_y == 29 {
+                coarse_y = 0;
+                self.vram_addr ^= 0x800;
+            } else if coarse_y == 31 {
+                coarse_y = 0;
             } else {
-                self.vram_addr &= !0x7000;
-                let mut coarse_y = (self.vram_addr & 0x3E0) >> 5;
-                if coarse_y == 29 {
-                    coarse_y = 0;
-                    self.vram_addr ^= 0x800;
-                } else if coarse_y == 31 {
-                    coarse_y = 0;
-                } else {
-                    coarse_y += 1;
-                }
-                self.vram_addr = (self.vram_addr & (!0x3E0)) | (coarse_y << 5);
+                coarse_y += 1;
             }
+            self.vram_addr = (self.vram_addr & (!0x3E0)) | (coarse_y << 5);
+        }
+    }
+
+    // $2007 accesses while rendering bump both scroll counters instead of adding 1 or 32
+    fn increment_vram_addr(&mut self, vertical: bool, rendering: bool) {
+        if rendering && (self.scanline < 240 || self.scanline == self.prerender_line()) {
+            self.increment_coarse_x();
+            self.increment_y();
+        } else {
+            self.vram_addr = (self.vram_addr + if vertical { 32 } else { 1 }) & 0x7FFF;
         }
     }
 
@@ -446,7 +530,9 @@ impl<'a> Ppu<'a> {
                 self.reload_shift();
             }
             2 => {
-                self.nametable = self.read_memory(self.addr, bus.mask.grayscale);
+                self.nametable = self
+                    .cartridge
+                    .fetch_nametable(self.addr, &self.internal_ram);
             }
             3 => {
                 self.addr = 0x23C0
@@ -455,7 +541,9 @@ impl<'a> Ppu<'a> {
                     | ((self.vram_addr >> 2) & 0x07);
             }
             4 => {
-                self.latch_attrtable = self.read_memory(self.addr, bus.mask.grayscale);
+                self.latch_attrtable = self
+                    .cartridge
+                    .fetch_nametable(self.addr, &self.internal_ram);
                 if ((self.vram_addr >> 5) & 2) > 0 {
                     self.latch_attrtable >>= 4;
                 }
@@ -498,6 +586,7 @@ impl<'a> Ppu<'a> {
             sprite.data_low = 0;
             sprite.data_high = 0;
         }
+        self.extra_sec_oam.clear();
     }
 
     fn eval_sprites(&mut self) {
@@ -537,54 +626,111 @@ impl<'a> Ppu<'a> {
                 }
             }
         }
+        if !self.sprite_limit && sprite_index == 8 {
+            self.eval_extra_sprites();
+        }
+    }
+
+    // every in-range sprite after the eighth, read straight from OAM rather than with the
+    // overflow check's misaligned reads
+    fn eval_extra_sprites(&mut self) {
+        for i in (self.sec_oam[7].id + 1)..64 {
+            let sprite_start = (i * 4) as usize;
+            let sprite_y = u16::from(self.oam_ram[sprite_start]);
+            let height = u16::from(self.spr_height());
+            if sprite_y <= self.scanline && self.scanline - sprite_y < height {
+                self.extra_sec_oam.push(Sprite {
+                    id: i,
+                    y: self.oam_ram[sprite_start],
+                    tile: self.oam_ram[sprite_start + 1],
+                    attr: self.oam_ram[sprite_start + 2],
+                    x: self.oam_ram[sprite_start + 3],
+                    data_low: 0,
+                    data_high: 0,
+                });
+            }
+        }
     }
 
     fn load_sprites(&mut self) {
-        for (i, sprite) in self.sec_oam.iter().enumerate() {
-            let mut sprite = sprite.clone();
-            let mut addr: u16;
-            let bus = self.bus.borrow();
-            if bus.ctrl.sprite_size_large {
-                addr = (u16::from(sprite.tile & 1) * 0x1000) + (u16::from(sprite.tile & (!1)) * 16);
+        for i in 0..self.sec_oam.len() {
+            self.oam[i] = self.load_sprite(&self.sec_oam[i]);
+        }
+        self.extra_oam = self
+            .extra_sec_oam
+            .iter()
+            .map(|sprite| self.load_sprite(sprite))
+            .collect();
+    }
+
+    fn load_sprite(&self, sprite: &Sprite) -> Sprite {
+        let mut sprite = sprite.clone();
+        let mut addr: u16;
+        let bus = self.bus.borrow();
+        if bus.ctrl.sprite_size_large {
+            addr = (u16::from(sprite.tile & 1) * 0x1000) + (u16::from(sprite.tile & (!1)) * 16);
+        } else {
+            addr = if bus.ctrl.sprite_pattern_table_high {
+                0x1000
             } else {
-                addr = if bus.ctrl.sprite_pattern_table_high {
-                    0x1000
-                } else {
-                    0
-                } + u16::from(sprite.tile) * 16;
+                0
+            } + u16::from(sprite.tile) * 16;
+        }
+        if self.scanline >= u16::from(sprite.y) {
+            let mut sprite_y = (self.scanline - u16::from(sprite.y)) % u16::from(self.spr_height());
+            if sprite.attr & 0x80 > 0 {
+                sprite_y ^= u16::from(self.spr_height()) - 1;
             }
-            if self.scanline >= u16::from(sprite.y) {
-                let mut sprite_y =
-                    (self.scanline - u16::from(sprite.y)) % u16::from(self.spr_height());
-                if sprite.attr & 0x80 > 0 {
-                    sprite_y ^= u16::from(self.spr_height()) - 1;
-                }
-                addr += sprite_y + (sprite_y & 8);
+            addr += sprite_y + (sprite_y & 8);
 
-                sprite.data_low = self.read_memory(addr, bus.mask.grayscale);
-                sprite.data_high = self.read_memory(addr + 8, bus.mask.grayscale);
-            }
-            self.oam[i] = sprite;
+            sprite.data_low = self.read_memory(addr, bus.mask.grayscale);
+            sprite.data_high = self.read_memory(addr + 8, bus.mask.grayscale);
         }
+        sprite
     }
 
     fn tick_render(&mut self) {
         match self.dot {
             1 => {
                 self.clear_oam();
-                if self.scanline == 261 {
+                if self.scanline == self.prerender_line() {
                     let mut bus = self.bus.borrow_mut();
                     bus.status.sprite_0_hit = false;
                     bus.status.sprite_overflow = false;
                 }
             }
             65 => {
-                if self.scanline != 261 {
+                if self.scanline != self.prerender_line() {
                     self.eval_sprites();
                 }
             }
+            // A12 rises once a line, at the first fetch from $1000: the sprites' if they use it
+            // and the background doesn't, or else the next line's background; with both tables
+            // the same it isn't clocked
+            260 => {
+                if self.rendering() && self.sprites_high() && !self.background_high() {
+                    self.cartridge.ppu_a12_rise();
+                }
+            }
             321 => {
-                self.load_sprites();
+                // the mapper sees the next line's sprite fetches, then its background tiles
+                let next_line = (self.scanline + 1) % self.lines;
+                if self.rendering() && next_line < 240 {
+                    let large = self.spr_height() == 16;
+                    self.cartridge.ppu_fetch(PpuFetch::Sprites { large });
+                    self.load_sprites();
+                    self.cartridge.ppu_fetch(PpuFetch::Background {
+                        scanline: next_line,
+                    });
+                } else {
+                    self.cartridge.ppu_fetch(PpuFetch::Idle);
+                    self.load_sprites();
+                }
+            }
+            324 => {
+                if self.rendering() && self.background_high() && !self.sprites_high() {
+                    self.cartridge.ppu_a12_rise();
+                }
             }
             _ => (),
         }
@@ -614,15 +760,20 @@ impl<'a> Ppu<'a> {
                 self.adjust_shifts();
             }
             337 | 339 => {
-                self.nametable = self.read_memory(self.addr, self.bus.borrow().mask.grayscale);
+                self.nametable = self
+                    .cartridge
+                    .fetch_nametable(self.addr, &self.internal_ram);
             }
             _ => (),
         }
-        // TODO signal scanline to mapper
     }
 
     fn tick_post_render(&mut self) {
         if self.dot == 0 {
+            if self.capture_frames {
+                self.frame
+                    .copy_from_slice(&self.image_buffer.input_buffer()[..]);
+            }
             self.image_buffer.publish();
         }
     }
@@ -661,6 +812,80 @@ impl<'a> Ppu<'a> {
         self.renderer.render(c, texture_ctx, gl, device, 8.0 / 7.0, 0);
     }
 
+    // both pattern tables side by side, colored with one of the background palettes
+    pub fn tilesheet(&self, palette_line: u8) -> RgbaImage {
+        let mut image = RgbaImage::new(256, 128);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let (x, y) = (x as u16, y as u16);
+            let tile_addr = (x / 128) * 0x1000 + (y / 8) * 0x100 + (x % 128) / 8 * 0x10;
+            let low = self.read_memory(tile_addr + y % 8, false);
+            let high = self.read_memory(tile_addr + y % 8 + 8, false);
+            let palette = (((high >> (7 - x % 8)) & 1) << 1) | ((low >> (7 - x % 8)) & 1);
+            let color = self.read_memory(
+                0x3F00
+                    + if palette > 0 {
+                        u16::from((palette_line & 3) << 2 | palette)
+                    } else {
+                        0
+                    },
+                false,
+            );
+            let color_index = (color as usize & 0x3F) * 3;
+            *pixel = Rgba([
+                NES_RGB[color_index],
+                NES_RGB[color_index + 1],
+                NES_RGB[color_index + 2],
+                0xff,
+            ]);
+        }
+        image
+    }
+
+    pub fn framebuffer(&self) -> (Vec<u8>, u32, u32) {
+        let mut rgb = Vec::with_capacity(self.frame.len() * 3);
+        for color_index in self.frame.iter() {
+            rgb.extend_from_slice(&NES_RGB[*color_index..*color_index + 3]);
+        }
+        (rgb, 256, 240)
+    }
+
+    // the last captured frame, or the picture last drawn when frames aren't being captured
+    pub fn frame_image(&self) -> Option<RgbImage> {
+        if self.capture_frames {
+            let (rgb, width, height) = self.framebuffer();
+            RgbImage::from_raw(width, height, rgb)
+        } else {
+            Some(self.renderer.image())
+        }
+    }
+
+    // debug console access, at PPU bus addresses; pattern tables in CHR ROM stay read-only
+    pub fn peek_vram(&self, addr: u16) -> u8 {
+        self.read_memory(addr % 0x4000, false)
+    }
+
+    pub fn poke_vram(&mut self, addr: u16, val: u8) {
+        self.write_memory(addr % 0x4000, val);
+    }
+
+    pub fn peek_oam(&self, addr: u8) -> u8 {
+        self.oam_ram[addr as usize]
+    }
+
+    pub fn poke_oam(&mut self, addr: u8, val: u8) {
+        self.oam_ram[addr as usize] = val;
+    }
+
+    #[cfg(feature = "test")]
+    pub fn oam_for_test(&self) -> &[u8] {
+        &self.oam_ram
+    }
+
+    #[cfg(feature = "test")]
+    pub fn scanline_for_test(&self) -> u16 {
+        self.scanline
+    }
+
     pub fn close(&mut self) {
         self.image_buffer.publish();
         self.renderer.close();
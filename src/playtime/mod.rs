@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use rom::db::crc32;
+
+// longer gaps between updates are the machine sleeping or the loop stalling, not play
+const MAX_STEP: Duration = Duration::from_secs(1);
+// at most this much play is lost if the emulator doesn't exit cleanly
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+// cumulative wall-clock time spent playing a ROM, kept beside its save as
+// `<CRC32 of the ROM file> <seconds>` lines, so a different ROM saving under the same name
+// keeps its own count
+pub struct PlayTime {
+    path: PathBuf,
+    key: u32,
+    total: Duration,
+    unflushed: Duration,
+    last_update: Option<Instant>,
+}
+
+impl PlayTime {
+    pub fn load(path: &Path, rom: &[u8]) -> PlayTime {
+        let key = crc32(rom);
+        let seconds = read_times(path).get(&key).cloned().unwrap_or(0);
+        PlayTime {
+            path: PathBuf::from(path),
+            key,
+            total: Duration::from_secs(seconds),
+            unflushed: Duration::ZERO,
+            last_update: None,
+        }
+    }
+
+    // called on every update; the time since the last one counts if a frame was emulated, at
+    // wall-clock rate whatever the emulation speed, so fast-forwarding counts and pausing doesn't
+    pub fn update(&mut self, playing: bool) {
+        let now = Instant::now();
+        if let (true, Some(last_update)) = (playing, self.last_update) {
+            self.add((now - last_update).min(MAX_STEP));
+        }
+        self.last_update = Some(now);
+    }
+
+    pub fn add(&mut self, elapsed: Duration) {
+        self.total += elapsed;
+        self.unflushed += elapsed;
+        if self.unflushed >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    // rewrites the file with this ROM's total, keeping the others as they are on disk now
+    pub fn flush(&mut self) {
+        self.unflushed = Duration::ZERO;
+        let mut times = read_times(&self.path);
+        times.insert(self.key, self.total.as_secs());
+        let temp_path = self.path.with_extension("tmp");
+        let result = File::create(&temp_path)
+            .and_then(|mut file| {
+                for (key, seconds) in times.iter() {
+                    writeln!(file, "{:08X} {}", key, seconds)?;
+                }
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp_path, &self.path));
+        if let Err(e) = result {
+            error!(target: "ctrl", "couldn't write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// h:mm:ss
+pub fn format_play_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// a missing file is no play yet; unreadable lines are skipped
+fn read_times(path: &Path) -> BTreeMap<u32, u64> {
+    let mut times = BTreeMap::new();
+    if let Ok(text) = fs::read_to_string(path) {
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(key), Some(seconds)) = (fields.next(), fields.next()) {
+                if let (Ok(key), Ok(seconds)) = (u32::from_str_radix(key, 16), seconds.parse()) {
+                    times.insert(key, seconds);
+                }
+            }
+        }
+    }
+    times
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::prelude::*;
+use std::mem::{discriminant, Discriminant};
+
+struct Entry {
+    mnemonic: String,
+    count: u64,
+    cycles: u64,
+}
+
+// how often each instruction ran and how many cycles it took, keyed by the opcode's variant so
+// operands don't split a mnemonic across rows
+pub struct Profile<T> {
+    entries: HashMap<Discriminant<T>, Entry>,
+    current: Option<(Discriminant<T>, u64)>,
+}
+
+impl<T: Debug> Profile<T> {
+    pub fn new() -> Profile<T> {
+        Profile {
+            entries: HashMap::new(),
+            current: None,
+        }
+    }
+
+    // called once the opcode is decoded, with the cycle count from before its fetch
+    pub fn begin(&mut self, opcode: &T, cycle: u64) {
+        let key = discriminant(opcode);
+        self.entries
+            .entry(key)
+            .or_insert_with(|| Entry {
+                mnemonic: mnemonic(opcode),
+                count: 0,
+                cycles: 0,
+            })
+            .count += 1;
+        self.current = Some((key, cycle));
+    }
+
+    pub fn end(&mut self, cycle: u64) {
+        if let Some((key, start)) = self.current.take() {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                entry.cycles += cycle.wrapping_sub(start);
+            }
+        }
+    }
+
+    // most cycles first
+    pub fn rows(&self) -> Vec<(&str, u64, u64)> {
+        let mut rows: Vec<_> = self
+            .entries
+            .values()
+            .map(|entry| (entry.mnemonic.as_str(), entry.count, entry.cycles))
+            .collect();
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)).then(a.0.cmp(b.0)));
+        rows
+    }
+
+    pub fn write(&self, title: &str, out: &mut dyn Write) -> std::io::Result<()> {
+        let total_cycles: u64 = self.entries.values().map(|entry| entry.cycles).sum();
+        writeln!(out, "{}", title)?;
+        writeln!(
+            out,
+            "{:<10}{:>14}{:>16}{:>8}",
+            "opcode", "count", "cycles", "%"
+        )?;
+        for (mnemonic, count, cycles) in self.rows() {
+            writeln!(
+                out,
+                "{:<10}{:>14}{:>16}{:>8.2}",
+                mnemonic,
+                count,
+                cycles,
+                cycles as f64 * 100.0 / total_cycles.max(1) as f64
+            )?;
+        }
+        writeln!(out)
+    }
+}
+
+impl<T: Debug> Default for Profile<T> {
+    fn default() -> Profile<T> {
+        Profile::new()
+    }
+}
+
+// the variant name, without any operands
+fn mnemonic<T: Debug>(opcode: &T) -> String {
+    let debug = format!("{:?}", opcode);
+    debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
@@ -0,0 +1,67 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAX_RECENT: usize = 10;
+
+// recently opened ROMs, most recent first, as `<seconds since the epoch> <path>` lines; entries
+// whose file has gone are skipped when the list is read and dropped the next time it's written
+pub struct RecentFiles {
+    path: PathBuf,
+    entries: Vec<(u64, PathBuf)>,
+}
+
+impl RecentFiles {
+    pub fn load(path: &Path) -> RecentFiles {
+        let mut entries = Vec::new();
+        if let Ok(text) = fs::read_to_string(path) {
+            for line in text.lines() {
+                let mut fields = line.splitn(2, ' ');
+                if let (Some(time), Some(rom)) = (fields.next(), fields.next()) {
+                    if let Ok(time) = time.parse() {
+                        entries.push((time, PathBuf::from(rom)));
+                    }
+                }
+            }
+        }
+        RecentFiles {
+            path: PathBuf::from(path),
+            entries,
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(u64, &Path)> {
+        self.entries
+            .iter()
+            .filter(|(_, rom)| rom.exists())
+            .map(|(time, rom)| (*time, rom.as_path()))
+            .collect()
+    }
+
+    pub fn last(&self) -> Option<&Path> {
+        self.entries().first().map(|&(_, rom)| rom)
+    }
+
+    // moves the ROM to the top of the list and rewrites the file
+    pub fn add(&mut self, rom: &Path) {
+        let rom = rom.canonicalize().unwrap_or_else(|_| PathBuf::from(rom));
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        self.entries
+            .retain(|(_, other)| *other != rom && other.exists());
+        self.entries.insert(0, (time, rom));
+        self.entries.truncate(MAX_RECENT);
+        let result = File::create(&self.path).and_then(|mut file| {
+            for (time, rom) in self.entries.iter() {
+                writeln!(file, "{} {}", time, rom.display())?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!(target: "ctrl", "couldn't write {}: {}", self.path.display(), e);
+        }
+    }
+}
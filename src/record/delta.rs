@@ -0,0 +1,89 @@
+// A save state stored as what changed since an earlier one, for keeping many states in memory:
+// the new state's length, then pairs of runs, one of bytes left as they were and one of bytes
+// XORed with what they replace. Run lengths are LEB128, so a frame that only touched a few
+// variables takes a few bytes. Bytes past the end of the earlier state count as zero.
+
+// unchanged stretches shorter than this stay inside the changed run, where they cost a byte each,
+// rather than ending it, which costs two length bytes
+const MIN_SKIP: usize = 3;
+
+pub fn encode_delta(prev: &[u8], state: &[u8], out: &mut Vec<u8>) {
+    let byte_at = |i: usize| prev.get(i).cloned().unwrap_or(0);
+    write_length(state.len(), out);
+    let mut i = 0;
+    while i < state.len() {
+        let start = i;
+        while i < state.len() && state[i] == byte_at(i) {
+            i += 1;
+        }
+        if i == state.len() {
+            break;
+        }
+        let skip = i - start;
+        let changed_start = i;
+        let mut unchanged = 0;
+        while i < state.len() && unchanged < MIN_SKIP {
+            if state[i] == byte_at(i) {
+                unchanged += 1;
+            } else {
+                unchanged = 0;
+            }
+            i += 1;
+        }
+        let changed_end = i - unchanged;
+        i = changed_end;
+        write_length(skip, out);
+        write_length(changed_end - changed_start, out);
+        out.extend((changed_start..changed_end).map(|i| state[i] ^ byte_at(i)));
+    }
+}
+
+// rebuilds the state encode_delta was given from the same earlier one
+pub fn apply_delta(prev: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    let mut delta = delta.iter().cloned().peekable();
+    let len = read_length(&mut delta)?;
+    let mut state = prev.to_vec();
+    state.resize(len, 0);
+    let mut i = 0;
+    while delta.peek().is_some() {
+        i += read_length(&mut delta)?;
+        let changed = read_length(&mut delta)?;
+        if i + changed > len {
+            return Err(format!(
+                "change at {} runs past the state's length {}",
+                i, len
+            ));
+        }
+        for byte in state[i..i + changed].iter_mut() {
+            *byte ^= delta.next().ok_or("delta cut short")?;
+        }
+        i += changed;
+    }
+    Ok(state)
+}
+
+fn write_length(mut len: usize, out: &mut Vec<u8>) {
+    while len >= 0x80 {
+        out.push((len & 0x7F) as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+}
+
+// a length whose bits don't all fit in a usize is an error rather than being truncated
+fn read_length(delta: &mut dyn Iterator<Item = u8>) -> Result<usize, String> {
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let byte = delta.next().ok_or("delta cut short")?;
+        let bits = (byte & 0x7F) as usize;
+        len |= bits
+            .checked_shl(shift)
+            .filter(|shifted| shifted >> shift == bits)
+            .ok_or("length too long")?;
+        if byte & 0x80 == 0 {
+            return Ok(len);
+        }
+        shift += 7;
+    }
+}
@@ -0,0 +1,43 @@
+use window::Cpu;
+
+// Compares the saved states of two machines and describes the first difference, if any.
+pub fn diff_states(a: &dyn Cpu, b: &dyn Cpu) -> Option<String> {
+    let mut state_a = Vec::new();
+    let mut state_b = Vec::new();
+    a.save_state(&mut state_a);
+    b.save_state(&mut state_b);
+    diff_state_bytes(&state_a, &state_b, &a.state_layout())
+}
+
+// `layout` names consecutive regions of the state; values of up to 8 bytes are shown in full,
+// larger regions report the offset of the first differing byte.
+pub fn diff_state_bytes(a: &[u8], b: &[u8], layout: &[(&str, usize)]) -> Option<String> {
+    let index = match a.iter().zip(b.iter()).position(|(a, b)| a != b) {
+        Some(index) => index,
+        None if a.len() == b.len() => return None,
+        None => return Some(format!("state length {} vs {}", a.len(), b.len())),
+    };
+    let mut start = 0;
+    for &(name, len) in layout {
+        if index < start + len {
+            return Some(if len <= 8 {
+                let value = |state: &[u8]| {
+                    state[start..start + len]
+                        .iter()
+                        .fold(0u64, |value, &byte| (value << 8) | byte as u64)
+                };
+                format!(
+                    "{}: 0x{:0width$X} vs 0x{:0width$X}",
+                    name,
+                    value(a),
+                    value(b),
+                    width = len * 2
+                )
+            } else {
+                format!("{}[0x{:04X}] differs", name, index - start)
+            });
+        }
+        start += len;
+    }
+    Some(format!("state[0x{:04X}] differs", index))
+}
@@ -0,0 +1,108 @@
+use std::io::prelude::*;
+
+use simple_error::{SimpleError, SimpleResult};
+
+// FCEUX gamepad columns, most significant bit first
+const BUTTON_COLUMNS: &[u8; 8] = b"RLDUTSBA";
+
+pub struct Fm2Writer<W: Write> {
+    out: W,
+    frame: u32,
+    inputs: [u8; 2],
+}
+
+impl<W: Write> Fm2Writer<W> {
+    pub fn new(mut out: W, rom_filename: &str) -> SimpleResult<Fm2Writer<W>> {
+        write!(
+            out,
+            "version 3\nemuVersion 0\nrerecordCount 0\npalFlag 0\nromFilename {}\n\
+             fourscore 0\nmicrophone 0\nport0 1\nport1 1\nport2 0\nFDS 0\nNewPPU 1\n",
+            rom_filename
+        )
+            .map_err(|io_error| SimpleError::new(io_error.to_string()))?;
+        Ok(Fm2Writer {
+            out,
+            frame: 0,
+            inputs: [0, 0],
+        })
+    }
+
+    pub fn input_changed(&mut self, frame: u32, inputs: [u8; 2]) -> SimpleResult<()> {
+        while self.frame < frame {
+            self.write_frame()?;
+        }
+        self.inputs = inputs;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> SimpleResult<()> {
+        self.write_frame()?;
+        self.out
+            .flush()
+            .map_err(|io_error| SimpleError::new(io_error.to_string()))
+    }
+
+    fn write_frame(&mut self) -> SimpleResult<()> {
+        writeln!(
+            self.out,
+            "|0|{}|{}||",
+            format_buttons(self.inputs[0]),
+            format_buttons(self.inputs[1])
+        )
+            .map_err(|io_error| SimpleError::new(io_error.to_string()))?;
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+pub fn read(src: &mut dyn Read) -> SimpleResult<Vec<[u8; 2]>> {
+    let mut contents = String::new();
+    src.read_to_string(&mut contents)
+        .map_err(|io_error| SimpleError::new(io_error.to_string()))?;
+    let mut frames = Vec::new();
+    for line in contents.lines() {
+        if !line.starts_with('|') {
+            if line.starts_with("palFlag") && line.trim_end().ends_with('1') {
+                return Err(SimpleError::new("PAL movies are not supported."));
+            }
+            continue;
+        }
+        let fields: Vec<&str> = line.split('|').collect();
+        if fields.len() < 4 {
+            return Err(SimpleError::new(format!("Malformed input line: {}", line)));
+        }
+        if fields[1].trim().parse::<u8>().unwrap_or(0) != 0 {
+            warn!(target: "ctrl", "ignoring movie command {} on frame {}", fields[1], frames.len());
+        }
+        frames.push([parse_buttons(fields[2])?, parse_buttons(fields[3])?]);
+    }
+    Ok(frames)
+}
+
+fn format_buttons(value: u8) -> String {
+    BUTTON_COLUMNS
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| {
+            if value & (0x80 >> i) > 0 {
+                c as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn parse_buttons(field: &str) -> SimpleResult<u8> {
+    if field.is_empty() {
+        return Ok(0);
+    }
+    if field.len() != BUTTON_COLUMNS.len() {
+        return Err(SimpleError::new(format!("Malformed gamepad field: {}", field)));
+    }
+    Ok(field
+        .bytes()
+        .enumerate()
+        .filter(|&(_, c)| c != b'.' && c != b' ')
+        .fold(0, |value, (i, _)| value | (0x80 >> i)))
+}
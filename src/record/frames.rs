@@ -0,0 +1,64 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+
+use image::RgbImage;
+
+const QUEUED_FRAMES: usize = 8;
+
+// writes each emulated frame to frame_00001.png, frame_00002.png, ... on a background thread,
+// stopping after max_frames so a forgotten run doesn't fill the disk. Only a few frames are
+// queued, so a slow disk slows the emulation instead of using up memory.
+pub struct FrameDumper {
+    sender: Option<SyncSender<(PathBuf, RgbImage)>>,
+    join_handle: Option<thread::JoinHandle<()>>,
+    dir: PathBuf,
+    frames: u32,
+    max_frames: u32,
+}
+
+impl FrameDumper {
+    pub fn new(dir: &Path, max_frames: u32) -> std::io::Result<FrameDumper> {
+        fs::create_dir_all(dir)?;
+        let (sender, receiver) = mpsc::sync_channel::<(PathBuf, RgbImage)>(QUEUED_FRAMES);
+        let join_handle = thread::spawn(move || {
+            for (path, image) in receiver {
+                if let Err(e) = image.save(&path) {
+                    error!(target: "ctrl", "couldn't write frame to {}: {}", path.display(), e);
+                }
+            }
+        });
+        info!(target: "ctrl", "dumping up to {} frames to {}", max_frames, dir.display());
+        Ok(FrameDumper {
+            sender: Some(sender),
+            join_handle: Some(join_handle),
+            dir: PathBuf::from(dir),
+            frames: 0,
+            max_frames,
+        })
+    }
+
+    pub fn frame(&mut self, image: RgbImage) {
+        if self.frames >= self.max_frames {
+            return;
+        }
+        self.frames += 1;
+        let path = self.dir.join(format!("frame_{:05}.png", self.frames));
+        if let Some(ref sender) = self.sender {
+            let _ = sender.send((path, image));
+        }
+        if self.frames == self.max_frames {
+            warn!(target: "ctrl", "stopped dumping frames after {}", self.max_frames);
+        }
+    }
+
+    // waits for the queued frames to be written
+    pub fn stop(&mut self) {
+        self.sender.take();
+        if let Some(join_handle) = self.join_handle.take() {
+            join_handle.join().unwrap();
+        }
+    }
+}
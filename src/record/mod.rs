@@ -1,6 +1,5 @@
 extern crate byteorder;
 
-use std::collections::VecDeque;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
@@ -11,12 +10,36 @@ use std::thread;
 use piston_window::*;
 
 use super::input::ControllerState;
+use super::window::Cpu;
 
 use self::byteorder::{BigEndian, ByteOrder};
 
+pub mod delta;
+pub mod diff;
+pub mod fm2;
+pub mod frames;
+pub mod rewind;
+
+// native movies start with "MOVI" and the length of the save state they start from (a big-endian
+// u32, 0 for power-on), then the state; files without the header are played from wherever
+// playback was started. After it come 8-byte big-endian inputs: the frame (relative to the start)
+// in the top 32 bits, then controller 1 and 2.
+const MOVIE_MAGIC: &[u8; 4] = b"MOVI";
+
+// what the writer thread is sent
+enum Message {
+    // starts the file over with a new movie, made from a state or power-on, with the inputs it
+    // already has
+    Begin {
+        anchor: Option<Vec<u8>>,
+        inputs: Vec<u64>,
+    },
+    Input(u64),
+}
+
 pub struct Recorder<const B: usize> {
     start_frame: u32,
-    sender: Option<Sender<u64>>,
+    sender: Option<Sender<Message>>,
     join_handle: Option<thread::JoinHandle<()>>,
     record_path: PathBuf,
     recording: bool,
@@ -28,21 +51,77 @@ impl<const B: usize> Recorder<B> {
         let (sender, receiver) = mpsc::channel();
         let path = PathBuf::from(path);
         let record_path = path.clone();
-        let join_handle = thread::spawn(move || {
-            let mut file: Option<File> = None;
-            let mut buf = [0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8];
-            loop {
-                match receiver.recv() {
-                    Ok(input) => {
-                        BigEndian::write_u64(&mut buf, input);
-                        file.get_or_insert_with(|| File::create(&path).unwrap())
-                            .write_all(&buf)
-                            .unwrap();
-                    }
-                    Err(_) => break,
+        let join_handle = if is_fm2(&path) {
+            thread::spawn(move || {
+                let mut writer: Option<fm2::Fm2Writer<File>> = None;
+                let write_input = |writer: &mut fm2::Fm2Writer<File>, input: u64| {
+                    writer
+                        .input_changed((input >> 32) as u32, [(input >> 8) as u8, input as u8])
+                        .unwrap();
                 };
-            }
-        });
+                loop {
+                    match receiver.recv() {
+                        Ok(Message::Begin { anchor, inputs }) => {
+                            if let Some(ref mut writer) = writer {
+                                writer.finish().unwrap();
+                            }
+                            if anchor.is_some() {
+                                warn!(
+                                    target: "ctrl",
+                                    "FCEUX movies can't start from a save state; {} will play from where it's started",
+                                    path.display()
+                                );
+                            }
+                            let mut new_writer = fm2::Fm2Writer::new(
+                                File::create(&path).unwrap(),
+                                &path.file_stem().unwrap().to_string_lossy(),
+                            )
+                                .unwrap();
+                            for input in inputs {
+                                write_input(&mut new_writer, input);
+                            }
+                            writer = Some(new_writer);
+                        }
+                        Ok(Message::Input(input)) => {
+                            if let Some(ref mut writer) = writer {
+                                write_input(writer, input);
+                            }
+                        }
+                        Err(_) => break,
+                    };
+                }
+                if let Some(ref mut writer) = writer {
+                    writer.finish().unwrap();
+                }
+            })
+        } else {
+            thread::spawn(move || {
+                let mut file: Option<File> = None;
+                let mut buf = [0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8];
+                loop {
+                    match receiver.recv() {
+                        Ok(Message::Begin { anchor, inputs }) => {
+                            let mut header = Vec::new();
+                            write_movie_header(anchor.as_ref().map(Vec::as_slice), &mut header);
+                            for input in inputs {
+                                BigEndian::write_u64(&mut buf, input);
+                                header.extend_from_slice(&buf);
+                            }
+                            let mut new_file = File::create(&path).unwrap();
+                            new_file.write_all(&header).unwrap();
+                            file = Some(new_file);
+                        }
+                        Ok(Message::Input(input)) => {
+                            if let Some(ref mut file) = file {
+                                BigEndian::write_u64(&mut buf, input);
+                                file.write_all(&buf).unwrap();
+                            }
+                        }
+                        Err(_) => break,
+                    };
+                }
+            })
+        };
         Recorder {
             start_frame: 0,
             sender: Some(sender),
@@ -53,12 +132,39 @@ impl<const B: usize> Recorder<B> {
         }
     }
 
-    pub fn toggle(&mut self, frame: u32) {
+    // starts a new movie, anchored to the current state if `from_state` and otherwise resetting
+    // the console to record from power-on, or stops recording. If a movie is playing, recording
+    // takes over from it instead: it keeps the movie's start and the inputs played so far, and the
+    // rest are replaced by whatever's recorded from here.
+    pub fn toggle(&mut self, cpu: &mut dyn Cpu, frame: u32, from_state: bool) {
         if self.recording {
             self.recording = false;
-        } else {
-            self.recording = true;
-            self.start_frame = frame;
+            return;
+        }
+        let (anchor, inputs) = match self.playback.take() {
+            Some(playback) => {
+                self.start_frame = playback.start_frame;
+                (playback.anchor, playback.entries[..playback.next].to_vec())
+            }
+            None => {
+                self.start_frame = frame;
+                let anchor = if from_state {
+                    let mut state = Vec::new();
+                    cpu.save_state(&mut state);
+                    Some(state)
+                } else {
+                    // FCEUX movies have nowhere to say where they start
+                    if !is_fm2(&self.record_path) {
+                        cpu.reset(false);
+                    }
+                    None
+                };
+                (anchor, Vec::new())
+            }
+        };
+        self.recording = true;
+        if let Some(ref sender) = self.sender {
+            sender.send(Message::Begin { anchor, inputs }).unwrap();
         }
     }
 
@@ -66,25 +172,41 @@ impl<const B: usize> Recorder<B> {
         if self.recording {
             if let Some(ref sender) = self.sender {
                 sender
-                    .send(
+                    .send(Message::Input(
                         (((frame_count - self.start_frame) as u64) << 32)
                             | ((inputs[0].to_u8() as u64) << 8)
                             | (inputs[1].to_u8() as u64),
-                    )
+                    ))
                     .unwrap();
             }
         }
     }
 
+    // a movie's inputs only change between frames
+    pub fn active(&self) -> bool {
+        self.recording || self.playback.is_some()
+    }
+
     pub fn stop(&mut self) {
         drop(self.sender.take().unwrap());
         self.join_handle.take().unwrap().join().unwrap();
     }
 
-    pub fn toggle_playback(&mut self, frame: u32) {
+    // a movie that starts from a save state loads it first, and one from power-on resets
+    pub fn toggle_playback(&mut self, cpu: &mut dyn Cpu, frame: u32) {
         if self.playback.is_none() {
             let mut src = File::open(&self.record_path).unwrap();
-            self.playback = Some(Playback::new(&mut src, frame));
+            let playback = if is_fm2(&self.record_path) {
+                Playback::from_fm2(&mut src, frame)
+            } else {
+                Playback::new(&mut src, frame)
+            };
+            if let Some(ref anchor) = playback.anchor {
+                cpu.load_state(&mut anchor.as_slice());
+            } else if playback.power_on {
+                cpu.reset(false);
+            }
+            self.playback = Some(playback);
         } else {
             self.playback = None;
         }
@@ -120,52 +242,88 @@ impl<const B: usize> Recorder<B> {
     }
 }
 
+fn is_fm2(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "fm2")
+}
+
+pub fn write_movie_header(anchor: Option<&[u8]>, out: &mut Vec<u8>) {
+    let anchor = anchor.unwrap_or(&[]);
+    let mut len = [0u8; 4];
+    BigEndian::write_u32(&mut len, anchor.len() as u32);
+    out.extend_from_slice(MOVIE_MAGIC);
+    out.extend_from_slice(&len);
+    out.extend_from_slice(anchor);
+}
+
+// the state a native movie starts from, if it has one, and its inputs
+pub fn read_movie_header(data: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    if data.len() < 8 || data[..4] != MOVIE_MAGIC[..] {
+        return (None, data);
+    }
+    let end = 8 + BigEndian::read_u32(&data[4..8]) as usize;
+    if data.len() < end {
+        error!(target: "ctrl", "movie's save state is cut short");
+        return (None, &[]);
+    }
+    let anchor = if end > 8 { Some(&data[8..end]) } else { None };
+    (anchor, &data[end..])
+}
+
 struct Playback<const B: usize> {
     start_frame: u32,
-    next_frame: u32,
-    input_data: VecDeque<u8>,
+    anchor: Option<Vec<u8>>,
+    // a header without a state; movies without one play from wherever they're started
+    power_on: bool,
+    entries: Vec<u64>,
+    // how many of the entries have been played
+    next: usize,
 }
 
 impl<const B: usize> Playback<B> {
     fn new(src: &mut dyn Read, start_frame: u32) -> Playback<B> {
-        let mut input_vec = Vec::new();
-        src.read_to_end(&mut input_vec).unwrap();
-        let mut input_data = VecDeque::from(input_vec);
-        let mut next_frame = 0u32;
-        for _ in 0..4 {
-            next_frame <<= 8;
-            next_frame += input_data.pop_front().unwrap() as u32;
+        let mut data = Vec::new();
+        src.read_to_end(&mut data).unwrap();
+        let (anchor, input_data) = read_movie_header(&data);
+        Playback {
+            start_frame,
+            anchor: anchor.map(<[u8]>::to_vec),
+            power_on: anchor.is_none() && data.starts_with(MOVIE_MAGIC),
+            entries: input_data
+                .chunks_exact(8)
+                .map(BigEndian::read_u64)
+                .collect(),
+            next: 0,
+        }
+    }
+
+    fn from_fm2(src: &mut dyn Read, start_frame: u32) -> Playback<B> {
+        let mut entries = Vec::new();
+        let mut prev_inputs = None;
+        for (frame, inputs) in fm2::read(src).unwrap().into_iter().enumerate() {
+            if prev_inputs != Some(inputs) {
+                entries
+                    .push(((frame as u64) << 32) | ((inputs[0] as u64) << 8) | (inputs[1] as u64));
+                prev_inputs = Some(inputs);
+            }
         }
-        next_frame += start_frame;
-        input_data.pop_front();
-        input_data.pop_front();
         Playback {
             start_frame,
-            next_frame,
-            input_data,
+            anchor: None,
+            power_on: false,
+            entries,
+            next: 0,
         }
     }
 
+    // true once the last input has been played
     pub fn set_frame_inputs(&mut self, inputs: &mut [ControllerState<B>; 2], frame: u32) -> bool {
-        if frame == self.next_frame {
-            inputs[0].set_from_u8(self.input_data.pop_front().unwrap());
-            inputs[1].set_from_u8(self.input_data.pop_front().unwrap());
-            if !self.input_data.is_empty() {
-                let mut next_frame = 0u32;
-                for _ in 0..4 {
-                    next_frame <<= 8;
-                    next_frame += self.input_data.pop_front().unwrap() as u32;
-                }
-                self.input_data.pop_front();
-                self.input_data.pop_front();
-                self.next_frame = self.start_frame + next_frame;
-                false
-            } else {
-                self.next_frame = 0;
-                true
+        if let Some(&entry) = self.entries.get(self.next) {
+            if frame == self.start_frame + (entry >> 32) as u32 {
+                inputs[0].set_from_u8((entry >> 8) as u8);
+                inputs[1].set_from_u8(entry as u8);
+                self.next += 1;
             }
-        } else {
-            false
         }
+        self.next == self.entries.len()
     }
 }
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+use record::delta::apply_delta;
+use window::Cpu;
+
+// a full state starts each run of this many frames; the rest are deltas against it
+const KEYFRAME_INTERVAL: usize = 60;
+
+// the last max_frames states, for stepping back through a frame at a time. Oldest states go a
+// keyframe's run at a time, so slightly fewer than max_frames may be kept.
+pub struct Rewind {
+    runs: VecDeque<(Vec<u8>, Vec<Vec<u8>>)>,
+    frames: usize,
+    max_frames: usize,
+}
+
+impl Rewind {
+    pub fn new(max_frames: usize) -> Rewind {
+        Rewind {
+            runs: VecDeque::new(),
+            frames: 0,
+            max_frames,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    pub fn push(&mut self, cpu: &dyn Cpu) {
+        if self.max_frames == 0 {
+            return;
+        }
+        match self.runs.back_mut() {
+            Some((keyframe, deltas)) if deltas.len() + 1 < KEYFRAME_INTERVAL => {
+                let mut delta = Vec::new();
+                cpu.save_state_delta(keyframe, &mut delta);
+                deltas.push(delta);
+            }
+            _ => {
+                let mut state = Vec::new();
+                cpu.save_state(&mut state);
+                self.runs.push_back((state, Vec::new()));
+            }
+        }
+        self.frames += 1;
+        while self.frames > self.max_frames {
+            match self.runs.pop_front() {
+                Some((_, deltas)) => self.frames -= deltas.len() + 1,
+                None => break,
+            }
+        }
+    }
+
+    // loads the most recent state and forgets it; false once there are none left
+    pub fn pop(&mut self, cpu: &mut dyn Cpu) -> bool {
+        let (keyframe, mut deltas) = match self.runs.pop_back() {
+            Some(run) => run,
+            None => return false,
+        };
+        self.frames -= 1;
+        let state = match deltas.pop() {
+            Some(delta) => {
+                let state = apply_delta(&keyframe, &delta);
+                self.runs.push_back((keyframe, deltas));
+                state
+            }
+            None => Ok(keyframe),
+        };
+        match state {
+            Ok(state) => {
+                cpu.load_state(&mut state.as_slice());
+                true
+            }
+            Err(e) => {
+                error!(target: "ctrl", "couldn't rewind: {}", e);
+                self.runs.clear();
+                self.frames = 0;
+                false
+            }
+        }
+    }
+}
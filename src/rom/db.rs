@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+// curated settings for known dumps, applied over the ROM header; command-line flags still win
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+    pub title: String,
+    pub mapper: Option<u16>,
+    pub vertical_mirroring: Option<bool>,
+    pub battery: Option<bool>,
+    pub ram: Option<usize>,
+    pub swap_controllers: bool,
+}
+
+pub struct RomDb {
+    nes: HashMap<u32, Entry>,
+    gen: HashMap<String, Entry>,
+}
+
+impl RomDb {
+    pub fn bundled() -> RomDb {
+        RomDb::parse(include_str!("romdb.txt")).expect("bundled ROM database is malformed")
+    }
+
+    // one entry per line: `nes <CRC32 of PRG+CHR>` or `gen <serial>`, then key=value settings,
+    // then an optional `# title`
+    pub fn parse(text: &str) -> Result<RomDb, String> {
+        let mut db = RomDb {
+            nes: HashMap::new(),
+            gen: HashMap::new(),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let (settings, title) = match line.split_once('#') {
+                Some((settings, title)) => (settings, title.trim()),
+                None => (line, ""),
+            };
+            let mut fields = settings.split_whitespace();
+            let system = match fields.next() {
+                Some(system) => system,
+                None => continue,
+            };
+            let key = fields
+                .next()
+                .ok_or_else(|| format!("line {}: missing ROM key", i + 1))?;
+            let mut entry = Entry {
+                title: String::from(title),
+                ..Default::default()
+            };
+            for field in fields {
+                parse_setting(&mut entry, field).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            }
+            match system {
+                "nes" => {
+                    let crc = u32::from_str_radix(key, 16)
+                        .map_err(|e| format!("line {}: invalid CRC32 {}: {}", i + 1, key, e))?;
+                    db.nes.insert(crc, entry);
+                }
+                "gen" => {
+                    db.gen.insert(String::from(key), entry);
+                }
+                _ => return Err(format!("line {}: unknown system {}", i + 1, system)),
+            }
+        }
+        Ok(db)
+    }
+
+    pub fn nes(&self, prg_chr: &[u8]) -> Option<&Entry> {
+        self.nes.get(&crc32(prg_chr))
+    }
+
+    // serials are compared with their spaces removed, so `GM 00001009-00` is `GM00001009-00`
+    pub fn gen(&self, serial: &str) -> Option<&Entry> {
+        self.gen
+            .get(&serial.chars().filter(|c| !c.is_whitespace()).collect::<String>())
+    }
+}
+
+fn parse_setting(entry: &mut Entry, field: &str) -> Result<(), String> {
+    match field.split_once('=') {
+        Some(("mapper", value)) => {
+            entry.mapper = Some(
+                value
+                    .parse()
+                    .map_err(|e| format!("invalid mapper {}: {}", value, e))?,
+            )
+        }
+        Some(("mirroring", "horizontal")) => entry.vertical_mirroring = Some(false),
+        Some(("mirroring", "vertical")) => entry.vertical_mirroring = Some(true),
+        Some(("battery", "yes")) => entry.battery = Some(true),
+        Some(("battery", "no")) => entry.battery = Some(false),
+        Some(("ram", value)) => {
+            let kb = value
+                .parse::<usize>()
+                .map_err(|e| format!("invalid RAM size {}: {}", value, e))?;
+            entry.ram = Some(kb * 1024);
+        }
+        Some(("controllers", "swapped")) => entry.swap_controllers = true,
+        _ => return Err(format!("unknown setting {}", field)),
+    }
+    Ok(())
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 > 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -1,6 +1,37 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use gen::cartridge::Cartridge as GenCartridge;
 use nes::cartridge::Cartridge;
 
+pub mod db;
+
 pub enum Rom {
     Nes(Cartridge),
-    Genesis(Box<[u8]>),
-}
\ No newline at end of file
+    Genesis(GenCartridge),
+}
+
+// cartridge RAM sizes from the command line, in bytes; these win over the ROM database and header
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RamOverrides {
+    pub cart_ram: Option<usize>,
+    pub sram: Option<usize>,
+}
+
+// writes cartridge RAM to the save file, if there is any, through a temporary file so a failed
+// write leaves the previous save intact; errors are logged, since this runs on the way out
+pub fn write_save(path: &Path, save: &[u8]) {
+    if save.is_empty() {
+        return;
+    }
+    let temp_path = path.with_extension("sav.tmp");
+    let result = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(save).and_then(|()| file.sync_all()))
+        .and_then(|()| fs::rename(&temp_path, path));
+    match result {
+        Ok(()) => info!(target: "cartridge", "saved to {}", path.display()),
+        Err(e) => error!(target: "cartridge", "couldn't write {}: {}", path.display(), e),
+    }
+}
@@ -0,0 +1,372 @@
+use std::cell::RefCell;
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use json::JsonValue;
+use piston_window::NoWindow;
+
+use gen::m68k::opcodes::Opcode;
+use gen::vdp::bus::VdpBus;
+use input::{player_1_gen, player_1_nes, player_2_gen, player_2_nes};
+use nes::apu::bus::ApuBus;
+use nes::apu::Apu;
+use nes::cartridge;
+use nes::ppu::bus::PpuBus;
+use nes::ppu::Ppu;
+use rom::RamOverrides;
+use window::renderer::RenderSettings;
+use window::Cpu as wcpu;
+
+// emulated time a Blargg ROM gets to report a result
+const NES_TIMEOUT_CYCLES: u64 = 1_789_773 * 120;
+// enough for all of zexdoc
+const ZEX_TIMEOUT_CYCLES: u64 = 46_734_978_649;
+
+// runs every test ROM under dir, recognized by extension:
+//   .nes        Blargg-style NES tests, reporting through $6000-
+//   .com, .cim  Z80 CP/M exercisers (zexdoc, zexall, prelim) loaded at 0x100
+//   .json       68000 single-step test cases, timed when they have a `length`
+// any ROM with a .frames file next to it is checked against that instead, and bless rewrites
+// those files with this run's hashes
+// prints a line per ROM and returns whether they all passed
+pub fn run(dir: &Path, bless: bool) -> bool {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files);
+    files.sort();
+    let mut passed = 0;
+    let mut failed = 0;
+    for path in files {
+        let frames_path = path.with_extension("frames");
+        let run_rom: Box<dyn Fn(&[u8]) -> Result<(), String>> =
+            if frames_path.is_file() && frames_path != path {
+                Box::new(move |rom: &[u8]| check_frames(rom, &frames_path, bless))
+            } else {
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("nes") => Box::new(run_nes_blargg),
+                    Some("com") | Some("cim") => Box::new(run_zex),
+                    Some("json") => Box::new(run_m68k_file),
+                    _ => continue,
+                }
+            };
+        let result = match fs::read(&path) {
+            Ok(contents) => catch_failure(|| run_rom(&contents)),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(()) => {
+                passed += 1;
+                println!("PASS {}", path.display());
+            }
+            Err(message) => {
+                failed += 1;
+                println!("FAIL {}", path.display());
+                for line in message.lines() {
+                    println!("     {}", line);
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+    failed == 0
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    collect_files(&path, files);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        Err(e) => error!("couldn't read {}: {}", dir.display(), e),
+    }
+}
+
+// the CPU test hooks report mismatches with assertions, so a panic is a failure
+fn catch_failure(f: impl FnOnce() -> Result<(), String>) -> Result<(), String> {
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        Err(match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => String::from(*message),
+                Err(_) => String::from("panicked"),
+            },
+        })
+    })
+}
+
+// Blargg's convention: $6001-$6003 holds DE B0 61 once $6000 is a status byte, which is 0x80
+// while running, 0x81 when the ROM wants a reset pressed, and otherwise the result code, with
+// 0 meaning success; $6004- holds the zero-terminated text output
+pub fn run_nes_blargg(rom: &[u8]) -> Result<(), String> {
+    let ppu_bus = RefCell::new(PpuBus::new());
+    let apu_bus = RefCell::new(ApuBus::new());
+    let mut cartridge = cartridge::read(&mut &rom[..], None, &RamOverrides::default())
+        .map_err(|e| e.to_string())?;
+    let ppu = Ppu::new::<NoWindow>(
+        &mut cartridge.ppu_bus,
+        &ppu_bus,
+        None,
+        RenderSettings::default(),
+        false,
+    );
+    let apu = Apu::new(&apu_bus, Vec::new());
+    let mut cpu =
+        ::nes::cpu::Cpu::boot(&mut cartridge.cpu_bus, ppu, &ppu_bus, apu, &apu_bus, false);
+    let inputs = [player_1_nes(), player_2_nes()];
+
+    let mut reset_delay = 0;
+    let mut did_reset = false;
+    loop {
+        if cpu.read_memory_no_tick(0x6001) == 0xDE
+            && cpu.read_memory_no_tick(0x6002) == 0xB0
+            && cpu.read_memory_no_tick(0x6003) == 0x61
+        {
+            let status = cpu.read_memory_no_tick(0x6000);
+            if status == 0x80 && did_reset {
+                did_reset = false;
+            } else if status == 0x81 && !did_reset && reset_delay == 0 {
+                did_reset = true;
+                reset_delay = 20_000;
+            } else if status != 0x80 && status != 0x81 {
+                let mut output = String::new();
+                let mut addr = 0x6004;
+                while addr < 0x8000 {
+                    match cpu.read_memory_no_tick(addr) {
+                        0 => break,
+                        c => output.push(c as char),
+                    }
+                    addr += 1;
+                }
+                return if status == 0 {
+                    Ok(())
+                } else {
+                    Err(format!("result {:02X}\n{}", status, output.trim()))
+                };
+            }
+        }
+        if cpu.cycle_count_for_test() > NES_TIMEOUT_CYCLES {
+            return Err(String::from("timed out without reporting a result"));
+        }
+        cpu.next_operation(&inputs);
+        if cpu.jammed() {
+            return Err(format!("CPU jammed at {:04X}", cpu.pc_for_test()));
+        }
+        if reset_delay > 0 {
+            reset_delay -= 1;
+            if reset_delay == 0 {
+                cpu.reset(true);
+            }
+        }
+    }
+}
+
+// a rendering regression test: a .frames file lists frame numbers from 1 in order, one per line,
+// each followed by that frame's --headless hash, as in "600 0123456789ABCDEF"; a frame without a
+// hash fails until the file is blessed
+fn check_frames(rom: &[u8], frames_path: &Path, bless: bool) -> Result<(), String> {
+    let golden = fs::read_to_string(frames_path).map_err(|e| e.to_string())?;
+    let golden = parse_frames(&golden)?;
+    let frames = golden.iter().map(|&(frame, _)| frame).collect::<Vec<_>>();
+    let hashes = frame_hashes(rom, &frames)?;
+    if bless {
+        let lines = frames
+            .iter()
+            .zip(&hashes)
+            .map(|(frame, hash)| format!("{} {:016X}\n", frame, hash))
+            .collect::<String>();
+        return fs::write(frames_path, lines).map_err(|e| e.to_string());
+    }
+    compare_frames(&golden, &hashes)
+}
+
+// blank lines and lines starting with # are skipped
+pub fn parse_frames(golden: &str) -> Result<Vec<(u64, Option<u64>)>, String> {
+    let mut frames: Vec<(u64, Option<u64>)> = Vec::new();
+    for line in golden.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let frame = fields
+            .next()
+            .and_then(|frame| frame.parse().ok())
+            .filter(|&frame: &u64| frame > 0)
+            .ok_or_else(|| format!("invalid frame number in \"{}\"", line))?;
+        let hash = match fields.next() {
+            Some(hash) => Some(
+                u64::from_str_radix(hash, 16)
+                    .map_err(|_| format!("invalid hash in \"{}\"", line))?,
+            ),
+            None => None,
+        };
+        if frames.last().map_or(false, |&(last, _)| last >= frame) {
+            return Err(format!("frame {} is out of order", frame));
+        }
+        frames.push((frame, hash));
+    }
+    Ok(frames)
+}
+
+// reports the first frame that doesn't match
+pub fn compare_frames(golden: &[(u64, Option<u64>)], hashes: &[u64]) -> Result<(), String> {
+    for (&(frame, expected), &hash) in golden.iter().zip(hashes) {
+        match expected {
+            Some(expected) if expected == hash => {}
+            Some(expected) => {
+                return Err(format!(
+                    "frame {} differs: {:016X}, expected {:016X}",
+                    frame, hash, expected
+                ))
+            }
+            None => return Err(format!("no hash for frame {}; bless to record it", frame)),
+        }
+    }
+    Ok(())
+}
+
+// runs an NES or Genesis ROM headless, picking out the hashes of the given frames, numbered from 1
+pub fn frame_hashes(rom: &[u8], frames: &[u64]) -> Result<Vec<u64>, String> {
+    let last = frames.last().cloned().unwrap_or(0);
+    let ram = RamOverrides::default();
+    let hashes = if let Ok(cartridge) = cartridge::read(&mut &rom[..], None, &ram) {
+        ::nes::run_headless(cartridge, last)
+    } else {
+        let cartridge =
+            ::gen::load_cartridge(&mut &rom[..], None, &ram).map_err(|e| e.to_string())?;
+        ::gen::run_headless(cartridge, last)
+    };
+    Ok(frames
+        .iter()
+        .map(|&frame| hashes[frame as usize - 1])
+        .collect())
+}
+
+// runs a CP/M program until it jumps to 0, collecting what it prints; the exercisers print
+// "ERROR" next to any failing CRC
+pub fn run_zex(program: &[u8]) -> Result<(), String> {
+    let cartridge = vec![].into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = ::gen::z80::Cpu::new(&cartridge, &vdp_bus, false);
+    cpu.set_pc(0x100);
+    cpu.load_ram(0x100, program);
+    cpu.init_zex_test_vectors();
+    let mut output = String::new();
+    let mut printed = false;
+    while cpu.get_pc() != 0 && !cpu.stopped && cpu.get_cycle_count() < ZEX_TIMEOUT_CYCLES {
+        if cpu.get_pc() == 5 {
+            if !printed {
+                output.push_str(&cpu.test_string());
+                printed = true;
+            }
+        } else {
+            printed = false;
+        }
+        cpu.step()
+    }
+    if cpu.get_pc() != 0 {
+        Err(format!("didn't exit\n{}", output.trim()))
+    } else if output.contains("ERROR") || output.to_lowercase().contains("failed") {
+        Err(String::from(output.trim()))
+    } else {
+        Ok(())
+    }
+}
+
+fn run_m68k_file(contents: &[u8]) -> Result<(), String> {
+    let test_cases = json::parse(&String::from_utf8_lossy(contents))
+        .map_err(|e| format!("invalid JSON: {}", e))?;
+    run_m68k_json(&test_cases);
+    Ok(())
+}
+
+// runs each case in a 68000 single-step test file, panicking on the first mismatch
+pub fn run_m68k_json(test_cases: &JsonValue) {
+    for test_case in test_cases.members() {
+        if !test_case.has_key("name") {
+            continue;
+        }
+        let cartridge = vec![0; 8].into_boxed_slice();
+        let vdp_bus = RefCell::new(VdpBus::new(false));
+        let mut cpu = ::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, true);
+        cpu.expand_ram(0x1000000);
+        cpu.reset(false);
+        let initial_state = &test_case["initial state"];
+        cpu.init_state(
+            initial_state["pc"].as_u32().unwrap(),
+            initial_state["sr"].as_u16().unwrap(),
+            registers(initial_state, "d", "d7"),
+            registers(initial_state, "a", "usp"),
+            initial_state["a7"].as_u32().unwrap(),
+        );
+        for (addr, val) in memory(&test_case["initial memory"]) {
+            cpu.poke_ram(addr, val);
+        }
+        let sr_mask = match cpu.peek_opcode() {
+            Opcode::CHK { .. } => 0b1111111111111000,
+            Opcode::ABCD { .. } | Opcode::NBCD { .. } | Opcode::SBCD { .. } => 0b1111111111110101,
+            _ => 0b1111111111111111,
+        };
+        if let Opcode::ILLEGAL = cpu.peek_opcode() {
+            continue;
+        }
+        let start_cycles = cpu.cycle_count_for_test();
+        cpu.next_operation(&[player_1_gen(), player_2_gen()]);
+        let final_state = &test_case["final state"];
+        let test_id = format!(
+            "{}  {}",
+            test_case["name"].as_str().unwrap(),
+            cpu.peek_opcode()
+        );
+        // cases that record how long the instruction took, in CPU cycles, are timed too
+        if let Some(length) = test_case["length"].as_u64() {
+            assert_eq!(
+                length,
+                cpu.cycle_count_for_test() - start_cycles,
+                "{}   cycles",
+                test_id
+            );
+        }
+        cpu.verify_state(
+            final_state["pc"].as_u32().unwrap(),
+            final_state["sr"].as_u16().unwrap(),
+            registers(final_state, "d", "d7"),
+            registers(final_state, "a", "usp"),
+            final_state["a7"].as_u32().unwrap(),
+            sr_mask,
+            &test_id,
+        );
+        for (addr, val) in memory(&test_case["final memory"]) {
+            cpu.verify_ram(addr, val, &test_id);
+        }
+    }
+}
+
+// d0-d7, or a0-a6 followed by usp
+fn registers(state: &JsonValue, prefix: &str, last: &str) -> [u32; 8] {
+    let mut registers = [0; 8];
+    for (i, register) in registers.iter_mut().enumerate() {
+        let key = if i == 7 {
+            String::from(last)
+        } else {
+            format!("{}{}", prefix, i)
+        };
+        *register = state[key.as_str()].as_u32().unwrap();
+    }
+    registers
+}
+
+// address/value pairs, terminated by -1
+fn memory(memory: &JsonValue) -> Vec<(u32, u8)> {
+    let values = memory.members().collect::<Vec<_>>();
+    values
+        .chunks(2)
+        .take_while(|pair| pair.len() == 2 && pair[0].as_i32().unwrap_or(-1) != -1)
+        .map(|pair| (pair[0].as_u32().unwrap(), pair[1].as_u8().unwrap()))
+        .collect()
+}
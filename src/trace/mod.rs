@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+// what opens the trace
+#[derive(Copy, Clone, Debug)]
+pub enum TraceStart {
+    // the PC first reaching this address
+    Pc(u32),
+    // the first write that changes this work RAM byte
+    Change(u32),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Waiting,
+    Tracing { remaining: u32 },
+    Done,
+}
+
+// a one-shot instruction trace: nothing is written until the start condition is met, and it
+// ends for good after a fixed number of instructions or once the PC reaches an end address,
+// whichever comes first
+pub struct Trace {
+    out: BufWriter<File>,
+    start: TraceStart,
+    until: Option<u32>,
+    count: u32,
+    state: State,
+}
+
+impl Trace {
+    pub fn create(
+        path: &Path,
+        start: TraceStart,
+        count: u32,
+        until: Option<u32>,
+    ) -> io::Result<Trace> {
+        info!(
+            target: "ctrl",
+            "tracing up to {} instructions from {:X?} to {}",
+            count,
+            start,
+            path.display()
+        );
+        Ok(Trace {
+            out: BufWriter::new(File::create(path)?),
+            start,
+            until,
+            count,
+            state: State::Waiting,
+        })
+    }
+
+    // the work RAM address whose change would start the trace, while it hasn't started
+    pub fn awaited_change(&self) -> Option<u32> {
+        match (self.state, self.start) {
+            (State::Waiting, TraceStart::Change(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+
+    pub fn begin(&mut self) {
+        if self.state == State::Waiting {
+            self.state = State::Tracing {
+                remaining: self.count,
+            };
+        }
+    }
+
+    // whether the instruction about to run at pc belongs in the trace; the one at the end
+    // address is the last one written
+    pub fn instruction(&mut self, pc: u32) -> bool {
+        if let (State::Waiting, TraceStart::Pc(start)) = (self.state, self.start) {
+            if pc == start {
+                self.begin();
+            }
+        }
+        match self.state {
+            State::Tracing { remaining } => {
+                if remaining <= 1 || self.until == Some(pc) {
+                    self.state = State::Done;
+                } else {
+                    self.state = State::Tracing {
+                        remaining: remaining - 1,
+                    };
+                }
+                remaining > 0
+            }
+            State::Waiting | State::Done => false,
+        }
+    }
+
+    pub fn line(&mut self, line: &str) {
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            error!(target: "ctrl", "couldn't write trace: {}", e);
+            self.state = State::Done;
+        }
+        if self.state == State::Done {
+            if let Err(e) = self.out.flush() {
+                error!(target: "ctrl", "couldn't write trace: {}", e);
+            }
+            info!(target: "ctrl", "trace finished");
+        }
+    }
+}
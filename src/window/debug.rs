@@ -0,0 +1,246 @@
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use gfx_device_gl::Device;
+use piston_window::*;
+use sdl2::event::{Event as SdlEvent, EventWatch, EventWatchCallback, WindowEvent};
+use sdl2_window::Sdl2Window;
+
+use window::Cpu;
+
+// what an auxiliary debug window shows
+#[derive(Copy, Clone, PartialEq, Debug, ValueEnum)]
+pub enum DebugView {
+    // the tilesheet, in the palette line last picked with Ctrl+T
+    Tiles,
+    // frame time, frame count, and audio buffer stats
+    Stats,
+}
+
+// building the tilesheet reads every tile, so it's only refreshed every few frames
+const TILES_REFRESH_FRAMES: u32 = 10;
+
+// what the main loop knows about the frame just rendered
+pub struct FrameStats {
+    pub frame_ms: f32,
+    pub frame_count: u32,
+}
+
+struct DebugWindow {
+    view: DebugView,
+    window: PistonWindow<Sdl2Window>,
+    texture_ctx: G2dTextureContext,
+    texture: Option<G2dTexture>,
+    glyphs: Glyphs,
+}
+
+// SDL only reports closing one of several windows as a window event, which Sdl2Window drops,
+// so closes are caught as they're queued
+struct CloseWatch(Arc<Mutex<Vec<u32>>>);
+
+impl EventWatchCallback for CloseWatch {
+    fn callback(&mut self, event: SdlEvent) {
+        if let SdlEvent::Window {
+            window_id,
+            win_event: WindowEvent::Close,
+            ..
+        } = event
+        {
+            self.0.lock().unwrap().push(window_id);
+        }
+    }
+}
+
+// windows opened next to the game's, sharing its SDL video subsystem; input typed into any of
+// them goes to the game, since they share one event queue
+pub struct DebugWindows {
+    windows: Vec<DebugWindow>,
+    main_window_id: u32,
+    closed: Arc<Mutex<Vec<u32>>>,
+    _close_watch: Option<EventWatch<'static, CloseWatch>>,
+    palette_line: u8,
+    frames_until_refresh: u32,
+}
+
+impl DebugWindows {
+    pub fn open(main: &PistonWindow<Sdl2Window>, views: &[DebugView]) -> DebugWindows {
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let mut windows = Vec::new();
+        for &view in views {
+            match open_window(main, view) {
+                Ok(window) => windows.push(window),
+                Err(e) => error!(target: "ctrl", "couldn't open {:?} window: {}", view, e),
+            }
+        }
+        let close_watch = if windows.is_empty() {
+            None
+        } else {
+            match main.window.sdl_context.event() {
+                Ok(events) => Some(events.add_event_watch(CloseWatch(closed.clone()))),
+                Err(e) => {
+                    error!(target: "ctrl", "couldn't watch for closed windows: {}", e);
+                    None
+                }
+            }
+        };
+        DebugWindows {
+            windows,
+            main_window_id: main.window.window.id(),
+            closed,
+            _close_watch: close_watch,
+            palette_line: 0,
+            frames_until_refresh: 0,
+        }
+    }
+
+    // drops the debug windows that were closed, and returns whether the game's window was
+    pub fn poll_closed(&mut self) -> bool {
+        let closed: Vec<u32> = self.closed.lock().unwrap().drain(..).collect();
+        self.windows
+            .retain(|window| !closed.contains(&window.window.window.window.id()));
+        closed.contains(&self.main_window_id)
+    }
+
+    pub fn set_palette_line(&mut self, palette_line: u8) {
+        self.palette_line = palette_line;
+        self.frames_until_refresh = 0;
+    }
+
+    // draws each window, then hands the GL context back to the game's window, which some
+    // platforms need current when its buffers are swapped
+    pub fn render(
+        &mut self,
+        main: &mut PistonWindow<Sdl2Window>,
+        cpu: &dyn Cpu,
+        stats: &FrameStats,
+    ) {
+        if self.windows.is_empty() {
+            return;
+        }
+        let refresh_tiles = self.frames_until_refresh == 0;
+        self.frames_until_refresh = if refresh_tiles {
+            TILES_REFRESH_FRAMES - 1
+        } else {
+            self.frames_until_refresh - 1
+        };
+        for window in self.windows.iter_mut() {
+            match window.view {
+                DebugView::Tiles => {
+                    if refresh_tiles {
+                        window.update_tiles(cpu, self.palette_line);
+                    }
+                    window.draw_tiles();
+                }
+                DebugView::Stats => window.draw_stats(cpu, stats),
+            }
+        }
+        main.window.make_current();
+    }
+}
+
+fn open_window(
+    main: &PistonWindow<Sdl2Window>,
+    view: DebugView,
+) -> Result<DebugWindow, Box<dyn std::error::Error>> {
+    let (title, size) = match view {
+        DebugView::Tiles => ("tiles", [512, 512]),
+        DebugView::Stats => ("stats", [160, 80]),
+    };
+    // resizing would be reported to the game's window, which reads the whole event queue
+    let settings = WindowSettings::new(title, size).resizable(false);
+    let sdl_window = Sdl2Window::with_subsystem(main.window.video_subsystem.clone(), &settings)?;
+    let mut window = PistonWindow::new(OpenGL::V3_2, 0, sdl_window);
+    let texture_ctx = window.create_texture_context();
+    let glyphs = Glyphs::from_bytes(
+        include_bytes!("../VeraMono.ttf"),
+        window.create_texture_context(),
+        TextureSettings::new(),
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    Ok(DebugWindow {
+        view,
+        window,
+        texture_ctx,
+        texture: None,
+        glyphs,
+    })
+}
+
+impl DebugWindow {
+    fn update_tiles(&mut self, cpu: &dyn Cpu, palette_line: u8) {
+        if let Some(tilesheet) = cpu.tilesheet(palette_line) {
+            match self.texture {
+                Some(ref mut texture) if texture.get_size() == tilesheet.dimensions() => {
+                    texture.update(&mut self.texture_ctx, &tilesheet).unwrap();
+                }
+                _ => {
+                    self.texture = G2dTexture::from_image(
+                        &mut self.texture_ctx,
+                        &tilesheet,
+                        &TextureSettings::new().filter(Filter::Nearest),
+                    )
+                    .ok();
+                }
+            }
+        }
+    }
+
+    fn draw_tiles(&mut self) {
+        let texture = &self.texture;
+        let texture_ctx = &mut self.texture_ctx;
+        draw(&mut self.window, |c, gl, device| {
+            clear([0.0, 0.0, 0.0, 1.0], gl);
+            if let Some(ref texture) = *texture {
+                let (width, height) = texture.get_size();
+                let view_size = c.get_view_size();
+                let scale = (view_size[0] / width as f64).min(view_size[1] / height as f64);
+                image(texture, c.transform.scale(scale, scale), gl);
+            }
+            texture_ctx.encoder.flush(device);
+        });
+    }
+
+    fn draw_stats(&mut self, cpu: &dyn Cpu, stats: &FrameStats) {
+        let mut lines = vec![
+            format!("{:.1} ms", stats.frame_ms),
+            format!("{:.1} fps", 1000.0 / stats.frame_ms),
+            format!("frame {}", stats.frame_count),
+        ];
+        if let Some((underruns, overruns)) = cpu.audio_stats() {
+            lines.push(format!("u{} o{}", underruns, overruns));
+        }
+        let glyphs = &mut self.glyphs;
+        draw(&mut self.window, |c, gl, device| {
+            clear([0.0, 0.0, 0.0, 1.0], gl);
+            for (i, line) in lines.iter().enumerate() {
+                text(
+                    [1.0, 1.0, 1.0, 1.0],
+                    10,
+                    line,
+                    glyphs,
+                    c.trans(8.0, 16.0 + 16.0 * i as f64).transform,
+                    gl,
+                )
+                .unwrap();
+            }
+            glyphs.factory.encoder.flush(device);
+        });
+    }
+}
+
+// these windows aren't driven by an event loop, so each draw is a render pass of its own
+fn draw<F>(window: &mut PistonWindow<Sdl2Window>, f: F)
+where
+    F: FnOnce(Context, &mut G2d, &mut Device),
+{
+    let size = window.size();
+    let draw_size = window.draw_size();
+    let render = Event::Loop(Loop::Render(RenderArgs {
+        ext_dt: 0.0,
+        window_size: [size.width, size.height],
+        draw_size: [draw_size.width as u32, draw_size.height as u32],
+    }));
+    window.draw_2d(&render, f);
+    window.swap_buffers();
+    window.event(&Event::Loop(Loop::AfterRender(AfterRenderArgs)));
+}
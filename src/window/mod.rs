@@ -1,18 +1,36 @@
+use std::collections::VecDeque;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use bytes::Buf;
 use gfx_device_gl::Device;
+use image::{DynamicImage, RgbImage, RgbaImage};
 use piston_window::*;
 use sdl2_window::Sdl2Window;
-use time::Instant;
+use time::{Instant, OffsetDateTime};
 
+use console::Console;
+use input::script::InputScript;
 use input::ControllerState;
+use playtime::{format_play_time, PlayTime};
+use record::delta::encode_delta;
+use record::frames::FrameDumper;
+use record::rewind::Rewind;
 
+use self::debug::{DebugWindows, FrameStats};
+use self::notify::Notifications;
+
+pub mod debug;
+pub mod notify;
 pub mod renderer;
 
 pub trait Cpu {
     fn reset(&mut self, soft: bool);
     fn do_frame(&mut self, time_secs: f64, inputs: &[ControllerState<8>; 2], debug: bool) -> bool;
+    // runs exactly one NTSC frame's worth of cycles whatever the speed, carrying any fraction of
+    // a cycle over to the next call
+    fn do_frame_exact(&mut self, inputs: &[ControllerState<8>; 2], debug: bool) -> bool;
     fn render(
         &mut self,
         c: Context,
@@ -24,21 +42,100 @@ pub trait Cpu {
     );
     fn save_state(&self, out: &mut Vec<u8>);
     fn load_state(&mut self, state: &mut dyn Buf);
+    // the state as changes from `prev`, an earlier full state; apply_delta on `prev` gives back
+    // exactly what save_state would have
+    fn save_state_delta(&self, prev: &[u8], out: &mut Vec<u8>) {
+        let mut state = Vec::new();
+        self.save_state(&mut state);
+        encode_delta(prev, &state, out);
+    }
+    fn jammed(&self) -> bool;
     fn increase_speed(&mut self);
     fn decrease_speed(&mut self);
+    fn set_speed(&mut self, speed_adj: f64);
+    fn speed(&self) -> f64;
+    fn tilesheet(&self, palette_line: u8) -> Option<RgbaImage>;
+    // the most recently completed frame; without capturing, which costs a copy per frame, it's
+    // whatever the window last drew
+    fn frame_image(&self) -> Option<RgbImage>;
+    fn set_capture_frames(&mut self, capture: bool);
+
+    // debug console access, in the CPU's address space
+    fn peek(&mut self, addr: u32) -> u8;
+    fn poke(&mut self, addr: u32, val: u8);
+    fn registers(&self) -> String;
+    fn add_breakpoint(&mut self, addr: u32);
+    fn remove_breakpoint(&mut self, addr: u32);
+    fn disassemble(&mut self, addr: u32, count: usize) -> Vec<String>;
+    // the same for the video chip's own memories, picked by name; edits show up on the next frame,
+    // and are refused while a DMA is under way, since it would write over the edit, or half of it
+    fn peek_video(&mut self, space: &str, addr: u32) -> Result<u8, String>;
+    fn poke_video(&mut self, space: &str, addr: u32, val: u8) -> Result<(), String>;
+
+    // (underruns, overruns) of the real-time audio output, if there is one
+    fn audio_stats(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    // silences the audio output without stopping it
+    fn set_muted(&mut self, _muted: bool) {}
+
+    // a one-shot check for the CPU reaching `addr`, reported once by pc_triggered
+    fn set_pc_trigger(&mut self, _addr: Option<u32>) {}
+    fn pc_triggered(&mut self) -> bool {
+        false
+    }
+
+    // names and byte lengths of the consecutive regions written by save_state
+    fn state_layout(&self) -> Vec<(&'static str, usize)> {
+        Vec::new()
+    }
+}
+
+// what ends a run at fast-forward speed from boot
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BootTurbo {
+    // this many seconds of emulated time
+    Secs(f64),
+    // the CPU first reaching this address
+    Pc(u32),
+}
+
+// why window_loop returned
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Exit {
+    Quit,
+    // reboot with the ROM re-read from disk, optionally carrying over cartridge RAM
+    Reload { keep_sram: bool },
 }
 
 pub fn window_loop(
-    mut window: PistonWindow<Sdl2Window>,
+    window: &mut PistonWindow<Sdl2Window>,
     mut inputs: &mut [ControllerState<8>; 2],
     record_path: &PathBuf,
     cpu: &mut dyn Cpu,
     width: f64,
     height: f64,
     settings_path: &Path,
+    control_labels: [(&'static str, usize); 8],
     pause: bool,
+    pause_on_unfocus: bool,
+    exit_on_jam: bool,
+    fast_forward_speed: f64,
+    input_polls: u32,
+    lockstep: bool,
+    mut input_script: Option<&mut InputScript>,
+    mut boot_turbo: Option<BootTurbo>,
+    quit_key: Key,
+    mut swap_controllers: bool,
     debug: bool,
-) {
+    console: &mut Option<Console>,
+    frame_dumper: &mut Option<FrameDumper>,
+    rewind_frames: usize,
+    watch: Option<&Path>,
+    debug_windows: &mut DebugWindows,
+    play_time: &mut PlayTime,
+) -> Exit {
 
     let mut frame_count = 0u32;
     let mut last_frame = Instant::now();
@@ -58,51 +155,219 @@ pub fn window_loop(
     let mut control = ::control::Control::new();
     control.pause = pause;
     control.debug_cpu = debug;
+    control.fast_forward_speed = fast_forward_speed;
+    control.quit_key = quit_key;
+    control.swap_controllers = swap_controllers;
+
+    // muted, since the intro it skips would play at the same speed
+    if let Some(trigger) = boot_turbo {
+        cpu.set_speed(fast_forward_speed);
+        cpu.set_muted(true);
+        if let BootTurbo::Pc(addr) = trigger {
+            cpu.set_pc_trigger(Some(addr));
+        }
+    }
+    let mut boot_turbo_secs = 0.0;
 
     let mut input_changed = false;
+    let mut unfocus_paused = false;
+    let mut notifications = Notifications::new();
 
-    let mut menu = ::menu::Menu::new(::menu::NES_CONTROLS, &inputs, settings_path);
+    let mut menu = ::menu::Menu::new(control_labels, &inputs, settings_path);
     menu.update_controls(&mut inputs);
 
     let mut recorder = ::record::Recorder::new(&record_path);
 
-    while let Some(e) = window.next() {
+    cpu.set_capture_frames(frame_dumper.is_some());
+    let mut rewind = Rewind::new(rewind_frames);
+
+    // polled twice a second, so a build in progress is usually finished by the time it's seen
+    let watched_mtime = watch.and_then(modified_time);
+    let mut watch_countdown = 30;
+
+    // input events taken off the queue partway through a frame, which still need to be seen by
+    // the menu and hotkeys
+    let mut polled_events = VecDeque::new();
+
+    let mut exit = Exit::Quit;
+    while let Some(e) = polled_events.pop_front().or_else(|| window.next()) {
         let menu_handled = menu.event(&e);
         if !menu_handled {
-            input_changed |= inputs[0].event(&e);
-            input_changed |= inputs[1].event(&e);
+            // a script's input replaces the keyboard and gamepads'
+            if input_script.is_none() {
+                input_changed |= inputs[0].event(&e);
+                input_changed |= inputs[1].event(&e);
+            }
             control.event(
                 &e,
                 cpu,
                 &mut recorder,
                 frame_count,
             );
+            if let Some(slot) = control.saved_slot.take() {
+                let thumbnail = control.slot_thumbnail(slot).and_then(|thumbnail| {
+                    G2dTexture::from_image(
+                        &mut texture_ctx,
+                        &DynamicImage::ImageRgb8(thumbnail).into_rgba8(),
+                        &TextureSettings::new(),
+                    )
+                    .ok()
+                });
+                menu.set_thumbnail(slot, thumbnail);
+                notifications.notify(&format!("Saved to F{}", slot + 1), Duration::from_secs(2));
+            }
+            if control.swap_controllers != swap_controllers {
+                swap_controllers = control.swap_controllers;
+                input_changed = true;
+                notifications.notify(
+                    if swap_controllers {
+                        "Controllers swapped"
+                    } else {
+                        "Controllers restored"
+                    },
+                    Duration::from_secs(2),
+                );
+            }
         } else {
             menu.update_controls(&mut inputs);
         }
 
+        // frames (and so audio) stop while unfocused; a pause the user asked for is left alone
+        if let Some(focused) = e.focus_args() {
+            if pause_on_unfocus && input_script.is_none() {
+                if !focused && !control.pause {
+                    control.pause = true;
+                    unfocus_paused = true;
+                } else if focused && unfocus_paused {
+                    control.pause = false;
+                    unfocus_paused = false;
+                }
+            }
+        }
+
         if let Some(u) = e.update_args() {
+            if debug_windows.poll_closed() {
+                window.set_should_close(true);
+            }
+            // leaves through the same path as closing the window
+            if control.quit {
+                break;
+            }
+            if let Some(keep_sram) = control.reload.take() {
+                exit = Exit::Reload { keep_sram };
+                break;
+            }
+            if let Some(path) = watch {
+                watch_countdown -= 1;
+                if watch_countdown == 0 {
+                    watch_countdown = 30;
+                    let mtime = modified_time(path);
+                    if mtime.is_some() && mtime != watched_mtime {
+                        info!(target: "ctrl", "{} changed, reloading", path.display());
+                        exit = Exit::Reload { keep_sram: true };
+                        break;
+                    }
+                }
+            }
             if control.reset {
                 control.reset = false;
                 cpu.reset(true);
             }
-            if !control.pause || control.step {
+            if let Some(palette_line) = control.export_tilesheet.take() {
+                export_tilesheet(cpu, record_path, palette_line);
+                debug_windows.set_palette_line(palette_line);
+            }
+            if let Some(ref mut console) = *console {
+                console.poll(cpu, &mut control.pause);
+            }
+            play_time.update(!control.pause);
+            // steps back a frame for each one that would have run, while there's history left; a
+            // movie's frame numbers would no longer line up, so not while one is going
+            let rewound =
+                control.rewinding && !control.pause && !recorder.active() && rewind.pop(cpu);
+            if rewound {
+                frame_count = frame_count.saturating_sub(1);
+            } else if !control.pause || control.step {
+                let step = control.step;
                 control.step = false;
+                if let Some(ref mut input_script) = input_script {
+                    let prev_inputs = [inputs[0].to_u8(), inputs[1].to_u8()];
+                    if !input_script.next_frame(inputs) {
+                        break;
+                    }
+                    input_changed |= prev_inputs != [inputs[0].to_u8(), inputs[1].to_u8()];
+                }
+                // the game sees the swapped ports; the physical inputs are restored after the frame
+                if swap_controllers {
+                    inputs.swap(0, 1);
+                }
                 if input_changed {
                     recorder.input_changed(&inputs, frame_count);
                     input_changed = false;
                 }
                 recorder.set_frame_inputs(&mut inputs, frame_count);
-                let brk = cpu.do_frame(if control.step { 1.0 / 60.0 } else { u.dt }, &inputs, control.debug_cpu);
+                let fixed_step = step || input_script.is_some() || lockstep;
+                let slices = if fixed_step || recorder.active() || menu.showing() {
+                    1
+                } else {
+                    input_polls.max(1)
+                };
+                let dt = if fixed_step { 1.0 / 60.0 } else { u.dt } / f64::from(slices);
+                let mut brk = false;
+                for slice in 0..slices {
+                    if slice > 0 {
+                        while let Some(e) = window.window.poll_event() {
+                            input_changed |= inputs[0].event(&e);
+                            input_changed |= inputs[1].event(&e);
+                            polled_events.push_back(e);
+                        }
+                    }
+                    brk = if lockstep {
+                        cpu.do_frame_exact(&inputs, control.debug_cpu)
+                    } else {
+                        cpu.do_frame(dt, &inputs, control.debug_cpu)
+                    };
+                    if brk {
+                        break;
+                    }
+                }
+                if swap_controllers {
+                    inputs.swap(0, 1);
+                }
+                if let Some(trigger) = boot_turbo {
+                    boot_turbo_secs += dt * f64::from(slices) * fast_forward_speed;
+                    let done = match trigger {
+                        BootTurbo::Secs(secs) => boot_turbo_secs >= secs,
+                        BootTurbo::Pc(_) => cpu.pc_triggered(),
+                    };
+                    if done {
+                        cpu.set_speed(1.0);
+                        cpu.set_muted(false);
+                        cpu.set_pc_trigger(None);
+                        boot_turbo = None;
+                    }
+                }
                 if brk {
-                    control.pause = true;
-                    control.debug_cpu = true;
+                    if exit_on_jam && cpu.jammed() {
+                        window.set_should_close(true);
+                    } else {
+                        control.pause = true;
+                        control.debug_cpu = true;
+                    }
                 }
                 frame_count += 1;
+                rewind.push(&*cpu);
+                // only frames that were actually emulated, so pausing doesn't repeat any
+                if let Some(ref mut frame_dumper) = *frame_dumper {
+                    if let Some(image) = cpu.frame_image() {
+                        frame_dumper.frame(image);
+                    }
+                }
             }
         }
 
         if let Some(_r) = e.render_args() {
+            let frame_ms = (Instant::now() - last_frame).as_seconds_f32() * 1000.0;
             window.draw_2d(&e, |c, gl, device| {
                 let trans = c.trans(x_trans, y_trans).scale(scale, scale);
                 cpu.render(trans, &mut texture_ctx, gl, device, control.render_layers, control.debug_video);
@@ -113,10 +378,7 @@ pub fn window_loop(
                     text(
                         [1.0, 1.0, 1.0, 1.0],
                         8,
-                        &format!(
-                            "{}",
-                            (Instant::now() - last_frame).as_seconds_f32() * 1000.0
-                        ),
+                        &format!("{}", frame_ms),
                         &mut glyphs,
                         c.trans(width - 40.0, 10.0).transform,
                         gl,
@@ -129,11 +391,38 @@ pub fn window_loop(
                         c.trans(width - 40.0, 30.0).transform,
                         gl,
                     ).unwrap();
+                    if let Some((underruns, overruns)) = cpu.audio_stats() {
+                        text(
+                            [1.0, 1.0, 1.0, 1.0],
+                            8,
+                            &format!("u{} o{}", underruns, overruns),
+                            &mut glyphs,
+                            c.trans(width - 40.0, 50.0).transform,
+                            gl,
+                        ).unwrap();
+                    }
+                    text(
+                        [1.0, 1.0, 1.0, 1.0],
+                        8,
+                        &format_play_time(play_time.total()),
+                        &mut glyphs,
+                        c.trans(width - 40.0, 70.0).transform,
+                        gl,
+                    ).unwrap();
                 }
-                menu.render(trans, gl, &mut glyphs);
+                notifications.render(c, gl, &mut glyphs);
+                menu.render(trans, gl, &mut glyphs, play_time.total());
                 glyphs.factory.encoder.flush(device);
                 last_frame = Instant::now();
             });
+            debug_windows.render(
+                window,
+                cpu,
+                &FrameStats {
+                    frame_ms,
+                    frame_count,
+                },
+            );
         }
 
         if let Some(r) = e.resize_args() {
@@ -148,4 +437,75 @@ pub fn window_loop(
     }
     recorder.stop();
     menu.save_settings();
+    play_time.flush();
+    exit
+}
+
+// the console and frame dumper outlive reloads, so connections stay open and the frame numbering
+// carries on
+pub fn open_console(debug_port: Option<u16>) -> Option<Console> {
+    debug_port.and_then(|port| match Console::listen(port) {
+        Ok(console) => Some(console),
+        Err(e) => {
+            error!(target: "console", "couldn't listen on port {}: {}", port, e);
+            None
+        }
+    })
+}
+
+pub fn open_frame_dumper(dump_frames: Option<PathBuf>, max_frames: u32) -> Option<FrameDumper> {
+    dump_frames.and_then(|dir| match FrameDumper::new(&dir, max_frames) {
+        Ok(frame_dumper) => Some(frame_dumper),
+        Err(e) => {
+            error!(target: "ctrl", "couldn't dump frames to {}: {}", dir.display(), e);
+            None
+        }
+    })
+}
+
+// runs without a window on the same fixed timestep as --stdin-input, hashing the picture after
+// each frame, so two builds can be checked for identical output
+pub fn hash_frames(cpu: &mut dyn Cpu, inputs: &[ControllerState<8>; 2], frames: u64) -> Vec<u64> {
+    cpu.set_capture_frames(true);
+    (0..frames)
+        .map(|_| {
+            cpu.do_frame(1.0 / 60.0, inputs, false);
+            cpu.frame_image().map_or(0, |image| frame_hash(&image))
+        })
+        .collect()
+}
+
+// FNV-1a over the frame's RGBA bytes
+pub fn frame_hash(image: &RgbImage) -> u64 {
+    image
+        .pixels()
+        .flat_map(|pixel| pixel.0.iter().cloned().chain(Some(0xFF)))
+        .fold(0xCBF29CE484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001B3)
+        })
+}
+
+fn modified_time(path: &Path) -> Option<std::time::SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn export_tilesheet(cpu: &dyn Cpu, record_path: &Path, palette_line: u8) {
+    if let Some(tilesheet) = cpu.tilesheet(palette_line) {
+        let now = OffsetDateTime::now_utc();
+        let path = record_path.with_file_name(format!(
+            "{}_{:04}{:02}{:02}_{:02}{:02}{:02}_pal{}.png",
+            record_path.file_stem().unwrap().to_string_lossy(),
+            now.year(),
+            now.month() as u8,
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+            palette_line,
+        ));
+        match tilesheet.save(&path) {
+            Ok(()) => info!(target: "ctrl", "wrote tilesheet to {}", path.display()),
+            Err(e) => error!(target: "ctrl", "couldn't write tilesheet to {}: {}", path.display(), e),
+        }
+    }
 }
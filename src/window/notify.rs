@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use piston_window::*;
+
+// the last part of a message's time on screen, over which it fades out
+const FADE: Duration = Duration::from_millis(500);
+// older messages are dropped past this many
+const MAX_SHOWN: usize = 5;
+
+struct Notification {
+    text: String,
+    shown_at: Instant,
+    duration: Duration,
+}
+
+// brief messages stacked in the top left corner of the window, oldest first
+pub struct Notifications {
+    queue: VecDeque<Notification>,
+}
+
+impl Notifications {
+    pub fn new() -> Notifications {
+        Notifications {
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn notify(&mut self, text: &str, duration: Duration) {
+        self.queue.push_back(Notification {
+            text: text.to_owned(),
+            shown_at: Instant::now(),
+            duration,
+        });
+        if self.queue.len() > MAX_SHOWN {
+            self.queue.pop_front();
+        }
+    }
+
+    // drops the messages whose time is up, and gives the rest with their opacity
+    pub fn visible(&mut self, now: Instant) -> Vec<(&str, f32)> {
+        self.queue
+            .retain(|notification| now < notification.shown_at + notification.duration);
+        self.queue
+            .iter()
+            .map(|notification| {
+                let left = notification.shown_at + notification.duration - now;
+                let alpha = if left < FADE {
+                    left.as_secs_f32() / FADE.as_secs_f32()
+                } else {
+                    1.0
+                };
+                (notification.text.as_str(), alpha)
+            })
+            .collect()
+    }
+
+    // drawn in window coordinates, so it's the same size whatever the scale; a dark shadow keeps
+    // it readable over the game
+    pub fn render(&mut self, c: Context, gl: &mut G2d, glyphs: &mut Glyphs) {
+        if self.queue.is_empty() {
+            return;
+        }
+        for (i, (message, alpha)) in self.visible(Instant::now()).into_iter().enumerate() {
+            let c = c.trans(10.0, 20.0 + 12.0 * i as f64);
+            text(
+                [0.0, 0.0, 0.0, alpha],
+                8,
+                message,
+                glyphs,
+                c.trans(1.0, 1.0).transform,
+                gl,
+            )
+            .unwrap();
+            text([1.0, 1.0, 1.0, alpha], 8, message, glyphs, c.transform, gl).unwrap();
+        }
+    }
+}
+
+impl Default for Notifications {
+    fn default() -> Notifications {
+        Notifications::new()
+    }
+}
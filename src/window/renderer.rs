@@ -3,17 +3,43 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
 
+use clap::ValueEnum;
 use gfx_device_gl::Device;
-use image::{DynamicImage, GenericImage};
+use image::{DynamicImage, GenericImage, Rgb, RgbImage};
 use piston_window::*;
 use triple_buffer::Output;
 
+#[derive(Copy, Clone, PartialEq, Debug, ValueEnum)]
+pub enum VideoFilter {
+    None,
+    Scanlines,
+    Crt,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RenderSettings {
+    pub filter: VideoFilter,
+    pub smooth: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            filter: VideoFilter::None,
+            smooth: true,
+        }
+    }
+}
+
 pub struct Renderer<const L: usize> {
     background: [f32; 4],
     images: [Arc<Mutex<DynamicImage>>; L],
     textures: Option<[G2dTexture; L]>,
     join_handle: Option<JoinHandle<()>>,
     closed: Arc<AtomicBool>,
+    settings: RenderSettings,
+    width: u32,
+    height: u32,
 }
 
 impl<const L: usize> Renderer<L> {
@@ -22,6 +48,7 @@ impl<const L: usize> Renderer<L> {
         mut image_buffer_outs: [Output<Box<[P; N]>>; L],
         width: u32,
         fill: fn(&mut Output<Box<[P; N]>>, &mut DynamicImage),
+        settings: RenderSettings,
     ) -> Renderer<L> {
         let height = (N as u32) / width;
         let images = [0; L].map(|_| Arc::new(Mutex::new(DynamicImage::new_rgba8(width, height))));
@@ -30,7 +57,11 @@ impl<const L: usize> Renderer<L> {
             [0; L].map(|i| G2dTexture::from_image(
                 &mut window.create_texture_context(),
                 images[i].lock().unwrap().as_rgba8().unwrap(),
-                &TextureSettings::new(),
+                &TextureSettings::new().filter(if settings.smooth {
+                    Filter::Linear
+                } else {
+                    Filter::Nearest
+                }),
             )
                 .unwrap())
         });
@@ -59,6 +90,9 @@ impl<const L: usize> Renderer<L> {
             textures,
             join_handle: Some(join_handle),
             closed,
+            settings,
+            width,
+            height,
         }
     }
 
@@ -81,6 +115,8 @@ impl<const L: usize> Renderer<L> {
     ) {
         let layers = layers % (L + 1);
         clear(if layers == 0 { self.background } else { [1.0, 0.0, 1.0, 1.0] }, gl);
+        let transform = c.transform.scale(x_scale, 1.0);
+        let filter = self.settings.filter;
         if let Some(ref mut textures) = self.textures {
             for (i, texture) in textures.iter_mut().enumerate() {
                 if layers == 0 || layers - 1 == i {
@@ -90,11 +126,52 @@ impl<const L: usize> Renderer<L> {
                             self.images[i].lock().unwrap().as_rgba8().unwrap(),
                         )
                         .unwrap();
-                    image(texture, c.transform.scale(x_scale, 1.0), gl);
+                    image(texture, transform, gl);
+                    if filter == VideoFilter::Crt {
+                        let glow = Image::new_color([1.0, 1.0, 1.0, 0.2]);
+                        glow.draw(texture, &c.draw_state, transform.trans(-0.5, 0.0), gl);
+                        glow.draw(texture, &c.draw_state, transform.trans(0.5, 0.0), gl);
+                    }
                 }
             }
             texture_ctx.encoder.flush(device);
         }
+        match filter {
+            VideoFilter::None => {}
+            VideoFilter::Scanlines => self.draw_scanlines(transform, gl, 0.35),
+            VideoFilter::Crt => self.draw_scanlines(transform, gl, 0.25),
+        }
+    }
+
+    // the picture as last drawn, with the layers over the background
+    pub fn image(&self) -> RgbImage {
+        let background = self.background.map(|c| (c * 255.0) as u8);
+        let mut image = RgbImage::from_pixel(
+            self.width,
+            self.height,
+            Rgb([background[0], background[1], background[2]]),
+        );
+        for layer in &self.images {
+            if let Some(layer) = layer.lock().unwrap().as_rgba8() {
+                for (x, y, pixel) in layer.enumerate_pixels() {
+                    if pixel[3] > 0 {
+                        image.put_pixel(x, y, Rgb([pixel[0], pixel[1], pixel[2]]));
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    fn draw_scanlines(&self, transform: math::Matrix2d, gl: &mut G2d, darkness: f32) {
+        for y in 0..self.height {
+            rectangle(
+                [0.0, 0.0, 0.0, darkness],
+                [0.0, y as f64 + 0.5, self.width as f64, 0.5],
+                transform,
+                gl,
+            );
+        }
     }
 
     pub fn close(&mut self) {
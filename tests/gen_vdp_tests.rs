@@ -9,6 +9,8 @@ use piston::NoWindow;
 
 use emu::gen;
 use emu::gen::{m68k, vdp};
+use emu::rom::RamOverrides;
+use emu::window::renderer::RenderSettings;
 use emu::window::Cpu;
 
 #[test]
@@ -640,6 +642,379 @@ fn test_dma_copy_9000_to_8000_cd03_1111() {
     run_vdp_test(0x0D3A)
 }
 
+#[test]
+fn test_status_flags() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    fixture.registers(&[0x8154, 0x8F01]);
+
+    let mut seen_vblank = false;
+    let mut seen_hblank = [false, false];
+    let mut frames = 0;
+    let mut prev_vpos = 0;
+    while frames < 2 {
+        fixture.tick(1);
+        let status = fixture.status();
+        let vpos = fixture.vpos();
+        let vblank = status & 0b1000 > 0;
+        assert_eq!(vblank, vpos >= 0xE0 && vpos < 0xFF, "vblank at line {:02X}", vpos);
+        seen_vblank |= vblank;
+        if vpos < 0xE0 {
+            seen_hblank[if status & 0b100 > 0 { 1 } else { 0 }] = true;
+        }
+        if vpos < prev_vpos && vpos == 0 {
+            frames += 1;
+        }
+        prev_vpos = vpos;
+    }
+    assert!(seen_vblank);
+    assert_eq!(seen_hblank, [true, true]);
+
+    fixture.registers(&[0x9310, 0x9400, 0x9780]);
+    vdp_bus.borrow_mut().write_long(0xC00004, 0x40000080);
+    assert_eq!(fixture.status() & 0b10, 0b10);
+    vdp_bus.borrow_mut().write_word(0xC00000, 0x1234);
+    fixture.tick(100);
+    assert_eq!(fixture.status() & 0b10, 0);
+}
+
+#[test]
+fn test_horizontal_interrupt_interval() {
+    // one every (register 0x0A + 1) lines of the 225 the counter runs on
+    assert_eq!(14, horizontal_interrupts_per_frame(0x14, 0x0F));
+    assert_eq!(225, horizontal_interrupts_per_frame(0x14, 0x00));
+    // an interval longer than the active display never expires
+    assert_eq!(0, horizontal_interrupts_per_frame(0x14, 0xFF));
+    // the counter still runs with the interrupt disabled, but nothing is raised
+    assert_eq!(0, horizontal_interrupts_per_frame(0x04, 0x00));
+}
+
+// counts the horizontal interrupts the 68000 would take in the second frame, acknowledging each
+// as it's raised
+fn horizontal_interrupts_per_frame(mode_1: u16, counter: u16) -> usize {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    fixture.registers(&[0x8000 | mode_1, 0x8A00 | counter]);
+
+    let mut counts = Vec::new();
+    let mut count = 0;
+    let mut prev_vpos = 0;
+    while counts.len() < 2 {
+        fixture.tick(1);
+        let pending = vdp_bus.borrow().pending_interrupt();
+        if let Some((vector, level)) = pending {
+            assert_eq!((28, 4), (vector, level));
+            vdp_bus.borrow_mut().acknowledge_interrupt(level);
+            count += 1;
+        }
+        let vpos = fixture.vpos();
+        if vpos < prev_vpos && vpos == 0 {
+            counts.push(count);
+            count = 0;
+        }
+        prev_vpos = vpos;
+    }
+    counts[1]
+}
+
+#[test]
+fn test_display_disabled_shows_backdrop() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    // red backdrop, planes filled with green tile 0x100
+    fixture.registers(&[0x8104, 0x8F02]);
+    fixture.write(0xC0020000, &[0x000E]);
+    fixture.write(0xC0040000, &[0x00E0]);
+    fixture.write(0x60000000, &[0x2222; 16]);
+    fixture.write(0x40000000, &[0x0100; 0x400]);
+    fixture.registers(&[0x8C81, 0x8701]);
+
+    let backdrop = [255u8, 0, 0];
+    for &(mode_2, debug, blank) in &[
+        (0x8144, 0x0000, false),
+        (0x8104, 0x0000, true),
+        (0x8144, 0x0040, true),
+    ] {
+        fixture.registers(&[mode_2]);
+        vdp_bus.borrow_mut().write_word(0xC0001C, debug);
+        fixture.run_frames(2);
+        let frame = fixture.frame();
+        let backdrop_pixels = frame.chunks(3).filter(|pixel| *pixel == backdrop).count();
+        if blank {
+            assert_eq!(320 * 224, backdrop_pixels, "mode 2 {:04X} debug {:04X}", mode_2, debug);
+        } else {
+            assert_eq!(0, backdrop_pixels);
+        }
+    }
+}
+
+#[test]
+fn test_backdrop_fills_border_and_blanked_column() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    // green backdrop, planes filled with red tile 0x100
+    fixture.registers(&[0x8104, 0x8F02]);
+    fixture.write(0xC0020000, &[0x000E]);
+    fixture.write(0xC0040000, &[0x00E0]);
+    fixture.write(0x60000000, &[0x1111; 16]);
+    fixture.write(0x40000000, &[0x0100; 0x400]);
+    fixture.registers(&[0x8702, 0x8144]);
+
+    let backdrop = [0u8, 255, 0];
+    // H40, then H32 with its 32-pixel borders, then H32 with the leftmost column blanked
+    for &(mode_1, mode_4, border, blanked) in &[
+        (0x8004, 0x8C81, 0, 0),
+        (0x8004, 0x8C00, 32, 0),
+        (0x8024, 0x8C00, 32, 8),
+    ] {
+        fixture.registers(&[mode_1, mode_4]);
+        fixture.run_frames(2);
+        let frame = fixture.frame();
+        for y in [0, 100, 223] {
+            for x in 0..320 {
+                let expected = if x < border + blanked || x >= 320 - border {
+                    backdrop
+                } else {
+                    [255, 0, 0]
+                };
+                assert_eq!(
+                    expected,
+                    pixel(&frame, x, y),
+                    "reg 0 {:04X} reg 12 {:04X} ({}, {})",
+                    mode_1,
+                    mode_4,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_backdrop_changes_mid_frame() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    // red and green in CRAM; the planes are all transparent tile 0
+    fixture.registers(&[0x8104, 0x8F02]);
+    fixture.write(0xC0020000, &[0x000E, 0x00E0]);
+    fixture.registers(&[0x8C81, 0x8144]);
+
+    // a red frame, then green from partway down the next
+    fixture.run_to_line(0);
+    fixture.registers(&[0x8701]);
+    fixture.run_to_line(100);
+    fixture.run_to_line(0);
+    fixture.run_to_line(100);
+    fixture.registers(&[0x8702]);
+    fixture.run_to_line(225);
+
+    // the V counter reaches 100 in line 99's right border, after its backdrop was latched, so
+    // line 100 is the first to change, and all of it does
+    let frame = fixture.frame();
+    let row = |y: usize| &frame[y * 320 * 3..(y + 1) * 320 * 3];
+    for y in 0..224 {
+        let expected = if y < 100 { [255u8, 0, 0] } else { [0, 255, 0] };
+        assert!(
+            row(y).chunks(3).all(|pixel| pixel == expected),
+            "line {}",
+            y
+        );
+    }
+}
+
+#[test]
+fn test_plane_a_nametable_base() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    // red tile 0x100; one nametable at 0xC000 using it in the first column, one at 0xE000 in
+    // the second. everything else is left at 0, which is transparent
+    fixture.registers(&[0x8104, 0x8F02]);
+    fixture.write(0xC0020000, &[0x000E]);
+    fixture.write(0x60000000, &[0x1111; 16]);
+    fixture.write(0x40000003, &[0x0100]);
+    fixture.write(0x60020003, &[0x0100]);
+    fixture.registers(&[0x8C81, 0x8144]);
+
+    // register 2's low 3 bits aren't part of the address
+    for &(reg_2, red_column) in &[(0x8230, 0), (0x8231, 0), (0x8238, 1), (0x823F, 1)] {
+        fixture.registers(&[reg_2]);
+        fixture.run_frames(2);
+        let frame = fixture.frame();
+        for x in 0..24 {
+            let expected = if x / 8 == red_column { [255, 0, 0] } else { [0, 0, 0] };
+            assert_eq!(expected, pixel(&frame, x, 4), "reg 2 {:04X} x {}", reg_2, x);
+        }
+    }
+}
+
+#[test]
+fn test_two_cell_vertical_scroll() {
+    // plane A at 0xC000 with red tile 0x100 along its top row, and its first three 2-cell
+    // columns scrolled not at all, 4 lines up, and 4 lines down
+    let red_lines = [0..8, 0..4, 4..12];
+    for &h_scroll in &[0u16, 4] {
+        let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+        let mut fixture = VdpFixture::new(&vdp_bus);
+        fixture.registers(&[0x8104, 0x8F02, 0x8230, 0x8B04, 0x8C81, 0x8D3C]);
+        fixture.write(0xC0020000, &[0x000E]);
+        fixture.write(0x60000000, &[0x1111; 16]);
+        fixture.write(0x40000003, &[0x0100; 32]);
+        fixture.write(0x70000003, &[h_scroll]);
+        fixture.write(0x40000010, &[0, 0, 4, 0, 0x3FC, 0]);
+        fixture.registers(&[0x8144]);
+        fixture.run_frames(2);
+
+        // the columns move right with the fine horizontal scroll
+        let frame = fixture.frame();
+        for (column, lines) in red_lines.iter().enumerate() {
+            for x in column * 16..column * 16 + 16 {
+                let x = x + h_scroll as usize;
+                for y in 0..16 {
+                    let expected = if lines.contains(&y) {
+                        [255u8, 0, 0]
+                    } else {
+                        [0, 0, 0]
+                    };
+                    assert_eq!(
+                        expected,
+                        pixel(&frame, x, y),
+                        "h scroll {} ({}, {})",
+                        h_scroll,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_window_row_width() {
+    // the window over the whole display, from a nametable at 0xB000, and red tile 0x100
+    for &(mode_4, border, row_command) in &[(0x8C81, 0, 0x70800002), (0x8C00, 32, 0x70400002)] {
+        let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+        let mut fixture = VdpFixture::new(&vdp_bus);
+        fixture.registers(&[0x8104, 0x8F02, 0x832C, 0x9100, 0x921C, mode_4]);
+        fixture.write(0xC0020000, &[0x000E]);
+        fixture.write(0x60000000, &[0x1111; 16]);
+        // the first tile of the second row: 64 entries in, or 32 in H32
+        fixture.write(row_command, &[0x0100]);
+        fixture.registers(&[0x8144]);
+        fixture.run_frames(2);
+
+        let frame = fixture.frame();
+        for &(x, y, expected) in &[
+            (0, 0, [0u8, 0, 0]),
+            (0, 8, [255, 0, 0]),
+            (7, 15, [255, 0, 0]),
+            (8, 8, [0, 0, 0]),
+            (0, 16, [0, 0, 0]),
+        ] {
+            assert_eq!(
+                expected,
+                pixel(&frame, border + x, y),
+                "reg 12 {:04X} ({}, {})",
+                mode_4,
+                x,
+                y
+            );
+        }
+    }
+}
+
+#[test]
+fn test_sprite_collision() {
+    assert_eq!(
+        0x0020,
+        render_sprites(&[(178, 228), (178, 228)], true).0 & 0x0060
+    );
+    assert_eq!(
+        0x0000,
+        render_sprites(&[(178, 228), (186, 228)], true).0 & 0x0060
+    );
+}
+
+#[test]
+fn test_sprite_overflow() {
+    let sprites = (0..21).map(|i| (128 + i * 8, 228)).collect::<Vec<_>>();
+    assert_eq!(0x0000, render_sprites(&sprites[..20], true).0 & 0x0060);
+    assert_eq!(0x0040, render_sprites(&sprites, true).0 & 0x0060);
+}
+
+#[test]
+fn test_no_sprite_limit() {
+    let sprites = (0..21).map(|i| (128 + i * 8, 228)).collect::<Vec<_>>();
+    // the 21st sprite on line 100 covers x 160-167
+    for &(sprite_limit, expected) in &[(true, [0u8, 0, 0]), (false, [255, 0, 0])] {
+        let (status, frame) = render_sprites(&sprites, sprite_limit);
+        // the hardware still overflowed
+        assert_eq!(0x0040, status & 0x0060, "limit {}", sprite_limit);
+        assert_eq!([255, 0, 0], pixel(&frame, 159, 100), "limit {}", sprite_limit);
+        for x in 160..168 {
+            assert_eq!(
+                expected,
+                pixel(&frame, x, 100),
+                "limit {} x {}",
+                sprite_limit,
+                x
+            );
+        }
+    }
+}
+
+// renders two H40 frames of solid red 8x8 sprites and returns the status register read twice,
+// and the last frame
+fn render_sprites(sprites: &[(u16, u16)], sprite_limit: bool) -> (u16, Vec<u8>) {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+    if !sprite_limit {
+        fixture.vdp.disable_sprite_limit();
+    }
+
+    // sprite table at 0xF000, tile 1 filled with color 1, which is red in palette line 0
+    fixture.registers(&[0x8104, 0x8F02, 0x8C81, 0x8578]);
+    let mut sprite_table = Vec::new();
+    for (i, &(x, y)) in sprites.iter().enumerate() {
+        let link = if i + 1 < sprites.len() { i as u16 + 1 } else { 0 };
+        sprite_table.extend(&[y, link, 0x0001, x]);
+    }
+    fixture.write(0x40200000, &[0x1111; 16]);
+    fixture.write(0x70000003, &sprite_table);
+    fixture.write(0xC0020000, &[0x000E]);
+    fixture.registers(&[0x8144]);
+
+    fixture.run_frames(2);
+    let status = fixture.status();
+    assert_eq!(0, fixture.status() & 0x0060, "cleared on read");
+    (status, fixture.frame())
+}
+
+#[test]
+fn test_tilesheet() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    // tile 1 filled with color 2, which is red in palette line 1
+    fixture.registers(&[0x8F02]);
+    fixture.write(0x40200000, &[0x2222; 16]);
+    fixture.write(0xC0240000, &[0x000E]);
+
+    let tilesheet = fixture.vdp.tilesheet(1);
+    assert_eq!((256, 512), tilesheet.dimensions());
+    assert_eq!([0, 0, 0, 255], tilesheet.get_pixel(7, 0).0);
+    assert_eq!([255, 0, 0, 255], tilesheet.get_pixel(8, 0).0);
+    assert_eq!([255, 0, 0, 255], tilesheet.get_pixel(15, 7).0);
+    assert_eq!([0, 0, 0, 255], fixture.vdp.tilesheet(0).get_pixel(8, 0).0);
+}
+
 fn run_vdp_test(start_addr: u32) {
     let _ = env_logger::try_init();
     let cartridge = gen::load_cartridge(
@@ -647,10 +1022,11 @@ fn run_vdp_test(start_addr: u32) {
             .as_mut()
             .unwrap(),
         None,
+        &RamOverrides::default(),
     ).unwrap();
     let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
-    let vdp = vdp::Vdp::new::<NoWindow>(&vdp_bus, None, false, false);
-    let mut cpu = m68k::Cpu::boot(&cartridge, Some(vdp), &vdp_bus, false);
+    let vdp = vdp::Vdp::new::<NoWindow>(&vdp_bus, None, RenderSettings::default(), false, false);
+    let mut cpu = m68k::Cpu::boot(&cartridge.rom, Some(vdp), &vdp_bus, false);
 
     cpu.reset(false);
     while cpu.pc_for_test() != 0x30C {
@@ -672,3 +1048,253 @@ fn run_vdp_test(start_addr: u32) {
         assert_eq!(expected, actual);
     }
 }
+
+#[test]
+fn test_save_and_load_state() {
+    let cartridge = gen::load_cartridge(
+        File::open(&Path::new("tests/gen_vdp/VDPFIFOTesting.bin"))
+            .as_mut()
+            .unwrap(),
+        None,
+        &RamOverrides::default(),
+    )
+    .unwrap();
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let vdp = vdp::Vdp::new::<NoWindow>(&vdp_bus, None, RenderSettings::default(), false, false);
+    let mut cpu = m68k::Cpu::boot(&cartridge.rom, Some(vdp), &vdp_bus, false);
+    let inputs = [emu::input::player_1_gen(), emu::input::player_2_gen()];
+
+    cpu.reset(false);
+    // partway into a frame, so the VDP is mid-line
+    for _ in 0..3 {
+        cpu.do_frame(1.0 / 60.0, &inputs, false);
+    }
+    cpu.do_frame(1.0 / 200.0, &inputs, false);
+    let mut saved = Vec::new();
+    cpu.save_state(&mut saved);
+
+    let run = |cpu: &mut m68k::Cpu| {
+        for _ in 0..2 {
+            cpu.do_frame(1.0 / 60.0, &inputs, false);
+        }
+        let mut state = Vec::new();
+        cpu.save_state(&mut state);
+        (state, cpu.registers())
+    };
+    let expected = run(&mut cpu);
+    cpu.load_state(&mut saved.as_slice());
+    assert_eq!(expected, run(&mut cpu));
+}
+
+#[test]
+fn test_fifo_status_bits() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    fixture.registers(&[0x8F02]);
+    vdp_bus.borrow_mut().write_long(0xC00004, 0x40000000);
+    for word in 0..3 {
+        vdp_bus.borrow_mut().write_word(0xC00000, 0x1111 * (word + 1));
+    }
+    assert_eq!(0x0000, fixture.status() & 0x0300);
+    // queued writes keep the address they were made to
+    vdp_bus.borrow_mut().write_long(0xC00004, 0x40100000);
+    vdp_bus.borrow_mut().write_word(0xC00000, 0x4444);
+    assert_eq!(0x0100, fixture.status() & 0x0300);
+
+    fixture.tick(40);
+    assert_eq!(0x0200, fixture.status() & 0x0300);
+
+    let mut vram = Vec::new();
+    for &addr in &[0x00000000u32, 0x00100000] {
+        vdp_bus.borrow_mut().write_long(0xC00004, addr);
+        fixture.tick(40);
+        vram.push(vdp_bus.borrow_mut().read_word(0xC00000));
+    }
+    assert_eq!(vec![0x1111, 0x4444], vram);
+}
+
+#[test]
+fn test_control_port_latch_and_auto_increment() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    // VRAM write to 0xC010 as two words: A13-A0 first, then A15-A14
+    fixture.registers(&[0x8104, 0x8F04, 0x4010, 0x0003]);
+    for &data in &[0x1111, 0x2222, 0x3333] {
+        vdp_bus.borrow_mut().write_word(0xC00000, data);
+        fixture.tick(40);
+    }
+    // a first word on its own keeps the second word's bits from before
+    fixture.registers(&[0x4020]);
+    vdp_bus.borrow_mut().write_word(0xC00000, 0x4444);
+    fixture.tick(40);
+
+    // reads advance the address too
+    fixture.registers(&[0x8F02]);
+    vdp_bus.borrow_mut().write_long(0xC00004, 0x00100003);
+    let mut vram = Vec::new();
+    for _ in 0..9 {
+        fixture.tick(40);
+        vram.push(vdp_bus.borrow_mut().read_word(0xC00000));
+    }
+    assert_eq!(vec![0x1111, 0, 0x2222, 0, 0x3333, 0, 0, 0, 0x4444], vram);
+    vdp_bus.borrow_mut().write_long(0xC00004, 0x00140003);
+    fixture.tick(40);
+    assert_eq!(0x22, vdp_bus.borrow_mut().read_byte(0xC00001));
+    fixture.tick(40);
+    assert_eq!(0x00, vdp_bus.borrow_mut().read_byte(0xC00000));
+    fixture.tick(40);
+    assert_eq!(0x33, vdp_bus.borrow_mut().read_byte(0xC00000));
+}
+
+#[test]
+fn test_h32_h40_width_and_dma_timing() {
+    let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+    let mut fixture = VdpFixture::new(&vdp_bus);
+
+    fixture.registers(&[0x8F02]);
+    // display off, so every line is blanking: 167 slots per 3420 master clocks in H32, 205 per
+    // 3360 in H40
+    for &(mode_4, width, slots_per_line, line_clocks) in
+        &[(0x8C00, 256, 167.0, 3420.0), (0x8C81, 320, 205.0, 3360.0)]
+    {
+        fixture.registers(&[0x8114, mode_4]);
+        fixture.run_frames(2);
+        assert_eq!(width, fixture.vdp.active_width());
+
+        // 0x1000 words from 68000 RAM to VRAM, two slots each
+        fixture.registers(&[0x9300, 0x9410, 0x9500, 0x9680, 0x977F]);
+        vdp_bus.borrow_mut().write_long(0xC00004, 0x40000080);
+        let mut ticks = 0;
+        while fixture.status() & 0x0002 != 0 {
+            fixture.tick(1);
+            ticks += 1;
+        }
+        let expected = 0x2000 as f64 / slots_per_line * line_clocks;
+        assert!(
+            (ticks as f64 / expected - 1.0).abs() < 0.02,
+            "mode 4 {:04X}: {} master clocks, expected about {}",
+            mode_4,
+            ticks,
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_hv_counter_jumps() {
+    // NTSC lines count $00-$EA, then $E5-$FF
+    let lines = (0x00..=0xEA).chain(0xE5..=0xFF).collect::<Vec<u16>>();
+    // H32 counts $00-$93 then $E9-$FF across a line, H40 $00-$B6 then $E4-$FF
+    for &(mode_4, last, next) in &[(0x8C00, 0x93, 0xE9), (0x8C81, 0xB6, 0xE4)] {
+        let vdp_bus = RefCell::new(vdp::bus::VdpBus::new(false));
+        let mut fixture = VdpFixture::new(&vdp_bus);
+        fixture.registers(&[mode_4]);
+
+        // each value as it changes, through one frame
+        let mut h = vec![0];
+        let mut v = vec![0];
+        loop {
+            fixture.tick(1);
+            let hv = vdp_bus.borrow_mut().read_word(0xC00008);
+            let (vpos, hpos) = (hv >> 8, hv & 0xFF);
+            if vpos == 0 && v.len() > 1 {
+                break;
+            }
+            if *v.last().unwrap() != vpos {
+                v.push(vpos);
+            }
+            if *h.last().unwrap() != hpos {
+                h.push(hpos);
+            }
+        }
+        assert_eq!(lines, v, "reg 12 {:04X}", mode_4);
+        let line_start = h.iter().skip(1).position(|&hpos| hpos == 0).unwrap() + 1;
+        let line = (0..=last).chain(next..=0xFF).collect::<Vec<u16>>();
+        assert_eq!(
+            line,
+            h[line_start..line_start + line.len()].to_vec(),
+            "reg 12 {:04X}",
+            mode_4
+        );
+        assert_eq!(0, h[line_start + line.len()], "reg 12 {:04X}", mode_4);
+    }
+}
+
+// a VDP on its own, ticked against a blank cartridge and 68000 RAM
+struct VdpFixture<'a> {
+    bus: &'a RefCell<vdp::bus::VdpBus>,
+    vdp: vdp::Vdp<'a>,
+    cartridge: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl<'a> VdpFixture<'a> {
+    fn new(bus: &'a RefCell<vdp::bus::VdpBus>) -> VdpFixture<'a> {
+        VdpFixture {
+            bus,
+            vdp: vdp::Vdp::new::<NoWindow>(bus, None, RenderSettings::default(), false, false),
+            cartridge: vec![0; 0x400000],
+            ram: vec![0; 0x10000],
+        }
+    }
+
+    fn tick(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.vdp.tick(&self.cartridge, &self.ram);
+        }
+    }
+
+    // control port words: register writes, or halves of an address command
+    fn registers(&self, words: &[u16]) {
+        for &word in words {
+            self.bus.borrow_mut().write_word(0xC00004, word);
+        }
+    }
+
+    // sends a write command, then the data, giving the FIFO time to drain after each word
+    fn write(&mut self, command: u32, data: &[u16]) {
+        self.bus.borrow_mut().write_long(0xC00004, command);
+        for &word in data {
+            self.bus.borrow_mut().write_word(0xC00000, word);
+            self.tick(20);
+        }
+    }
+
+    fn status(&self) -> u16 {
+        self.bus.borrow_mut().read_word(0xC00004)
+    }
+
+    fn vpos(&self) -> u16 {
+        self.bus.borrow_mut().read_word(0xC00008) >> 8
+    }
+
+    fn run_to_line(&mut self, line: u16) {
+        while self.vpos() != line {
+            self.tick(1);
+        }
+    }
+
+    // runs until the line counter has wrapped back to 0 this many times
+    fn run_frames(&mut self, frames: usize) {
+        let mut prev_vpos = 0;
+        let mut seen = 0;
+        while seen < frames {
+            self.tick(1);
+            let vpos = self.vpos();
+            if vpos < prev_vpos && vpos == 0 {
+                seen += 1;
+            }
+            prev_vpos = vpos;
+        }
+    }
+
+    fn frame(&self) -> Vec<u8> {
+        self.vdp.framebuffer().0
+    }
+}
+
+fn pixel(frame: &[u8], x: usize, y: usize) -> &[u8] {
+    &frame[(y * 320 + x) * 3..(y * 320 + x) * 3 + 3]
+}
@@ -1,16 +1,20 @@
 extern crate emu;
-extern crate itertools;
 extern crate json;
 extern crate piston;
 
 use std::cell::RefCell;
+use std::fs;
 
-use itertools::{Itertools, Tuples};
-use json::iterators::Members;
 use json::JsonValue;
+use piston::NoWindow;
 
-use emu::gen::m68k::opcodes::Opcode;
 use emu::gen::vdp::bus::VdpBus;
+use emu::gen::vdp::Vdp;
+use emu::input::{player_1_gen, player_2_gen};
+use emu::record::diff::diff_states;
+use emu::rom::{write_save, RamOverrides};
+use emu::trace::{Trace, TraceStart};
+use emu::window::renderer::RenderSettings;
 use emu::window::Cpu as cpuw;
 
 #[test]
@@ -23,6 +27,11 @@ fn add_sub() {
     run_json_test(json::parse(include_str!("m68k/add_sub.json")).unwrap());
 }
 
+#[test]
+fn adda_suba() {
+    run_json_test(json::parse(include_str!("m68k/adda_suba.json")).unwrap());
+}
+
 #[test]
 fn addi_subi_cmpi() {
     run_json_test(json::parse(include_str!("m68k/addi_subi_cmpi.json")).unwrap());
@@ -103,6 +112,11 @@ fn link_unlk() {
     run_json_test(json::parse(include_str!("m68k/link_unlk.json")).unwrap());
 }
 
+#[test]
+fn link_unlk_a7() {
+    run_json_test(json::parse(include_str!("m68k/link_unlk_a7.json")).unwrap());
+}
+
 #[test]
 fn lslr_aslr_roxlr_rolr() {
     run_json_test(json::parse(include_str!("m68k/lslr_aslr_roxlr_rolr.json")).unwrap());
@@ -118,6 +132,11 @@ fn movem() {
     run_json_test(json::parse(include_str!("m68k/movem.json")).unwrap());
 }
 
+#[test]
+fn movem_a7() {
+    run_json_test(json::parse(include_str!("m68k/movem_a7.json")).unwrap());
+}
+
 #[test]
 fn movep() {
     run_json_test(json::parse(include_str!("m68k/movep.json")).unwrap());
@@ -168,111 +187,371 @@ fn rts() {
     run_json_test(json::parse(include_str!("m68k/rts.json")).unwrap());
 }
 
+#[test]
+fn scc() {
+    run_json_test(json::parse(include_str!("m68k/scc.json")).unwrap());
+}
+
 #[test]
 fn tas() {
     run_json_test(json::parse(include_str!("m68k/tas.json")).unwrap());
 }
 
+#[test]
+fn sub_cmp_edges() {
+    run_json_test(json::parse(include_str!("m68k/sub_cmp_edges.json")).unwrap());
+}
+
 #[test]
 fn tst() {
     run_json_test(json::parse(include_str!("m68k/tst.json")).unwrap());
 }
 
+#[test]
+fn trap_trapv_chk() {
+    run_json_test(json::parse(include_str!("m68k/trap_trapv_chk.json")).unwrap());
+}
+
 fn run_json_test(test_cases: JsonValue) {
-    for test_case in test_cases.members() {
-        if !test_case.has_key("name") {
-            continue;
-        }
-        let cartridge = vec![0; 8].into_boxed_slice();
-        let vdp_bus = RefCell::new(VdpBus::new(false));
-        let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, true);
-        cpu.expand_ram(0x1000000);
-        cpu.reset(false);
-        let initial_state = &test_case["initial state"];
-        cpu.init_state(
-            initial_state["pc"].as_u32().unwrap(),
-            initial_state["sr"].as_u16().unwrap(),
-            [
-                initial_state["d0"].as_u32().unwrap(),
-                initial_state["d1"].as_u32().unwrap(),
-                initial_state["d2"].as_u32().unwrap(),
-                initial_state["d3"].as_u32().unwrap(),
-                initial_state["d4"].as_u32().unwrap(),
-                initial_state["d5"].as_u32().unwrap(),
-                initial_state["d6"].as_u32().unwrap(),
-                initial_state["d7"].as_u32().unwrap(),
-            ],
-            [
-                initial_state["a0"].as_u32().unwrap(),
-                initial_state["a1"].as_u32().unwrap(),
-                initial_state["a2"].as_u32().unwrap(),
-                initial_state["a3"].as_u32().unwrap(),
-                initial_state["a4"].as_u32().unwrap(),
-                initial_state["a5"].as_u32().unwrap(),
-                initial_state["a6"].as_u32().unwrap(),
-                initial_state["usp"].as_u32().unwrap(),
-            ],
-            initial_state["a7"].as_u32().unwrap(),
-        );
-        let initial_memory: Tuples<Members, (&JsonValue, &JsonValue)> =
-            test_case["initial memory"].members().tuples();
-        for (addr, val) in initial_memory {
-            if addr.as_i32().unwrap_or(-1) == -1 {
-                break;
-            }
-            cpu.poke_ram(addr.as_u32().unwrap(), val.as_u8().unwrap());
-        }
-        let sr_mask = match cpu.peek_opcode() {
-            Opcode::CHK { .. } => 0b1111111111111000,
-            Opcode::ABCD { .. } | Opcode::NBCD { .. } | Opcode::SBCD { .. } => 0b1111111111110101,
-            _ => 0b1111111111111111,
-        };
-        if let Opcode::ILLEGAL = cpu.peek_opcode() {
-            continue;
+    emu::suite::run_m68k_json(&test_cases);
+}
+
+#[test]
+fn movep_timing() {
+    run_cycle_test(
+        &[0x01C8, 0x0010, 0x0188, 0x0018, 0x0348, 0x0010, 0x0508, 0x0018],
+        [0x12345678, 0, 0xFFFFFFFF, 0, 0, 0, 0, 0],
+        [0x2000, 0, 0, 0, 0, 0, 0, 0],
+        &[24, 16, 24, 16],
+        &mut |cpu| {
+            assert_eq!(0x12, cpu.peek_ram(0x2010) >> 8);
+            assert_eq!(0x34, cpu.peek_ram(0x2012) >> 8);
+            assert_eq!(0x56, cpu.peek_ram(0x2014) >> 8);
+            assert_eq!(0x78, cpu.peek_ram(0x2016) >> 8);
+            assert_eq!(0x56, cpu.peek_ram(0x2018) >> 8);
+            assert_eq!(0x78, cpu.peek_ram(0x201A) >> 8);
+            assert_eq!(0x12345678, cpu.d_for_test(1));
+            assert_eq!(0xFFFF5678, cpu.d_for_test(2));
+        },
+    );
+}
+
+#[test]
+fn move_sr_ccr_timing() {
+    run_cycle_test(
+        &[
+            0x46FC, 0xFFFF, 0x40C1, 0x46C0, 0x44FC, 0xFFFF, 0x40C2, 0x44C3, 0x40D0, 0x023C, 0x0000,
+            0x44D0, 0x40C4, 0x0A7C, 0x0010, 0x40C5,
+        ],
+        [0x2700, 0xFFFF0000, 0, 0xFF15, 0, 0xFFFFFFFF, 0, 0],
+        [0x2000, 0, 0, 0, 0, 0, 0, 0],
+        &[16, 6, 12, 16, 6, 12, 12, 20, 16, 6, 20, 6],
+        &mut |cpu| {
+            // only implemented bits are settable, and CCR writes leave the system byte alone
+            assert_eq!(0xFFFFA71F, cpu.d_for_test(1));
+            assert_eq!(0x271F, cpu.d_for_test(2));
+            assert_eq!(0x2715, cpu.peek_ram(0x2000));
+            assert_eq!(0x2715, cpu.d_for_test(4));
+            assert_eq!(0xFFFF2705, cpu.d_for_test(5));
+        },
+    );
+}
+
+#[test]
+fn dbcc_timing() {
+    run_cycle_test(
+        &[0x50C8, 0x0010, 0x51C9, 0x0002, 0x51CA, 0x0002, 0x51CB, 0xFFF2],
+        [0, 0x12340000, 0xABCD0002, 0x00008000, 0, 0, 0, 0],
+        [0; 8],
+        &[12, 14, 10, 10],
+        &mut |cpu| {
+            assert_eq!(0, cpu.d_for_test(0));
+            assert_eq!(0x1234FFFF, cpu.d_for_test(1));
+            assert_eq!(0xABCD0001, cpu.d_for_test(2));
+            // only the low word counts down, and 0x8000 wraps to 0x7FFF without expiring
+            assert_eq!(0x00007FFF, cpu.d_for_test(3));
+            assert_eq!(0x100, cpu.pc_for_test());
+        },
+    );
+}
+
+#[test]
+fn mul_timing() {
+    run_cycle_test(
+        &[0xC0C1, 0xC8C2, 0xCAC3, 0xCDC2, 0xCFC3, 0xC3FC, 0x8000],
+        [2, 0, 0xFFFF, 0x5555, 2, 2, 2, 2],
+        [0; 8],
+        // MULU is 38 + 2 per set bit, MULS 38 + 2 per 01/10 pair, plus effective address time
+        &[38, 70, 54, 40, 70, 44],
+        &mut |cpu| {
+            assert_eq!(0, cpu.d_for_test(0));
+            assert_eq!(0, cpu.d_for_test(1));
+            assert_eq!(0x1FFFE, cpu.d_for_test(4));
+            assert_eq!(0xAAAA, cpu.d_for_test(5));
+            assert_eq!(0xFFFFFFFE, cpu.d_for_test(6));
+            assert_eq!(0xAAAA, cpu.d_for_test(7));
+        },
+    );
+}
+
+#[test]
+fn scc_clr_timing() {
+    run_cycle_test(
+        &[0x50C0, 0x51C0, 0x50D0, 0x51D0, 0x4210, 0x4281],
+        [0x12345678, 0xFFFFFFFF, 0, 0, 0, 0, 0, 0],
+        [0x3000, 0, 0, 0, 0, 0, 0, 0],
+        // setting a data register takes 2 more cycles; a memory destination is read, then
+        // written, whether the condition holds or not
+        &[6, 4, 12, 12, 12, 6],
+        &mut |cpu| {
+            assert_eq!(0x12345600, cpu.d_for_test(0));
+            assert_eq!(0, cpu.d_for_test(1));
+            assert_eq!(0, cpu.peek_ram(0x3000));
+        },
+    );
+}
+
+#[test]
+fn clr_reads_before_writing() {
+    // clr.w $C00000
+    let mut cartridge = vec![0; 0x200];
+    cartridge[0x100..0x106].copy_from_slice(&[0x42, 0x79, 0x00, 0xC0, 0x00, 0x00]);
+    let cartridge = cartridge.into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, false);
+    cpu.reset(false);
+    cpu.init_state(0x100, 0x2700, [0; 8], [0; 8], 0xFF8000);
+    vdp_bus.borrow_mut().write_word(0xC00004, 0x8F02);
+    vdp_bus.borrow_mut().write_long(0xC00004, 0x00000000);
+    cpu.next_operation(&[emu::input::player_1_gen(), emu::input::player_2_gen()]);
+    // reading the data port in VRAM read mode advances the address; the write doesn't
+    assert_eq!(2, vdp_bus.borrow().addr.unwrap().addr);
+}
+
+#[test]
+fn reset_registers() {
+    // initial SSP $FFFE00, initial PC $000200
+    let mut cartridge = vec![0; 0x200];
+    cartridge[..8].copy_from_slice(&[0x00, 0xFF, 0xFE, 0x00, 0x00, 0x00, 0x02, 0x00]);
+    let cartridge = cartridge.into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, false);
+    assert!(cpu
+        .registers()
+        .ends_with(" SSP:00FFFE00 USP:00000000 SR:2700 PC:000200 cyc:0"));
+    // trace on, user mode, interrupts unmasked
+    cpu.init_state(0x1234, 0x801F, [0; 8], [0; 8], 0);
+    cpu.reset(false);
+    // only the condition codes are kept
+    assert!(cpu
+        .registers()
+        .contains(" SSP:00FFFE00 USP:00000000 SR:271F PC:000200 "));
+}
+
+#[test]
+fn long_access_wraps_at_top_of_address_space() {
+    // move.l #$11223344,$FFFFFE; move.l $FFFFFE,d0
+    let program = [
+        0x23, 0xFC, 0x11, 0x22, 0x33, 0x44, 0x00, 0xFF, 0xFF, 0xFE, //
+        0x20, 0x39, 0x00, 0xFF, 0xFF, 0xFE,
+    ];
+    let mut cartridge = vec![0; 0x200];
+    cartridge[..4].copy_from_slice(&[0x00, 0xFF, 0xFE, 0x00]);
+    cartridge[0x100..0x100 + program.len()].copy_from_slice(&program);
+    let cartridge = cartridge.into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, false);
+    cpu.reset(false);
+    cpu.init_state(0x100, 0x2700, [0; 8], [0; 8], 0xFF8000);
+    let inputs = [emu::input::player_1_gen(), emu::input::player_2_gen()];
+    cpu.next_operation(&inputs);
+    cpu.next_operation(&inputs);
+    // the upper word is in work RAM; the lower one wrapped around to ROM, which ignored the write
+    assert!(cpu.registers().starts_with("D0:112200FF "));
+}
+
+#[test]
+fn z80_clock_ratio() {
+    // move.w #$100,$A11200 to release the Z80 from reset, 150 NOPs, stop #$2000
+    let mut program = vec![0x33, 0xFC, 0x01, 0x00, 0x00, 0xA1, 0x12, 0x00];
+    for _ in 0..150 {
+        program.extend(&[0x4E, 0x71]);
+    }
+    program.extend(&[0x4E, 0x72, 0x20, 0x00]);
+    let mut cartridge = vec![0; 0x400];
+    cartridge[0x100..0x100 + program.len()].copy_from_slice(&program);
+    let cartridge = cartridge.into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, false);
+    cpu.reset(false);
+    cpu.init_state(0x100, 0x2700, [0; 8], [0; 8], 0xFF8000);
+    let inputs = [emu::input::player_1_gen(), emu::input::player_2_gen()];
+    cpu.next_operation(&inputs);
+    let counts = |cpu: &emu::gen::m68k::Cpu| {
+        (
+            cpu.master_clock_for_test(),
+            cpu.cycle_count_for_test(),
+            cpu.z80_cycle_count_for_test(),
+        )
+    };
+
+    // 600 68000 cycles are 4200 master clocks, which are 280 Z80 cycles
+    let (master, m68k, z80) = counts(&cpu);
+    for _ in 0..150 {
+        cpu.next_operation(&inputs);
+    }
+    let (master_after, m68k_after, z80_after) = counts(&cpu);
+    assert_eq!(4200, master_after - master);
+    assert_eq!(600, m68k_after - m68k);
+    assert_eq!(280, z80_after - z80);
+
+    // the Z80 keeps running while the 68000 waits in STOP
+    cpu.next_operation(&inputs);
+    let (master, _, z80) = counts(&cpu);
+    for _ in 0..15 {
+        cpu.next_operation(&inputs);
+    }
+    let (master_after, _, z80_after) = counts(&cpu);
+    assert_eq!(420, master_after - master);
+    assert_eq!(28, z80_after - z80);
+    assert_eq!(0x100 + program.len() as u32, cpu.pc_for_test());
+}
+
+#[test]
+fn exception_return_addresses() {
+    // TRAP #0: the next instruction is stacked, so RTE resumes after the TRAP
+    run_exception_test(&[0x4E40, 0x4E71], [0; 8], 32, &[0x4E73], 2, &mut |cpu| {
+        assert_eq!(0x102, cpu.peek_ram_long(0x7FFC));
+        assert_eq!(0x102, cpu.pc_for_test());
+    });
+    // from user mode the frame goes on the supervisor stack, and RTE takes it off again before
+    // switching back
+    run_exception_test(
+        &[0x027C, 0xDFFF, 0x4E40, 0x4E71],
+        [0; 8],
+        32,
+        &[0x4E73],
+        3,
+        &mut |cpu| {
+            assert_eq!(0x106, cpu.peek_ram_long(0x7FFC));
+            assert_eq!(0x106, cpu.pc_for_test());
+            assert_eq!(0, cpu.a_for_test(7));
+        },
+    );
+    // CHK #5,D0 out of bounds: after the immediate operand
+    run_exception_test(
+        &[0x41BC, 0x0005],
+        [10, 0, 0, 0, 0, 0, 0, 0],
+        6,
+        &[0x4E73],
+        2,
+        &mut |cpu| {
+            assert_eq!(0x104, cpu.peek_ram_long(0x7FFC));
+            assert_eq!(0x104, cpu.pc_for_test());
+        },
+    );
+    // DIVU D1,D0 by zero
+    run_exception_test(&[0x80C1], [0; 8], 5, &[0x4E73], 2, &mut |cpu| {
+        assert_eq!(0x102, cpu.peek_ram_long(0x7FFC));
+        assert_eq!(0x102, cpu.pc_for_test());
+    });
+    // ILLEGAL stacks its own address; the handler steps past it with ADDQ.L #2,2(A7)
+    run_exception_test(
+        &[0x4AFC, 0x4E71],
+        [0; 8],
+        4,
+        &[0x54AF, 0x0002, 0x4E73],
+        3,
+        &mut |cpu| {
+            assert_eq!(0x102, cpu.peek_ram_long(0x7FFC));
+            assert_eq!(0x102, cpu.pc_for_test());
+        },
+    );
+    // line A and line F instructions have their own vectors and also stack their own address
+    run_exception_test(&[0xA000], [0; 8], 10, &[0x4E73], 1, &mut |cpu| {
+        assert_eq!(0x100, cpu.peek_ram_long(0x7FFC));
+        assert_eq!(0x200, cpu.pc_for_test());
+    });
+    run_exception_test(&[0xF000], [0; 8], 11, &[0x4E73], 1, &mut |cpu| {
+        assert_eq!(0x100, cpu.peek_ram_long(0x7FFC));
+        assert_eq!(0x200, cpu.pc_for_test());
+    });
+}
+
+// runs the program at 0x100 with the handler for vector at 0x200 and the stack at 0x8000
+fn run_exception_test(
+    program: &[u16],
+    d: [u32; 8],
+    vector: u32,
+    handler: &[u16],
+    instructions: usize,
+    verify: &mut dyn FnMut(&mut emu::gen::m68k::Cpu),
+) {
+    let cartridge = vec![0; 8].into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, false);
+    cpu.expand_ram(0x1000000);
+    cpu.reset(false);
+    cpu.init_state(0x100, 0x2700, d, [0; 8], 0x8000);
+    for (i, byte) in 0x200u32.to_be_bytes().iter().enumerate() {
+        cpu.poke_ram(vector * 4 + i as u32, *byte);
+    }
+    for &(start, words) in &[(0x100, program), (0x200, handler)] {
+        for (i, word) in words.iter().enumerate() {
+            cpu.poke_ram(start + i as u32 * 2, (word >> 8) as u8);
+            cpu.poke_ram(start + 1 + i as u32 * 2, (word & 0xFF) as u8);
         }
+    }
+    for _ in 0..instructions {
         cpu.next_operation(&[emu::input::player_1_gen(), emu::input::player_2_gen()]);
-        let final_state = &test_case["final state"];
-        let test_id = format!(
-            "{}  {}",
-            test_case["name"].as_str().unwrap(),
-            cpu.peek_opcode()
-        );
-        cpu.verify_state(
-            final_state["pc"].as_u32().unwrap(),
-            final_state["sr"].as_u16().unwrap(),
-            [
-                final_state["d0"].as_u32().unwrap(),
-                final_state["d1"].as_u32().unwrap(),
-                final_state["d2"].as_u32().unwrap(),
-                final_state["d3"].as_u32().unwrap(),
-                final_state["d4"].as_u32().unwrap(),
-                final_state["d5"].as_u32().unwrap(),
-                final_state["d6"].as_u32().unwrap(),
-                final_state["d7"].as_u32().unwrap(),
-            ],
-            [
-                final_state["a0"].as_u32().unwrap(),
-                final_state["a1"].as_u32().unwrap(),
-                final_state["a2"].as_u32().unwrap(),
-                final_state["a3"].as_u32().unwrap(),
-                final_state["a4"].as_u32().unwrap(),
-                final_state["a5"].as_u32().unwrap(),
-                final_state["a6"].as_u32().unwrap(),
-                final_state["usp"].as_u32().unwrap(),
-            ],
-            final_state["a7"].as_u32().unwrap(),
-            sr_mask,
-            &test_id,
+    }
+    verify(&mut cpu);
+}
+
+fn run_cycle_test(
+    program: &[u16],
+    d: [u32; 8],
+    a: [u32; 8],
+    expected_cycles: &[u64],
+    verify: &mut dyn FnMut(&mut emu::gen::m68k::Cpu),
+) {
+    let cartridge = vec![0; 8].into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, false);
+    cpu.expand_ram(0x1000000);
+    cpu.reset(false);
+    cpu.init_state(0x100, 0x2700, d, a, 0x8000);
+    for (i, word) in program.iter().enumerate() {
+        cpu.poke_ram(0x100 + i as u32 * 2, (word >> 8) as u8);
+        cpu.poke_ram(0x101 + i as u32 * 2, (word & 0xFF) as u8);
+    }
+    for (i, cycles) in expected_cycles.iter().enumerate() {
+        let start = cpu.cycle_count_for_test();
+        let opcode = cpu.peek_opcode();
+        cpu.next_operation(&[emu::input::player_1_gen(), emu::input::player_2_gen()]);
+        assert_eq!(
+            *cycles,
+            cpu.cycle_count_for_test() - start,
+            "instruction {} {}",
+            i,
+            opcode
         );
-        let final_memory: Tuples<Members, (&JsonValue, &JsonValue)> =
-            test_case["final memory"].members().tuples();
-        for (addr, val) in final_memory {
-            if addr.as_i32().unwrap_or(-1) == -1 {
-                break;
-            }
-            cpu.verify_ram(addr.as_u32().unwrap(), val.as_u8().unwrap(), &test_id);
-        }
     }
+    verify(&mut cpu);
+}
+
+#[cfg(feature = "dev")]
+#[test]
+fn inject_and_enter_work_ram() {
+    let cartridge = vec![0; 8].into_boxed_slice();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge, None, &vdp_bus, false);
+    cpu.inject(0xFF0000, &[0x70, 0x05, 0x52, 0x80]);
+    cpu.set_entry(0xFF0000);
+    cpu.next_operation(&[emu::input::player_1_gen(), emu::input::player_2_gen()]);
+    cpu.next_operation(&[emu::input::player_1_gen(), emu::input::player_2_gen()]);
+    assert_eq!(6, cpu.d_for_test(0));
+    assert_eq!(0xFF0004, cpu.pc_for_test());
 }
 
 #[test]
@@ -317,3 +596,221 @@ fn test_bcd_verifier() {
     assert_eq!(0, cpu.peek_ram_long(0xFFFFFF10), "NBCD flags");
     assert_eq!(0, cpu.peek_ram_long(0xFFFFFF14), "NBCD values");
 }
+
+#[test]
+fn test_sram_mapping() {
+    let mut rom = vec![0; 0x400];
+    rom[0x100..0x104].copy_from_slice(b"SEGA");
+    rom[0x1B0..0x1BC].copy_from_slice(&[
+        b'R', b'A', 0xF8, 0x20, 0x00, 0x20, 0x00, 0x01, 0x00, 0x20, 0x3F, 0xFF,
+    ]);
+    let cartridge =
+        emu::gen::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let sram = cartridge.sram.unwrap();
+    assert_eq!(0x200001, sram.start);
+    assert_eq!(0x2000, sram.data.len());
+    assert_eq!(0x203FFF, sram.end());
+    assert!(sram.battery);
+
+    let overrides = RamOverrides {
+        cart_ram: Some(0x10000),
+        sram: None,
+    };
+    let cartridge = emu::gen::load_cartridge(&mut rom.as_slice(), None, &overrides).unwrap();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge.rom, None, &vdp_bus, false);
+    cpu.attach_sram(cartridge.sram.unwrap());
+    cpu.poke(0x200001, 0x5A);
+    cpu.poke(0x200002, 0x3C);
+    cpu.poke(0x200003, 0xC3);
+    cpu.poke(0x220001, 0xA5);
+    assert_eq!(0x5A, cpu.peek(0x200001));
+    assert_eq!(0, cpu.peek(0x200002));
+    assert_eq!(0xC3, cpu.peek(0x200003));
+    assert_eq!(0, cpu.peek(0x220001));
+    let mut save = Vec::new();
+    assert_eq!(0x10000, cpu.save_to_battery(&mut save).unwrap());
+    // only the odd bytes are stored, one after another
+    assert_eq!([0x5A, 0xC3, 0], save[..3]);
+
+    // without an "RA" block, RAM only comes from an override and starts at 0x200000
+    rom[0x1B0] = 0;
+    assert!(emu::gen::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default())
+        .unwrap()
+        .sram
+        .is_none());
+    let overrides = RamOverrides {
+        cart_ram: None,
+        sram: Some(0x800),
+    };
+    let sram = emu::gen::load_cartridge(&mut rom.as_slice(), None, &overrides)
+        .unwrap()
+        .sram
+        .unwrap();
+    assert_eq!(0x200000, sram.start);
+    assert_eq!(0x800, sram.data.len());
+    assert!(sram.battery);
+}
+
+#[test]
+fn test_sram_round_trip() {
+    let mut rom = vec![0; 0x400];
+    rom[0x100..0x104].copy_from_slice(b"SEGA");
+    rom[0x1B0..0x1BC].copy_from_slice(&[
+        b'R', b'A', 0xF8, 0x20, 0x00, 0x20, 0x00, 0x01, 0x00, 0x20, 0x3F, 0xFF,
+    ]);
+    let path = std::env::temp_dir().join(format!("emu_gen_sram_{}.sav", std::process::id()));
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+
+    let cartridge =
+        emu::gen::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge.rom, None, &vdp_bus, false);
+    cpu.attach_sram(cartridge.sram.unwrap());
+    cpu.poke(0x200001, 0x5A);
+    let mut save = Vec::new();
+    cpu.save_to_battery(&mut save).unwrap();
+    write_save(&path, &save);
+
+    // the next session starts from the file
+    let mut file = fs::File::open(&path).unwrap();
+    let cartridge = emu::gen::load_cartridge(
+        &mut rom.as_slice(),
+        Some(&mut file),
+        &RamOverrides::default(),
+    )
+    .unwrap();
+    fs::remove_file(&path).unwrap();
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge.rom, None, &vdp_bus, false);
+    cpu.attach_sram(cartridge.sram.unwrap());
+    assert_eq!(0x5A, cpu.peek(0x200001));
+}
+
+#[test]
+fn test_peek_has_no_side_effects() {
+    let mut rom = vec![0; 0x400];
+    rom[0x100..0x104].copy_from_slice(b"SEGA");
+    rom[0x200] = 0x4E;
+    let cartridge =
+        emu::gen::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge.rom, None, &vdp_bus, false);
+    cpu.poke(0xFF0010, 0x42);
+    // a VRAM read command, so a read of the data port would advance the address
+    vdp_bus.borrow_mut().write_long(0xC00004, 0);
+    let mut before = Vec::new();
+    vdp_bus.borrow().save_state(&mut before);
+    assert_eq!(0x42, cpu.peek(0xFF0010));
+    assert_eq!(0x4E, cpu.peek(0x200));
+    for addr in 0xC00000..0xC00010 {
+        cpu.peek(addr);
+    }
+    let mut after = Vec::new();
+    vdp_bus.borrow().save_state(&mut after);
+    assert_eq!(before, after);
+}
+
+#[test]
+fn test_state_layout() {
+    let mut rom = vec![0; 0x400];
+    rom[0x100..0x104].copy_from_slice(b"SEGA");
+    let cartridge =
+        emu::gen::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let bus_a = RefCell::new(VdpBus::new(false));
+    let bus_b = RefCell::new(VdpBus::new(false));
+    fn boot<'a>(rom: &'a Box<[u8]>, bus: &'a RefCell<VdpBus>) -> emu::gen::m68k::Cpu<'a> {
+        let vdp = Vdp::new::<NoWindow>(bus, None, RenderSettings::default(), false, false);
+        emu::gen::m68k::Cpu::boot(rom, Some(vdp), bus, false)
+    }
+    let a = boot(&cartridge.rom, &bus_a);
+    let mut b = boot(&cartridge.rom, &bus_b);
+
+    let mut state = Vec::new();
+    a.save_state(&mut state);
+    let layout = a.state_layout();
+    assert_eq!(
+        state.len(),
+        layout.iter().map(|&(_, len)| len).sum::<usize>()
+    );
+    assert_eq!(None, diff_states(&a, &b));
+    b.poke(0xFF0123, 0x42);
+    assert_eq!(Some("RAM[0x0123] differs".to_string()), diff_states(&a, &b));
+}
+
+#[test]
+fn test_do_frame_exact() {
+    let mut rom = vec![0; 0x400];
+    rom[0..8].copy_from_slice(&[0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+    rom[0x100..0x104].copy_from_slice(b"SEGA");
+    let program: &[u16] = &[
+        0x5279, 0x00FF, 0x0000, // ADDQ.W #1, $FF0000
+        0x60F8, // BRA.S $200
+    ];
+    for (i, word) in program.iter().enumerate() {
+        rom[0x200 + i * 2..0x202 + i * 2].copy_from_slice(&word.to_be_bytes());
+    }
+    let cartridge =
+        emu::gen::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge.rom, None, &vdp_bus, false);
+    let inputs = [player_1_gen(), player_2_gen()];
+    let mut start = Vec::new();
+    cpu.save_state(&mut start);
+    let start_cycles = cpu.cycle_count_for_test();
+
+    // the speed setting doesn't change how far a frame goes
+    let mut states = Vec::new();
+    for &speed in &[1.0, 2.5] {
+        cpu.load_state(&mut start.as_slice());
+        cpu.set_speed(speed);
+        for _ in 0..3 {
+            cpu.do_frame_exact(&inputs, false);
+        }
+        // the speed is part of the state
+        cpu.set_speed(1.0);
+        let mut state = Vec::new();
+        cpu.save_state(&mut state);
+        states.push(state);
+        // 896,040 master clock ticks a frame at 7 a CPU cycle, overshot by at most the
+        // instruction that crosses the end
+        let cycles = cpu.cycle_count_for_test() - start_cycles;
+        assert!(cycles >= 384_017 && cycles < 384_017 + 40, "{}", cycles);
+    }
+    assert_eq!(states[0], states[1]);
+    assert_ne!(start, states[0]);
+    // thousands of trips round the loop, so the high byte of the count has moved
+    assert_ne!(0, cpu.peek(0xFF0000));
+}
+
+#[test]
+fn test_trace_on_change() {
+    let mut rom = vec![0; 0x400];
+    rom[0..8].copy_from_slice(&[0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+    rom[0x100..0x104].copy_from_slice(b"SEGA");
+    let program: &[u16] = &[
+        0x13FC, 0x0000, 0x00FF, 0x0100, // MOVE.B #0, $FF0100
+        0x13FC, 0x0005, 0x00FF, 0x0100, // MOVE.B #5, $FF0100
+        0x4E71, // NOP
+        0x4E71, // NOP
+        0x4E71, // NOP
+    ];
+    for (i, word) in program.iter().enumerate() {
+        rom[0x200 + i * 2..0x202 + i * 2].copy_from_slice(&word.to_be_bytes());
+    }
+    let path = std::env::temp_dir().join(format!("emu_gen_trace_{}.trace", std::process::id()));
+    let cartridge =
+        emu::gen::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let vdp_bus = RefCell::new(VdpBus::new(false));
+    let mut cpu = emu::gen::m68k::Cpu::boot(&cartridge.rom, None, &vdp_bus, false);
+    let trace = Trace::create(&path, TraceStart::Change(0xFF0100), 2, None).unwrap();
+    cpu.enable_trace(trace);
+    let inputs = [player_1_gen(), player_2_gen()];
+    for _ in 0..5 {
+        cpu.next_operation(&inputs);
+    }
+    drop(cpu);
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    // writing the value that's already there isn't a change
+    let pcs = contents.lines().map(|line| &line[..6]).collect::<Vec<_>>();
+    assert_eq!(vec!["000210", "000212"], pcs);
+}
@@ -0,0 +1,63 @@
+extern crate emu;
+extern crate piston_window;
+
+use std::path::Path;
+
+use piston_window::*;
+
+use emu::input::{player_1_nes, player_2_nes, Input as ControllerInput};
+use emu::menu::{Menu, NES_CONTROLS};
+
+fn release(key: Key) -> Event {
+    Event::Input(
+        Input::Button(ButtonArgs {
+            state: ButtonState::Release,
+            button: Button::Keyboard(key),
+            scancode: None,
+        }),
+        None,
+    )
+}
+
+fn axis(position: f64) -> Event {
+    Event::Input(
+        Input::Move(Motion::ControllerAxis(ControllerAxisArgs {
+            id: 0,
+            axis: 1,
+            position,
+        })),
+        None,
+    )
+}
+
+#[test]
+fn test_remap_cancel_and_conflicts() {
+    let mut controls = [player_1_nes(), player_2_nes()];
+    let mut menu = Menu::new(NES_CONTROLS, &controls, Path::new("no_such_settings.dat"));
+    assert!(menu.conflicts().is_empty());
+    assert!(menu.event(&release(Key::Escape)));
+
+    // Escape while waiting for an input cancels without binding it or closing the menu
+    menu.event(&release(Key::Return));
+    assert!(menu.event(&release(Key::Escape)));
+    menu.update_controls(&mut controls);
+    assert!(controls[0].buttons()[4] == ControllerInput::Button(Button::Keyboard(Key::Up)));
+
+    // player 2's Up is already W
+    menu.event(&release(Key::Return));
+    menu.event(&release(Key::W));
+    menu.update_controls(&mut controls);
+    assert!(controls[0].buttons()[4] == ControllerInput::Button(Button::Keyboard(Key::W)));
+    assert_eq!(vec![0, 8], menu.conflicts());
+
+    // a stick is only captured once it's pushed past halfway
+    menu.event(&release(Key::Down));
+    menu.event(&release(Key::Return));
+    menu.event(&axis(0.3));
+    menu.event(&axis(-0.9));
+    menu.update_controls(&mut controls);
+    match controls[0].buttons()[5] {
+        ControllerInput::Axis(args) => assert!(args.axis == 1 && args.position < 0.0),
+        _ => panic!("Down wasn't bound to the axis"),
+    }
+}
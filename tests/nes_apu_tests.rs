@@ -1,5 +1,21 @@
 extern crate emu;
 
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use emu::audio::{AudioSink, BufferStats};
+use emu::audio::filter::AudioFilter;
+use emu::audio::pull::SampleBuffer;
+use emu::audio::sync::{downsample, ticks_per_sample};
+use emu::audio::wav::WavSink;
+use emu::nes::apu::Apu;
+use emu::nes::apu::bus::ApuBus;
+use emu::nes::cartridge::Cartridge;
+use emu::nes::cpu::disassembler;
+use emu::rom::RamOverrides;
+use nes_test::nrom;
 use nes_test::run_test_until_memory_matches;
 
 mod nes_test;
@@ -185,3 +201,669 @@ fn apu_test_8_dmc_rates() {
         &[(0x6000, 0)],
     );
 }
+
+#[test]
+fn wav_recording_header() {
+    let path = std::env::temp_dir().join("emu_wav_recording_header.wav");
+    let mut sink = WavSink::create(&path, 44_100, 4.0).unwrap();
+    sink.push_samples(&[0.5; 400]);
+    sink.push_samples(&[-0.5; 2]);
+    assert_eq!(None, sink.buffer_stats());
+    sink.close();
+
+    let wav = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let u32_at = |offset: usize| u32::from_le_bytes([wav[offset], wav[offset + 1], wav[offset + 2], wav[offset + 3]]);
+    assert_eq!(b"RIFF", &wav[0..4]);
+    assert_eq!(wav.len() as u32 - 8, u32_at(4));
+    assert_eq!(44_100, u32_at(24));
+    assert_eq!(b"data", &wav[36..40]);
+    assert_eq!(100 * 2, u32_at(40));
+    assert_eq!(44 + 100 * 2, wav.len());
+    assert_eq!(i16::MAX / 2, i16::from_le_bytes([wav[44], wav[45]]));
+}
+
+#[test]
+fn audio_buffer_stats() {
+    let stats = BufferStats::default();
+    assert_eq!((0, 0), stats.get());
+    stats.underrun();
+    stats.underrun();
+    stats.overrun();
+    assert_eq!((2, 1), stats.get());
+}
+
+#[test]
+fn audio_rate_control() {
+    let nominal = emu::nes::apu::TICKS_PER_SAMPLE;
+    assert_eq!(nominal, ticks_per_sample(nominal, 1000, 1000));
+    // a fuller buffer is drained faster, an emptier one slower, by at most half a percent
+    assert!(ticks_per_sample(nominal, 1500, 1000) > nominal);
+    assert!(ticks_per_sample(nominal, 500, 1000) < nominal);
+    assert!((ticks_per_sample(nominal, 100_000, 1000) - nominal * 1.005).abs() < 1e-9);
+    assert!((ticks_per_sample(nominal, 0, 1000) - nominal * 0.995).abs() < 1e-9);
+
+    let mut output = [0.0; 3];
+    downsample(&[1.0, 1.0, 0.0, 0.0, -1.0, -1.0, -1.0], &mut output);
+    assert_eq!([1.0, 0.0, -1.0], output);
+}
+
+#[test]
+fn audio_filters() {
+    let rate = emu::nes::apu::MIX_HZ;
+    // how much a sine wave at `hz` is scaled by, once it's had a second to settle
+    let gain = |filter: AudioFilter, hz: f64| -> f32 {
+        let mut chain = filter.chain(rate as f32, &[1000.0], Some(1000.0));
+        (0..rate as usize * 3 / 2)
+            .map(|i| {
+                let phase = 2.0 * std::f64::consts::PI * hz * i as f64 / rate;
+                chain.process(phase.sin() as f32)
+            })
+            .skip(rate as usize)
+            .fold(0.0, |peak: f32, sample| peak.max(sample.abs()))
+    };
+
+    // the raw mix passes through, DC and all
+    let mut raw = AudioFilter::None.chain(rate as f32, &[1000.0], Some(1000.0));
+    assert_eq!(0.5, raw.process(0.5f32));
+    assert_eq!(0.5, raw.process(0.5f32));
+
+    // the NES filters settle a constant level back to zero, cut the lowest and highest
+    // frequencies, and leave the middle of the range mostly alone
+    let mut ntsc = AudioFilter::Ntsc.chain(rate as f32, &[], None);
+    let settled: f32 = (0..rate as usize)
+        .map(|_| ntsc.process(0.5))
+        .last()
+        .unwrap();
+    assert!(settled.abs() < 0.001, "{}", settled);
+    assert!(gain(AudioFilter::Ntsc, 50.0) < 0.1);
+    assert!(gain(AudioFilter::Ntsc, 2000.0) > 0.9);
+    assert!(gain(AudioFilter::Ntsc, 40_000.0) < 0.5);
+
+    // custom cutoffs both at 1kHz cut the frequencies on either side
+    assert!(gain(AudioFilter::Custom, 100.0) < 0.2);
+    assert!(gain(AudioFilter::Custom, 10_000.0) < 0.2);
+
+    // the Genesis only has a low-pass, so a constant level stays, and high notes are duller
+    let mut genesis = AudioFilter::Genesis.chain(rate as f32, &[], None);
+    let settled: f32 = (0..rate as usize)
+        .map(|_| genesis.process(0.5))
+        .last()
+        .unwrap();
+    assert!((settled - 0.5).abs() < 0.001, "{}", settled);
+    assert!(gain(AudioFilter::Genesis, 200.0) > 0.95);
+    assert!(gain(AudioFilter::Genesis, 15_000.0) < 0.3);
+
+    // each channel of a frame is filtered on its own
+    let mut stereo = AudioFilter::Ntsc.chain(rate as f32, &[], None);
+    let mut mono = AudioFilter::Ntsc.chain(rate as f32, &[], None);
+    for i in 0..100 {
+        let level = (i % 7) as f32 / 7.0;
+        let [left, right] = stereo.process([level, 0.0]);
+        assert_eq!(mono.process(level), left);
+        assert_eq!(0.0, right);
+    }
+}
+
+struct SampleCapture(Rc<RefCell<Vec<f32>>>);
+
+impl AudioSink for SampleCapture {
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.0.borrow_mut().extend_from_slice(samples);
+    }
+}
+
+// an APU bus with an empty NROM cartridge to tick against, and the samples mixed by the APUs made
+// on it
+struct ApuFixture {
+    cartridge: Cartridge,
+    bus: RefCell<ApuBus>,
+    samples: Rc<RefCell<Vec<f32>>>,
+}
+
+impl ApuFixture {
+    fn new() -> ApuFixture {
+        let rom = nrom(&[]);
+        ApuFixture {
+            cartridge: emu::nes::load_cartridge(
+                &mut rom.as_slice(),
+                None,
+                &RamOverrides::default(),
+            )
+            .unwrap(),
+            bus: RefCell::new(ApuBus::new()),
+            samples: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    fn apu(&self) -> Apu {
+        let sinks: Vec<Box<dyn AudioSink>> = vec![Box::new(SampleCapture(self.samples.clone()))];
+        Apu::new(&self.bus, sinks)
+    }
+
+    fn write(&self, writes: &[(u16, u8)]) {
+        for &(address, value) in writes {
+            self.bus.borrow_mut().write(address, value);
+        }
+    }
+
+    // the samples mixed over that many CPU cycles
+    fn run(&self, apu: &mut Apu, cpu_cycles: usize) -> Vec<f32> {
+        let start = self.samples.borrow().len();
+        for _ in 0..cpu_cycles {
+            apu.tick(&self.cartridge.cpu_bus);
+        }
+        self.samples.borrow()[start..].to_vec()
+    }
+}
+
+// the output sample `t` mixed samples in, interpolated between the two either side of it
+fn interpolated(samples: &[f32], t: f64) -> f32 {
+    let n = t.floor() as usize;
+    let (left, right) = (samples[n] as f64, samples[n + 1] as f64);
+    (left + (right - left) * (t - n as f64)) as f32
+}
+
+#[test]
+fn drain_samples() {
+    let fixture = ApuFixture::new();
+    let mut apu = fixture.apu();
+    let mut out = [0.0; 20];
+    assert_eq!(0, apu.drain_samples(&mut out));
+
+    let buffer = Arc::new(Mutex::new(SampleBuffer::new(10.5)));
+    apu.enable_sample_buffer(buffer.clone());
+    // a triangle wave, so the mix isn't flat
+    fixture.write(&[
+        (0x4015, 0x04),
+        (0x4008, 0xFF),
+        (0x400A, 0x10),
+        (0x400B, 0x08),
+    ]);
+    // one mixed sample every other CPU cycle
+    let mut samples = fixture.run(&mut apu, 220);
+    assert_eq!(110, samples.len());
+
+    // an output sample needs the mixed samples either side of it, so the 12th, at 115.5, waits
+    // for the next call
+    assert_eq!(11, apu.drain_samples(&mut out));
+    for (i, &sample) in out[..11].iter().enumerate() {
+        let expected = interpolated(&samples, 10.5 * i as f64);
+        assert!((sample - expected).abs() < 1e-6, "sample {}", i);
+    }
+    assert_eq!((1, 0), buffer.lock().unwrap().stats());
+    // nothing buffered isn't an underrun
+    assert_eq!(0, apu.drain_samples(&mut out));
+    assert_eq!((1, 0), buffer.lock().unwrap().stats());
+
+    samples.extend(fixture.run(&mut apu, 20));
+    assert_eq!(1, apu.drain_samples(&mut out[..1]));
+    let expected = interpolated(&samples, 115.5);
+    assert!((out[0] - expected).abs() < 1e-6);
+}
+
+#[test]
+fn sample_buffer_drops_oldest() {
+    let mut buffer = SampleBuffer::new(1.0);
+    buffer.push(&vec![0.0; 1 << 18]);
+    assert_eq!((0, 0), buffer.stats());
+    buffer.push(&[1.0, 2.0]);
+    assert_eq!(1 << 18, buffer.len());
+    assert_eq!((0, 1), buffer.stats());
+
+    let mut out = vec![0.0; 1 << 18];
+    assert_eq!((1 << 18) - 1, buffer.drain(&mut out));
+    assert_eq!(1.0, out[(1 << 18) - 2]);
+}
+
+#[test]
+fn triangle_sequence() {
+    let fixture = ApuFixture::new();
+    let mut apu = fixture.apu();
+    let mut run = |cpu_cycles: usize| -> Vec<u8> {
+        // every other channel is silent, so each sample is the triangle's step
+        fixture
+            .run(&mut apu, cpu_cycles)
+            .iter()
+            .map(|sample| (sample / 0.00851).round() as u8)
+            .collect()
+    };
+
+    // linear counter held at its reload value, period 7 (8 CPU cycles, so 4 samples a step);
+    // switching the frame counter to 5-step mode clocks the linear counter right away
+    fixture.write(&[
+        (0x4015, 0x04),
+        (0x4008, 0xFF),
+        (0x400A, 0x07),
+        (0x400B, 0x08),
+        (0x4017, 0x80),
+    ]);
+    let before = run(4);
+    assert!(before.iter().all(|&step| step == 15), "{:?}", before);
+    let output = run(8 * 64);
+    let start = output.iter().position(|&step| step == 14).unwrap();
+    for (i, &step) in output[start..start + 128].iter().enumerate() {
+        let phase = 1 + i / 4;
+        let expected = if phase % 32 < 16 {
+            15 - phase % 32
+        } else {
+            phase % 32 - 16
+        };
+        assert_eq!(expected as u8, step, "sample {}", i);
+    }
+
+    // ultrasonic periods hold the current step instead of stepping or dropping to 0
+    let held = *output.last().unwrap();
+    fixture.write(&[(0x400A, 0x01)]);
+    let ultrasonic = run(1000);
+    assert!(
+        ultrasonic.iter().all(|&step| step == held),
+        "{:?}",
+        ultrasonic
+    );
+
+    // so does the linear counter running out: reloading it with 0 stops the sequencer
+    fixture.write(&[(0x400A, 0x07)]);
+    let output = run(40);
+    let held = *output.last().unwrap();
+    fixture.write(&[(0x4008, 0x00), (0x400B, 0x08), (0x4017, 0x80)]);
+    let stopped = run(1000);
+    assert!(stopped.iter().all(|&step| step == held), "{:?}", stopped);
+}
+
+#[test]
+fn noise_short_mode() {
+    let fixture = ApuFixture::new();
+    let mut apu = fixture.apu();
+    let mut run = |cpu_cycles: usize| -> Vec<bool> {
+        // the other channels hold still, so the noise is whatever rises above the quietest sample
+        let samples = fixture.run(&mut apu, cpu_cycles);
+        let floor = samples.iter().cloned().fold(f32::MAX, f32::min);
+        samples
+            .iter()
+            .map(|&sample| sample - floor > 0.037)
+            .collect()
+    };
+
+    // constant volume 15, length counter halted, period 4 (so 2 samples a step), short mode
+    fixture.write(&[
+        (0x4015, 0x08),
+        (0x400C, 0x3F),
+        (0x400E, 0x80),
+        (0x400F, 0x08),
+    ]);
+    run(16);
+    let short = run(4 * 93 * 4);
+    assert_eq!(Some(93 * 2), repeat_period(&short, 1000));
+
+    // a slower period stretches each step
+    fixture.write(&[(0x400E, 0x81)]);
+    run(16);
+    let slower = run(8 * 93 * 4);
+    assert_eq!(Some(93 * 4), repeat_period(&slower, 1000));
+
+    // long mode doesn't repeat for 32767 steps
+    fixture.write(&[(0x400E, 0x00)]);
+    run(16);
+    let long = run(4 * 2000);
+    assert_eq!(None, repeat_period(&long, 1000));
+}
+
+#[test]
+fn pulse_sweep() {
+    let fixture = ApuFixture::new();
+    let mut apu = fixture.apu();
+    // switching the frame counter to 5-step mode clocks the sweep units right away; this runs up to
+    // just short of the next clock, long enough for a half cycle at any unmuted period, and returns
+    // both periods and how many distinct levels the output had in the meantime
+    let mut half_frame = || -> (u16, u16, usize) {
+        fixture.write(&[(0x4017, 0x80)]);
+        let samples = fixture.run(&mut apu, 14000);
+        let mut levels: Vec<u32> = samples.iter().map(|s| s.to_bits()).collect();
+        levels.sort();
+        levels.dedup();
+        let bus = fixture.bus.borrow();
+        (bus.pulse_1.timer, bus.pulse_2.timer, levels.len())
+    };
+
+    // pulse 1 alone, constant volume 15, length counter halted, period 0x100, sweeping up by half
+    // every half frame
+    fixture.write(&[
+        (0x4015, 0x01),
+        (0x4000, 0xBF),
+        (0x4001, 0x81),
+        (0x4002, 0x00),
+        (0x4003, 0x09),
+    ]);
+    let mut periods = Vec::new();
+    for _ in 0..7 {
+        let (period, _, levels) = half_frame();
+        periods.push((period, levels));
+    }
+    // the move to 0xB64 is out of range, which mutes the channel and holds the period
+    assert_eq!(
+        vec![
+            (0x180, 2),
+            (0x240, 2),
+            (0x360, 2),
+            (0x510, 2),
+            (0x798, 1),
+            (0x798, 1),
+            (0x798, 1),
+        ],
+        periods
+    );
+    assert!(fixture.bus.borrow().pulse_1.sweep_muted());
+
+    // negating subtracts one more on pulse 1 than on pulse 2, and both stop at a period under 8
+    fixture.write(&[
+        (0x4015, 0x03),
+        (0x4001, 0x89),
+        (0x4002, 0x00),
+        (0x4003, 0x09),
+        (0x4004, 0xBF),
+        (0x4005, 0x89),
+        (0x4006, 0x00),
+        (0x4007, 0x09),
+    ]);
+    let periods: Vec<(u16, u16)> = (0..7)
+        .map(|_| {
+            let (pulse_1, pulse_2, _) = half_frame();
+            (pulse_1, pulse_2)
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            (0x7F, 0x80),
+            (0x3F, 0x40),
+            (0x1F, 0x20),
+            (0x0F, 0x10),
+            (0x07, 0x08),
+            (0x07, 0x04),
+            (0x07, 0x04),
+        ],
+        periods
+    );
+
+    // a target out of range mutes the channel even with the sweep disabled
+    fixture.write(&[
+        (0x4015, 0x01),
+        (0x4001, 0x00),
+        (0x4002, 0x00),
+        (0x4003, 0x0C),
+    ]);
+    let (period, _, levels) = half_frame();
+    assert_eq!((0x400, 1), (period, levels));
+    assert!(fixture.bus.borrow().pulse_1.sweep_muted());
+}
+
+#[test]
+fn music_mode() {
+    for &music_mode in &[false, true] {
+        let fixture = ApuFixture::new();
+        let mut apu = fixture.apu();
+        if music_mode {
+            apu.enable_music_mode(false);
+        }
+        // how many distinct levels the output had
+        let mut run = |cpu_cycles: usize| -> usize {
+            let samples = fixture.run(&mut apu, cpu_cycles);
+            let mut levels: Vec<u32> = samples.iter().map(|s| s.to_bits()).collect();
+            levels.sort();
+            levels.dedup();
+            levels.len()
+        };
+
+        // pulse 1 alone, constant volume 15, period 0xFF, length counter loaded with 2
+        fixture.write(&[
+            (0x4015, 0x01),
+            (0x4000, 0x9F),
+            (0x4002, 0xFF),
+            (0x4003, 0x18),
+        ]);
+        run(16);
+        assert_eq!(2, run(4000));
+
+        // switching the frame counter to 5-step mode clocks the length counter right away
+        for _ in 0..2 {
+            fixture.write(&[(0x4017, 0x80)]);
+            run(16);
+        }
+        assert_eq!(0, fixture.bus.borrow_mut().read_status() & 0x01);
+        assert_eq!(if music_mode { 2 } else { 1 }, run(4000), "{}", music_mode);
+
+        // disabling the channel silences it either way
+        fixture.write(&[(0x4015, 0x00)]);
+        assert_eq!(1, run(4000), "{}", music_mode);
+    }
+}
+
+#[test]
+fn music_loop() {
+    for &looping in &[false, true] {
+        let fixture = ApuFixture::new();
+        let mut apu = fixture.apu();
+        apu.enable_music_mode(looping);
+        let levels = |samples: Vec<f32>| {
+            let mut levels: Vec<u32> = samples.iter().map(|s| s.to_bits()).collect();
+            levels.sort();
+            levels.dedup();
+            levels.len()
+        };
+
+        // pulse 1 alone, its envelope decaying a step every quarter frame with no loop flag,
+        // period 0xFF, and a length counter that outlasts the test
+        fixture.write(&[
+            (0x4015, 0x01),
+            (0x4000, 0x80),
+            (0x4002, 0xFF),
+            (0x4003, 0x08),
+        ]);
+        assert!(levels(fixture.run(&mut apu, 40_000)) > 2);
+        // 16 quarter frames take the envelope down to 0
+        fixture.run(&mut apu, 120_000);
+        let after = levels(fixture.run(&mut apu, 40_000));
+        assert_eq!(looping, after > 1, "{} levels", after);
+    }
+}
+
+#[test]
+fn channel_enable_and_length_halt() {
+    let fixture = ApuFixture::new();
+    let mut apu = fixture.apu();
+    let mut run = |cpu_cycles: usize| {
+        fixture.run(&mut apu, cpu_cycles);
+    };
+    let write = |writes: &[(u16, u8)]| fixture.write(writes);
+    let status = || fixture.bus.borrow_mut().read_status() & 0x0F;
+    // the length counters of the pulse, triangle, and noise channels, loaded with 254
+    let load_lengths = [
+        (0x4003, 0x08),
+        (0x4007, 0x08),
+        (0x400B, 0x08),
+        (0x400F, 0x08),
+    ];
+
+    // all four on, none halted
+    write(&[
+        (0x4015, 0x0F),
+        (0x4000, 0x1F),
+        (0x4004, 0x1F),
+        (0x4008, 0x7F),
+        (0x400C, 0x1F),
+    ]);
+    write(&load_lengths);
+    run(16);
+    assert_eq!(0x0F, status());
+
+    // disabling silences a channel straight away, without waiting for it to tick
+    write(&[(0x4015, 0x05)]);
+    assert_eq!(0x05, status());
+
+    // enabling it again doesn't bring the note back, and neither does a load that was still
+    // waiting when it was disabled
+    write(&[(0x4015, 0x0F)]);
+    run(16);
+    assert_eq!(0x05, status());
+    write(&[
+        (0x4007, 0x08),
+        (0x400F, 0x08),
+        (0x4015, 0x05),
+        (0x4015, 0x0F),
+    ]);
+    run(16);
+    assert_eq!(0x05, status());
+
+    // length loads are ignored while a channel is off
+    write(&[(0x4015, 0x00)]);
+    write(&load_lengths);
+    write(&[(0x4015, 0x0F)]);
+    run(16);
+    assert_eq!(0x00, status());
+
+    // pulse 1 and the triangle halted, all loaded with 2; switching the frame counter to 5-step
+    // mode clocks the length counters right away
+    write(&[(0x4000, 0x3F), (0x4008, 0xFF)]);
+    write(&[
+        (0x4003, 0x18),
+        (0x4007, 0x18),
+        (0x400B, 0x18),
+        (0x400F, 0x18),
+    ]);
+    run(16);
+    assert_eq!(0x0F, status());
+    for _ in 0..2 {
+        write(&[(0x4017, 0x80)]);
+        run(16);
+    }
+    assert_eq!(0x05, status());
+
+    // releasing the halt lets pulse 1 count down from where it was frozen
+    write(&[(0x4000, 0x1F)]);
+    for _ in 0..2 {
+        write(&[(0x4017, 0x80)]);
+        run(16);
+    }
+    assert_eq!(0x04, status());
+}
+
+// the shortest shift that maps the sequence onto itself
+fn repeat_period(bits: &[bool], max: usize) -> Option<usize> {
+    (1..=max.min(bits.len() / 2))
+        .find(|&period| bits.iter().zip(&bits[period..]).all(|(a, b)| a == b))
+}
+
+#[test]
+fn vrc6_banking() {
+    for &(mapper, chr_register) in &[(24, 0xD001), (26, 0xD002)] {
+        let mut cartridge = vrc6_cartridge(mapper);
+        cartridge.cpu_bus.write_memory(0x8000, 3, 0);
+        cartridge.cpu_bus.write_memory(0xC000, 5, 0);
+        cartridge.cpu_bus.write_memory(chr_register, 9, 0);
+        assert_eq!(6, cartridge.cpu_bus.read_memory(0x8000, 0), "mapper {}", mapper);
+        assert_eq!(7, cartridge.cpu_bus.read_memory(0xA000, 0), "mapper {}", mapper);
+        assert_eq!(5, cartridge.cpu_bus.read_memory(0xC000, 0), "mapper {}", mapper);
+        assert_eq!(15, cartridge.cpu_bus.read_memory(0xE000, 0), "mapper {}", mapper);
+        assert_eq!(9, cartridge.ppu_bus.read_memory(0x0400, 0), "mapper {}", mapper);
+    }
+}
+
+#[test]
+fn vrc6_prg_ram() {
+    for &mapper in &[24, 26] {
+        let mut cartridge = vrc6_cartridge(mapper);
+        cartridge.cpu_bus.write_memory(0xB003, 0x80, 0);
+        cartridge.cpu_bus.write_memory(0x6001, 0x11, 0);
+        cartridge.cpu_bus.write_memory(0x6002, 0x22, 0);
+        assert_eq!(0x11, cartridge.cpu_bus.read_memory(0x6001, 0), "mapper {}", mapper);
+        assert_eq!(0x22, cartridge.cpu_bus.read_memory(0x6002, 0), "mapper {}", mapper);
+    }
+}
+
+#[test]
+fn vrc6_trainer() {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 8, 2, 0x84, 0x10];
+    rom.extend(&[0; 8]);
+    rom.extend(vec![0xAA; 0x200]);
+    rom.extend(vec![0; 0x20000 + 0x4000]);
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    cartridge.cpu_bus.write_memory(0xB003, 0x80, 0);
+    assert_eq!(0xAA, cartridge.cpu_bus.read_memory(0x7000, 0));
+    assert_eq!(0xAA, cartridge.cpu_bus.read_memory(0x71FF, 0));
+    assert_eq!(0, cartridge.cpu_bus.read_memory(0x7200, 0));
+}
+
+#[test]
+fn vrc6_irq() {
+    let mut cartridge = vrc6_cartridge(24);
+    cartridge.cpu_bus.write_memory(0xF000, 0xFE, 0);
+    cartridge.cpu_bus.write_memory(0xF001, 0b110, 0);
+    cartridge.cpu_bus.tick();
+    assert!(!cartridge.cpu_bus.irq_interrupt());
+    cartridge.cpu_bus.tick();
+    assert!(cartridge.cpu_bus.irq_interrupt());
+    cartridge.cpu_bus.write_memory(0xF002, 0, 0);
+    assert!(!cartridge.cpu_bus.irq_interrupt());
+}
+
+#[test]
+fn vrc6_audio() {
+    let mut cartridge = vrc6_cartridge(24);
+    assert_eq!(0.0, cartridge.cpu_bus.expansion_audio());
+    // pulse 1 at full volume ignoring duty, sawtooth stepping every cycle
+    for &(address, value) in &[(0x9000, 0x8F), (0x9002, 0x80), (0xB000, 0x20), (0xB002, 0x80)] {
+        cartridge.cpu_bus.write_memory(address, value, 0);
+    }
+    assert_eq!(15.0, cartridge.cpu_bus.expansion_audio());
+    let mut saw = Vec::new();
+    for _ in 0..14 {
+        cartridge.cpu_bus.tick();
+        saw.push(cartridge.cpu_bus.expansion_audio() - 15.0);
+    }
+    assert_eq!(
+        vec![0.0, 4.0, 4.0, 8.0, 8.0, 12.0, 12.0, 16.0, 16.0, 20.0, 20.0, 24.0, 24.0, 0.0],
+        saw
+    );
+    cartridge.cpu_bus.write_memory(0x9003, 0x01, 0);
+    cartridge.cpu_bus.tick();
+    cartridge.cpu_bus.tick();
+    assert_eq!(15.0, cartridge.cpu_bus.expansion_audio(), "halted");
+}
+
+// the banks are disassembled one after another, each at the address it's switched in at
+#[test]
+fn vrc6_disassembly_banks() {
+    let cartridge = vrc6_cartridge(24);
+    let mut expected: Vec<u16> = (0..14).map(|i| 0x8000 + i % 2 * 0x2000).collect();
+    expected.extend(&[0xC000, 0xE000]);
+    let starts: Vec<u16> = cartridge.cpu_bus.prg_banks().iter().map(|b| b.0).collect();
+    assert_eq!(expected, starts);
+
+    let mut out = Vec::new();
+    disassembler::disassemble(cartridge.cpu_bus, 0x8000, false, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    let headers: Vec<_> = lines.iter().filter(|l| l.starts_with("; bank")).collect();
+    assert_eq!(16, headers.len());
+    for (bank, &start) in expected.iter().enumerate() {
+        let header = format!("; bank {} at ${:04X}", bank, start);
+        let i = lines.iter().position(|&l| l == header).unwrap();
+        // every byte of a bank is its number
+        let first = format!("{:04X}\t{:02X} ", start, bank);
+        assert!(lines[i + 1].starts_with(&first), "{}", lines[i + 1]);
+    }
+}
+
+// 128K of PRG and 16K of CHR, with every bank filled with its own number
+fn vrc6_cartridge(mapper: u8) -> Cartridge {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 8, 2, (mapper & 0xF) << 4, mapper & 0xF0];
+    rom.extend(&[0; 8]);
+    for bank in 0..16 {
+        rom.extend(vec![bank; 0x2000]);
+    }
+    for bank in 0..16 {
+        rom.extend(vec![bank; 0x400]);
+    }
+    emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap()
+}
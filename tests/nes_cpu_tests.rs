@@ -1,10 +1,38 @@
 extern crate emu;
+extern crate piston_window;
 
+use nes_test::nrom;
 use nes_test::run_test_to_pc;
 use nes_test::run_test_to_pc_and_check_accumulator;
+use nes_test::run_test_to_pc_and_inspect;
+use nes_test::run_test_to_pc_counting_cycles;
 use nes_test::run_test_to_success_or_fail_pc;
 use nes_test::run_test_until_memory_matches;
 
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use piston_window::{Button, ButtonArgs, ButtonState, Event, Input, Key};
+
+use emu::console::{execute, Console};
+use emu::control::Control;
+use emu::coverage::CODE;
+use emu::input::{player_1_nes, player_2_nes};
+use emu::nes::cpu::{MemoryWatchHit, Opcode};
+use emu::nes::Region;
+use emu::record::delta::{apply_delta, encode_delta};
+use emu::record::diff::diff_state_bytes;
+use emu::record::fm2::{self, Fm2Writer};
+use emu::record::rewind::Rewind;
+use emu::record::{read_movie_header, Recorder};
+use emu::rom::db::{crc32, RomDb};
+use emu::rom::{write_save, RamOverrides};
+use emu::trace::{Trace, TraceStart};
+use emu::window::Cpu as cpuw;
+
 mod nes_test;
 
 #[test]
@@ -457,3 +485,1233 @@ fn test_instr_misc_dummy_reads_apu() {
         &[(0x6000, 0)],
     );
 }
+
+#[test]
+fn test_oam_dma_with_dmc_fetch() {
+    // (DMC enabled, 3-cycle delays before $4014 write, expected cycles for write + DMA + NOP)
+    for &(dmc, delay, expected_cycles) in &[
+        (false, 16, 519),
+        (false, 17, 520),
+        (true, 16, 519),
+        (true, 17, 523),
+        (true, 18, 520),
+        (true, 19, 522),
+        (true, 20, 521),
+    ] {
+        let (rom, dma_pc, end_pc) = oam_dma_rom(dmc, delay);
+        run_test_to_pc_counting_cycles(&mut rom.as_slice(), dma_pc, end_pc, &mut |cpu, cycles| {
+            assert_eq!(expected_cycles, cycles, "DMC {} delay {}", dmc, delay);
+            for (i, &value) in cpu.oam_for_test().iter().enumerate() {
+                let expected = if i & 0x3 == 0x2 { i as u8 & 0xE3 } else { i as u8 };
+                assert_eq!(expected, value, "OAM byte {:02X}", i);
+            }
+        });
+    }
+}
+
+#[test]
+fn test_cartridge_header_layouts() {
+    // (header bytes 6-15, trainer present)
+    for &(header, trainer) in &[
+        ([0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0], true),
+        ([0x00, 0x08, 0, 0, 0x07, 0, 0x01, 0, 0, 0x01], false),
+        ([0x00, b'D', b'i', b's', b'k', b'D', b'u', b'd', b'e', b'!'], false),
+    ] {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1];
+        rom.extend(&header);
+        if trainer {
+            rom.extend(vec![0xAA; 0x200]);
+        }
+        let mut prg = vec![0x22; 0x4000];
+        prg[0] = 0x11;
+        rom.extend(prg);
+        rom.extend(vec![0x33; 0x2000]);
+        let cartridge =
+            emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default())
+                .unwrap();
+        assert_eq!(0x11, cartridge.cpu_bus.read_memory(0x8000, 0), "{:02X?}", header);
+        assert_eq!(0x22, cartridge.cpu_bus.read_memory(0xFFFF, 0), "{:02X?}", header);
+        assert_eq!(0x33, cartridge.ppu_bus.read_memory(0x1FFF, 0), "{:02X?}", header);
+        let trainer_value = if trainer { 0xAA } else { 0 };
+        assert_eq!(trainer_value, cartridge.cpu_bus.read_memory(0x7000, 0), "{:02X?}", header);
+        assert_eq!(trainer_value, cartridge.cpu_bus.read_memory(0x71FF, 0), "{:02X?}", header);
+        assert_eq!(0, cartridge.cpu_bus.read_memory(0x7200, 0), "{:02X?}", header);
+    }
+}
+
+#[test]
+fn test_prg_rom_mirroring() {
+    // (header bytes 4-9, PRG size)
+    for &(header, prg_size) in &[
+        ([1, 0, 0, 0, 0, 0], 0x4000),
+        ([2, 0, 0, 0, 0, 0], 0x8000),
+        // NES 2.0, 2^13 * 1 bytes
+        ([13 << 2, 0, 0, 0x08, 0, 0x0F], 0x2000),
+        ([0, 0, 0, 0, 0, 0], 0),
+    ] {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A];
+        rom.extend(&header);
+        rom.extend(&[0; 6]);
+        rom.extend((0..prg_size).map(|i| (i * 7 + i / 0x100) as u8));
+        let cartridge =
+            emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+        for address in 0x8000..=0xFFFFu16 {
+            let expected = if prg_size == 0 {
+                0x5A
+            } else {
+                let i = (address as usize - 0x8000) % prg_size;
+                (i * 7 + i / 0x100) as u8
+            };
+            assert_eq!(
+                expected,
+                cartridge.cpu_bus.read_memory(address, 0x5A),
+                "{:02X?} {:04X}",
+                header,
+                address
+            );
+        }
+    }
+}
+
+#[test]
+fn test_prg_ram_override() {
+    let rom = nrom(&[]);
+    let overrides = RamOverrides {
+        cart_ram: None,
+        sram: Some(0x800),
+    };
+    let mut cartridge = emu::nes::load_cartridge(&mut rom.as_slice(), None, &overrides).unwrap();
+    cartridge.cpu_bus.write_memory(0x6001, 0x5A, 0);
+    assert_eq!(0x5A, cartridge.cpu_bus.read_memory(0x6801, 0));
+    assert_eq!(0x5A, cartridge.cpu_bus.read_memory(0x7801, 0));
+    let mut save = Vec::new();
+    assert_eq!(0x800, cartridge.cpu_bus.save_to_battery(&mut save).unwrap());
+
+    let reloaded =
+        emu::nes::load_cartridge(&mut rom.as_slice(), Some(&mut save.as_slice()), &overrides)
+            .unwrap();
+    assert_eq!(0x5A, reloaded.cpu_bus.read_memory(0x6001, 0));
+
+    // too big for the $6000-$7FFF window, so the header's 8KB of volatile RAM is kept
+    let overrides = RamOverrides {
+        cart_ram: Some(0x8000),
+        sram: None,
+    };
+    let mut cartridge = emu::nes::load_cartridge(&mut rom.as_slice(), None, &overrides).unwrap();
+    cartridge.cpu_bus.write_memory(0x6001, 0x5A, 0);
+    assert_eq!(0, cartridge.cpu_bus.read_memory(0x6801, 0));
+    assert_eq!(0, cartridge.cpu_bus.save_to_battery(&mut Vec::new()).unwrap());
+}
+
+#[test]
+fn test_battery_save_round_trip() {
+    let mut rom = nrom(&[
+        0xA9, 0x5A, // LDA #$5A
+        0x8D, 0x34, 0x72, // STA $7234
+        0x4C, 0x05, 0x80, // JMP $8005
+    ]);
+    rom[6] |= 0x02;
+    let mut save = Vec::new();
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8005, &mut |cpu| {
+        assert_eq!(0x2000, cpu.save_to_battery(&mut save).unwrap());
+    });
+
+    // through the file, the way a session ends and the next begins
+    let path = std::env::temp_dir().join(format!("emu_battery_{}.sav", std::process::id()));
+    write_save(&path, &save);
+    let mut file = fs::File::open(&path).unwrap();
+    let reloaded = emu::nes::load_cartridge(
+        &mut rom.as_slice(),
+        Some(&mut file),
+        &RamOverrides::default(),
+    )
+    .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(0x5A, reloaded.cpu_bus.read_memory(0x7234, 0));
+    assert_eq!(0, reloaded.cpu_bus.read_memory(0x6000, 0));
+
+    // without the battery bit nothing is kept
+    rom[6] &= !0x02;
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8005, &mut |cpu| {
+        assert_eq!(0, cpu.save_to_battery(&mut Vec::new()).unwrap());
+    });
+}
+
+#[test]
+fn test_short_battery_save() {
+    let mut rom = nrom(&[]);
+    rom[6] |= 0x02;
+
+    // an empty save leaves the RAM blank and the same size
+    let mut cartridge = emu::nes::load_cartridge(
+        &mut rom.as_slice(),
+        Some(&mut std::io::empty()),
+        &RamOverrides::default(),
+    )
+    .unwrap();
+    cartridge.cpu_bus.write_memory(0x7FFF, 0x5A, 0);
+    assert_eq!(0x5A, cartridge.cpu_bus.read_memory(0x7FFF, 0));
+
+    // a short one only fills the start, and a long one is cut off at the RAM's size
+    let cartridge = emu::nes::load_cartridge(
+        &mut rom.as_slice(),
+        Some(&mut &[1u8, 2, 3][..]),
+        &RamOverrides::default(),
+    )
+    .unwrap();
+    assert_eq!(2, cartridge.cpu_bus.read_memory(0x6001, 0));
+    assert_eq!(0, cartridge.cpu_bus.read_memory(0x6003, 0));
+    let cartridge = emu::nes::load_cartridge(
+        &mut rom.as_slice(),
+        Some(&mut &vec![7u8; 0x3000][..]),
+        &RamOverrides::default(),
+    )
+    .unwrap();
+    let mut save = Vec::new();
+    assert_eq!(
+        0x2000,
+        cartridge.cpu_bus.save_to_battery(&mut save).unwrap()
+    );
+    assert_eq!(vec![7; 0x2000], save);
+
+    // with no RAM at all, $6000-$7FFF is open bus
+    let overrides = RamOverrides {
+        cart_ram: Some(0),
+        sram: None,
+    };
+    let mut cartridge = emu::nes::load_cartridge(&mut rom.as_slice(), None, &overrides).unwrap();
+    cartridge.cpu_bus.write_memory(0x6000, 0x5A, 0);
+    assert_eq!(0xEE, cartridge.cpu_bus.read_memory(0x6000, 0xEE));
+}
+
+#[test]
+fn test_uxrom_banking() {
+    // 128KB of PRG ROM in 16KB banks, each filled with its number
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 8, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    rom.extend((0..0x20000).map(|i| (i / 0x4000) as u8));
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    assert_eq!(0, cartridge.cpu_bus.read_memory(0x8000, 0));
+    assert_eq!(7, cartridge.cpu_bus.read_memory(0xC000, 0));
+    cartridge.cpu_bus.write_memory(0xFFF0, 5, 0);
+    assert_eq!(5, cartridge.cpu_bus.read_memory(0xBFFF, 0));
+    assert_eq!(7, cartridge.cpu_bus.read_memory(0xFFFF, 0));
+
+    // CHR RAM
+    cartridge.ppu_bus.write_memory(0x1234, 0x5A, 0);
+    assert_eq!(0x5A, cartridge.ppu_bus.read_memory(0x1234, 0));
+}
+
+#[test]
+fn test_cnrom_banking() {
+    // 32KB of CHR ROM in 8KB banks, each filled with its number
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 1, 4, 0x30, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    rom.extend(vec![0xEA; 0x4000]);
+    rom.extend((0..0x8000).map(|i| (i / 0x2000) as u8));
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    assert_eq!(0, cartridge.ppu_bus.read_memory(0x0000, 0));
+    cartridge.cpu_bus.write_memory(0x8000, 2, 0);
+    assert_eq!(2, cartridge.ppu_bus.read_memory(0x1FFF, 0));
+    cartridge.cpu_bus.write_memory(0x8000, 3, 0);
+    assert_eq!(3, cartridge.ppu_bus.read_memory(0x0000, 0));
+
+    // a 16KB CHR ROM only decodes one bit
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 1, 2, 0x30, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    rom.extend(vec![0xEA; 0x4000]);
+    rom.extend((0..0x4000).map(|i| (i / 0x2000) as u8));
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    cartridge.cpu_bus.write_memory(0x8000, 3, 0);
+    assert_eq!(1, cartridge.ppu_bus.read_memory(0x0000, 0));
+}
+
+#[test]
+fn test_mmc3_banking() {
+    let rom = mmc3(&[]);
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let cpu_bus = &mut cartridge.cpu_bus;
+    cpu_bus.write_memory(0x8000, 6, 0);
+    cpu_bus.write_memory(0x8001, 1, 0);
+    assert_eq!(1, cpu_bus.read_memory(0x8000, 0));
+    assert_eq!(2, cpu_bus.read_memory(0xC000, 0));
+    // the fixed second-to-last bank swaps over to $8000
+    cpu_bus.write_memory(0x8000, 0x46, 0);
+    assert_eq!(2, cpu_bus.read_memory(0x8000, 0));
+    assert_eq!(1, cpu_bus.read_memory(0xC000, 0));
+
+    // R0 picks a 2K pair of 1K banks, which inversion moves to $1000
+    cpu_bus.write_memory(0x8000, 0, 0);
+    cpu_bus.write_memory(0x8001, 5, 0);
+    cpu_bus.write_memory(0x8000, 5, 0);
+    cpu_bus.write_memory(0x8001, 7, 0);
+    let ppu_bus = &cartridge.ppu_bus;
+    assert_eq!(4, ppu_bus.read_memory(0x0000, 0));
+    assert_eq!(5, ppu_bus.read_memory(0x0400, 0));
+    assert_eq!(7, ppu_bus.read_memory(0x1C00, 0));
+    cartridge.cpu_bus.write_memory(0x8000, 0x80, 0);
+    assert_eq!(4, cartridge.ppu_bus.read_memory(0x1000, 0));
+    assert_eq!(7, cartridge.ppu_bus.read_memory(0x0C00, 0));
+
+    cartridge.cpu_bus.write_memory(0xA000, 1, 0);
+    assert_eq!(0x000, cartridge.ppu_bus.mirror_nametable(0x2400));
+    assert_eq!(0x400, cartridge.ppu_bus.mirror_nametable(0x2800));
+    cartridge.cpu_bus.write_memory(0xA000, 0, 0);
+    assert_eq!(0x400, cartridge.ppu_bus.mirror_nametable(0x2400));
+    assert_eq!(0x000, cartridge.ppu_bus.mirror_nametable(0x2800));
+}
+
+#[test]
+fn test_mmc3_prg_ram() {
+    let mut rom = mmc3(&[]);
+    rom[6] |= 0x02;
+    let mut cartridge = emu::nes::load_cartridge(
+        &mut rom.as_slice(),
+        Some(&mut &[1u8, 2, 3][..]),
+        &RamOverrides::default(),
+    )
+    .unwrap();
+    assert_eq!(3, cartridge.cpu_bus.read_memory(0x6002, 0));
+    cartridge.cpu_bus.write_memory(0x7FFF, 0x5A, 0);
+    let mut save = Vec::new();
+    assert_eq!(
+        0x2000,
+        cartridge.cpu_bus.save_to_battery(&mut save).unwrap()
+    );
+    assert_eq!(0x5A, save[0x1FFF]);
+
+    let overrides = RamOverrides {
+        cart_ram: None,
+        sram: Some(0),
+    };
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), Some(&mut std::io::empty()), &overrides)
+            .unwrap();
+    cartridge.cpu_bus.write_memory(0x6000, 0x5A, 0);
+    assert_eq!(0xEE, cartridge.cpu_bus.read_memory(0x6000, 0xEE));
+}
+
+#[test]
+fn test_mmc3_irq() {
+    let rom = mmc3(&[
+        0x78, // SEI
+        0xA9, 0x40, // LDA #$40
+        0x8D, 0x17, 0x40, // STA $4017 (no APU frame IRQ)
+        0xA2, 0xFF, // LDX #$FF
+        0x9A, // TXS
+        0x2C, 0x02, 0x20, // BIT $2002
+        0x10, 0xFB, // BPL -5
+        0x2C, 0x02, 0x20, // BIT $2002
+        0x10, 0xFB, // BPL -5
+        0xA9, 0x08, // LDA #$08
+        0x8D, 0x00, 0x20, // STA $2000 (sprites from $1000)
+        0x8D, 0x01, 0x20, // STA $2001 (background on)
+        0xA9, 0x10, // LDA #$10
+        0x8D, 0x00, 0xC0, // STA $C000
+        0x8D, 0x01, 0xC0, // STA $C001
+        0x8D, 0x01, 0xE0, // STA $E001
+        0x58, // CLI
+        0x4C, 0x27, 0xE0, // JMP $E027
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0xE100, &mut |cpu| {
+        // reloaded on the pre-render line, then counted down to zero over lines 0-15
+        assert_eq!(15, cpu.scanline_for_test());
+        // after that, every 17 lines
+        let inputs = [player_1_nes(), player_2_nes()];
+        cpu.next_operation(&inputs);
+        while cpu.pc_for_test() != 0xE100 {
+            cpu.next_operation(&inputs);
+        }
+        assert_eq!(32, cpu.scanline_for_test());
+    });
+}
+
+#[test]
+fn test_rom_db() {
+    assert_eq!(0xCBF43926, crc32(b"123456789"));
+    RomDb::bundled();
+
+    let prg_chr = nrom(&[])[16..].to_vec();
+    let db = RomDb::parse(&format!(
+        "# comment\n\nnes {:08X} mapper=3 mirroring=vertical battery=yes ram=2 # Test Game\n\
+         gen GM00001009-00 ram=64 controllers=swapped\n",
+        crc32(&prg_chr)
+    ))
+    .unwrap();
+    let entry = db.nes(&prg_chr).unwrap();
+    assert_eq!("Test Game", entry.title);
+    assert_eq!(Some(3), entry.mapper);
+    assert_eq!(Some(true), entry.vertical_mirroring);
+    assert_eq!(Some(true), entry.battery);
+    assert_eq!(Some(0x800), entry.ram);
+    assert!(!entry.swap_controllers);
+    assert!(db.nes(&prg_chr[1..]).is_none());
+    let entry = db.gen("GM 00001009-00").unwrap();
+    assert_eq!(Some(0x10000), entry.ram);
+    assert!(entry.swap_controllers);
+
+    assert!(RomDb::parse("nes 1234 speed=fast").is_err());
+    assert!(RomDb::parse("snes 1234").is_err());
+    assert!(RomDb::parse("nes").is_err());
+}
+
+#[test]
+fn test_write_save() {
+    let path = std::env::temp_dir().join(format!("emu_write_save_{}.sav", std::process::id()));
+    write_save(&path, &[1, 2, 3]);
+    assert_eq!(vec![1, 2, 3], std::fs::read(&path).unwrap());
+    // nothing to save leaves the last save alone
+    write_save(&path, &[]);
+    assert_eq!(vec![1, 2, 3], std::fs::read(&path).unwrap());
+    write_save(&path, &[4, 5]);
+    assert_eq!(vec![4, 5], std::fs::read(&path).unwrap());
+    assert!(!path.with_extension("sav.tmp").exists());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_state_diff() {
+    let mut states = Vec::new();
+    let mut layout = Vec::new();
+    for &value in &[0x42, 0x43] {
+        let rom = nrom(&[
+            0xA9, value, // LDA #value
+            0x8D, 0x23, 0x01, // STA $0123
+            0xA9, 0x00, // LDA #$00
+            0x4C, 0x07, 0x80, // JMP $8007
+        ]);
+        run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8007, &mut |cpu| {
+            let mut state = Vec::new();
+            cpu.save_state(&mut state);
+            states.push(state);
+            layout = cpu.state_layout();
+        });
+    }
+    assert_eq!(None, diff_state_bytes(&states[0], &states[0], &layout));
+    assert_eq!(
+        Some("RAM[0x0123] differs".to_string()),
+        diff_state_bytes(&states[0], &states[1], &layout)
+    );
+    assert_eq!(
+        Some("PC: 0x8000 vs 0x8003".to_string()),
+        diff_state_bytes(&[1, 2, 0x80, 0x00], &[1, 2, 0x80, 0x03], &[("A", 1), ("X", 1), ("PC", 2)])
+    );
+}
+
+#[test]
+fn test_state_delta() {
+    let mut keyframe = Vec::new();
+    let mut deltas = Vec::new();
+    let mut states = Vec::new();
+    for &value in &[0x42, 0x43] {
+        let rom = nrom(&[
+            0xA9, value, // LDA #value
+            0x8D, 0x23, 0x01, // STA $0123
+            0xA9, 0x00, // LDA #$00
+            0x4C, 0x07, 0x80, // JMP $8007
+        ]);
+        run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8007, &mut |cpu| {
+            let mut state = Vec::new();
+            cpu.save_state(&mut state);
+            if keyframe.is_empty() {
+                keyframe = state.clone();
+            }
+            let mut delta = Vec::new();
+            cpu.save_state_delta(&keyframe, &mut delta);
+            deltas.push(delta);
+            states.push(state);
+        });
+    }
+    // the same state twice is just its length; one changed byte of RAM (and whatever cycle
+    // counts moved) is a handful more
+    assert_eq!(Ok(states[0].clone()), apply_delta(&keyframe, &deltas[0]));
+    assert!(deltas[0].len() <= 4, "{}", deltas[0].len());
+    assert_eq!(Ok(states[1].clone()), apply_delta(&keyframe, &deltas[1]));
+    assert!(deltas[1].len() < 64, "{}", deltas[1].len());
+
+    // states that grow or shrink, and deltas that don't match the state they're applied to
+    let cases: [(&[u8], &[u8]); 4] = [
+        (&[], &[1, 2, 3]),
+        (&[1, 2, 3, 4, 5, 6, 7, 8], &[1, 9, 3, 4, 5, 6, 7, 0]),
+        (&[1, 2, 3, 4], &[1, 2]),
+        (&[1, 2, 3], &[]),
+    ];
+    for &(prev, state) in &cases {
+        let mut delta = Vec::new();
+        encode_delta(prev, state, &mut delta);
+        assert_eq!(Ok(state.to_vec()), apply_delta(prev, &delta));
+    }
+    let mut delta = Vec::new();
+    encode_delta(&[0; 300], &[1; 300], &mut delta);
+    assert!(apply_delta(&[0; 300], &delta[..delta.len() - 1]).is_err());
+    assert!(apply_delta(&[], &[2, 1, 5, 0]).is_err());
+
+    // lengths with more bits than a usize holds, whether in the last byte or past it
+    let too_long = Err("length too long".to_owned());
+    let mut len = vec![0xFF; 9];
+    len.push(0x7F);
+    assert_eq!(too_long, apply_delta(&[], &len));
+    let mut len = vec![0x80; 10];
+    len.push(0x01);
+    assert_eq!(too_long, apply_delta(&[], &len));
+}
+
+// a frame at a time, with the states loaded back byte-identical to the ones saved
+#[test]
+fn test_rewind() {
+    let rom = nrom(&[
+        0xE6, 0x10, // INC $10
+        0x4C, 0x00, 0x80, // JMP $8000
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let mut rewind = Rewind::new(100);
+        let mut states = Vec::new();
+        for _ in 0..150 {
+            let mut state = Vec::new();
+            cpu.save_state(&mut state);
+            states.push(state);
+            rewind.push(&*cpu);
+            cpu.step_instruction();
+            cpu.step_instruction();
+        }
+        // the oldest go a keyframe's 60 frames at a time
+        assert_eq!(90, rewind.len());
+        for expected in states[60..].iter().rev() {
+            assert!(rewind.pop(cpu));
+            let mut state = Vec::new();
+            cpu.save_state(&mut state);
+            assert_eq!(*expected, state);
+        }
+        assert!(!rewind.pop(cpu));
+        assert!(rewind.is_empty());
+    });
+}
+
+// run with --release --ignored; keeping a rewind history costs a push every frame
+#[test]
+#[ignore]
+fn bench_rewind() {
+    let rom = nrom(&[
+        0xE6, 0x10, // INC $10
+        0x4C, 0x00, 0x80, // JMP $8000
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let inputs = [player_1_nes(), player_2_nes()];
+        let frames = 600;
+        let mut rewind = Rewind::new(frames);
+        let mut push_time = std::time::Duration::default();
+        for _ in 0..frames {
+            cpu.do_frame_exact(&inputs, false);
+            let start = std::time::Instant::now();
+            rewind.push(&*cpu);
+            push_time += start.elapsed();
+        }
+        let start = std::time::Instant::now();
+        while rewind.pop(cpu) {}
+        let pop_time = start.elapsed();
+        println!(
+            "push {:?}/frame, pop {:?}/frame",
+            push_time / frames as u32,
+            pop_time / frames as u32
+        );
+        let frame_time = std::time::Duration::from_secs(1) / 60;
+        assert!(push_time / (frames as u32) < frame_time / 10);
+        assert!(pop_time / (frames as u32) < frame_time / 10);
+    });
+}
+
+#[test]
+fn test_movie_from_state_and_splice() {
+    let path = std::env::temp_dir().join(format!("emu_movie_{}.mov", std::process::id()));
+    let rom = nrom(&[
+        0xA9, 0x42, // LDA #$42
+        0x8D, 0x23, 0x01, // STA $0123
+        0x4C, 0x05, 0x80, // JMP $8005
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8005, &mut |cpu| {
+        let mut inputs = [player_1_nes(), player_2_nes()];
+        let mut state = Vec::new();
+        cpu.save_state(&mut state);
+
+        // recorded from the current state, starting on frame 100
+        let mut recorder = Recorder::new(&path);
+        recorder.toggle(cpu, 100, true);
+        inputs[0].set_from_u8(0x01);
+        recorder.input_changed(&inputs, 103);
+        inputs[0].set_from_u8(0x80);
+        recorder.input_changed(&inputs, 110);
+        recorder.toggle(cpu, 120, true);
+        recorder.stop();
+        let movie = fs::read(&path).unwrap();
+        let inputs_3_and_10: &[u8] = &[0, 0, 0, 3, 0, 0, 0x01, 0, 0, 0, 0, 10, 0, 0, 0x80, 0];
+        assert_eq!(
+            (Some(state.as_slice()), inputs_3_and_10),
+            read_movie_header(&movie)
+        );
+
+        // playing it back loads the state; recording partway through keeps the inputs so far
+        cpu.poke(0x0123, 0);
+        inputs[0].set_from_u8(0);
+        let mut recorder = Recorder::new(&path);
+        recorder.toggle_playback(cpu, 500);
+        assert_eq!(0x42, cpu.peek(0x0123));
+        for frame in 500..506 {
+            recorder.set_frame_inputs(&mut inputs, frame);
+        }
+        assert_eq!(0x01, inputs[0].to_u8());
+        recorder.toggle(cpu, 506, false);
+        inputs[0].set_from_u8(0x02);
+        recorder.input_changed(&inputs, 507);
+        recorder.toggle(cpu, 520, false);
+        recorder.stop();
+        let movie = fs::read(&path).unwrap();
+        let inputs_3_and_7: &[u8] = &[0, 0, 0, 3, 0, 0, 0x01, 0, 0, 0, 0, 7, 0, 0, 0x02, 0];
+        assert_eq!(
+            (Some(state.as_slice()), inputs_3_and_7),
+            read_movie_header(&movie)
+        );
+
+        // movies from before the header play from wherever they're started
+        assert_eq!((None, inputs_3_and_7), read_movie_header(inputs_3_and_7));
+    });
+    fs::remove_file(&path).unwrap();
+}
+
+// a movie from power-on resets the console as recording starts, and again when it's played
+#[test]
+fn test_movie_from_power_on() {
+    let path = std::env::temp_dir().join(format!("emu_power_on_{}.mov", std::process::id()));
+    let rom = nrom(&[
+        0xA9, 0x42, // LDA #$42
+        0x8D, 0x23, 0x01, // STA $0123
+        0x4C, 0x05, 0x80, // JMP $8005
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8005, &mut |cpu| {
+        let mut recorder = Recorder::<8>::new(&path);
+        recorder.toggle(cpu, 100, false);
+        assert_eq!(0x8000, cpu.pc_for_test());
+        recorder.toggle(cpu, 110, false);
+        recorder.stop();
+        assert_eq!(b"MOVI\0\0\0\0".to_vec(), fs::read(&path).unwrap());
+
+        cpu.step_instruction();
+        assert_eq!(0x8002, cpu.pc_for_test());
+        let mut recorder = Recorder::<8>::new(&path);
+        recorder.toggle_playback(cpu, 500);
+        assert_eq!(0x8000, cpu.pc_for_test());
+        recorder.stop();
+    });
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_fm2_round_trip() {
+    let mut movie = Vec::new();
+    {
+        let mut writer = Fm2Writer::new(&mut movie, "game").unwrap();
+        writer.input_changed(0, [0x81, 0x00]).unwrap();
+        writer.input_changed(2, [0x00, 0x10]).unwrap();
+        writer.input_changed(3, [0xFF, 0x42]).unwrap();
+        writer.finish().unwrap();
+    }
+    let text = String::from_utf8(movie.clone()).unwrap();
+    assert!(text.contains("\nromFilename game\n"));
+    assert_eq!(
+        vec![
+            "|0|R......A|........||",
+            "|0|R......A|........||",
+            "|0|........|...U....||",
+            "|0|RLDUTSBA|.L....B.||",
+        ],
+        text.lines().filter(|line| line.starts_with('|')).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![[0x81, 0x00], [0x81, 0x00], [0x00, 0x10], [0xFF, 0x42]],
+        fm2::read(&mut movie.as_slice()).unwrap()
+    );
+}
+
+// recorded to and played back from an .fm2 file by the recorder, as with --fm2
+#[test]
+fn test_fm2_movie_record_and_playback() {
+    let path = std::env::temp_dir().join(format!("emu_movie_{}.fm2", std::process::id()));
+    let rom = nrom(&[
+        0x4C, 0x00, 0x80, // JMP $8000
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let mut inputs = [player_1_nes(), player_2_nes()];
+        let mut recorder = Recorder::new(&path);
+        recorder.toggle(cpu, 10, false);
+        inputs[0].set_from_u8(0x01);
+        recorder.input_changed(&inputs, 13);
+        inputs[1].set_from_u8(0x80);
+        recorder.input_changed(&inputs, 15);
+        recorder.stop();
+
+        inputs[0].set_from_u8(0);
+        inputs[1].set_from_u8(0);
+        let mut recorder = Recorder::new(&path);
+        recorder.toggle_playback(cpu, 50);
+        let mut played = Vec::new();
+        for frame in 50..56 {
+            recorder.set_frame_inputs(&mut inputs, frame);
+            played.push([inputs[0].to_u8(), inputs[1].to_u8()]);
+        }
+        recorder.stop();
+        assert_eq!(
+            vec![[0, 0], [0, 0], [0, 0], [0x01, 0], [0x01, 0], [0x01, 0x80]],
+            played
+        );
+        assert!(!recorder.active());
+    });
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_pc_trigger() {
+    let rom = nrom(&[
+        0xA9, 0x42, // LDA #$42
+        0x8D, 0x23, 0x01, // STA $0123
+        0x4C, 0x05, 0x80, // JMP $8005
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8005, &mut |cpu| {
+        let inputs = [player_1_nes(), player_2_nes()];
+        cpu.set_pc_trigger(Some(0x9000));
+        cpu.do_frame(1.0 / 60.0, &inputs, false);
+        assert!(!cpu.pc_triggered());
+        cpu.set_pc_trigger(Some(0x8005));
+        cpu.do_frame(1.0 / 60.0, &inputs, false);
+        assert!(cpu.pc_triggered());
+        // only once
+        cpu.do_frame(1.0 / 60.0, &inputs, false);
+        assert!(!cpu.pc_triggered());
+    });
+}
+
+#[test]
+fn test_do_frame_exact() {
+    let rom = nrom(&[
+        0xE6, 0x10, // INC $10
+        0x4C, 0x00, 0x80, // JMP $8000
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let inputs = [player_1_nes(), player_2_nes()];
+        let mut start = Vec::new();
+        cpu.save_state(&mut start);
+
+        // the speed setting doesn't change how far a frame goes
+        let mut states = Vec::new();
+        for &speed in &[1.0, 2.5] {
+            cpu.load_state(&mut start.as_slice());
+            cpu.set_speed(speed);
+            for _ in 0..3 {
+                cpu.do_frame_exact(&inputs, false);
+            }
+            let mut state = Vec::new();
+            cpu.save_state(&mut state);
+            states.push(state);
+        }
+        assert_eq!(states[0], states[1]);
+        assert_ne!(start, states[0]);
+    });
+}
+
+#[test]
+fn test_pal_region() {
+    // NES 2.0, PAL timing
+    let mut rom = nrom(&[]);
+    rom[7] = 0x08;
+    rom[12] = 0x01;
+    let cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    assert_eq!(Region::Pal, cartridge.region);
+    rom[12] = 0x00;
+    let cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    assert_eq!(Region::Ntsc, cartridge.region);
+
+    // counts loop iterations in $10-$11
+    let rom = nrom(&[
+        0xE6, 0x10, // INC $10
+        0xD0, 0x02, // BNE +2
+        0xE6, 0x11, // INC $11
+        0x4C, 0x00, 0x80, // JMP $8000
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let inputs = [player_1_nes(), player_2_nes()];
+        let mut start = Vec::new();
+        cpu.save_state(&mut start);
+        let mut counts = Vec::new();
+        for &region in &[Region::Ntsc, Region::Pal] {
+            cpu.load_state(&mut start.as_slice());
+            cpu.set_region(region);
+            let before =
+                cpu.read_memory_no_tick(0x10) as u16 | (cpu.read_memory_no_tick(0x11) as u16) << 8;
+            cpu.do_frame_exact(&inputs, false);
+            let after =
+                cpu.read_memory_no_tick(0x10) as u16 | (cpu.read_memory_no_tick(0x11) as u16) << 8;
+            counts.push(after.wrapping_sub(before) as f64);
+        }
+        // a PAL frame is about 33,248 CPU cycles to NTSC's 29,781
+        let ratio = counts[1] / counts[0];
+        assert!(ratio > 1.1 && ratio < 1.13, "{:?}", counts);
+    });
+}
+
+// 312 lines of 341 dots with none skipped, at 3.2 dots a cycle, so an NMI every 33,247.5 cycles
+// and 70 lines of vertical blank between each and the pre-render line
+#[test]
+fn test_pal_frame_timing() {
+    let rom = nrom(&[
+        0xA9, 0x80, // LDA #$80
+        0x8D, 0x00, 0x20, // STA $2000
+        0xE6, 0x10, // INC $10
+        0x4C, 0x07, 0x80, // JMP $8007
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8007, &mut |cpu| {
+        cpu.set_region(Region::Pal);
+        let mut lines = HashSet::new();
+        let mut nmis = Vec::new();
+        let mut pre_render = Vec::new();
+        let mut nmi_count = cpu.peek(0x10);
+        while nmis.len() < 4 {
+            let line = cpu.scanline_for_test();
+            cpu.step_instruction();
+            lines.insert(cpu.scanline_for_test());
+            if cpu.scanline_for_test() == 311 && line != 311 && !nmis.is_empty() {
+                pre_render.push(cpu.cycle_count_for_test());
+            }
+            if cpu.peek(0x10) != nmi_count {
+                nmi_count = cpu.peek(0x10);
+                nmis.push(cpu.cycle_count_for_test());
+            }
+        }
+        assert_eq!(312, lines.len());
+        assert_eq!(Some(&311), lines.iter().max());
+        for pair in nmis.windows(2) {
+            let period = pair[1] - pair[0];
+            assert!(period > 33_240 && period < 33_256, "{:?}", nmis);
+        }
+        // 70 lines is 7,459 cycles, less the 20 or so it takes the handler to count the NMI
+        for (nmi, pre_render) in nmis.iter().zip(pre_render) {
+            let vblank = pre_render - nmi;
+            assert!(vblank > 7_420 && vblank < 7_460, "{:?} {}", nmis, vblank);
+        }
+    });
+}
+
+#[test]
+fn test_step_instruction() {
+    let rom = nrom(&[
+        0xEA, // NOP
+        0xA9, 0x42, // LDA #$42
+        0x8D, 0x23, 0x01, // STA $0123
+        0x4C, 0x06, 0x80, // JMP $8006
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8001, &mut |cpu| {
+        let step = cpu.step_instruction();
+        assert_eq!(
+            (0x8001, Opcode::LDA, 2, false),
+            (step.pc, step.opcode, step.cycles, step.pc_watched)
+        );
+        assert!(step.memory_watches.is_empty());
+
+        cpu.set_pc_watch(0x8003);
+        cpu.set_memory_watch(0x0123);
+        let step = cpu.step_instruction();
+        assert_eq!(
+            (0x8003, Opcode::STA, 4, true),
+            (step.pc, step.opcode, step.cycles, step.pc_watched)
+        );
+        assert_eq!(
+            vec![MemoryWatchHit {
+                addr: 0x0123,
+                old: 0,
+                new: 0x42,
+                pc: 0x8003,
+            }],
+            step.memory_watches
+        );
+        assert!(cpu.take_memory_watch_hits().is_empty());
+        assert_eq!(0x42, cpu.read_memory_no_tick(0x0123));
+
+        let step = cpu.step_instruction();
+        assert_eq!(
+            (0x8006, Opcode::JMP, 3),
+            (step.pc, step.opcode, step.cycles)
+        );
+        assert_eq!(0x8006, cpu.pc_for_test());
+    });
+}
+
+#[test]
+fn test_coverage_wraps_around() {
+    let mut rom = nrom(&[0x4C, 0x00, 0x80]);
+    // LDA $0000 at $FFFE, with the high byte of the address read from RAM
+    rom[0x10 + 0x3FFE..0x10 + 0x4000].copy_from_slice(&[0xAD, 0x00]);
+    let path = std::env::temp_dir().join(format!("emu_coverage_{}.cdl", std::process::id()));
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        cpu.enable_coverage(0x2000);
+        cpu.poke(0x0000, 0);
+        cpu.setup_for_test(0x24, 0xFFFE);
+        cpu.step_instruction();
+        cpu.write_coverage(&path).unwrap();
+    });
+    let flags = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(0x6000, flags.len());
+    assert_eq!(0, flags[0x3FFD] & CODE);
+    assert_eq!(CODE, flags[0x3FFE] & CODE);
+    assert_eq!(CODE, flags[0x3FFF] & CODE);
+}
+
+fn key(state: ButtonState, key: Key) -> Event {
+    Event::Input(
+        Input::Button(ButtonArgs {
+            state,
+            button: Button::Keyboard(key),
+            scancode: None,
+        }),
+        None,
+    )
+}
+
+#[test]
+fn test_fast_forward_restores_speed() {
+    let rom = nrom(&[0x4C, 0x00, 0x80]); // JMP $8000
+    let path = std::env::temp_dir().join(format!("emu_fast_forward_{}.mov", std::process::id()));
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let mut control = Control::new();
+        let mut recorder = Recorder::<8>::new(&path);
+        cpu.increase_speed();
+        // the key repeats while it's held
+        for _ in 0..3 {
+            control.event(&key(ButtonState::Press, Key::Backquote), cpu, &mut recorder, 0);
+            assert_eq!(8.0, cpu.speed());
+        }
+        control.event(&key(ButtonState::Release, Key::Backquote), cpu, &mut recorder, 0);
+        assert_eq!(1.25, cpu.speed());
+        recorder.stop();
+    });
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_breakpoint() {
+    let rom = nrom(&[
+        0xA9, 0x42, // LDA #$42
+        0x85, 0x10, // STA $10
+        0xE6, 0x11, // INC $11
+        0x4C, 0x04, 0x80, // JMP $8004
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let inputs = [player_1_nes(), player_2_nes()];
+        cpu.add_breakpoint(0x8004);
+        assert!(cpu.do_frame(1.0 / 60.0, &inputs, false));
+        assert_eq!(Some(0x8004), cpu.take_breakpoint());
+        assert_eq!(None, cpu.take_breakpoint());
+        assert_eq!(0x8004, cpu.pc_for_test());
+        assert_eq!(0x42, cpu.read_memory_no_tick(0x10));
+        assert_eq!(0, cpu.read_memory_no_tick(0x11));
+
+        // resuming runs the instruction it stopped at, and stops the next time round
+        assert!(cpu.do_frame(1.0 / 60.0, &inputs, false));
+        assert_eq!(Some(0x8004), cpu.take_breakpoint());
+        assert_eq!(1, cpu.read_memory_no_tick(0x11));
+
+        cpu.remove_breakpoint(0x8004);
+        assert!(!cpu.do_frame(1.0 / 60.0, &inputs, false));
+        assert_eq!(None, cpu.take_breakpoint());
+    });
+}
+
+#[test]
+fn test_memory_watch_hits() {
+    let rom = nrom(&[
+        0xA9, 0x42, // LDA #$42
+        0x8D, 0x10, 0x08, // STA $0810
+        0xA9, 0x43, // LDA #$43
+        0x8D, 0x10, 0x10, // STA $1010
+        0x85, 0x11, // STA $11
+        0xA5, 0x10, // LDA $10
+        0x8D, 0xF9, 0x3F, // STA $3FF9
+        0x4C, 0x11, 0x80, // JMP $8011
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let inputs = [player_1_nes(), player_2_nes()];
+        cpu.set_memory_watch(0x0010);
+        // a mirror of PPUMASK
+        cpu.set_memory_watch(0x2009);
+        cpu.do_frame(1.0 / 60.0, &inputs, false);
+        let hits = cpu.take_memory_watch_hits();
+        assert_eq!(
+            vec![
+                MemoryWatchHit {
+                    addr: 0x0010,
+                    old: 0,
+                    new: 0x42,
+                    pc: 0x8002,
+                },
+                MemoryWatchHit {
+                    addr: 0x0010,
+                    old: 0x42,
+                    new: 0x43,
+                    pc: 0x8007,
+                },
+            ],
+            hits[..2]
+        );
+        // the read of $10 at $800C isn't a hit; the PPU reads as open bus, so only the write
+        // itself is checked
+        assert_eq!(
+            (0x2001, 0x43, 0x800E),
+            (hits[2].addr, hits[2].new, hits[2].pc)
+        );
+        assert_eq!(3, hits.len());
+        assert!(cpu.take_memory_watch_hits().is_empty());
+    });
+}
+
+// the addresses of the instructions traced once a write through `write_addr` changes `watch`
+fn trace_on_change(watch: u16, write_addr: u16) -> Vec<String> {
+    let (lo, hi) = (write_addr as u8, (write_addr >> 8) as u8);
+    let rom = nrom(&[
+        0xA9, 0x00, // LDA #$00
+        0x8D, lo, hi, // STA write_addr
+        0xA9, 0x05, // LDA #$05
+        0x8D, lo, hi, // STA write_addr
+        0xEA, // NOP
+        0xEA, // NOP
+        0x4C, 0x0C, 0x80, // JMP $800C
+    ]);
+    let path = std::env::temp_dir().join(format!(
+        "emu_nes_trace_{:04X}_{}.trace",
+        watch,
+        std::process::id()
+    ));
+    let trace = Trace::create(&path, TraceStart::Change(u32::from(watch)), 2, None).unwrap();
+    let mut trace = Some(trace);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        cpu.enable_trace(trace.take().unwrap());
+        for _ in 0..8 {
+            cpu.step_instruction();
+        }
+    });
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    contents
+        .lines()
+        .map(|line| String::from(&line[..4]))
+        .collect()
+}
+
+#[test]
+fn test_trace_on_change() {
+    // writing the value that's already there isn't a change
+    assert_eq!(vec!["800A", "800B"], trace_on_change(0x0300, 0x0300));
+    assert_eq!(vec!["800A", "800B"], trace_on_change(0x0300, 0x0B00));
+    assert_eq!(vec!["800A", "800B"], trace_on_change(0x6000, 0x6000));
+    assert!(trace_on_change(0x6001, 0x6000).is_empty());
+}
+
+#[test]
+fn test_console_commands() {
+    let rom = nrom(&[
+        0xA9, 0x42, // LDA #$42
+        0x8D, 0x23, 0x01, // STA $0123
+        0x4C, 0x05, 0x80, // JMP $8005
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8005, &mut |cpu| {
+        let mut state = Vec::new();
+        let mut pause = true;
+        let mut run = |line: &str| execute(line, cpu, &mut state, &mut pause);
+        assert_eq!("42", run("peek 0123"));
+        assert!(run("regs").starts_with("A:42 "));
+        assert!(run("state save").starts_with("saved "));
+        assert_eq!("ok", run("poke $0123 7F"));
+        assert_eq!("7F", run("peek 0x123"));
+        assert_eq!("ok", run("state load"));
+        assert_eq!("42", run("peek 0123"));
+        let disasm = run("disasm 8000 2");
+        let lines = disasm.lines().collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("8000\tA9 42"));
+        assert!(lines[1].starts_with("8002\t8D 23 01"));
+        assert!(run("peek").starts_with("error: "));
+        assert_eq!("ok", run("continue"));
+        assert!(!pause);
+    });
+}
+
+#[test]
+fn test_console_video_memory() {
+    let rom = nrom(&[
+        0x4C, 0x00, 0x80, // JMP $8000
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        let mut state = Vec::new();
+        let mut pause = true;
+        let mut run = |line: &str| execute(line, cpu, &mut state, &mut pause);
+        // nametables are mirrored above $3000, and the sprite palettes' first entries onto the
+        // background's
+        assert_eq!("ok", run("vpoke vram 2405 5A"));
+        assert_eq!("5A", run("vpeek vram 3405"));
+        assert_eq!("ok", run("vpoke vram 3F10 21"));
+        assert_eq!("21", run("vpeek vram 3F00"));
+        assert_eq!("ok", run("vpoke oam FF 99"));
+        assert_eq!("99", run("vpeek oam FF"));
+        assert!(run("vpeek vram 4000").starts_with("error: "));
+        assert!(run("vpoke oam 100 00").starts_with("error: "));
+        assert!(run("vpeek cram 0").starts_with("error: "));
+    });
+}
+
+// a reload boots a new core, but the console it was given stays put, along with any connection
+// to it
+#[test]
+fn test_console_survives_reload() {
+    let port = 20000 + (std::process::id() % 10000) as u16;
+    let mut console = Console::listen(port).unwrap();
+    assert!(Console::listen(port).is_err(), "still bound");
+    let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let mut writer = client.try_clone().unwrap();
+    let mut reader = BufReader::new(client);
+
+    let mut peek_after_boot = |value: u8| {
+        let rom = nrom(&[
+            0xA9, value, // LDA #value
+            0x8D, 0x23, 0x01, // STA $0123
+            0x4C, 0x05, 0x80, // JMP $8005
+        ]);
+        let mut response = String::new();
+        run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8005, &mut |cpu| {
+            writeln!(writer, "peek 0123").unwrap();
+            let mut pause = true;
+            while !response.ends_with('\n') {
+                console.poll(cpu, &mut pause);
+                let _ = reader.read_line(&mut response);
+            }
+        });
+        response
+    };
+    assert_eq!("42\n", peek_after_boot(0x42));
+    assert_eq!("24\n", peek_after_boot(0x24));
+}
+
+#[test]
+fn test_disassemble_xref() {
+    let program = [
+        0x20, 0x08, 0x80, // JSR $8008
+        0xAD, 0x0C, 0x80, // LDA $800C
+        0xD0, 0xFB, // BNE -5
+        0xE8, // INX
+        0x4C, 0x00, 0x80, // JMP $8000
+        0x60, // RTS
+    ];
+    let read_memory = |addr: u16| program[usize::from(addr - 0x8000)];
+    let mut out = Vec::new();
+    emu::nes::cpu::disassembler::disassemble_xref(&read_memory, 0x8000, 0x800C, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    // operands are followed by a space
+    let lines: Vec<_> = out.lines().map(str::trim_end).collect();
+    let after = |line: &str| lines[lines.iter().position(|&l| l == line).unwrap() + 1];
+    assert_eq!("L8000:", after("; jumped to from $8009"));
+    assert_eq!("L8003:", after("; jumped to from $8006"));
+    assert_eq!("L8008:", after("; called from $8000"));
+    assert_eq!("L800C:", after("; read from $8003"));
+    // control flow goes by label, data by address
+    assert!(after("L8000:").ends_with("JSR L8008"));
+    assert!(after("L8003:").ends_with("LDA $800C"));
+    assert!(lines.iter().any(|l| l.ends_with("BNE L8003")));
+    assert!(lines.iter().any(|l| l.ends_with("JMP L8000")));
+}
+
+#[test]
+fn test_power_on_and_reset_registers() {
+    let rom = nrom(&[
+        0xA9, 0x42, // LDA #$42
+        0xA2, 0x12, // LDX #$12
+        0xA0, 0x34, // LDY #$34
+        0x4C, 0x06, 0x80, // JMP $8006
+    ]);
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8000, &mut |cpu| {
+        assert!(cpu
+            .registers()
+            .starts_with("A:00 X:00 Y:00 P:34 SP:FD PC:8000 "));
+    });
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), 0x8006, &mut |cpu| {
+        cpu.reset(true);
+        assert!(cpu
+            .registers()
+            .starts_with("A:42 X:12 Y:34 P:34 SP:FA PC:8000 "));
+        // SP wraps around when soft resets pile up
+        for _ in 0..85 {
+            cpu.reset(true);
+        }
+        assert!(cpu
+            .registers()
+            .starts_with("A:42 X:12 Y:34 P:34 SP:FB PC:8000 "));
+        cpu.reset(false);
+        assert!(cpu
+            .registers()
+            .starts_with("A:00 X:00 Y:00 P:34 SP:FD PC:8000 "));
+    });
+}
+
+// MMC3 image with four 8K PRG banks and eight 1K CHR banks, each filled with its number; the
+// program runs from $E000 and the IRQ handler at $E100 acknowledges and returns
+fn mmc3(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![
+        0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let mut prg: Vec<u8> = (0..0x8000).map(|i| (i / 0x2000) as u8).collect();
+    prg[0x6000..0x6000 + program.len()].copy_from_slice(program);
+    prg[0x6100..0x6104].copy_from_slice(&[
+        0x8D, 0x00, 0xE0, // STA $E000
+        0x40, // RTI
+    ]);
+    prg[0x7FFA..].copy_from_slice(&[0x03, 0xE1, 0x00, 0xE0, 0x00, 0xE1]);
+    rom.extend(prg);
+    rom.extend((0..0x2000).map(|i| (i / 0x400) as u8));
+    rom
+}
+
+// NROM image that copies $0200-$02FF to OAM while a DMC sample is playing
+fn oam_dma_rom(dmc: bool, delay: usize) -> (Vec<u8>, u16, u16) {
+    let mut program = vec![
+        0x78, // SEI
+        0xA2, 0x00, // LDX #$00
+        0x8A, // TXA
+        0x9D, 0x00, 0x02, // STA $0200,X
+        0xE8, // INX
+        0xD0, 0xF9, // BNE -7
+        0xA9, 0x0D, // LDA #$0D
+        0x8D, 0x10, 0x40, // STA $4010
+        0xA9, 0x00, // LDA #$00
+        0x8D, 0x12, 0x40, // STA $4012
+        0xA9, 0x01, // LDA #$01
+        0x8D, 0x13, 0x40, // STA $4013
+        0xA9, if dmc { 0x10 } else { 0x00 }, // LDA #$10
+        0x8D, 0x15, 0x40, // STA $4015
+    ];
+    for _ in 0..delay {
+        program.extend(&[0xA5, 0x00]); // LDA $00
+    }
+    program.extend(&[0xA9, 0x02]); // LDA #$02
+    let dma_pc = 0x8000 + program.len() as u16;
+    program.extend(&[0x8D, 0x14, 0x40]); // STA $4014
+    program.push(0xEA); // NOP
+    let end_pc = 0x8000 + program.len() as u16;
+    program.extend(&[0x4C, end_pc as u8, (end_pc >> 8) as u8]); // JMP end_pc
+    (nrom(&program), dma_pc, end_pc)
+}
@@ -1,10 +1,27 @@
 extern crate emu;
-
+extern crate image;
+
+use image::{Rgb, RgbImage};
+
+use emu::input::{player_1_nes, player_2_nes};
+use emu::nes::cartridge::PpuFetch;
+use emu::nes::Region;
+use emu::record::frames::FrameDumper;
+use emu::rom::RamOverrides;
+use emu::suite;
+use emu::window::frame_hash;
+use emu::window::Cpu;
+use nes_test::nrom;
+use nes_test::nrom_with_chr;
 use nes_test::run_test_to_pc;
+use nes_test::run_test_to_pc_and_inspect;
+use nes_test::run_test_to_pc_without_sprite_limit_and_inspect;
 use nes_test::run_test_until_memory_matches;
 
 mod nes_test;
 
+const WAIT_VBLANK: [u8; 5] = [0x2C, 0x02, 0x20, 0x10, 0xFB]; // BIT $2002; BPL -5
+
 #[test]
 fn test_1_frame_basics_test() {
     run_test_to_pc(
@@ -403,3 +420,429 @@ fn test_sprite_overflow_5_emulator() {
         &[(0xf8, 1)],
     );
 }
+
+#[test]
+fn test_fine_x_scroll() {
+    for &(scroll_x, ref white) in &[
+        (0u8, vec![0..8]),
+        (3, vec![0..5, 253..256]),
+        (8, vec![248..256]),
+        (13, vec![243..251]),
+    ] {
+        let (rom, end_pc) = scroll_rom(scroll_x);
+        run_test_to_pc_and_inspect(&mut rom.as_slice(), end_pc, &mut |cpu| {
+            let (frame, width, _) = cpu.framebuffer();
+            let row = &frame[(100 * width * 3) as usize..(101 * width * 3) as usize];
+            let pixel = |x: usize| &row[x * 3..x * 3 + 3];
+            let (white_pixel, black_pixel) = (pixel(white[0].start), pixel(128));
+            assert_ne!(white_pixel, black_pixel);
+            for x in 0..width as usize {
+                let expected = if white.iter().any(|range| range.contains(&x)) {
+                    white_pixel
+                } else {
+                    black_pixel
+                };
+                assert_eq!(expected, pixel(x), "scroll {} x {}", scroll_x, x);
+            }
+        });
+    }
+}
+
+#[test]
+fn test_axrom_banking_and_mirroring() {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 8, 0, 0x70, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    for bank in 0..4 {
+        rom.extend(vec![bank; 0x8000]);
+    }
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    assert_eq!(0, cartridge.cpu_bus.read_memory(0x8000, 0));
+    for &address in &[0x2000, 0x2400, 0x2800, 0x2C00] {
+        assert_eq!(0x0000, cartridge.ppu_bus.mirror_nametable(address + 0x10));
+    }
+    cartridge.cpu_bus.write_memory(0x8000, 0x12, 0);
+    assert_eq!(2, cartridge.cpu_bus.read_memory(0xFFFF, 0));
+    for &address in &[0x2000, 0x2400, 0x2800, 0x2C00] {
+        assert_eq!(0x0410, cartridge.ppu_bus.mirror_nametable(address + 0x10));
+    }
+    cartridge.cpu_bus.write_memory(0xC000, 0x03, 0);
+    assert_eq!(3, cartridge.cpu_bus.read_memory(0x8000, 0));
+    assert_eq!(0x0010, cartridge.ppu_bus.mirror_nametable(0x2C10));
+}
+
+#[test]
+fn test_mmc5_banking_and_nametables() {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 8, 2, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    for bank in 0..16 {
+        rom.extend(vec![bank; 0x2000]);
+    }
+    for bank in 0..16 {
+        rom.extend(vec![bank; 0x400]);
+    }
+    let mut cartridge =
+        emu::nes::load_cartridge(&mut rom.as_slice(), None, &RamOverrides::default()).unwrap();
+    let cpu_bus = &mut cartridge.cpu_bus;
+    let ppu_bus = &mut cartridge.ppu_bus;
+
+    // powers on in 8K mode with the last bank everywhere
+    assert_eq!(15, cpu_bus.read_memory(0x8000, 0));
+    assert_eq!(15, cpu_bus.read_memory(0xFFFF, 0));
+    cpu_bus.write_memory(0x5114, 0x83, 0);
+    cpu_bus.write_memory(0x5116, 0x85, 0);
+    assert_eq!(3, cpu_bus.read_memory(0x8000, 0));
+    assert_eq!(5, cpu_bus.read_memory(0xC000, 0));
+    cpu_bus.write_memory(0x5100, 0, 0);
+    cpu_bus.write_memory(0x5117, 0x86, 0);
+    assert_eq!(4, cpu_bus.read_memory(0x8000, 0));
+    assert_eq!(7, cpu_bus.read_memory(0xE000, 0));
+
+    // PRG RAM only takes writes once both protect registers are set
+    cpu_bus.write_memory(0x6000, 0x12, 0);
+    assert_eq!(0, cpu_bus.read_memory(0x6000, 0));
+    cpu_bus.write_memory(0x5102, 2, 0);
+    cpu_bus.write_memory(0x5103, 1, 0);
+    cpu_bus.write_memory(0x6000, 0x12, 0);
+    assert_eq!(0x12, cpu_bus.read_memory(0x6000, 0));
+
+    cpu_bus.write_memory(0x5205, 12, 0);
+    cpu_bus.write_memory(0x5206, 34, 0);
+    // 12 * 34 = 0x198
+    assert_eq!(0x98, cpu_bus.read_memory(0x5205, 0));
+    assert_eq!(0x01, cpu_bus.read_memory(0x5206, 0));
+
+    // ExRAM is only readable from the CPU in modes 2 and 3
+    cpu_bus.write_memory(0x5104, 2, 0);
+    cpu_bus.write_memory(0x5C10, 0x34, 0);
+    assert_eq!(0x34, cpu_bus.read_memory(0x5C10, 0));
+    cpu_bus.write_memory(0x5104, 0, 0);
+    assert_eq!(0xAA, cpu_bus.read_memory(0x5C10, 0xAA));
+
+    // 1K CHR banks
+    cpu_bus.write_memory(0x5120, 5, 0);
+    cpu_bus.write_memory(0x5127, 9, 0);
+    assert_eq!(5, ppu_bus.read_memory(0x0000, 0));
+    assert_eq!(9, ppu_bus.read_memory(0x1C00, 0));
+
+    // CIRAM page 0, page 1, ExRAM and fill mode, one per nametable
+    cpu_bus.write_memory(0x5105, 0b11100100, 0);
+    cpu_bus.write_memory(0x5106, 0x42, 0);
+    cpu_bus.write_memory(0x5107, 2, 0);
+    let mut internal_ram = vec![0; 0x800];
+    ppu_bus.write_nametable(0x2005, 1, &mut internal_ram);
+    ppu_bus.write_nametable(0x2405, 2, &mut internal_ram);
+    ppu_bus.write_nametable(0x2805, 3, &mut internal_ram);
+    assert_eq!(1, internal_ram[0x005]);
+    assert_eq!(2, internal_ram[0x405]);
+    assert_eq!(3, ppu_bus.read_nametable(0x2805, &internal_ram));
+    assert_eq!(0x42, ppu_bus.read_nametable(0x2C05, &internal_ram));
+    assert_eq!(0xAA, ppu_bus.read_nametable(0x2FC5, &internal_ram));
+
+    // extended attributes pick each tile's palette and 4K CHR bank
+    cpu_bus.write_memory(0x5104, 1, 0);
+    ppu_bus.ppu_fetch(PpuFetch::Background { scanline: 0 });
+    cpu_bus.write_memory(0x5C00, 0b11000010, 0);
+    cpu_bus.write_memory(0x5C01, 0b01000011, 0);
+    assert_eq!(0, ppu_bus.fetch_nametable(0x2000, &internal_ram));
+    assert_eq!(0xFF, ppu_bus.fetch_nametable(0x23C0, &internal_ram));
+    assert_eq!(8, ppu_bus.read_memory(0x0000, 0));
+    assert_eq!(11, ppu_bus.read_memory(0x1C00, 0));
+    // $2007 reads and peeks don't move on to the next tile
+    assert_eq!(0, ppu_bus.read_nametable(0x2001, &internal_ram));
+    assert_eq!(0, ppu_bus.read_nametable(0x23C0, &internal_ram));
+    assert_eq!(8, ppu_bus.read_memory(0x0000, 0));
+    assert_eq!(0, ppu_bus.fetch_nametable(0x2001, &internal_ram));
+    assert_eq!(0x55, ppu_bus.fetch_nametable(0x23C0, &internal_ram));
+    assert_eq!(12, ppu_bus.read_memory(0x0000, 0));
+
+    // the scanline IRQ, acknowledged by reading the status
+    cpu_bus.write_memory(0x5203, 3, 0);
+    cpu_bus.write_memory(0x5204, 0x80, 0);
+    for scanline in 1..3 {
+        ppu_bus.ppu_fetch(PpuFetch::Background { scanline });
+        assert!(!cpu_bus.irq_interrupt());
+    }
+    ppu_bus.ppu_fetch(PpuFetch::Background { scanline: 3 });
+    assert!(cpu_bus.irq_interrupt());
+    assert_eq!(0xC0, cpu_bus.read_memory(0x5204, 0));
+    assert!(!cpu_bus.irq_interrupt());
+    ppu_bus.ppu_fetch(PpuFetch::Idle);
+    assert_eq!(0, cpu_bus.read_memory(0x5204, 0));
+}
+
+#[test]
+fn test_mid_frame_mask() {
+    let mut program = vec![0x78, 0xD8]; // SEI; CLD
+    program.extend(&WAIT_VBLANK);
+    program.extend(&WAIT_VBLANK);
+    program.extend(&[
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, // $3F00
+        0xA9, 0x21, 0x8D, 0x07, 0x20, // light blue backdrop
+        0xA9, 0x0A, 0x8D, 0x01, 0x20, // show background
+    ]);
+    program.extend(&WAIT_VBLANK);
+    program.extend(&[
+        0xA2, 0x0C, 0x88, 0xD0, 0xFD, 0xCA, 0xD0, 0xFA, // wait until about halfway down
+        0xA9, 0xEB, 0x8D, 0x01, 0x20, // grayscale and all emphasis bits
+    ]);
+    program.extend(&WAIT_VBLANK);
+    let end_pc = 0x8000 + program.len() as u16;
+    program.extend(&[0x4C, end_pc as u8, (end_pc >> 8) as u8]); // JMP end_pc
+    let rom = nrom(&program);
+
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), end_pc, &mut |cpu| {
+        let (frame, width, height) = cpu.framebuffer();
+        let pixel = |x: u32, y: u32| {
+            let offset = ((y * width + x) * 3) as usize;
+            &frame[offset..offset + 3]
+        };
+        assert_ne!(pixel(128, 0), pixel(128, height - 1));
+        let split = (0..height).find(|&y| pixel(128, y) != pixel(128, 0)).unwrap();
+        assert!(split > 60 && split < 180, "split at line {}", split);
+        // the line the write lands on is only partly tinted
+        for y in (0..split - 1).chain(split + 1..height) {
+            let expected = if y < split { pixel(0, 0) } else { pixel(0, height - 1) };
+            for x in 0..width {
+                assert_eq!(expected, pixel(x, y), "x {} y {}", x, y);
+            }
+        }
+    });
+}
+
+// PAL's PPU has the red and green emphasis bits swapped, so its red is NTSC's green
+#[test]
+fn test_pal_emphasis() {
+    let backdrop = |region: Region, mask: u8| {
+        let mut program = vec![0x78, 0xD8]; // SEI; CLD
+        program.extend(&WAIT_VBLANK);
+        program.extend(&WAIT_VBLANK);
+        program.extend(&[
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, // $3F00
+            0xA9, 0x2A, 0x8D, 0x07, 0x20, // green backdrop
+            0xA9, mask, 0x8D, 0x01, 0x20, // emphasis and show background
+        ]);
+        let end_pc = 0x8000 + program.len() as u16;
+        program.extend(&[0x4C, end_pc as u8, (end_pc >> 8) as u8]); // JMP end_pc
+        let rom = nrom(&program);
+        let mut pixel = Vec::new();
+        run_test_to_pc_and_inspect(&mut rom.as_slice(), end_pc, &mut |cpu| {
+            cpu.set_region(region);
+            let inputs = [player_1_nes(), player_2_nes()];
+            for _ in 0..2 {
+                cpu.do_frame_exact(&inputs, false);
+            }
+            pixel = cpu.framebuffer().0[0..3].to_vec();
+        });
+        pixel
+    };
+    let ntsc_red = backdrop(Region::Ntsc, 0x2A);
+    let ntsc_green = backdrop(Region::Ntsc, 0x4A);
+    assert_ne!(ntsc_red, ntsc_green);
+    assert_eq!(ntsc_green, backdrop(Region::Pal, 0x2A));
+    assert_eq!(ntsc_red, backdrop(Region::Pal, 0x4A));
+    assert_eq!(backdrop(Region::Ntsc, 0x8A), backdrop(Region::Pal, 0x8A));
+}
+
+#[test]
+fn test_sprite_priority() {
+    let mut program = vec![0x78, 0xD8]; // SEI; CLD
+    program.extend(&WAIT_VBLANK);
+    program.extend(&WAIT_VBLANK);
+    let mut store = |address: u16, value: u8| {
+        program.extend(&[0xA9, value, 0x8D, address as u8, (address >> 8) as u8]);
+    };
+    // black backdrop, red background, green sprite palette 0, blue sprite palette 1
+    for &(address, color) in &[
+        (0x3F00, 0x0F),
+        (0x3F01, 0x16),
+        (0x3F11, 0x2A),
+        (0x3F15, 0x12),
+    ] {
+        store(0x2006, (address >> 8) as u8);
+        store(0x2006, address as u8);
+        store(0x2007, color);
+    }
+    // solid background tiles in columns 4, 16 and 20 of row 10
+    for &column in &[4, 16, 20] {
+        store(0x2006, 0x21);
+        store(0x2006, 0x40 + column);
+        store(0x2007, 1);
+    }
+    // sprite 0 off screen, then solid sprites on row 10: (x, attributes)
+    store(0x2003, 0);
+    for &(x, attributes) in &[
+        (0, 0),
+        (32, 0x20),  // behind the background, over an opaque pixel
+        (64, 0x20),  // behind the background, over a transparent pixel
+        (96, 0x00),  // in front, over sprite 4
+        (96, 0x01),  // in front, but a lower index wins
+        (128, 0x20), // behind the background, over sprite 6 and an opaque pixel
+        (128, 0x01), // in front, but hidden along with sprite 5
+        (208, 0x01), // alone
+    ] {
+        for &byte in &[if x == 0 { 0xF0 } else { 79 }, 1, attributes, x] {
+            store(0x2004, byte);
+        }
+    }
+    store(0x2000, 0);
+    store(0x2005, 0);
+    store(0x2005, 0);
+    store(0x2001, 0x1E); // show background and sprites
+    program.extend(&WAIT_VBLANK);
+    program.extend(&WAIT_VBLANK);
+    let end_pc = 0x8000 + program.len() as u16;
+    program.extend(&[0x4C, end_pc as u8, (end_pc >> 8) as u8]); // JMP end_pc
+    let rom = nrom_with_chr(&program, &solid_tile_chr());
+
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), end_pc, &mut |cpu| {
+        let (frame, width, _) = cpu.framebuffer();
+        // the middle of a tile in row 10
+        let pixel = |column: u32| {
+            let offset = ((84 * width + column * 8 + 4) * 3) as usize;
+            &frame[offset..offset + 3]
+        };
+        let (black, red, blue) = (pixel(0), pixel(20), pixel(26));
+        let green = pixel(12);
+        for color in &[red, green, blue] {
+            assert_ne!(black, *color);
+        }
+        assert_ne!(red, green);
+        assert_ne!(green, blue);
+        assert_eq!(red, pixel(4));
+        assert_eq!(green, pixel(8));
+        assert_eq!(red, pixel(16));
+    });
+}
+
+// ten solid sprites side by side on row 10, over a black backdrop
+fn sprite_row_rom() -> (Vec<u8>, u16) {
+    let mut program = vec![0x78, 0xD8]; // SEI; CLD
+    program.extend(&WAIT_VBLANK);
+    program.extend(&WAIT_VBLANK);
+    let mut store = |address: u16, value: u8| {
+        program.extend(&[0xA9, value, 0x8D, address as u8, (address >> 8) as u8]);
+    };
+    for &(address, color) in &[(0x3F00, 0x0F), (0x3F11, 0x2A)] {
+        store(0x2006, (address >> 8) as u8);
+        store(0x2006, address as u8);
+        store(0x2007, color);
+    }
+    store(0x2003, 0);
+    for sprite in 0..64 {
+        let (y, x) = if sprite < 10 {
+            (79, 8 + sprite * 16)
+        } else {
+            (0xF0, 0)
+        };
+        for &byte in &[y, 1, 0, x] {
+            store(0x2004, byte);
+        }
+    }
+    store(0x2000, 0);
+    store(0x2005, 0);
+    store(0x2005, 0);
+    store(0x2001, 0x1E); // show background and sprites
+    program.extend(&WAIT_VBLANK);
+    program.extend(&WAIT_VBLANK);
+    let end_pc = 0x8000 + program.len() as u16;
+    program.extend(&[0x4C, end_pc as u8, (end_pc >> 8) as u8]); // JMP end_pc
+    let rom = nrom_with_chr(&program, &solid_tile_chr());
+    (rom, end_pc)
+}
+
+// the middle of each sprite in the row, and whether it was drawn over the backdrop
+fn visible_sprites(cpu: &mut emu::nes::cpu::Cpu) -> Vec<bool> {
+    let (frame, width, _) = cpu.framebuffer();
+    let pixel = |x: u32| {
+        let offset = ((84 * width + x) * 3) as usize;
+        &frame[offset..offset + 3]
+    };
+    let black = pixel(2);
+    (0..10)
+        .map(|sprite| pixel(12 + sprite * 16) != black)
+        .collect()
+}
+
+#[test]
+fn test_sprite_limit() {
+    let (rom, end_pc) = sprite_row_rom();
+    run_test_to_pc_and_inspect(&mut rom.as_slice(), end_pc, &mut |cpu| {
+        let mut expected = vec![true; 8];
+        expected.extend(&[false, false]);
+        assert_eq!(expected, visible_sprites(cpu));
+    });
+    run_test_to_pc_without_sprite_limit_and_inspect(&mut rom.as_slice(), end_pc, &mut |cpu| {
+        assert_eq!(vec![true; 10], visible_sprites(cpu));
+    });
+}
+
+// CHR ROM whose tile 1 is solid color 1
+fn solid_tile_chr() -> Vec<u8> {
+    let mut chr = vec![0; 0x2000];
+    chr[0x10..0x18].copy_from_slice(&[0xFF; 8]);
+    chr
+}
+
+// fills nametable column 0 with a solid tile and renders two frames at the given X scroll
+fn scroll_rom(scroll_x: u8) -> (Vec<u8>, u16) {
+    let mut program = vec![0x78, 0xD8]; // SEI; CLD
+    program.extend(&WAIT_VBLANK);
+    program.extend(&WAIT_VBLANK);
+    program.extend(&[
+        0xA9, 0x04, 0x8D, 0x00, 0x20, // increment by 32
+        0xA9, 0x20, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, // $2000
+        0xA2, 0x1E, 0xA9, 0x01, 0x8D, 0x07, 0x20, 0xCA, 0xD0, 0xFA, // 30 x tile 1
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, // $3F00
+        0xA9, 0x0F, 0x8D, 0x07, 0x20, 0xA9, 0x30, 0x8D, 0x07, 0x20, // black, white
+        0xA9, 0x00, 0x8D, 0x00, 0x20, // nametable 0
+        0x2C, 0x02, 0x20, // reset the write toggle
+        0xA9, scroll_x, 0x8D, 0x05, 0x20, 0xA9, 0x00, 0x8D, 0x05, 0x20,
+        0xA9, 0x0A, 0x8D, 0x01, 0x20, // show background
+    ]);
+    program.extend(&WAIT_VBLANK);
+    program.extend(&WAIT_VBLANK);
+    let end_pc = 0x8000 + program.len() as u16;
+    program.extend(&[0x4C, end_pc as u8, (end_pc >> 8) as u8]); // JMP end_pc
+
+    let rom = nrom_with_chr(&program, &solid_tile_chr());
+    (rom, end_pc)
+}
+
+#[test]
+fn test_frame_dump() {
+    let dir = std::env::temp_dir().join(format!("emu_frame_dump_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut frame_dumper = FrameDumper::new(&dir, 2).unwrap();
+    for shade in 0..3 {
+        frame_dumper.frame(RgbImage::from_pixel(4, 2, Rgb([shade, 0, 0])));
+    }
+    frame_dumper.stop();
+    let frame = image::open(dir.join("frame_00002.png")).unwrap().to_rgb8();
+    assert_eq!((4, 2), frame.dimensions());
+    assert_eq!(Rgb([1, 0, 0]), *frame.get_pixel(3, 1));
+    assert!(dir.join("frame_00001.png").exists());
+    assert!(!dir.join("frame_00003.png").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_run_headless() {
+    assert_eq!(
+        0xBE7A03775164DDBC,
+        frame_hash(&RgbImage::from_pixel(1, 1, Rgb([1, 2, 3])))
+    );
+
+    let rom = include_bytes!("nes_roms/vbl_nmi_timing/1.frame_basics.nes");
+    let run = || {
+        let cartridge =
+            emu::nes::load_cartridge(&mut rom.as_ref(), None, &RamOverrides::default()).unwrap();
+        emu::nes::run_headless(cartridge, 30)
+    };
+    let hashes = run();
+    assert_eq!(30, hashes.len());
+    assert_eq!(hashes, run());
+    // the same hashes as the test suite's .frames files
+    assert_eq!(
+        Ok(vec![hashes[0], hashes[29]]),
+        suite::frame_hashes(rom, &[1, 30])
+    );
+}
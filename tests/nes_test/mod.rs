@@ -1,7 +1,7 @@
 extern crate env_logger;
 extern crate piston_window;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::io::Read;
 
 use emu::input::{player_1_nes, player_2_nes};
@@ -11,6 +11,8 @@ use emu::nes::cartridge;
 use emu::nes::cpu::*;
 use emu::nes::ppu::*;
 use emu::nes::ppu::bus::*;
+use emu::rom::RamOverrides;
+use emu::window::renderer::RenderSettings;
 use emu::window::Cpu as cpuw;
 
 use self::piston_window::*;
@@ -26,6 +28,7 @@ pub fn run_test_to_pc(
         pc_start,
         &mut |cpu| cpu.pc_for_test() == pc_end,
         None,
+        true,
         &mut |cpu| {
             for &(addr, val) in assert {
                 assert_eq!(
@@ -50,6 +53,7 @@ pub fn run_test_to_pc_and_check_accumulator(
         pc_start,
         &mut |cpu| cpu.pc_for_test() == pc_end,
         None,
+        true,
         &mut |cpu| {
             assert_eq!(
                 expected_accumulator,
@@ -61,6 +65,61 @@ pub fn run_test_to_pc_and_check_accumulator(
     );
 }
 
+pub fn run_test_to_pc_and_inspect(
+    rom: &mut dyn Read,
+    pc_end: u16,
+    assert: &mut dyn FnMut(&mut Cpu),
+) {
+    run_test(
+        rom,
+        None,
+        &mut |cpu| cpu.pc_for_test() == pc_end,
+        None,
+        true,
+        assert,
+    );
+}
+
+pub fn run_test_to_pc_without_sprite_limit_and_inspect(
+    rom: &mut dyn Read,
+    pc_end: u16,
+    assert: &mut dyn FnMut(&mut Cpu),
+) {
+    run_test(
+        rom,
+        None,
+        &mut |cpu| cpu.pc_for_test() == pc_end,
+        None,
+        false,
+        assert,
+    );
+}
+
+pub fn run_test_to_pc_counting_cycles(
+    rom: &mut dyn Read,
+    pc_count_from: u16,
+    pc_end: u16,
+    assert: &mut dyn FnMut(&mut Cpu, u64),
+) {
+    let start_cycle = Cell::new(None);
+    run_test(
+        rom,
+        None,
+        &mut |cpu| {
+            if start_cycle.get().is_none() && cpu.pc_for_test() == pc_count_from {
+                start_cycle.set(Some(cpu.cycle_count_for_test()));
+            }
+            cpu.pc_for_test() == pc_end
+        },
+        None,
+        true,
+        &mut |cpu| {
+            let cycles = cpu.cycle_count_for_test() - start_cycle.get().unwrap();
+            assert(cpu, cycles)
+        },
+    );
+}
+
 pub fn run_test_to_success_or_fail_pc(
     rom: &mut dyn Read,
     pc_start: Option<u16>,
@@ -73,6 +132,7 @@ pub fn run_test_to_success_or_fail_pc(
         pc_start,
         &mut |cpu| cpu.pc_for_test() == pc_success || cpu.pc_for_test() == pc_fail,
         None,
+        true,
         &mut |cpu| {
             if cpu.pc_for_test() == pc_fail {
                 assert_eq!(0, cpu.read_memory_no_tick(error_code_addr));
@@ -105,6 +165,7 @@ pub fn run_test_until_memory_matches(
             status != running_status && status != reset_status
         },
         Some((status_addr, running_status, reset_status)),
+        true,
         &mut |cpu| {
             for &(addr, val) in assert {
                 assert_eq!(
@@ -123,14 +184,24 @@ fn run_test(
     pc_start: Option<u16>,
     terminate_condition: &mut dyn FnMut(&mut Cpu) -> bool,
     status: Option<(u16, u8, u8)>,
+    sprite_limit: bool,
     assert: &mut dyn FnMut(&mut Cpu),
 ) {
     let _ = env_logger::try_init();
     let ppu_bus = RefCell::new(PpuBus::new());
     let apu_bus = RefCell::new(ApuBus::new());
-    let mut cartridge = cartridge::read(rom, None).unwrap();
-    let ppu = Ppu::new::<NoWindow>(&mut cartridge.ppu_bus, &ppu_bus, None, true);
-    let apu = Apu::new(&apu_bus, None).unwrap();
+    let mut cartridge = cartridge::read(rom, None, &RamOverrides::default()).unwrap();
+    let mut ppu = Ppu::new::<NoWindow>(
+        &mut cartridge.ppu_bus,
+        &ppu_bus,
+        None,
+        RenderSettings::default(),
+        true,
+    );
+    if !sprite_limit {
+        ppu.disable_sprite_limit();
+    }
+    let apu = Apu::new(&apu_bus, Vec::new());
     let mut cpu = Cpu::boot(&mut cartridge.cpu_bus, ppu, &ppu_bus, apu, &apu_bus, true);
     let inputs = [player_1_nes(), player_2_nes()];
 
@@ -142,6 +213,7 @@ fn run_test(
     let mut did_reset = false;
     while !terminate_condition(&mut cpu) {
         cpu.next_operation(&inputs);
+        assert!(!cpu.jammed(), "CPU jammed at {:04X}", cpu.pc_for_test());
         if let Some((addr, running, reset)) = status {
             if reset_delay > 0 {
                 reset_delay -= 1;
@@ -159,3 +231,18 @@ fn run_test(
 
     assert(&mut cpu);
 }
+
+// NROM image with the program at $8000, where all three vectors point, and blank CHR ROM
+pub fn nrom(program: &[u8]) -> Vec<u8> {
+    nrom_with_chr(program, &[0; 0x2000])
+}
+
+pub fn nrom_with_chr(program: &[u8], chr: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0xEA; 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+    rom.extend(prg);
+    rom.extend(chr);
+    rom
+}
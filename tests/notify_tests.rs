@@ -0,0 +1,40 @@
+extern crate emu;
+
+use std::time::{Duration, Instant};
+
+use emu::window::notify::Notifications;
+
+#[test]
+fn notifications_stack_and_fade() {
+    let mut notifications = Notifications::new();
+    notifications.notify("first", Duration::from_secs(1));
+    notifications.notify("second", Duration::from_secs(3));
+    let start = Instant::now();
+    assert_eq!(
+        vec![("first", 1.0), ("second", 1.0)],
+        notifications.visible(start)
+    );
+
+    // halfway through its last half second
+    let visible = notifications.visible(start + Duration::from_millis(750));
+    assert_eq!("first", visible[0].0);
+    let alpha = visible[0].1;
+    assert!(alpha > 0.0 && alpha <= 0.5, "{}", alpha);
+    assert_eq!(("second", 1.0), visible[1]);
+
+    assert_eq!(
+        vec![("second", 1.0)],
+        notifications.visible(start + Duration::from_secs(2))
+    );
+    assert!(notifications
+        .visible(start + Duration::from_secs(4))
+        .is_empty());
+
+    // only the newest few are kept
+    for i in 0..10 {
+        notifications.notify(&i.to_string(), Duration::from_secs(1));
+    }
+    let visible = notifications.visible(Instant::now());
+    assert_eq!(5, visible.len());
+    assert_eq!("9", visible[4].0);
+}
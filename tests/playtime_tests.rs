@@ -0,0 +1,41 @@
+extern crate emu;
+
+use std::fs;
+use std::time::Duration;
+
+use emu::playtime::{format_play_time, PlayTime};
+
+#[test]
+fn play_time_per_rom() {
+    let path = std::env::temp_dir().join(format!("emu_playtime_{}.txt", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut first = PlayTime::load(&path, b"first");
+    first.add(Duration::from_secs(59));
+    // nothing is written until a minute has built up
+    assert!(!path.exists());
+    first.add(Duration::from_millis(1500));
+    assert!(path.exists());
+    assert_eq!(Duration::from_secs(60), PlayTime::load(&path, b"first").total());
+
+    let mut second = PlayTime::load(&path, b"second");
+    assert_eq!(Duration::ZERO, second.total());
+    second.add(Duration::from_secs(3725));
+    second.flush();
+    first.flush();
+    assert_eq!(Duration::from_secs(60), PlayTime::load(&path, b"first").total());
+    let second = PlayTime::load(&path, b"second");
+    assert_eq!("1:02:05", format_play_time(second.total()));
+
+    // time only counts while playing
+    let mut third = PlayTime::load(&path, b"third");
+    third.update(false);
+    std::thread::sleep(Duration::from_millis(20));
+    third.update(false);
+    assert_eq!(Duration::ZERO, third.total());
+    std::thread::sleep(Duration::from_millis(20));
+    third.update(true);
+    assert!(third.total() >= Duration::from_millis(20));
+
+    fs::remove_file(&path).unwrap();
+}
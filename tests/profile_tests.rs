@@ -0,0 +1,43 @@
+extern crate emu;
+
+use emu::profile::Profile;
+
+#[allow(dead_code)]
+#[derive(Debug)]
+enum Opcode {
+    LDA(u8),
+    NOP,
+    STA { addr: u16 },
+}
+
+#[test]
+fn counts_by_mnemonic() {
+    let mut profile = Profile::new();
+    let mut cycle = 0;
+    for (opcode, cycles) in [
+        (Opcode::LDA(1), 2),
+        (Opcode::STA { addr: 0x200 }, 4),
+        (Opcode::LDA(2), 2),
+        (Opcode::NOP, 2),
+        (Opcode::STA { addr: 0x201 }, 4),
+    ] {
+        profile.begin(&opcode, cycle);
+        cycle += cycles;
+        profile.end(cycle);
+    }
+    // operands don't matter, and the most cycles come first
+    assert_eq!(
+        vec![("STA", 2, 8), ("LDA", 2, 4), ("NOP", 1, 2)],
+        profile.rows()
+    );
+
+    // an end without a begin, like after an interrupt, isn't counted
+    profile.end(cycle + 100);
+    assert_eq!(("STA", 2, 8), profile.rows()[0]);
+
+    let mut out = Vec::new();
+    profile.write("test", &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("test\n"));
+    assert!(out.contains("STA"));
+}
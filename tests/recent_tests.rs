@@ -0,0 +1,42 @@
+extern crate emu;
+
+use std::fs;
+
+use emu::recent::{RecentFiles, MAX_RECENT};
+
+#[test]
+fn recent_files() {
+    let dir = std::env::temp_dir().join(format!("emu_recent_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("recent.txt");
+    let roms: Vec<_> = (0..MAX_RECENT + 2)
+        .map(|i| {
+            let rom = dir.join(format!("game {}.nes", i));
+            fs::write(&rom, b"rom").unwrap();
+            rom.canonicalize().unwrap()
+        })
+        .collect();
+
+    assert!(RecentFiles::load(&path).last().is_none());
+    let mut recent = RecentFiles::load(&path);
+    for rom in roms.iter() {
+        recent.add(rom);
+    }
+    // opening one again moves it to the top rather than listing it twice
+    recent.add(&roms[5]);
+    let recent = RecentFiles::load(&path);
+    let entries = recent.entries();
+    assert_eq!(MAX_RECENT, entries.len());
+    assert_eq!(roms[5], entries[0].1);
+    assert_eq!(roms[MAX_RECENT + 1], entries[1].1);
+    assert!(!entries
+        .iter()
+        .any(|&(_, rom)| rom == roms[0] || rom == roms[1]));
+
+    // files that have gone are skipped
+    fs::remove_file(&roms[5]).unwrap();
+    assert_eq!(Some(roms[MAX_RECENT + 1].as_path()), recent.last());
+    assert_eq!(MAX_RECENT - 1, recent.entries().len());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,44 @@
+extern crate emu;
+
+use std::io::Cursor;
+
+use emu::input::script::{parse_line, InputScript};
+use emu::input::{player_1_nes, player_2_nes};
+
+#[test]
+fn parse_script_lines() {
+    assert_eq!(Ok([0x09, 0x00]), parse_line("09\n"));
+    assert_eq!(Ok([0x80, 0x41]), parse_line("0x80 41"));
+    assert_eq!(Ok([0, 0]), parse_line("  \n"));
+    assert!(parse_line("1G").is_err());
+    assert!(parse_line("100").is_err());
+    assert!(parse_line("01 02 03").is_err());
+}
+
+#[test]
+fn script_frames_and_end() {
+    for &exit_on_eof in &[false, true] {
+        let script = "01\n# comment\n\n02 80\n";
+        let mut script = InputScript::new(Box::new(Cursor::new(script)), exit_on_eof);
+        let mut inputs = [player_1_nes(), player_2_nes()];
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            let running = script.next_frame(&mut inputs);
+            frames.push((running, inputs[0].to_u8(), inputs[1].to_u8()));
+        }
+        assert_eq!(
+            vec![
+                (true, 0x01, 0x00),
+                (true, 0x00, 0x00),
+                (true, 0x02, 0x80),
+                (!exit_on_eof, 0x00, 0x00),
+            ],
+            frames
+        );
+    }
+
+    let mut script = InputScript::new(Box::new(Cursor::new("01\nxyz\n")), false);
+    let mut inputs = [player_1_nes(), player_2_nes()];
+    assert!(script.next_frame(&mut inputs));
+    assert!(!script.next_frame(&mut inputs));
+}
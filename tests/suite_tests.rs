@@ -0,0 +1,71 @@
+extern crate emu;
+extern crate json;
+
+use std::fs;
+
+use emu::suite;
+
+#[test]
+fn test_suite_reports_failures() {
+    let dir = std::env::temp_dir().join(format!("emu_suite_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("m68k")).unwrap();
+    fs::write(
+        dir.join("cpu_exec_space_ppuio.nes"),
+        include_bytes!("nes_roms/cpu_exec_space/test_cpu_exec_space_ppuio.nes").as_ref(),
+    )
+    .unwrap();
+    fs::write(
+        dir.join("prelim.com"),
+        include_bytes!("z80/prelim.com").as_ref(),
+    )
+    .unwrap();
+    fs::write(dir.join("m68k/swap.json"), include_str!("m68k/swap.json")).unwrap();
+    fs::write(dir.join("README.md"), "not a test").unwrap();
+    assert!(suite::run(&dir, false));
+
+    let mut test_cases = json::parse(include_str!("m68k/swap.json")).unwrap();
+    let pc = test_cases[0]["final state"]["pc"].as_u32().unwrap();
+    test_cases[0]["final state"]["pc"] = (pc + 2).into();
+    fs::write(dir.join("m68k/swap_broken.json"), test_cases.dump()).unwrap();
+    assert!(!suite::run(&dir, false));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_frame_hashes() {
+    let dir = std::env::temp_dir().join(format!("emu_frames_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let rom = include_bytes!("nes_roms/cpu_exec_space/test_cpu_exec_space_ppuio.nes");
+    fs::write(dir.join("ppuio.nes"), rom.as_ref()).unwrap();
+
+    // frames listed without hashes fail until blessed
+    fs::write(dir.join("ppuio.frames"), "# checkpoints\n1\n\n30\n").unwrap();
+    assert!(!suite::run(&dir, false));
+    assert!(suite::run(&dir, true));
+    let golden = fs::read_to_string(dir.join("ppuio.frames")).unwrap();
+    let hashes = suite::frame_hashes(rom, &[1, 30]).unwrap();
+    assert_eq!(
+        format!("1 {:016X}\n30 {:016X}\n", hashes[0], hashes[1]),
+        golden
+    );
+    assert!(suite::run(&dir, false));
+
+    let golden = suite::parse_frames(&golden).unwrap();
+    let mut changed = hashes.clone();
+    changed[1] ^= 1;
+    assert_eq!(
+        Err(format!(
+            "frame 30 differs: {:016X}, expected {:016X}",
+            changed[1], hashes[1]
+        )),
+        suite::compare_frames(&golden, &changed)
+    );
+    assert!(suite::parse_frames("30\n1\n").is_err());
+    assert!(suite::parse_frames("1 XYZ\n").is_err());
+    assert!(suite::parse_frames("0\n").is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
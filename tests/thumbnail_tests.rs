@@ -0,0 +1,34 @@
+extern crate emu;
+extern crate image;
+
+use image::{Rgb, RgbImage};
+
+use emu::control::thumbnail::{read_thumbnail, skip_thumbnail, write_thumbnail};
+
+#[test]
+fn thumbnail_header() {
+    // red on the left half, blue on the right
+    let frame = RgbImage::from_fn(256, 240, |x, _| {
+        if x < 128 {
+            Rgb([255, 0, 0])
+        } else {
+            Rgb([0, 0, 255])
+        }
+    });
+    let mut state = Vec::new();
+    write_thumbnail(&frame, &mut state);
+    assert_eq!(8 + 64 * 60 * 3, state.len());
+    state.extend(&[1, 2, 3]);
+
+    let thumbnail = read_thumbnail(&state).unwrap();
+    assert_eq!((64, 60), thumbnail.dimensions());
+    assert_eq!(Rgb([255, 0, 0]), *thumbnail.get_pixel(0, 30));
+    assert_eq!(Rgb([0, 0, 255]), *thumbnail.get_pixel(63, 30));
+    assert_eq!(&[1, 2, 3], skip_thumbnail(&state));
+
+    // states saved without one, or cut short, load as they are
+    for state in [&[1u8, 2, 3][..], &state[..100]] {
+        assert!(read_thumbnail(state).is_none());
+        assert_eq!(state, skip_thumbnail(state));
+    }
+}
@@ -0,0 +1,58 @@
+extern crate emu;
+
+use std::fs;
+
+use emu::trace::{Trace, TraceStart};
+
+fn run_trace(
+    start: TraceStart,
+    count: u32,
+    until: Option<u32>,
+    pcs: &[u32],
+) -> (Vec<bool>, String) {
+    let path = std::env::temp_dir().join(format!("emu_trace_test_{:?}.trace", start));
+    let mut trace = Trace::create(&path, start, count, until).unwrap();
+    let mut traced = Vec::new();
+    for &pc in pcs {
+        let tracing = trace.instruction(pc);
+        if tracing {
+            trace.line(&format!("{:04X}", pc));
+        }
+        traced.push(tracing);
+        // the instruction at 0x200 changes the watched address
+        if let TraceStart::Change(addr) = start {
+            if pc == 0x200 && trace.awaited_change() == Some(addr) {
+                trace.begin();
+            }
+        }
+    }
+    drop(trace);
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    (traced, contents)
+}
+
+#[test]
+fn test_trace_from_pc_for_count() {
+    let (traced, contents) = run_trace(
+        TraceStart::Pc(0x8000),
+        3,
+        None,
+        &[0x7000, 0x8000, 0x8002, 0x8004, 0x8006, 0x8000],
+    );
+    assert_eq!(vec![false, true, true, true, false, false], traced);
+    assert_eq!("8000\n8002\n8004\n", contents);
+}
+
+#[test]
+fn test_trace_on_change_until_pc() {
+    // tracing starts with the instruction after the one that made the change
+    let (traced, contents) = run_trace(
+        TraceStart::Change(0xFF0100),
+        100,
+        Some(0x304),
+        &[0x200, 0x300, 0x302, 0x304, 0x306],
+    );
+    assert_eq!(vec![false, true, true, true, false], traced);
+    assert_eq!("0300\n0302\n0304\n", contents);
+}